@@ -15,15 +15,16 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use anyhow::{Context, Result};
-use bitpart_common::socket::SocketMessage;
+use bitpart_common::{error::FlowError, socket::SocketMessage};
 use clap::{Parser, Subcommand};
 use clap_verbosity_flag::Verbosity;
 use futures_util::{Sink, SinkExt, StreamExt};
 use http::HeaderValue;
-use serde_json::json;
+use serde_json::{Value, json};
 use similar::{ChangeTag, TextDiff};
 use std::io;
 use std::{fs, marker::Unpin, path::PathBuf};
+use tokio::sync::mpsc;
 use tokio_tungstenite::{
     connect_async,
     tungstenite::client::IntoClientRequest,
@@ -159,6 +160,18 @@ enum Commands {
         id: String,
     },
 
+    /// show one version of a bot
+    #[command(arg_required_else_help = true)]
+    Version {
+        /// Bot ID
+        #[arg(short, long)]
+        id: String,
+
+        /// Version to show
+        #[arg(long)]
+        version_id: String,
+    },
+
     /// list bots
     #[command()]
     List {},
@@ -175,6 +188,22 @@ enum Commands {
         version_id: String,
     },
 
+    /// show the messages exchanged with a user, oldest first
+    #[command(arg_required_else_help = true)]
+    History {
+        /// Bot ID
+        #[arg(short, long)]
+        bot_id: String,
+
+        /// User ID
+        #[arg(short, long)]
+        user_id: String,
+
+        /// Channel ID
+        #[arg(short, long)]
+        channel_id: String,
+    },
+
     /// talk to a bot
     #[command(arg_required_else_help = true)]
     Talk {
@@ -238,6 +267,9 @@ async fn main() -> Result<()> {
     };
 
     let (mut sender, mut receiver) = ws_stream.split();
+    // The request id of each chat reply, or None for an error, so that `Talk`
+    // can wait for the answer to a line before reading the next one
+    let (replied_tx, mut replied_rx) = mpsc::unbounded_channel::<Option<String>>();
     match args.command {
         Commands::Add {
             default: default_flow,
@@ -363,6 +395,18 @@ async fn main() -> Result<()> {
             send(&mut sender, &req).await?;
             hangup(&mut sender).await?;
         }
+        Commands::Version { id, version_id } => {
+            let req = json!({"message_type": "ReadBotVersion",
+                "data" : {
+                    "id": id,
+                    "version_id": version_id
+                }
+            });
+            debug!("Request: {:?}", req.to_string());
+
+            send(&mut sender, &req).await?;
+            hangup(&mut sender).await?;
+        }
         Commands::Rollback { id, version_id } => {
             let req = json!({"message_type": "RollbackBot",
                 "data" : {
@@ -375,27 +419,45 @@ async fn main() -> Result<()> {
             send(&mut sender, &req).await?;
             hangup(&mut sender).await?;
         }
+        Commands::History {
+            bot_id,
+            user_id,
+            channel_id,
+        } => {
+            let req = json!({"message_type": "GetMessages",
+                "data" : {
+                    "client": {
+                        "bot_id": bot_id,
+                        "user_id": user_id,
+                        "channel_id": channel_id
+                    }
+                }
+            });
+            debug!("Request: {:?}", req.to_string());
+
+            send(&mut sender, &req).await?;
+            hangup(&mut sender).await?;
+        }
         Commands::Talk { id } => {
             println!("Type 'q' to quit");
             tokio::spawn(async move {
                 let mut buffer = String::new();
                 loop {
                     buffer.clear();
-                    io::stdin()
+                    let read = io::stdin()
                         .read_line(&mut buffer)
                         .expect("Failed to read line");
 
-                    if buffer == "q\n" {
+                    if read == 0 || buffer == "q\n" {
                         break;
                     };
 
+                    let request_id = uuid::Uuid::new_v4().to_string();
                     let req = json!({ "message_type": "ChatRequest",
                         "data" : {
                         "bot_id": id,
-                        "apps_endpoint": "http://localhost",
-                        "multibot": serde_json::Value::Null,
                         "event": {
-                            "id": uuid::Uuid::new_v4().to_string(),
+                            "id": request_id,
                             "client": {
                                 "user_id": "cli",
                                 "channel_id": "cli",
@@ -411,6 +473,15 @@ async fn main() -> Result<()> {
                         }
                     }});
                     send(&mut sender, &req).await.unwrap();
+                    // don't read the next line until this one is answered
+                    while let Some(replied) = replied_rx.recv().await {
+                        match replied {
+                            Some(replied) if replied != request_id => {
+                                debug!("Skipping reply to earlier request {}", replied)
+                            }
+                            _ => break,
+                        }
+                    }
                 }
                 hangup(&mut sender).await.unwrap();
             });
@@ -462,12 +533,27 @@ async fn main() -> Result<()> {
                                     .iter()
                                     .for_each(|v| println!("{}", v.get("version_id").unwrap()));
                             }
+                            res_type if res_type == "ReadBotVersion" => {
+                                match res.response.get("bot") {
+                                    Some(bot) => println!(
+                                        "{}",
+                                        unescaper::unescape(
+                                            &serde_json::to_string_pretty(bot).unwrap(),
+                                        )
+                                        .unwrap()
+                                    ),
+                                    None => println!("No such version"),
+                                }
+                            }
                             res_type if res_type == "RollbackBot" => {
-                                println!(
-                                    "Rolled back bot {} to version {}",
-                                    res.response.get("bot").and_then(|v| v.get("id")).unwrap(),
-                                    res.response.get("version_id").unwrap()
-                                );
+                                match res.response.get("version_id") {
+                                    Some(version_id) => println!(
+                                        "Rolled back bot {} as new version {}",
+                                        res.response.get("bot").and_then(|v| v.get("id")).unwrap(),
+                                        version_id
+                                    ),
+                                    None => println!("No such version"),
+                                }
                             }
                             res_type if res_type == "DiffBot" => {
                                 let array = res.response.as_array().unwrap();
@@ -505,9 +591,10 @@ async fn main() -> Result<()> {
                             res_type if res_type == "ListChannels" => {
                                 res.response.as_array().unwrap().iter().for_each(|v| {
                                     println!(
-                                        "Channel: {}  for Bot: {}",
+                                        "Channel: {}  for Bot: {}  last heard at: {}",
                                         v.get("channel_id").unwrap(),
                                         v.get("bot_id").unwrap(),
+                                        v.get("last_received_at").unwrap_or(&Value::Null),
                                     )
                                 });
                             }
@@ -521,6 +608,29 @@ async fn main() -> Result<()> {
                                 let _ = qr2term::print_qr(res.response.to_string());
                                 println!("{}", res.response);
                             }
+                            res_type if res_type == "GetMessages" => {
+                                res.response.as_array().unwrap().iter().for_each(|msg| {
+                                    let payload = msg.get("payload").unwrap_or(&Value::Null);
+                                    let text = match payload
+                                        .get("content")
+                                        .and_then(|v| v.get("text"))
+                                        .and_then(|v| v.as_str())
+                                    {
+                                        Some(text) => text.to_owned(),
+                                        None => payload.to_string(),
+                                    };
+                                    println!(
+                                        "{} {:<7} {}",
+                                        msg.get("created_at")
+                                            .and_then(|v| v.as_str())
+                                            .unwrap_or_default(),
+                                        msg.get("direction")
+                                            .and_then(|v| v.as_str())
+                                            .unwrap_or_default(),
+                                        text
+                                    )
+                                });
+                            }
                             res_type if res_type == "ChatRequest" => {
                                 res.response
                                     .get("messages")
@@ -554,13 +664,23 @@ async fn main() -> Result<()> {
                                             ),
                                         }
                                     });
+                                let request_id = res
+                                    .response
+                                    .get("request_id")
+                                    .and_then(|v| v.as_str())
+                                    .unwrap_or_default();
+                                let _ = replied_tx.send(Some(request_id.to_owned()));
                             }
                             _ => {
                                 error!("Unrecognized message response: {:?}", res.response);
                             }
                         },
                         SocketMessage::Error(res) => {
-                            println!("{}", res.response);
+                            match serde_json::from_value::<Vec<FlowError>>(res.response.clone()) {
+                                Ok(errors) => errors.iter().for_each(|e| println!("{}", e)),
+                                Err(_) => println!("{}", res.response),
+                            }
+                            let _ = replied_tx.send(None);
                         }
                         _ => {
                             println!("Wrong socket message type")
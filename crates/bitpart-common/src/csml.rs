@@ -38,6 +38,14 @@ pub struct Request {
     pub apps_endpoint: Option<String>,
     pub multibot: Option<Vec<MultiBot>>,
     pub event: SerializedEvent,
+    /// Requests repeated with the same key get the first one's response
+    /// instead of being handled again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// Handle the request without writing anything to the database, see
+    /// `api::request::process_request`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
 }
 
 impl TryInto<BotOpt> for Request {
@@ -168,7 +176,7 @@ fn request_to_event(request: &SerializedEvent) -> Result<Event, BitpartError> {
         ttl_duration: json_event["ttl_duration"].as_i64(),
         low_data_mode: json_event["low_data_mode"].as_bool(),
         step_limit,
-        secure: json_event["payload"]["secure"].as_bool().unwrap_or(false),
+        secure: request.is_secure(),
     })
 }
 
@@ -177,9 +185,24 @@ pub struct SerializedEvent {
     pub id: String,
     pub client: Client,
     pub metadata: serde_json::Value,
+    /// The event's `content_type` and `content`, and optionally `secure`
     pub payload: serde_json::Value,
     pub step_limit: Option<usize>,
     pub callback_url: Option<String>,
+    /// Days until the conversation and its data expire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_duration: Option<i64>,
+    /// Whether nothing from the conversation is written to the `message` table
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub low_data_mode: Option<bool>,
+}
+
+impl SerializedEvent {
+    /// Whether the payload is marked `"secure": true`. A secure event, and
+    /// the rest of its conversation, is never persisted verbatim.
+    pub fn is_secure(&self) -> bool {
+        self.payload["secure"].as_bool().unwrap_or(false)
+    }
 }
 
 impl TryFrom<&SerializedEvent> for Event {
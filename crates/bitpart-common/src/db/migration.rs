@@ -24,10 +24,31 @@ use crate::error::{BitpartErrorKind, Result};
 
 const SCHEMA_V1: &str = include_str!("schema.sql");
 const SCHEMA_V2: &str = include_str!("schema_v2.sql");
+const SCHEMA_V3: &str = include_str!("schema_v3.sql");
+const SCHEMA_V4: &str = include_str!("schema_v4.sql");
+const SCHEMA_V5: &str = include_str!("schema_v5.sql");
+const SCHEMA_V6: &str = include_str!("schema_v6.sql");
+const SCHEMA_V7: &str = include_str!("schema_v7.sql");
+const SCHEMA_V8: &str = include_str!("schema_v8.sql");
+const SCHEMA_V9: &str = include_str!("schema_v9.sql");
+const SCHEMA_V10: &str = include_str!("schema_v10.sql");
 
 fn migrations() -> &'static Migrations<'static> {
     static MIGRATIONS: OnceLock<Migrations<'static>> = OnceLock::new();
-    MIGRATIONS.get_or_init(|| Migrations::new(vec![M::up(SCHEMA_V1), M::up(SCHEMA_V2)]))
+    MIGRATIONS.get_or_init(|| {
+        Migrations::new(vec![
+            M::up(SCHEMA_V1),
+            M::up(SCHEMA_V2),
+            M::up(SCHEMA_V3),
+            M::up(SCHEMA_V4),
+            M::up(SCHEMA_V5),
+            M::up(SCHEMA_V6),
+            M::up(SCHEMA_V7),
+            M::up(SCHEMA_V8),
+            M::up(SCHEMA_V9),
+            M::up(SCHEMA_V10),
+        ])
+    })
 }
 
 pub fn migrate_conn(conn: &mut Connection) -> Result<()> {
@@ -621,20 +642,22 @@ fn insert_message(
 mod tests {
     use super::*;
 
+    const LATEST_VERSION: i64 = 10;
+
     #[test]
     fn schema_parses() {
         migrations().validate().expect("schema.sql is valid");
     }
 
     #[test]
-    fn fresh_db_initialises_to_v2() {
+    fn fresh_db_initialises_to_latest() {
         let mut conn = Connection::open_in_memory().unwrap();
         migrate_conn(&mut conn).unwrap();
 
         let v: i64 = conn
             .pragma_query_value(None, "user_version", |r| r.get(0))
             .unwrap();
-        assert_eq!(v, 2);
+        assert_eq!(v, LATEST_VERSION);
 
         let table_count: i64 = conn
             .query_row(
@@ -643,7 +666,7 @@ mod tests {
                 |r| r.get(0),
             )
             .unwrap();
-        assert_eq!(table_count, 28);
+        assert_eq!(table_count, 30);
 
         let channel_state_exists: bool = conn
             .query_row(
@@ -659,6 +682,46 @@ mod tests {
         assert!(!channel_state_exists);
     }
 
+    #[test]
+    fn moves_callback_secrets_out_of_bot_env() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations().to_version(&mut conn, 9).unwrap();
+        let bot =
+            |env: &str| serde_json::json!({"id": "bot_id", "name": "test", "env": env}).to_string();
+        conn.execute(
+            "INSERT INTO bot (id, bot_id, bot, engine_version) VALUES ('signed', 'bot_id', ?1, '0.1.0')",
+            [bot(r#"{"callback_secret":"whsec_test","ttl_duration":3}"#)],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO bot (id, bot_id, bot, engine_version) VALUES ('unsigned', 'bot_id', ?1, '0.1.0')",
+            [bot(r#"{"ttl_duration":3}"#)],
+        )
+        .unwrap();
+
+        migrate_conn(&mut conn).unwrap();
+
+        let row = |id: &str| -> (Option<String>, serde_json::Value) {
+            conn.query_row(
+                "SELECT callback_secret, bot FROM bot WHERE id = ?1",
+                [id],
+                |r| Ok((r.get(0)?, r.get::<_, String>(1)?)),
+            )
+            .map(|(secret, bot)| {
+                let bot: serde_json::Value = serde_json::from_str(&bot).unwrap();
+                let env = serde_json::from_str(bot["env"].as_str().unwrap()).unwrap();
+                (secret, env)
+            })
+            .unwrap()
+        };
+        let (secret, env) = row("signed");
+        assert_eq!(secret.as_deref(), Some("whsec_test"));
+        assert_eq!(env, serde_json::json!({"ttl_duration": 3}));
+        let (secret, env) = row("unsigned");
+        assert_eq!(secret, None);
+        assert_eq!(env, serde_json::json!({"ttl_duration": 3}));
+    }
+
     #[test]
     fn migrator_is_idempotent_v2() {
         let mut conn = Connection::open_in_memory().unwrap();
@@ -668,7 +731,7 @@ mod tests {
         let v1: i64 = conn
             .pragma_query_value(None, "user_version", |r| r.get(0))
             .unwrap();
-        assert_eq!(v1, 2);
+        assert_eq!(v1, LATEST_VERSION);
 
         let table_count_1: i64 = conn
             .query_row(
@@ -689,8 +752,8 @@ mod tests {
             .pragma_query_value(None, "user_version", |r| r.get(0))
             .unwrap();
         assert_eq!(
-            v2, 2,
-            "user_version should stay 2 after idempotent migration"
+            v2, LATEST_VERSION,
+            "user_version should stay at the latest version after idempotent migration"
         );
 
         let table_count_2: i64 = conn
@@ -774,7 +837,7 @@ mod tests {
         let v: i64 = conn
             .pragma_query_value(None, "user_version", |r| r.get(0))
             .unwrap();
-        assert_eq!(v, 2);
+        assert_eq!(v, LATEST_VERSION);
 
         let marker_exists: bool = conn
             .query_row(
@@ -963,7 +1026,7 @@ mod tests {
         let v: i64 = conn
             .pragma_query_value(None, "user_version", |r| r.get(0))
             .unwrap();
-        assert_eq!(v, 2);
+        assert_eq!(v, LATEST_VERSION);
 
         let channel_state_exists: bool = conn
             .query_row(
@@ -0,0 +1,22 @@
+-- Bitpart schema, version 10. Do not edit in place; add a new migration
+-- in `bitpart_common::db::migration` and bump the version.
+
+-- The secret a bot version's callbacks are signed with, kept out of the bot's
+-- `env` so that it isn't returned with the bot or visible to its flows
+ALTER TABLE "bot" ADD COLUMN "callback_secret" varchar;
+
+-- Move secrets already stored in a bot's `env`, which is itself a JSON
+-- string inside the bot, into the new column
+UPDATE "bot"
+SET "callback_secret" = json_extract(json_extract("bot", '$.env'), '$.callback_secret'),
+    -- appending '' keeps the env a string, rather than an object, in the bot
+    "bot" = json_set(
+        "bot",
+        '$.env',
+        json_remove(json_extract("bot", '$.env'), '$.callback_secret') || ''
+    )
+WHERE CASE
+    WHEN json_valid(json_extract("bot", '$.env'))
+        THEN json_type(json_extract("bot", '$.env'), '$.callback_secret') = 'text'
+    ELSE 0
+END;
@@ -0,0 +1,5 @@
+-- Bitpart schema, version 3. Do not edit in place; add a new migration
+-- in `bitpart_common::db::migration` and bump the version.
+
+-- Delivery status of a message, e.g. 'FAILED' when a channel could not send it
+ALTER TABLE "message" ADD COLUMN "status" varchar;
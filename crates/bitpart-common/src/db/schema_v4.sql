@@ -0,0 +1,25 @@
+-- Bitpart schema, version 4. Do not edit in place; add a new migration
+-- in `bitpart_common::db::migration` and bump the version.
+
+-- Pending callback_url deliveries, kept across restarts. Entries that run out
+-- of attempts stay behind with status 'DEAD'.
+CREATE TABLE "callback_queue" (
+    "id" uuid_text NOT NULL PRIMARY KEY,
+    "url" varchar NOT NULL,
+    "payload" varchar NOT NULL,
+    "attempts" integer NOT NULL DEFAULT 0,
+    "status" varchar NOT NULL,
+    "last_error" varchar,
+    "next_attempt_at" datetime_text DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    "created_at" datetime_text DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    "updated_at" datetime_text DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE TRIGGER callback_queue_updated_at
+            AFTER UPDATE ON callback_queue
+            FOR EACH ROW
+            BEGIN
+                UPDATE callback_queue
+                SET updated_at = (datetime('now','localtime'))
+                WHERE id = NEW.id;
+            END;
@@ -0,0 +1,6 @@
+-- Bitpart schema, version 5. Do not edit in place; add a new migration
+-- in `bitpart_common::db::migration` and bump the version.
+
+-- When a channel last received a message, to spot channels that have
+-- silently stopped receiving
+ALTER TABLE "channel" ADD COLUMN "last_received_at" datetime_text;
@@ -0,0 +1,6 @@
+-- Bitpart schema, version 6. Do not edit in place; add a new migration
+-- in `bitpart_common::db::migration` and bump the version.
+
+-- Whether the operator wants the channel connected to Signal (and so shown as
+-- active), reapplied when Bitpart starts
+ALTER TABLE "channel" ADD COLUMN "online" boolean NOT NULL DEFAULT 1;
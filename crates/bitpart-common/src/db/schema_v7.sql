@@ -0,0 +1,11 @@
+-- Bitpart schema, version 7. Do not edit in place; add a new migration
+-- in `bitpart_common::db::migration` and bump the version.
+
+-- Messages already received on a channel, by server guid or else sender and
+-- timestamp, so that messages Signal delivers again are handled once
+CREATE TABLE "signal_seen_messages" (
+    "channel_id" varchar NOT NULL,
+    "key" varchar NOT NULL,
+    "seen_at" integer NOT NULL,
+    PRIMARY KEY ("channel_id", "key")
+);
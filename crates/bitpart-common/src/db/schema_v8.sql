@@ -0,0 +1,6 @@
+-- Bitpart schema, version 8. Do not edit in place; add a new migration
+-- in `bitpart_common::db::migration` and bump the version.
+
+-- The endpoint for the CSML apps of requests from the channel, when it
+-- shouldn't be the bot's
+ALTER TABLE "channel" ADD COLUMN "apps_endpoint" varchar;
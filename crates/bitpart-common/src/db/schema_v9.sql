@@ -0,0 +1,6 @@
+-- Bitpart schema, version 9. Do not edit in place; add a new migration
+-- in `bitpart_common::db::migration` and bump the version.
+
+-- The bot a callback was queued for, so it can be signed with the bot's
+-- secret when it is delivered
+ALTER TABLE "callback_queue" ADD COLUMN "bot_id" varchar;
@@ -26,13 +26,47 @@ use presage::{
 };
 use presage_store_bitpart::BitpartStoreError;
 use prost;
+use serde::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
-use std::{array, io, num::ParseIntError};
+use std::{array, fmt, io, num::ParseIntError};
 use thiserror::Error;
 use thiserror_ext::Box;
 use tokio;
 use uuid;
 
+/// Where a bot's flows fail to validate, so that an editor can point at the line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlowError {
+    pub flow: String,
+    pub line: u32,
+    pub column: u32,
+    pub message: String,
+}
+
+impl fmt::Display for FlowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.flow, self.line, self.column, self.message
+        )
+    }
+}
+
+struct FlowErrors<'a>(&'a [FlowError]);
+
+impl fmt::Display for FlowErrors<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Error, Box)]
 #[thiserror_ext(newtype(name = BitpartError))]
 pub enum BitpartErrorKind {
@@ -40,6 +74,8 @@ pub enum BitpartErrorKind {
     Api(String),
     #[error("Interpreter error: `{0}`")]
     Interpreter(String),
+    #[error("Validation error: `{}`", FlowErrors(.0))]
+    Validation(Vec<FlowError>),
     #[error("Rusqlite error: `{0}`")]
     Rusqlite(#[from] rusqlite::Error),
     // Deadpool's `PoolError` / `InteractError` are stringified here
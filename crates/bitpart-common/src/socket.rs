@@ -1,5 +1,6 @@
-use csml_interpreter::data::CsmlBot;
+use csml_interpreter::data::{Client, CsmlBot, CsmlFlow};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::csml::Request;
 
@@ -9,6 +10,55 @@ pub struct Paginate {
     pub offset: Option<u64>,
 }
 
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ListBotsOptions {
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+    /// Return a summary of each bot's latest version instead of bare ids
+    #[serde(default)]
+    pub detailed: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct MessageQuery {
+    /// `SEND` or `RECEIVE`, or both if unset
+    pub direction: Option<String>,
+    /// Only messages created at or after this RFC 3339 time
+    pub from: Option<String>,
+    /// Only messages created before this RFC 3339 time
+    pub to: Option<String>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+}
+
+/// A memory to store for a user, as with CSML's `remember`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryData {
+    pub key: String,
+    pub value: Value,
+}
+
+/// How `LinkChannel` returns the provisioning link
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QrFormat {
+    /// The bare url
+    #[default]
+    Url,
+    /// The url with an SVG QR code of it
+    Svg,
+    /// The url with a PNG QR code of it
+    Png,
+}
+
+/// A provisioning link with a QR code of it, base64-encoded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QrCode {
+    pub url: String,
+    pub format: QrFormat,
+    pub image: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response<S: Serialize> {
     pub response_type: String,
@@ -26,10 +76,22 @@ pub enum SocketMessage<S: Serialize> {
         id: String,
         options: Option<Paginate>,
     },
+    ReadBotVersion {
+        id: String,
+        version_id: String,
+    },
     RollbackBot {
         id: String,
         version_id: String,
     },
+    UpsertFlow {
+        bot_id: String,
+        flow: Box<CsmlFlow>,
+    },
+    DeleteFlow {
+        bot_id: String,
+        flow_id: String,
+    },
     DiffBot {
         version_a: String,
         version_b: String,
@@ -37,7 +99,8 @@ pub enum SocketMessage<S: Serialize> {
     DeleteBot {
         id: String,
     },
-    ListBots(Option<Paginate>),
+    ListBots(Option<ListBotsOptions>),
+    RecompileBots,
     CreateChannel {
         id: String,
         bot_id: String,
@@ -55,12 +118,114 @@ pub enum SocketMessage<S: Serialize> {
         id: String,
         bot_id: String,
         device_name: String,
+        #[serde(default)]
+        format: QrFormat,
+        /// Overrides the bot's `apps_endpoint` for requests from the channel
+        #[serde(default)]
+        apps_endpoint: Option<String>,
     },
     ResetChannel {
         id: String,
         bot_id: String,
     },
+    StoreStats {
+        id: String,
+        bot_id: String,
+    },
+    DescribeChannel {
+        id: String,
+        bot_id: String,
+    },
+    DeleteContact {
+        id: String,
+        bot_id: String,
+        uuid: String,
+    },
+    DeleteGroup {
+        id: String,
+        bot_id: String,
+        master_key: String,
+    },
+    SetPresence {
+        id: String,
+        bot_id: String,
+        online: bool,
+    },
+    StartChannel {
+        id: String,
+        bot_id: String,
+        /// Overrides the bot's `apps_endpoint` for requests from the channel
+        #[serde(default)]
+        apps_endpoint: Option<String>,
+    },
+    StopChannel {
+        id: String,
+        bot_id: String,
+    },
+    SetConversationStep {
+        client: Client,
+        flow: String,
+        step: String,
+    },
+    GetConversation {
+        client: Client,
+    },
+    ListConversations {
+        bot_id: String,
+        channel_id: Option<String>,
+        user_id: Option<String>,
+        /// `OPEN`, `CLOSED` or `HANDOFF`, or any if unset
+        status: Option<String>,
+        limit: Option<u64>,
+        offset: Option<u64>,
+    },
+    GetMemories {
+        client: Client,
+        /// Only memories whose keys start with this
+        #[serde(default)]
+        prefix: String,
+        limit: Option<u64>,
+        offset: Option<u64>,
+    },
+    ForgetUser {
+        client: Client,
+    },
+    CreateMemories {
+        client: Client,
+        memories: Vec<MemoryData>,
+    },
+    EndHandoff {
+        client: Client,
+    },
+    PatchConversation {
+        client: Client,
+        status: String,
+    },
+    Send {
+        client: Client,
+        payload: Value,
+    },
+    GetMessages {
+        client: Client,
+        options: Option<MessageQuery>,
+    },
+    ResendRecent {
+        client: Client,
+        count: u64,
+    },
+    FailedSends {
+        bot_id: String,
+        options: Option<Paginate>,
+    },
+    FailedCallbacks(Option<Paginate>),
+    ReplayCallbacks {
+        /// Only replay the callbacks to this url
+        url: Option<String>,
+    },
     ChatRequest(Box<Request>),
+    SetMaintenance {
+        enabled: bool,
+    },
     Response(Response<S>),
     Error(Response<S>),
 }
@@ -14,34 +14,268 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use bitpart_common::error::{BitpartErrorKind, Result};
+use bitpart_common::error::{BitpartErrorKind, FlowError, Result};
 use csml_interpreter::{
-    data::{CsmlBot, CsmlResult},
+    data::{CsmlBot, CsmlFlow, CsmlResult},
     load_components, search_for_modules, validate_bot,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::OnceLock;
+use tracing::warn;
 
-use crate::{api::ApiState, csml::data::BotVersion, db};
+use crate::{
+    api::ApiState,
+    csml::{callback, conversation, data::BotVersion, utils},
+    db,
+    db::bot::BotSummary,
+};
 
-pub async fn create_bot(mut bot: CsmlBot, state: &ApiState) -> Result<BotVersion> {
+/// Default maximum size in bytes of a single flow, see `max_flow_size`
+const DEFAULT_MAX_FLOW_SIZE: usize = 1024 * 1024;
+/// Default maximum size in bytes of all of a bot's flows, see `max_bot_size`
+const DEFAULT_MAX_BOT_SIZE: usize = 8 * 1024 * 1024;
+
+static MAX_FLOW_SIZE: OnceLock<usize> = OnceLock::new();
+static MAX_BOT_SIZE: OnceLock<usize> = OnceLock::new();
+
+/**
+ * Set the maximum size in bytes of a single flow from the server
+ * configuration. Only the first call has any effect.
+ */
+pub fn set_max_flow_size(max_size: usize) {
+    let _ = MAX_FLOW_SIZE.set(max_size);
+}
+
+/**
+ * Set the maximum size in bytes of all of a bot's flows from the server
+ * configuration. Only the first call has any effect.
+ */
+pub fn set_max_bot_size(max_size: usize) {
+    let _ = MAX_BOT_SIZE.set(max_size);
+}
+
+/**
+ * Reject oversized flows before they reach validation, which parses and
+ * serializes every flow in memory.
+ */
+fn check_flow_sizes(bot: &CsmlBot) -> Result<()> {
+    let max_flow = *MAX_FLOW_SIZE.get_or_init(|| DEFAULT_MAX_FLOW_SIZE);
+    let max_bot = *MAX_BOT_SIZE.get_or_init(|| DEFAULT_MAX_BOT_SIZE);
+
+    let mut total = 0;
+    for flow in bot.flows.iter() {
+        let size = flow.content.len();
+        if size > max_flow {
+            return Err(BitpartErrorKind::Api(format!(
+                "flow \"{}\" is {} bytes, over the limit of {} bytes",
+                flow.id, size, max_flow
+            ))
+            .into());
+        }
+        total += size;
+    }
+
+    if total > max_bot {
+        return Err(BitpartErrorKind::Api(format!(
+            "flows total {} bytes, over the limit of {} bytes",
+            total, max_bot
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/**
+ * Flows are looked up by id or name case-insensitively, so two different flows
+ * whose ids/names only differ by case would make routing ambiguous.
+ */
+fn check_flow_collisions(bot: &CsmlBot) -> Result<()> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    let mut conflicts = BTreeSet::new();
+
+    for flow in bot.flows.iter() {
+        let keys: BTreeSet<String> = [&flow.id, &flow.name]
+            .iter()
+            .map(|key| key.to_ascii_lowercase())
+            .collect();
+        for key in keys {
+            match seen.get(&key) {
+                Some(other) => {
+                    conflicts.insert(format!("\"{}\" and \"{}\"", other, flow.id));
+                }
+                None => {
+                    seen.insert(key, &flow.id);
+                }
+            }
+        }
+    }
+
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(BitpartErrorKind::Api(format!(
+            "flow ids/names must be unique ignoring case, conflicting flows: {}",
+            conflicts.into_iter().collect::<Vec<_>>().join(", ")
+        ))
+        .into())
+    }
+}
+
+/**
+ * Load the native components and modules a bot uses, then validate it with
+ * the running engine. Validation errors are returned with the flow, line and
+ * column they were found at.
+ */
+fn compile_bot(bot: &mut CsmlBot) -> Result<()> {
     bot.native_components = match load_components() {
         Ok(components) => Some(components),
         Err(err) => return Err(BitpartErrorKind::Interpreter(err.format_error()).into()),
     };
 
-    if let Err(err) = search_for_modules(&mut bot) {
+    if let Err(err) = search_for_modules(bot) {
         return Err(BitpartErrorKind::Api(format!("{:?}", err)).into());
     }
 
-    match validate_bot(&bot) {
+    match validate_bot(bot) {
         CsmlResult {
             errors: Some(errors),
             ..
-        } => Err(BitpartErrorKind::Api(format!("{:?}", errors)).into()),
-        CsmlResult { .. } => {
-            let created = db::bot::create(bot, &state.pool).await?;
-            Ok(created)
+        } => Err(BitpartErrorKind::Validation(
+            errors
+                .into_iter()
+                .map(|error| FlowError {
+                    flow: error.position.flow,
+                    line: error.position.interval.start_line,
+                    column: error.position.interval.start_column,
+                    message: error.message,
+                })
+                .collect(),
+        )
+        .into()),
+        CsmlResult { .. } => Ok(()),
+    }
+}
+
+/**
+ * Every flow named in the bot's `entry_steps` must exist and have the step it
+ * is given, or conversations would be started at a step that isn't there.
+ */
+fn check_entry_steps(bot: &CsmlBot) -> Result<()> {
+    let Some(steps) = bot.env.as_ref().and_then(|env| env.get(utils::ENTRY_STEPS)) else {
+        return Ok(());
+    };
+    let Some(steps) = steps.as_object() else {
+        return Err(BitpartErrorKind::Api(format!(
+            "{} must map flows to steps",
+            utils::ENTRY_STEPS
+        ))
+        .into());
+    };
+
+    let mut compiled = bot.clone();
+    conversation::init_bot(&mut compiled)?;
+    for (flow_id, step) in steps {
+        let Ok(flow) = utils::get_flow_by_id(flow_id, &compiled.flows) else {
+            return Err(BitpartErrorKind::Api(format!(
+                "entry step given for flow '{}', which does not exist",
+                flow_id
+            ))
+            .into());
+        };
+        let step = step.as_str().unwrap_or_default();
+        if !utils::step_exists(&compiled, flow, step)? {
+            return Err(BitpartErrorKind::Api(format!(
+                "entry step '{}' does not exist in flow '{}'",
+                step, flow_id
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/**
+ * The bot's `fallback_flow`, if it has one, must be one of its flows.
+ */
+fn check_fallback_flow(bot: &CsmlBot) -> Result<()> {
+    match bot
+        .env
+        .as_ref()
+        .and_then(|env| env.get(utils::FALLBACK_FLOW))
+    {
+        None => Ok(()),
+        Some(flow_id) => match flow_id.as_str() {
+            Some(flow_id) if utils::get_flow_by_id(flow_id, &bot.flows).is_ok() => Ok(()),
+            _ => Err(
+                BitpartErrorKind::Api(format!("fallback flow {} does not exist", flow_id)).into(),
+            ),
+        },
+    }
+}
+
+/**
+ * Put a stored bot's callback secret back in its `env`, so that a new version
+ * made from it goes on signing callbacks with it. `db::bot::create` takes it
+ * out again.
+ */
+fn with_callback_secret(mut bot: CsmlBot, secret: Option<String>) -> CsmlBot {
+    if let Some(secret) = secret {
+        let env = bot.env.get_or_insert_with(|| Value::Object(Map::new()));
+        if let Some(env) = env.as_object_mut() {
+            env.insert(callback::CALLBACK_SECRET.to_owned(), Value::String(secret));
         }
     }
+    bot
+}
+
+pub async fn create_bot(mut bot: CsmlBot, state: &ApiState) -> Result<BotVersion> {
+    check_flow_sizes(&bot)?;
+    check_flow_collisions(&bot)?;
+    compile_bot(&mut bot)?;
+    check_entry_steps(&bot)?;
+    check_fallback_flow(&bot)?;
+
+    let created = db::bot::create(bot, &state.pool).await?;
+    Ok(created)
+}
+
+/// Outcome of recompiling one bot with `RecompileBots`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BotRecompile {
+    pub id: String,
+    pub version_id: String,
+    /// Why the bot no longer validates, if it doesn't
+    pub error: Option<String>,
+}
+
+/**
+ * Re-validate the latest version of every bot against the running engine,
+ * e.g. after an upgrade. Bots that still validate are saved again in place;
+ * the rest are left untouched and reported with their errors.
+ */
+pub async fn recompile_bots(state: &ApiState) -> Result<Vec<BotRecompile>> {
+    let mut results = Vec::new();
+    for version in db::bot::list_latest(&state.pool).await? {
+        let mut bot = version.bot;
+        let error = match compile_bot(&mut bot) {
+            Ok(()) => {
+                db::bot::update(&version.version_id, &bot, &state.pool).await?;
+                None
+            }
+            Err(err) => {
+                warn!(bot_id = bot.id, "bot no longer validates: {}", err);
+                Some(err.to_string())
+            }
+        };
+        results.push(BotRecompile {
+            id: bot.id,
+            version_id: version.version_id,
+            error,
+        });
+    }
+    Ok(results)
 }
 
 pub async fn list_bots(
@@ -53,6 +287,14 @@ pub async fn list_bots(
     Ok(list)
 }
 
+pub async fn list_bot_summaries(
+    limit: Option<u64>,
+    offset: Option<u64>,
+    state: &ApiState,
+) -> Result<Vec<BotSummary>> {
+    db::bot::list_summaries(limit, offset, &state.pool).await
+}
+
 pub async fn read_bot(id: &str, state: &ApiState) -> Result<Option<BotVersion>> {
     if let Some(bot) = db::bot::get_latest_by_bot_id(id, &state.pool).await? {
         Ok(Some(bot))
@@ -80,16 +322,80 @@ pub async fn get_bot_versions(
     db::bot::get(id, limit, offset, &state.pool).await
 }
 
-pub async fn get_bot_version(id: &str, state: &ApiState) -> Result<Option<BotVersion>> {
-    db::bot::get_by_id(id, &state.pool).await
+/// Get one version of a bot, if it exists and belongs to that bot
+pub async fn get_bot_version(
+    id: &str,
+    version_id: &str,
+    state: &ApiState,
+) -> Result<Option<BotVersion>> {
+    Ok(db::bot::get_by_id(version_id, &state.pool)
+        .await?
+        .filter(|version| version.bot.id == id))
 }
 
-pub async fn touch_bot_version(
+/**
+ * Roll a bot back by saving a copy of one of its versions as a new version,
+ * so that the history stays linear. Returns the new version, or `None` if the
+ * bot has no such version.
+ */
+pub async fn rollback_bot(
     id: &str,
     version_id: &str,
     state: &ApiState,
 ) -> Result<Option<BotVersion>> {
-    db::bot::touch(id, version_id, &state.pool).await
+    match get_bot_version(id, version_id, state).await? {
+        Some(version) => {
+            let secret = db::bot::get_callback_secret_by_id(version_id, &state.pool).await?;
+            let bot = with_callback_secret(version.bot, secret);
+            Ok(Some(db::bot::create(bot, &state.pool).await?))
+        }
+        None => Ok(None),
+    }
+}
+
+/**
+ * Add a flow to the latest version of a bot, or replace the flow with the
+ * same id, and save the result as a new version. The bot is validated as
+ * with `create_bot`, and left as it was if it doesn't validate. Returns the
+ * new version id.
+ */
+pub async fn upsert_flow(bot_id: &str, flow: CsmlFlow, state: &ApiState) -> Result<String> {
+    let Some(version) = db::bot::get_latest_by_bot_id(bot_id, &state.pool).await? else {
+        return Err(BitpartErrorKind::Api("Updating flow of non-existent bot".into()).into());
+    };
+    let secret = db::bot::get_callback_secret(bot_id, &state.pool).await?;
+    let mut bot = with_callback_secret(version.bot, secret);
+    match bot.flows.iter_mut().find(|f| f.id == flow.id) {
+        Some(existing) => *existing = flow,
+        None => bot.flows.push(flow),
+    }
+    Ok(create_bot(bot, state).await?.version_id)
+}
+
+/**
+ * Remove a flow from the latest version of a bot and save the result as a new
+ * version, validated as with `create_bot`. The default flow can't be removed.
+ * Returns the new version id.
+ */
+pub async fn delete_flow(bot_id: &str, flow_id: &str, state: &ApiState) -> Result<String> {
+    let Some(version) = db::bot::get_latest_by_bot_id(bot_id, &state.pool).await? else {
+        return Err(BitpartErrorKind::Api("Deleting flow of non-existent bot".into()).into());
+    };
+    let secret = db::bot::get_callback_secret(bot_id, &state.pool).await?;
+    let mut bot = with_callback_secret(version.bot, secret);
+    let Some(index) = bot.flows.iter().position(|f| f.id == flow_id) else {
+        return Err(BitpartErrorKind::Api(format!("no flow {} in bot {}", flow_id, bot_id)).into());
+    };
+    let flow = &bot.flows[index];
+    if bot.default_flow == flow.id || bot.default_flow == flow.name {
+        return Err(BitpartErrorKind::Api(format!(
+            "flow {} is the default flow and can't be deleted",
+            flow_id
+        ))
+        .into());
+    }
+    bot.flows.remove(index);
+    Ok(create_bot(bot, state).await?.version_id)
 }
 
 pub async fn get_bot_diff(
@@ -108,7 +414,7 @@ pub async fn delete_bot_version(id: &str, state: &ApiState) -> Result<()> {
 
 #[cfg(test)]
 mod test_bot {
-    use crate::utils::get_test_socket;
+    use crate::utils::{get_test_socket, get_test_socket_with_pool};
     use serde_json::json;
 
     #[tokio::test]
@@ -137,6 +443,46 @@ mod test_bot {
         socket.assert_receive_text_contains("Hello").await
     }
 
+    #[tokio::test]
+    async fn it_should_reject_colliding_flow_names() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Main",
+                        "name": "Main",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      },
+                      {
+                        "id": "main",
+                        "name": "main",
+                        "content": "start: say \"Goodbye\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Main",
+                }
+            }))
+            .await;
+
+        socket
+            .assert_receive_json(&json!({
+                "message_type": "Error",
+                "data": {
+                    "response_type": "CreateBot",
+                    "response": "API error: `flow ids/names must be unique ignoring case, conflicting flows: \"Main\" and \"main\"`"
+                }
+            }))
+            .await
+    }
+
     #[tokio::test]
     async fn it_should_get_a_bot() {
         let mut socket = get_test_socket().await;
@@ -286,4 +632,539 @@ mod test_bot {
 
         socket.assert_receive_text_contains("Hello").await
     }
+
+    #[tokio::test]
+    async fn it_should_read_a_single_version() {
+        let mut socket = get_test_socket().await;
+
+        for greeting in ["Hello", "Goodbye"] {
+            socket
+                .send_json(&json!({
+                    "message_type": "CreateBot",
+                    "data": {
+                        "id": "bot_id",
+                        "name": "test",
+                        "flows": [
+                          {
+                            "id": "Default",
+                            "name": "Default",
+                            "content": format!("start: say \"{greeting}\" goto end"),
+                            "commands": [],
+                          }
+                        ],
+                        "default_flow": "Default",
+                    }
+                }))
+                .await;
+            socket.receive_json::<serde_json::Value>().await;
+        }
+
+        socket
+            .send_json(&json!({
+                "message_type": "BotVersions",
+                "data": { "id": "bot_id" }
+            }))
+            .await;
+        let versions = socket.receive_json::<serde_json::Value>().await;
+        let versions = versions["data"]["response"].as_array().unwrap();
+        let first = versions
+            .iter()
+            .find(|v| {
+                v["bot"]["flows"][0]["content"]
+                    .as_str()
+                    .unwrap()
+                    .contains("Hello")
+            })
+            .unwrap()["version_id"]
+            .clone();
+
+        socket
+            .send_json(&json!({
+                "message_type": "ReadBotVersion",
+                "data": { "id": "bot_id", "version_id": first }
+            }))
+            .await;
+        let res = socket.receive_json::<serde_json::Value>().await;
+        assert_eq!(res["data"]["response_type"], "ReadBotVersion");
+        assert_eq!(res["data"]["response"]["version_id"], first);
+        assert!(
+            res["data"]["response"]["bot"]["flows"][0]["content"]
+                .as_str()
+                .unwrap()
+                .contains("Hello")
+        );
+
+        socket
+            .send_json(&json!({
+                "message_type": "ReadBotVersion",
+                "data": { "id": "other_bot", "version_id": first }
+            }))
+            .await;
+        let res = socket.receive_json::<serde_json::Value>().await;
+        assert!(res["data"]["response"].is_null());
+    }
+
+    #[tokio::test]
+    async fn it_should_roll_back_to_a_new_version() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        let mut version_ids = Vec::new();
+        for greeting in ["Hello", "Goodbye"] {
+            socket
+                .send_json(&json!({
+                    "message_type": "CreateBot",
+                    "data": {
+                        "id": "bot_id",
+                        "name": "test",
+                        "flows": [
+                          {
+                            "id": "Default",
+                            "name": "Default",
+                            "content": format!("start: say \"{greeting}\" goto end"),
+                            "commands": [],
+                          }
+                        ],
+                        "default_flow": "Default",
+                    }
+                }))
+                .await;
+            let created = socket.receive_json::<serde_json::Value>().await;
+            version_ids.push(created["data"]["response"]["version_id"].clone());
+        }
+
+        socket
+            .send_json(&json!({
+                "message_type": "RollbackBot",
+                "data": { "id": "bot_id", "version_id": version_ids[0] }
+            }))
+            .await;
+        let res = socket.receive_json::<serde_json::Value>().await;
+        let new_version_id = res["data"]["response"]["version_id"].as_str().unwrap();
+        assert!(!version_ids.contains(&json!(new_version_id)));
+
+        let versions = crate::db::bot::get("bot_id", None, None, &pool)
+            .await
+            .unwrap();
+        assert_eq!(versions.len(), 3);
+
+        let latest = crate::db::bot::get_latest_by_bot_id("bot_id", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        let original = crate::db::bot::get_by_id(version_ids[0].as_str().unwrap(), &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.bot.flows[0].content, original.bot.flows[0].content);
+        assert_eq!(versions[0].version_id, new_version_id);
+    }
+
+    #[tokio::test]
+    async fn it_should_say_where_flows_fail_to_validate() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start:\n  say \"Hello\"\n  say\n  goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+
+        let res = socket.receive_json::<serde_json::Value>().await;
+        assert_eq!(res["message_type"], "Error");
+        let errors = res["data"]["response"].as_array().unwrap();
+        assert!(!errors.is_empty());
+        assert_eq!(errors[0]["flow"], "Default");
+        assert!(errors[0]["line"].as_u64().unwrap() >= 1);
+        assert!(errors[0]["column"].is_u64());
+        assert!(!errors[0]["message"].as_str().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_should_edit_one_flow_at_a_time() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+        socket.assert_receive_text_contains("Hello").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "UpsertFlow",
+                "data": {
+                    "bot_id": "bot_id",
+                    "flow": {
+                        "id": "Help",
+                        "name": "Help",
+                        "content": "start: say \"Ask away\" goto end",
+                        "commands": ["/help"],
+                    }
+                }
+            }))
+            .await;
+        let res = socket.receive_json::<serde_json::Value>().await;
+        let added = res["data"]["response"].as_str().unwrap().to_owned();
+        let latest = crate::db::bot::get_latest_by_bot_id("bot_id", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.version_id, added);
+        assert_eq!(latest.bot.flows.len(), 2);
+
+        // a flow that doesn't validate leaves the bot as it was
+        socket
+            .send_json(&json!({
+                "message_type": "UpsertFlow",
+                "data": {
+                    "bot_id": "bot_id",
+                    "flow": {
+                        "id": "Help",
+                        "name": "Help",
+                        "content": "start: say",
+                        "commands": ["/help"],
+                    }
+                }
+            }))
+            .await;
+        let res = socket.receive_json::<serde_json::Value>().await;
+        assert_eq!(res["message_type"], "Error");
+        let latest = crate::db::bot::get_latest_by_bot_id("bot_id", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.version_id, added);
+
+        socket
+            .send_json(&json!({
+                "message_type": "DeleteFlow",
+                "data": { "bot_id": "bot_id", "flow_id": "Default" }
+            }))
+            .await;
+        socket.assert_receive_text_contains("default flow").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "DeleteFlow",
+                "data": { "bot_id": "bot_id", "flow_id": "Help" }
+            }))
+            .await;
+        let res = socket.receive_json::<serde_json::Value>().await;
+        let deleted = res["data"]["response"].as_str().unwrap();
+        let latest = crate::db::bot::get_latest_by_bot_id("bot_id", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.version_id, deleted);
+        assert_eq!(latest.bot.flows.len(), 1);
+        assert_eq!(latest.bot.flows[0].id, "Default");
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_the_callback_secret_out_of_the_bot() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                    "env": { "callback_secret": "whsec_test" },
+                }
+            }))
+            .await;
+        let res = socket.receive_json::<serde_json::Value>().await;
+        assert!(!res.to_string().contains("whsec_test"));
+
+        socket
+            .send_json(&json!({
+                "message_type": "ReadBot",
+                "data": { "id": "bot_id" }
+            }))
+            .await;
+        let res = socket.receive_json::<serde_json::Value>().await;
+        assert!(res.to_string().contains("Hello"));
+        assert!(!res.to_string().contains("whsec_test"));
+
+        // a version made from the stored bot goes on signing with the secret
+        socket
+            .send_json(&json!({
+                "message_type": "UpsertFlow",
+                "data": {
+                    "bot_id": "bot_id",
+                    "flow": {
+                        "id": "Help",
+                        "name": "Help",
+                        "content": "start: say \"Ask away\" goto end",
+                        "commands": ["/help"],
+                    }
+                }
+            }))
+            .await;
+        socket.receive_json::<serde_json::Value>().await;
+        let latest = crate::db::bot::get_latest_by_bot_id("bot_id", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(latest.bot.flows.len(), 2);
+        assert_eq!(latest.bot.env, Some(json!({})));
+        assert_eq!(
+            crate::db::bot::get_callback_secret("bot_id", &pool)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("whsec_test")
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_list_bot_summaries() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      },
+                      {
+                        "id": "Other",
+                        "name": "Other",
+                        "content": "start: say \"Other\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+        let created = socket.receive_json::<serde_json::Value>().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "ListBots",
+                "data": {
+                    "detailed": true
+                }
+            }))
+            .await;
+
+        socket
+            .assert_receive_json(&json!({
+                "message_type": "Response",
+                "data": {
+                    "response_type": "ListBots",
+                    "response": [{
+                        "id": "bot_id",
+                        "name": "test",
+                        "version_id": created["data"]["response"]["version_id"],
+                        "engine_version": env!("CARGO_PKG_VERSION"),
+                        "flow_count": 2
+                    }]
+                }
+            }))
+            .await
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_oversized_flows() {
+        let mut socket = get_test_socket().await;
+        let content = format!(
+            "start: say \"{}\" goto end",
+            "a".repeat(super::DEFAULT_MAX_FLOW_SIZE)
+        );
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": content,
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+
+        socket
+            .assert_receive_json(&json!({
+                "message_type": "Error",
+                "data": {
+                    "response_type": "CreateBot",
+                    "response": format!(
+                        "API error: `flow \"Default\" is {} bytes, over the limit of {} bytes`",
+                        content.len(),
+                        super::DEFAULT_MAX_FLOW_SIZE
+                    )
+                }
+            }))
+            .await
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_missing_entry_steps() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                    "env": {
+                        "entry_steps": { "Default": "welcome" }
+                    },
+                }
+            }))
+            .await;
+
+        socket
+            .assert_receive_json(&json!({
+                "message_type": "Error",
+                "data": {
+                    "response_type": "CreateBot",
+                    "response": "API error: `entry step 'welcome' does not exist in flow 'Default'`"
+                }
+            }))
+            .await
+    }
+
+    #[tokio::test]
+    async fn it_should_recompile_bots() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Hello").await;
+
+        // saved by an old engine, and a bot that never validated
+        let version_id = crate::db::bot::get("bot_id", None, None, &pool)
+            .await
+            .unwrap()
+            .remove(0)
+            .version_id;
+        pool.get()
+            .await
+            .unwrap()
+            .interact(move |conn| {
+                conn.execute(
+                    "UPDATE bot SET engine_version = '0.0.1' WHERE id = ?",
+                    [version_id],
+                )
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let broken: csml_interpreter::data::CsmlBot = serde_json::from_value(json!({
+            "id": "broken_bot_id",
+            "name": "broken",
+            "flows": [
+              {
+                "id": "Default",
+                "name": "Default",
+                "content": "start: say \"Hello",
+                "commands": [],
+              }
+            ],
+            "default_flow": "Default",
+        }))
+        .unwrap();
+        crate::db::bot::create(broken, &pool).await.unwrap();
+
+        socket
+            .send_json(&json!({
+                "message_type": "RecompileBots",
+            }))
+            .await;
+
+        let res = socket.receive_json::<serde_json::Value>().await;
+        assert_eq!(res["data"]["response_type"], "RecompileBots");
+        let results = res["data"]["response"].as_array().unwrap();
+        let result = |id: &str| results.iter().find(|r| r["id"] == id).unwrap().clone();
+        assert!(result("bot_id")["error"].is_null());
+        assert!(result("broken_bot_id")["error"].is_string());
+
+        let repaired = crate::db::bot::get_latest_by_bot_id("bot_id", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(repaired.engine_version, env!("CARGO_PKG_VERSION"));
+    }
 }
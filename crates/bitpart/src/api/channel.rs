@@ -15,9 +15,16 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 
+use base64::prelude::*;
 use bitpart_common::error::{BitpartErrorKind, Result};
+use bitpart_common::socket::{QrCode, QrFormat};
+use presage::store::StateStore;
+use presage_store_bitpart::TreeStats;
+use serde::{Deserialize, Serialize};
 use tokio::sync::oneshot;
+use tracing::{error, info};
 
 use crate::{api::ApiState, channels::signal, db, db::channel};
 
@@ -25,14 +32,52 @@ pub async fn create_channel(id: &str, bot_id: &str, state: &ApiState) -> Result<
     db::channel::create(id, bot_id, &state.pool).await
 }
 
+/// Render a provisioning link as a QR code in the given image format
+fn qr_code(url: String, format: QrFormat) -> Result<QrCode> {
+    let code = qrcode::QrCode::new(url.as_bytes())
+        .map_err(|e| BitpartErrorKind::Api(format!("Failed to make QR code: {e}")))?;
+    let image = match format {
+        QrFormat::Url => return Err(BitpartErrorKind::Api("No QR code format given".into()).into()),
+        QrFormat::Svg => code
+            .render::<qrcode::render::svg::Color>()
+            .min_dimensions(256, 256)
+            .build()
+            .into_bytes(),
+        QrFormat::Png => {
+            let mut png = Vec::new();
+            code.render::<image::Luma<u8>>()
+                .min_dimensions(256, 256)
+                .build()
+                .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+                .map_err(|e| BitpartErrorKind::Api(format!("Failed to make QR code: {e}")))?;
+            png
+        }
+    };
+    Ok(QrCode {
+        url,
+        format,
+        image: BASE64_STANDARD.encode(image),
+    })
+}
+
+/**
+ * Start linking a channel as a secondary device. The provisioning link is
+ * returned as a bare url, or for `svg` and `png` with a QR code of it that a
+ * web frontend can show as is.
+ */
 pub async fn link_channel(
     id: &str,
     bot_id: &str,
     device_name: &str,
+    format: QrFormat,
+    apps_endpoint: Option<&str>,
     attachments_dir: PathBuf,
     state: &mut ApiState,
-) -> Result<String> {
+) -> Result<serde_json::Value> {
     let db_id = db::channel::create(id, bot_id, &state.pool).await?;
+    if apps_endpoint.is_some() {
+        db::channel::set_apps_endpoint(id, bot_id, apps_endpoint, &state.pool).await?;
+    }
     let (send, recv) = oneshot::channel();
     let contents = signal::ChannelMessageContents::LinkChannel {
         id: db_id.clone(),
@@ -51,23 +96,37 @@ pub async fn link_channel(
         sender: send,
     };
     state.manager.send(msg).await?;
-    Ok(recv.await?)
+    let url = recv.await?;
+    match format {
+        QrFormat::Url => Ok(serde_json::Value::String(url)),
+        format => Ok(serde_json::to_value(qr_code(url, format)?)?),
+    }
 }
 
-pub async fn start_channel(channel_id: &str, bot_id: &str, state: &mut ApiState) -> Result<String> {
+/**
+ * Start receiving messages on a channel, by its id in the database. A channel
+ * that is already running is left as it is.
+ */
+async fn spawn_channel(id: &str, bot_id: &str, state: &mut ApiState) -> Result<String> {
+    if in_maintenance(state) {
+        return Err(BitpartErrorKind::Api("Server is in maintenance".into()).into());
+    }
     let (send, recv) = oneshot::channel();
     let contents = signal::ChannelMessageContents::StartChannel {
-        id: channel_id.to_owned(),
+        id: id.to_owned(),
         attachments_dir: state.attachments_dir.clone(),
     };
     let mut data = state.tokens.lock().await;
-    let token = data
-        .entry((bot_id.to_owned(), channel_id.to_owned()))
-        .or_insert(state.parent_token.child_token());
+    let key = (bot_id.to_owned(), id.to_owned());
+    if data.get(&key).is_some_and(|token| !token.is_cancelled()) {
+        return Ok("".to_owned());
+    }
+    let token = state.parent_token.child_token();
+    data.insert(key, token.clone());
     let msg = signal::ChannelMessage {
         msg: contents,
         pool: state.pool.clone(),
-        token: token.clone(),
+        token,
         tracker: state.tracker.clone(),
         sender: send,
     };
@@ -75,6 +134,93 @@ pub async fn start_channel(channel_id: &str, bot_id: &str, state: &mut ApiState)
     Ok(recv.await?)
 }
 
+/**
+ * Start a channel's receiver without restarting the server, for example
+ * right after it was linked. Starting a running channel does nothing. Unlike
+ * `set_presence`, this doesn't change whether the channel is started with the
+ * server. An `apps_endpoint` given is kept for the channel, and applies from
+ * the next time it starts.
+ */
+pub async fn start_channel(
+    channel_id: &str,
+    bot_id: &str,
+    apps_endpoint: Option<&str>,
+    state: &mut ApiState,
+) -> Result<()> {
+    let Some(channel) = db::channel::get(channel_id, bot_id, &state.pool).await? else {
+        return Err(BitpartErrorKind::Api("Starting non-existent channel".into()).into());
+    };
+    if apps_endpoint.is_some() {
+        db::channel::set_apps_endpoint(channel_id, bot_id, apps_endpoint, &state.pool).await?;
+    }
+    // a channel that was just linked is already receiving
+    let linking = state
+        .tokens
+        .lock()
+        .await
+        .get(&(bot_id.to_owned(), channel_id.to_owned()))
+        .is_some_and(|token| !token.is_cancelled());
+    if !linking {
+        spawn_channel(&channel.id, bot_id, state).await?;
+    }
+    Ok(())
+}
+
+/**
+ * Stop a channel's receiver without restarting the server. Stopping a channel
+ * that isn't running does nothing.
+ */
+pub async fn stop_channel(channel_id: &str, bot_id: &str, state: &ApiState) -> Result<()> {
+    let Some(channel) = db::channel::get(channel_id, bot_id, &state.pool).await? else {
+        return Err(BitpartErrorKind::Api("Stopping non-existent channel".into()).into());
+    };
+    cancel_channel(&channel, state).await
+}
+
+/**
+ * Start the receiver of every channel that is online, as on server startup.
+ * A channel that fails to start is logged and skipped, so that one broken
+ * channel can't keep the others down. Returns the ids of the channels started.
+ */
+pub async fn start_online_channels(state: &mut ApiState) -> Result<Vec<String>> {
+    let channels = db::channel::list(None, None, &state.pool).await?;
+    let mut started = Vec::new();
+    for channel in channels.iter().filter(|channel| channel.online) {
+        match spawn_channel(&channel.id, &channel.bot_id, state).await {
+            Ok(_) => {
+                info!(id = channel.id, bot_id = channel.bot_id, "Started channel");
+                started.push(channel.id.clone());
+            }
+            Err(err) => error!(id = channel.id, "Failed to start channel: {:?}", err),
+        }
+    }
+    Ok(started)
+}
+
+/**
+ * Maintenance mode pauses the server without stopping it, for upgrades: every
+ * channel stops receiving messages, and chat requests are turned away.
+ * Leaving it restarts the channels that are online.
+ */
+pub async fn set_maintenance(enabled: bool, state: &mut ApiState) -> Result<()> {
+    if enabled {
+        state.maintenance.store(true, Ordering::SeqCst);
+        let mut data = state.tokens.lock().await;
+        for (_, token) in data.drain() {
+            token.cancel();
+        }
+        info!("Entered maintenance mode");
+    } else if state.maintenance.swap(false, Ordering::SeqCst) {
+        start_online_channels(state).await?;
+        info!("Left maintenance mode");
+    }
+    Ok(())
+}
+
+pub fn in_maintenance(state: &ApiState) -> bool {
+    state.maintenance.load(Ordering::SeqCst)
+}
+
 pub async fn reset_channel(channel_id: &str, bot_id: &str, state: &mut ApiState) -> Result<String> {
     if let Some(channel) = db::channel::get(channel_id, bot_id, &state.pool).await? {
         let (send, recv) = oneshot::channel();
@@ -99,6 +245,155 @@ pub async fn reset_channel(channel_id: &str, bot_id: &str, state: &mut ApiState)
     }
 }
 
+/**
+ * Signal has no online indicator of its own: an account shows as active while
+ * one of its devices keeps its connection open. Going online starts the
+ * channel's receiver, going offline stops it. The choice is persisted so that
+ * it is reapplied on restart.
+ */
+pub async fn set_presence(
+    channel_id: &str,
+    bot_id: &str,
+    online: bool,
+    state: &mut ApiState,
+) -> Result<()> {
+    let Some(channel) = db::channel::get(channel_id, bot_id, &state.pool).await? else {
+        return Err(
+            BitpartErrorKind::Api("Setting presence of non-existent channel".into()).into(),
+        );
+    };
+    db::channel::set_online(channel_id, bot_id, online, &state.pool).await?;
+
+    if online {
+        spawn_channel(&channel.id, bot_id, state).await?;
+    } else {
+        cancel_channel(&channel, state).await?;
+    }
+    Ok(())
+}
+
+/**
+ * Stop a channel's receiver, if it is running, by cancelling only that
+ * channel's token. The receive loop exits and drops its manager. A linked
+ * channel's token is kept under its channel id, a started one's under its
+ * database id, so both are looked up.
+ */
+async fn cancel_channel(channel: &channel::Model, state: &ApiState) -> Result<()> {
+    let token = {
+        let mut data = state.tokens.lock().await;
+        [&channel.id, &channel.channel_id]
+            .into_iter()
+            .filter_map(|key| data.remove(&(channel.bot_id.clone(), key.to_owned())))
+            .reduce(|token, other| {
+                other.cancel();
+                token
+            })
+    };
+    let Some(token) = token else {
+        return Ok(());
+    };
+
+    let (send, recv) = oneshot::channel();
+    let msg = signal::ChannelMessage {
+        msg: signal::ChannelMessageContents::StopChannel {
+            id: channel.id.clone(),
+        },
+        pool: state.pool.clone(),
+        token,
+        tracker: state.tracker.clone(),
+        sender: send,
+    };
+    state.manager.send(msg).await?;
+    let err = recv.await?;
+    if !err.is_empty() {
+        return Err(BitpartErrorKind::Api(err).into());
+    }
+    Ok(())
+}
+
+/**
+ * Per-tree row counts and sizes of a channel's Signal store, for diagnostics.
+ */
+pub async fn store_stats(
+    channel_id: &str,
+    bot_id: &str,
+    state: &ApiState,
+) -> Result<Vec<TreeStats>> {
+    let Some(channel) = db::channel::get(channel_id, bot_id, &state.pool).await? else {
+        return Err(BitpartErrorKind::Api("Reading stats of non-existent channel".into()).into());
+    };
+    let store = signal::open_store(&channel.id, &state.pool).await?;
+    Ok(store.tree_stats().await?)
+}
+
+/// Whether a channel's store holds a Signal registration, and the account it
+/// is linked to if so
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelDescription {
+    pub registered: bool,
+    pub device_name: Option<String>,
+    pub phone_number: Option<String>,
+    pub aci: Option<String>,
+}
+
+/// Describes a channel's Signal registration, so that a half-linked channel
+/// can be told apart from a working one.
+pub async fn describe_channel(
+    channel_id: &str,
+    bot_id: &str,
+    state: &ApiState,
+) -> Result<ChannelDescription> {
+    let Some(channel) = db::channel::get(channel_id, bot_id, &state.pool).await? else {
+        return Err(BitpartErrorKind::Api("Describing non-existent channel".into()).into());
+    };
+    let store = signal::open_store(&channel.id, &state.pool).await?;
+    let registration = store.load_registration_data().await?;
+    Ok(ChannelDescription {
+        registered: registration.is_some(),
+        device_name: registration.as_ref().and_then(|r| r.device_name.clone()),
+        phone_number: registration.as_ref().map(|r| r.phone_number.to_string()),
+        aci: registration.as_ref().map(|r| r.service_ids.aci.to_string()),
+    })
+}
+
+/// Forgets a single contact, identified by its ACI uuid, from a channel's
+/// store. Returns whether the contact was known.
+pub async fn delete_contact(
+    channel_id: &str,
+    bot_id: &str,
+    uuid: &str,
+    state: &ApiState,
+) -> Result<bool> {
+    let uuid = uuid::Uuid::parse_str(uuid)
+        .map_err(|e| BitpartErrorKind::Api(format!("Invalid contact uuid: {e}")))?;
+    let Some(channel) = db::channel::get(channel_id, bot_id, &state.pool).await? else {
+        return Err(
+            BitpartErrorKind::Api("Deleting contact of non-existent channel".into()).into(),
+        );
+    };
+    let store = signal::open_store(&channel.id, &state.pool).await?;
+    Ok(store.delete_contact(uuid).await?)
+}
+
+/// Forgets a single group, identified by its hex-encoded master key, from a
+/// channel's store. Returns whether the group was known.
+pub async fn delete_group(
+    channel_id: &str,
+    bot_id: &str,
+    master_key: &str,
+    state: &ApiState,
+) -> Result<bool> {
+    let master_key: [u8; 32] = hex::decode(master_key)
+        .ok()
+        .and_then(|k| k.try_into().ok())
+        .ok_or_else(|| BitpartErrorKind::Api("Invalid group master key".into()))?;
+    let Some(channel) = db::channel::get(channel_id, bot_id, &state.pool).await? else {
+        return Err(BitpartErrorKind::Api("Deleting group of non-existent channel".into()).into());
+    };
+    let store = signal::open_store(&channel.id, &state.pool).await?;
+    Ok(store.delete_group(master_key).await?)
+}
+
 pub async fn read_channel(
     id: &str,
     bot_id: &str,
@@ -120,18 +415,263 @@ pub async fn list_channels(
 }
 
 pub async fn delete_channel(id: &str, bot_id: &str, state: &ApiState) -> Result<()> {
-    db::channel::delete(id, bot_id, &state.pool).await?;
-    let data = state.tokens.lock().await;
-    if let Some(token) = data.get(&(bot_id.to_owned(), id.to_owned())) {
-        token.cancel();
+    if let Some(channel) = db::channel::get(id, bot_id, &state.pool).await? {
+        cancel_channel(&channel, state).await?;
     }
+    db::channel::delete(id, bot_id, &state.pool).await?;
     Ok(())
 }
 
 #[cfg(test)]
 mod test_channel {
-    use crate::utils::get_test_socket;
+    use crate::channels::signal::{ChannelBackend, ChannelMessage, ChannelMessageContents};
+    use crate::utils::{get_test_socket, get_test_state};
+    use bitpart_common::error::Result;
     use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the channels it was asked to start and stop
+    #[derive(Default)]
+    struct RecordingChannelBackend {
+        started: Mutex<Vec<String>>,
+        stopped: Mutex<Vec<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ChannelBackend for RecordingChannelBackend {
+        async fn send(&self, msg: ChannelMessage) -> Result<()> {
+            match &msg.msg {
+                ChannelMessageContents::StartChannel { id, .. } => {
+                    self.started.lock().unwrap().push(id.clone());
+                }
+                ChannelMessageContents::StopChannel { id } => {
+                    self.stopped.lock().unwrap().push(id.clone());
+                    msg.token.cancel();
+                }
+                ChannelMessageContents::LinkChannel { .. } => {
+                    let _ = msg.sender.send("sgnl://linkdevice?uuid=test".to_owned());
+                    return Ok(());
+                }
+                _ => {}
+            }
+            let _ = msg.sender.send("".to_owned());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_set_presence() {
+        let backend = Arc::new(RecordingChannelBackend::default());
+        let mut state = get_test_state(backend.clone()).await;
+        let id = crate::db::channel::create("signal", "bot_id", &state.pool)
+            .await
+            .unwrap();
+
+        super::set_presence("signal", "bot_id", false, &mut state)
+            .await
+            .unwrap();
+        let channel = super::read_channel("signal", "bot_id", &state)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!channel.online);
+        assert!(backend.started.lock().unwrap().is_empty());
+
+        super::set_presence("signal", "bot_id", true, &mut state)
+            .await
+            .unwrap();
+        let channel = super::read_channel("signal", "bot_id", &state)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(channel.online);
+        assert_eq!(*backend.started.lock().unwrap(), vec![id]);
+    }
+
+    #[tokio::test]
+    async fn it_should_start_and_stop_a_channel() {
+        let backend = Arc::new(RecordingChannelBackend::default());
+        let mut state = get_test_state(backend.clone()).await;
+        let id = crate::db::channel::create("signal", "bot_id", &state.pool)
+            .await
+            .unwrap();
+
+        // starting a running channel does nothing
+        for _ in 0..2 {
+            super::start_channel("signal", "bot_id", None, &mut state)
+                .await
+                .unwrap();
+        }
+        assert_eq!(*backend.started.lock().unwrap(), vec![id.clone()]);
+
+        super::stop_channel("signal", "bot_id", &state)
+            .await
+            .unwrap();
+        super::stop_channel("signal", "bot_id", &state)
+            .await
+            .unwrap();
+        assert_eq!(*backend.stopped.lock().unwrap(), vec![id.clone()]);
+
+        super::start_channel("signal", "bot_id", None, &mut state)
+            .await
+            .unwrap();
+        assert_eq!(*backend.started.lock().unwrap(), vec![id.clone(), id]);
+
+        assert!(
+            super::start_channel("other", "bot_id", None, &mut state)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_pause_channels_in_maintenance() {
+        let backend = Arc::new(RecordingChannelBackend::default());
+        let mut state = get_test_state(backend.clone()).await;
+        let id = crate::db::channel::create("signal", "bot_id", &state.pool)
+            .await
+            .unwrap();
+        super::spawn_channel(&id, "bot_id", &mut state)
+            .await
+            .unwrap();
+        let token = state
+            .tokens
+            .lock()
+            .await
+            .get(&("bot_id".to_owned(), id.clone()))
+            .cloned()
+            .unwrap();
+
+        super::set_maintenance(true, &mut state).await.unwrap();
+        assert!(token.is_cancelled());
+        assert!(
+            super::spawn_channel(&id, "bot_id", &mut state)
+                .await
+                .is_err()
+        );
+
+        super::set_maintenance(false, &mut state).await.unwrap();
+        assert_eq!(*backend.started.lock().unwrap(), vec![id.clone(), id]);
+    }
+
+    #[tokio::test]
+    async fn it_should_return_the_link_as_a_qr_code() {
+        use base64::prelude::*;
+        use bitpart_common::socket::QrFormat;
+
+        let backend = Arc::new(RecordingChannelBackend::default());
+        let mut state = get_test_state(backend).await;
+        let dir = state.attachments_dir.clone();
+
+        let url = super::link_channel(
+            "signal",
+            "bot_id",
+            "bitpart",
+            QrFormat::Url,
+            Some("http://apps"),
+            dir.clone(),
+            &mut state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(url, "sgnl://linkdevice?uuid=test");
+        let channel = super::read_channel("signal", "bot_id", &state)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(channel.apps_endpoint.as_deref(), Some("http://apps"));
+
+        let svg = super::link_channel(
+            "signal",
+            "bot_id",
+            "bitpart",
+            QrFormat::Svg,
+            None,
+            dir.clone(),
+            &mut state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(svg["url"], "sgnl://linkdevice?uuid=test");
+        assert_eq!(svg["format"], "svg");
+        let image = BASE64_STANDARD
+            .decode(svg["image"].as_str().unwrap())
+            .unwrap();
+        assert!(String::from_utf8(image).unwrap().contains("<svg"));
+
+        let png = super::link_channel(
+            "signal",
+            "bot_id",
+            "bitpart",
+            QrFormat::Png,
+            None,
+            dir,
+            &mut state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(png["format"], "png");
+        let image = BASE64_STANDARD
+            .decode(png["image"].as_str().unwrap())
+            .unwrap();
+        assert!(image.starts_with(b"\x89PNG"));
+    }
+
+    #[tokio::test]
+    async fn it_should_stop_a_deleted_channel() {
+        let backend = Arc::new(RecordingChannelBackend::default());
+        let mut state = get_test_state(backend.clone()).await;
+        let id = crate::db::channel::create("signal", "bot_id", &state.pool)
+            .await
+            .unwrap();
+        let other = crate::db::channel::create("signal", "other_bot_id", &state.pool)
+            .await
+            .unwrap();
+        super::spawn_channel(&id, "bot_id", &mut state)
+            .await
+            .unwrap();
+        super::spawn_channel(&other, "other_bot_id", &mut state)
+            .await
+            .unwrap();
+        let tokens = state.tokens.lock().await.clone();
+        let token = &tokens[&("bot_id".to_owned(), id.clone())];
+        let other_token = &tokens[&("other_bot_id".to_owned(), other.clone())];
+
+        super::delete_channel("signal", "bot_id", &state)
+            .await
+            .unwrap();
+
+        assert!(token.is_cancelled());
+        assert!(!other_token.is_cancelled());
+        assert_eq!(*backend.stopped.lock().unwrap(), vec![id]);
+        assert_eq!(state.tokens.lock().await.len(), 1);
+        assert!(
+            super::read_channel("signal", "bot_id", &state)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_start_online_channels() {
+        let backend = Arc::new(RecordingChannelBackend::default());
+        let mut state = get_test_state(backend.clone()).await;
+        let online = crate::db::channel::create("signal", "bot_id", &state.pool)
+            .await
+            .unwrap();
+        crate::db::channel::create("signal", "other_bot_id", &state.pool)
+            .await
+            .unwrap();
+        crate::db::channel::set_online("signal", "other_bot_id", false, &state.pool)
+            .await
+            .unwrap();
+
+        let started = super::start_online_channels(&mut state).await.unwrap();
+
+        assert_eq!(started, vec![online.clone()]);
+        assert_eq!(*backend.started.lock().unwrap(), vec![online]);
+    }
 
     #[tokio::test]
     async fn it_should_create_a_channel() {
@@ -356,4 +896,153 @@ mod test_channel {
 
         socket.assert_receive_text_contains("test2").await
     }
+
+    #[tokio::test]
+    async fn it_should_report_store_stats() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateChannel",
+                "data": {
+                    "id": "signal",
+                    "bot_id": "bot_id",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("CreateChannel").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "StoreStats",
+                "data": {
+                    "id": "signal",
+                    "bot_id": "bot_id",
+                }
+            }))
+            .await;
+
+        let res = socket.receive_json::<serde_json::Value>().await;
+        assert_eq!(res["data"]["response_type"], "StoreStats");
+        let trees = res["data"]["response"].as_array().unwrap();
+        for tree in ["identities", "sessions", "pre_keys", "state"] {
+            let stats = trees.iter().find(|t| t["tree"] == tree).unwrap();
+            assert_eq!(stats["rows"], 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_describe_an_unlinked_channel() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateChannel",
+                "data": {
+                    "id": "signal",
+                    "bot_id": "bot_id",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("CreateChannel").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "DescribeChannel",
+                "data": {
+                    "id": "signal",
+                    "bot_id": "bot_id",
+                }
+            }))
+            .await;
+
+        let res = socket.receive_json::<serde_json::Value>().await;
+        assert_eq!(
+            res["data"]["response"],
+            json!({
+                "registered": false,
+                "device_name": null,
+                "phone_number": null,
+                "aci": null,
+            })
+        );
+
+        socket
+            .send_json(&json!({
+                "message_type": "DescribeChannel",
+                "data": {
+                    "id": "signal",
+                    "bot_id": "other_bot",
+                }
+            }))
+            .await;
+
+        socket
+            .assert_receive_text_contains("Describing non-existent channel")
+            .await
+    }
+
+    #[tokio::test]
+    async fn it_should_delete_a_contact_and_group() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateChannel",
+                "data": {
+                    "id": "signal",
+                    "bot_id": "bot_id",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("CreateChannel").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "DeleteContact",
+                "data": {
+                    "id": "signal",
+                    "bot_id": "bot_id",
+                    "uuid": "8c4d6a4e-4bd6-4cbd-a7b1-7b1c2e0a4a8e",
+                }
+            }))
+            .await;
+
+        let res = socket.receive_json::<serde_json::Value>().await;
+        assert_eq!(res["data"]["response_type"], "DeleteContact");
+        assert_eq!(res["data"]["response"], false);
+
+        socket
+            .send_json(&json!({
+                "message_type": "DeleteGroup",
+                "data": {
+                    "id": "signal",
+                    "bot_id": "bot_id",
+                    "master_key": "00".repeat(32),
+                }
+            }))
+            .await;
+
+        let res = socket.receive_json::<serde_json::Value>().await;
+        assert_eq!(res["data"]["response_type"], "DeleteGroup");
+        assert_eq!(res["data"]["response"], false);
+
+        socket
+            .send_json(&json!({
+                "message_type": "DeleteGroup",
+                "data": {
+                    "id": "signal",
+                    "bot_id": "bot_id",
+                    "master_key": "not-hex",
+                }
+            }))
+            .await;
+
+        socket
+            .assert_receive_text_contains("Invalid group master key")
+            .await;
+    }
 }
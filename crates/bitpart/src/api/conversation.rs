@@ -0,0 +1,1033 @@
+// Bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use bitpart_common::error::{BitpartErrorKind, Result};
+use bitpart_common::socket::MemoryData;
+use chrono::{DateTime, NaiveDateTime};
+use csml_interpreter::data::{Client, Memory};
+use presage::libsignal_service::protocol::ServiceId;
+use presage::store::{ContentsStore, Thread};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use tokio::sync::oneshot;
+use tracing::{error, warn};
+
+use crate::{
+    api::ApiState,
+    channels::signal,
+    csml::{callback, conversation::init_bot, interpret, utils},
+    db,
+};
+
+/**
+ * Move a user's conversation to the given flow and step, so that their next
+ * message resumes there. Any pending hold is cleared. Returns the id of the
+ * conversation that was updated (or created, if none was open).
+ */
+pub async fn set_conversation_step(
+    client: &Client,
+    flow: &str,
+    step: &str,
+    state: &ApiState,
+) -> Result<String> {
+    let mut bot = db::bot::get_latest_by_bot_id(&client.bot_id, &state.pool)
+        .await?
+        .ok_or_else(|| BitpartErrorKind::Api(format!("No such bot: {}", client.bot_id)))?
+        .bot;
+    init_bot(&mut bot)?;
+
+    let found = utils::get_flow_by_id(flow, &bot.flows)?;
+    if !utils::step_exists(&bot, found, step)? {
+        return Err(BitpartErrorKind::Api(format!(
+            "Step '{}' does not exist in flow '{}'",
+            step, found.id
+        ))
+        .into());
+    }
+
+    let conversation_id =
+        match db::conversation::get_latest_open_by_client(client, &state.pool).await? {
+            Some(conversation) => {
+                db::conversation::update(
+                    &conversation.id,
+                    Some(found.id.clone()),
+                    Some(step.to_owned()),
+                    &state.pool,
+                )
+                .await?;
+                conversation.id
+            }
+            None => db::conversation::create(&found.id, step, client, None, &state.pool).await?,
+        };
+    db::state::delete(client, "hold", "position", &state.pool).await?;
+
+    Ok(conversation_id)
+}
+
+/// Where a user is in the bot's flows
+#[derive(Debug, Serialize)]
+pub struct ConversationState {
+    pub id: String,
+    pub flow: String,
+    pub step: String,
+    pub status: String,
+    pub last_interaction_at: String,
+    /// Set while the bot waits for the user's answer. Its variables are
+    /// redacted when the hold or the conversation is secure.
+    pub hold: Option<Value>,
+}
+
+/**
+ * The state of a user's latest conversation, open or not, or `None` if they
+ * never talked to the bot.
+ */
+pub async fn get_conversation(
+    client: &Client,
+    state: &ApiState,
+) -> Result<Option<ConversationState>> {
+    let Some(conversation) = db::conversation::get_latest_by_client(client, &state.pool).await?
+    else {
+        return Ok(None);
+    };
+
+    let hold = match db::state::get(client, "hold", "position", &state.pool).await {
+        Ok(hold) => {
+            let secure = hold["secure"].as_bool().unwrap_or(false)
+                || utils::is_secure_conversation(client, &conversation.id, &state.pool).await;
+            let step_vars = if secure {
+                utils::secure_placeholder()
+            } else {
+                hold["step_vars"].clone()
+            };
+            Some(json!({ "secure": secure, "step_vars": step_vars }))
+        }
+        Err(_) => None,
+    };
+
+    Ok(Some(ConversationState {
+        id: conversation.id,
+        flow: conversation.flow_id,
+        step: conversation.step_id,
+        status: conversation.status,
+        last_interaction_at: conversation.last_interaction_at,
+        hold,
+    }))
+}
+
+/**
+ * A bot's conversations, most recently active first, for auditing active
+ * sessions. They can be narrowed to one channel, one user or one status.
+ * Expired conversations are left out.
+ */
+pub async fn list_conversations(
+    bot_id: &str,
+    channel_id: Option<&str>,
+    user_id: Option<&str>,
+    status: Option<&str>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    state: &ApiState,
+) -> Result<Vec<db::conversation::Model>> {
+    if let Some(status) = status
+        && status != "OPEN"
+        && status != "CLOSED"
+        && status != interpret::HANDOFF
+    {
+        return Err(BitpartErrorKind::Api(format!(
+            "invalid status {}, expected OPEN, CLOSED or {}",
+            status,
+            interpret::HANDOFF
+        ))
+        .into());
+    }
+    db::conversation::query(
+        bot_id,
+        channel_id,
+        user_id,
+        status,
+        limit,
+        offset,
+        &state.pool,
+    )
+    .await
+}
+
+/**
+ * Hand a user's conversation back from the operator to the bot. The handed off
+ * conversation is closed, so the user's next message starts a new one. Returns
+ * the id of the conversation that was closed, or `None` if the user's latest
+ * conversation wasn't handed off.
+ */
+pub async fn end_handoff(client: &Client, state: &ApiState) -> Result<Option<String>> {
+    let Some(conversation) = db::conversation::get_latest_by_client(client, &state.pool).await?
+    else {
+        return Ok(None);
+    };
+    if conversation.status != interpret::HANDOFF {
+        return Ok(None);
+    }
+
+    db::conversation::set_status_by_id(&conversation.id, "CLOSED", &state.pool).await?;
+    utils::clear_secure_conversation(client, &conversation.id, &state.pool).await?;
+    if let Some(version) = db::bot::get_latest_by_bot_id(&client.bot_id, &state.pool).await? {
+        callback::conversation_ended(
+            callback::lifecycle_webhook(&version.bot, callback::END_WEBHOOK).as_deref(),
+            &conversation.id,
+            client,
+            &conversation.flow_id,
+            "handoff_end",
+            &state.pool,
+        )
+        .await?;
+    }
+
+    Ok(Some(conversation.id))
+}
+
+/**
+ * Set the status of a user's latest conversation to `OPEN` or `CLOSED`, e.g.
+ * to force-close one that is stuck holding state. Closing it drops any hold
+ * and fires the bot's end webhook, so the user's next message starts a new
+ * conversation from the beginning. Returns
+ * the id of the conversation, or `None` if the user has none.
+ */
+pub async fn patch_conversation(
+    client: &Client,
+    status: &str,
+    state: &ApiState,
+) -> Result<Option<String>> {
+    if status != "OPEN" && status != "CLOSED" {
+        return Err(BitpartErrorKind::Api(format!(
+            "invalid status {}, expected OPEN or CLOSED",
+            status
+        ))
+        .into());
+    }
+    let Some(conversation) = db::conversation::get_latest_by_client(client, &state.pool).await?
+    else {
+        return Ok(None);
+    };
+    if conversation.status == status {
+        return Ok(Some(conversation.id));
+    }
+
+    db::conversation::set_status_by_id(&conversation.id, status, &state.pool).await?;
+    if status == "CLOSED" {
+        // a pending hold would otherwise resume in the user's next conversation
+        db::state::delete(client, "hold", "position", &state.pool).await?;
+        utils::clear_secure_conversation(client, &conversation.id, &state.pool).await?;
+        if let Some(version) = db::bot::get_latest_by_bot_id(&client.bot_id, &state.pool).await? {
+            callback::conversation_ended(
+                callback::lifecycle_webhook(&version.bot, callback::END_WEBHOOK).as_deref(),
+                &conversation.id,
+                client,
+                &conversation.flow_id,
+                "closed",
+                &state.pool,
+            )
+            .await?;
+        }
+    }
+
+    Ok(Some(conversation.id))
+}
+
+/**
+ * Send a message to a user outside of the bot's flows, for an operator
+ * answering a handed off conversation. The message is recorded in the
+ * conversation if it is handed off.
+ */
+pub async fn send_message(client: &Client, payload: Value, state: &ApiState) -> Result<()> {
+    let Some(channel) = db::channel::get(&client.channel_id, &client.bot_id, &state.pool).await?
+    else {
+        return Err(BitpartErrorKind::Api("Sending on non-existent channel".into()).into());
+    };
+
+    let (send, recv) = oneshot::channel();
+    let msg = signal::ChannelMessage {
+        msg: signal::ChannelMessageContents::SendMessages {
+            id: channel.id,
+            user_id: client.user_id.clone(),
+            messages: vec![json!({ "payload": payload })],
+        },
+        pool: state.pool.clone(),
+        token: state.parent_token.child_token(),
+        tracker: state.tracker.clone(),
+        sender: send,
+    };
+    state.manager.send(msg).await?;
+    let err = recv.await?;
+    if !err.is_empty() {
+        return Err(BitpartErrorKind::Api(err).into());
+    }
+
+    if let Some(conversation) = db::conversation::get_latest_by_client(client, &state.pool).await?
+        && conversation.status == interpret::HANDOFF
+    {
+        db::message::create_in_conversation(&conversation.id, "SEND", &payload, &state.pool)
+            .await?;
+    }
+    Ok(())
+}
+
+/**
+ * Deliver the last `count` messages the bot sent to a user again, for when
+ * their device missed them. Only messages in the `message` table can be
+ * re-sent: nothing is stored in low_data mode, and secure messages are only
+ * kept redacted, so neither is ever re-sent. Returns the number of messages
+ * re-sent.
+ */
+pub async fn resend_recent(client: &Client, count: u64, state: &ApiState) -> Result<usize> {
+    let Some(channel) = db::channel::get(&client.channel_id, &client.bot_id, &state.pool).await?
+    else {
+        return Err(BitpartErrorKind::Api("Resending on non-existent channel".into()).into());
+    };
+
+    let messages = db::message::get_last_sent(client, count, &state.pool)
+        .await?
+        .into_iter()
+        .map(|message| {
+            let payload: Value = serde_json::from_str(&message.payload)?;
+            Ok(json!({ "payload": payload }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if messages.is_empty() {
+        return Ok(0);
+    }
+
+    let (send, recv) = oneshot::channel();
+    let sent = messages.len();
+    let msg = signal::ChannelMessage {
+        msg: signal::ChannelMessageContents::SendMessages {
+            id: channel.id,
+            user_id: client.user_id.clone(),
+            messages,
+        },
+        pool: state.pool.clone(),
+        token: state.parent_token.child_token(),
+        tracker: state.tracker.clone(),
+        sender: send,
+    };
+    state.manager.send(msg).await?;
+    let err = recv.await?;
+    if !err.is_empty() {
+        return Err(BitpartErrorKind::Api(err).into());
+    }
+    Ok(sent)
+}
+
+/**
+ * The replies a bot's channels failed to deliver, including those that timed
+ * out. Their content is never kept, but `resend_recent` can deliver the user's
+ * last messages again where they were stored.
+ */
+pub async fn list_failed_sends(
+    bot_id: &str,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    state: &ApiState,
+) -> Result<Vec<db::message::FailedSend>> {
+    db::message::get_failed(bot_id, limit, offset, &state.pool).await
+}
+
+/**
+ * The `callback_url` messages and webhooks that were given up on after every
+ * delivery attempt failed, oldest first.
+ */
+pub async fn list_failed_callbacks(
+    limit: Option<u64>,
+    offset: Option<u64>,
+    state: &ApiState,
+) -> Result<Vec<db::callback::Model>> {
+    db::callback::get_dead(limit, offset, &state.pool).await
+}
+
+/**
+ * Queue the callbacks that were given up on for delivery again, or only those
+ * to `url`. Returns the number queued.
+ */
+pub async fn replay_callbacks(url: Option<&str>, state: &ApiState) -> Result<usize> {
+    callback::replay(url, &state.pool).await
+}
+
+fn parse_time(time: Option<&str>) -> Result<Option<NaiveDateTime>> {
+    time.map(|time| {
+        DateTime::parse_from_rfc3339(time)
+            .map(|time| time.naive_utc())
+            .map_err(|e| BitpartErrorKind::Api(format!("invalid time {}: {}", time, e)).into())
+    })
+    .transpose()
+}
+
+/**
+ * A user's stored messages, oldest first, optionally only those in one
+ * `direction` (`SEND` or `RECEIVE`) and created between `from` (included) and
+ * `to` (excluded), given as RFC 3339 times. Nothing is stored in low_data
+ * mode outside of handoffs, and secure messages are only kept redacted.
+ */
+pub async fn query_messages(
+    client: &Client,
+    direction: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    state: &ApiState,
+) -> Result<Vec<db::message::MessageRecord>> {
+    if let Some(direction) = direction
+        && direction != "SEND"
+        && direction != "RECEIVE"
+    {
+        return Err(BitpartErrorKind::Api(format!(
+            "invalid direction {}, expected SEND or RECEIVE",
+            direction
+        ))
+        .into());
+    }
+    let messages = db::message::query(
+        client,
+        direction,
+        parse_time(from)?,
+        parse_time(to)?,
+        limit,
+        offset,
+        &state.pool,
+    )
+    .await?;
+    Ok(messages.into_iter().map(Into::into).collect())
+}
+
+/**
+ * Forget a user, for a request to erase their data: everything the bot
+ * stored about them and, on Signal, their message thread in the channel's
+ * store. Group threads are left alone, as they hold other users' messages
+ * too. Returns the number of rows removed from the bot's tables, and whether
+ * the channel's thread was cleared too.
+ */
+pub async fn forget_user(client: &Client, state: &ApiState) -> Result<db::user::Forgotten> {
+    let mut forgotten = db::user::forget(client, &state.pool).await?;
+
+    // the bot's tables are already cleared, so a failure here is reported in
+    // the response rather than failing the whole request
+    forgotten.channel_thread = match forget_channel_thread(client, state).await {
+        Ok(cleared) => cleared,
+        Err(err) => {
+            error!(
+                bot_id = %client.bot_id,
+                channel_id = %client.channel_id,
+                "Failed to clear forgotten user's thread from the channel's store: {:?}",
+                err
+            );
+            false
+        }
+    };
+    Ok(forgotten)
+}
+
+/// Clear a user's message thread from their channel's Signal store. Returns
+/// whether there was a store to clear it from.
+async fn forget_channel_thread(client: &Client, state: &ApiState) -> Result<bool> {
+    let Some(channel) = db::channel::get(&client.channel_id, &client.bot_id, &state.pool).await?
+    else {
+        return Ok(false);
+    };
+    let Some(service_id) = ServiceId::parse_from_service_id_string(&client.user_id) else {
+        warn!(
+            bot_id = %client.bot_id,
+            channel_id = %client.channel_id,
+            "Forgotten user's id is not a Signal service id, so no thread was cleared"
+        );
+        return Ok(false);
+    };
+    let mut store = signal::open_store(&channel.id, &state.pool).await?;
+    store.clear_thread(&Thread::Contact(service_id)).await?;
+    Ok(true)
+}
+
+/**
+ * Store many memories for a user at once, e.g. when migrating from another
+ * platform. Existing memories with the same keys are overwritten. A batch
+ * that gives the same key twice is rejected. Returns the number stored.
+ */
+pub async fn create_memories(
+    client: &Client,
+    memories: Vec<MemoryData>,
+    state: &ApiState,
+) -> Result<usize> {
+    let mut by_key = HashMap::with_capacity(memories.len());
+    for MemoryData { key, value } in memories {
+        if by_key.contains_key(&key) {
+            return Err(BitpartErrorKind::Api(format!("duplicate memory key {}", key)).into());
+        }
+        by_key.insert(key.clone(), Memory { key, value });
+    }
+    db::memory::create_many(client, &by_key, None, &state.pool).await?;
+    Ok(by_key.len())
+}
+
+/**
+ * A user's memories whose keys start with `prefix`, in key order, with when
+ * they expire. An empty prefix returns all of them.
+ */
+pub async fn get_memories(
+    client: &Client,
+    prefix: &str,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    state: &ApiState,
+) -> Result<Vec<db::memory::Model>> {
+    db::memory::get_by_client_prefix(client, prefix, limit, offset, &state.pool).await
+}
+
+#[cfg(test)]
+mod test_conversation {
+    use crate::channels::signal::{ChannelBackend, ChannelMessage, ChannelMessageContents};
+    use crate::utils::{get_test_socket, get_test_socket_with_pool, get_test_state};
+    use bitpart_common::error::Result;
+    use csml_interpreter::data::Client;
+    use serde_json::{Value, json};
+    use std::sync::{Arc, Mutex};
+
+    /// Records the messages it was asked to send
+    #[derive(Default)]
+    struct RecordingChannelBackend {
+        sent: Mutex<Vec<(String, Vec<Value>)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ChannelBackend for RecordingChannelBackend {
+        async fn send(&self, msg: ChannelMessage) -> Result<()> {
+            if let ChannelMessageContents::SendMessages {
+                user_id, messages, ..
+            } = msg.msg
+            {
+                self.sent.lock().unwrap().push((user_id, messages));
+            }
+            let _ = msg.sender.send("".to_owned());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_resend_recent_messages() {
+        let backend = Arc::new(RecordingChannelBackend::default());
+        let state = get_test_state(backend.clone()).await;
+        crate::db::channel::create("signal", "bot_id", &state.pool)
+            .await
+            .unwrap();
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "signal".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let conversation_id =
+            crate::db::conversation::create("Default", "start", &client, None, &state.pool)
+                .await
+                .unwrap();
+        let sent: Vec<Value> = ["one", "two", "three"]
+            .iter()
+            .map(|text| json!({"content_type": "text", "content": {"text": text}}))
+            .collect();
+        for payload in &sent {
+            crate::db::message::create_in_conversation(
+                &conversation_id,
+                "SEND",
+                payload,
+                &state.pool,
+            )
+            .await
+            .unwrap();
+        }
+
+        let count = super::resend_recent(&client, 2, &state).await.unwrap();
+
+        assert_eq!(count, 2);
+        let resent = backend.sent.lock().unwrap();
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].0, "user_id");
+        assert_eq!(
+            resent[0].1,
+            vec![json!({"payload": sent[1]}), json!({"payload": sent[2]})]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_send_an_operator_reply() {
+        let backend = Arc::new(RecordingChannelBackend::default());
+        let state = get_test_state(backend.clone()).await;
+        crate::db::channel::create("signal", "bot_id", &state.pool)
+            .await
+            .unwrap();
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "signal".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let conversation_id =
+            crate::db::conversation::create("Default", "start", &client, None, &state.pool)
+                .await
+                .unwrap();
+        crate::db::conversation::set_status_by_id(&conversation_id, "HANDOFF", &state.pool)
+            .await
+            .unwrap();
+        let reply = json!({"content_type": "text", "content": {"text": "Hi, I'm here"}});
+
+        super::send_message(&client, reply.clone(), &state)
+            .await
+            .unwrap();
+
+        let sent = backend.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "user_id");
+        assert_eq!(sent[0].1, vec![json!({"payload": reply})]);
+        let recorded = crate::db::message::get_last_sent(&client, 1, &state.pool)
+            .await
+            .unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert!(recorded[0].payload.contains("Hi, I'm here"));
+    }
+
+    #[tokio::test]
+    async fn it_should_import_memories_in_bulk() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+        let client = json!({
+            "user_id": "user_id",
+            "channel_id": "channel_id",
+            "bot_id": "bot_id"
+        });
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateMemories",
+                "data": {
+                    "client": client,
+                    "memories": [
+                        { "key": "name", "value": "Ada" },
+                        { "key": "visits", "value": 3 },
+                    ]
+                }
+            }))
+            .await;
+        let res = socket.receive_json::<Value>().await;
+        assert_eq!(res["data"]["response"], 2);
+
+        let client: Client = serde_json::from_value(client).unwrap();
+        let mut stored = crate::db::memory::get_by_client(&client, None, None, &pool)
+            .await
+            .unwrap();
+        stored.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].key, "name");
+        assert_eq!(stored[0].value, json!("Ada"));
+        assert_eq!(stored[1].value, json!(3));
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateMemories",
+                "data": {
+                    "client": client,
+                    "memories": [
+                        { "key": "name", "value": "Grace" },
+                        { "key": "name", "value": "Ada" },
+                    ]
+                }
+            }))
+            .await;
+        let res = socket.receive_json::<Value>().await;
+        assert_eq!(res["message_type"], "Error");
+        assert!(
+            res["data"]["response"]
+                .as_str()
+                .unwrap()
+                .contains("duplicate memory key name")
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_set_conversation_step() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end\nother: say \"Other\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Hello").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "SetConversationStep",
+                "data": {
+                    "client": {
+                        "user_id": "user_id",
+                        "channel_id": "channel_id",
+                        "bot_id": "bot_id"
+                    },
+                    "flow": "default",
+                    "step": "other",
+                }
+            }))
+            .await;
+
+        socket
+            .assert_receive_text_contains("SetConversationStep")
+            .await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "ChatRequest",
+                "data": {
+                    "bot_id": "bot_id",
+                    "event": {
+                        "id": "request_id",
+                        "client": {
+                            "user_id": "user_id",
+                            "channel_id": "channel_id",
+                            "bot_id": "bot_id"
+                        },
+                        "payload": {
+                          "content_type": "text" ,
+                          "content": {
+                            "text": "hi"
+                          }
+                        },
+                        "metadata": Value::Null,
+                    }
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Other").await
+    }
+
+    #[tokio::test]
+    async fn it_should_start_fresh_after_closing_a_conversation() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Name?\" hold say \"Thanks\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+        socket.receive_json::<Value>().await;
+
+        let client = json!({
+            "user_id": "user_id",
+            "channel_id": "channel_id",
+            "bot_id": "bot_id"
+        });
+        let chat = json!({
+            "message_type": "ChatRequest",
+            "data": {
+                "bot_id": "bot_id",
+                "event": {
+                    "id": "request_id",
+                    "client": client,
+                    "payload": {
+                      "content_type": "text" ,
+                      "content": {
+                        "text": "hi"
+                      }
+                    },
+                    "metadata": Value::Null,
+                }
+            }
+        });
+
+        socket.send_json(&chat).await;
+        socket.assert_receive_text_contains("Name?").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "PatchConversation",
+                "data": { "client": client, "status": "PAUSED" }
+            }))
+            .await;
+        socket
+            .assert_receive_text_contains("expected OPEN or CLOSED")
+            .await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "PatchConversation",
+                "data": { "client": client, "status": "CLOSED" }
+            }))
+            .await;
+        let res = socket.receive_json::<Value>().await;
+        assert!(res["data"]["response"].is_string());
+
+        socket.send_json(&chat).await;
+        socket.assert_receive_text_contains("Name?").await
+    }
+
+    #[tokio::test]
+    async fn it_should_report_whether_the_channel_thread_was_forgotten() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+        let forget = |user_id: &str| {
+            json!({
+                "message_type": "ForgetUser",
+                "data": {
+                    "client": {
+                        "bot_id": "bot_id",
+                        "channel_id": "signal",
+                        "user_id": user_id
+                    }
+                }
+            })
+        };
+        let aci = "a1b2c3d4-0000-4000-8000-000000000001";
+
+        // without a channel there is no thread to clear
+        socket.send_json(&forget(aci)).await;
+        let res = socket.receive_json::<Value>().await;
+        assert_eq!(res["data"]["response"]["channel_thread"], false);
+
+        crate::db::channel::create("signal", "bot_id", &pool)
+            .await
+            .unwrap();
+        socket.send_json(&forget(aci)).await;
+        let res = socket.receive_json::<Value>().await;
+        assert_eq!(res["data"]["response"]["channel_thread"], true);
+
+        // nor for a user that isn't a Signal contact
+        socket.send_json(&forget("user_id")).await;
+        let res = socket.receive_json::<Value>().await;
+        assert_eq!(res["data"]["response"]["channel_thread"], false);
+        assert_eq!(res["data"]["response"]["conversations"], 0);
+    }
+
+    #[tokio::test]
+    async fn it_should_get_the_conversation_mid_flow() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto ask\nask: say \"Name?\" hold say \"Thanks\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Hello").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "ChatRequest",
+                "data": {
+                    "bot_id": "bot_id",
+                    "event": {
+                        "id": "request_id",
+                        "client": {
+                            "user_id": "user_id",
+                            "channel_id": "channel_id",
+                            "bot_id": "bot_id"
+                        },
+                        "payload": {
+                          "content_type": "text" ,
+                          "content": {
+                            "text": "hi"
+                          }
+                        },
+                        "metadata": Value::Null,
+                    }
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Name?").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "GetConversation",
+                "data": {
+                    "client": {
+                        "user_id": "user_id",
+                        "channel_id": "channel_id",
+                        "bot_id": "bot_id"
+                    }
+                }
+            }))
+            .await;
+
+        let response: Value = socket.receive_json().await;
+        let conversation = &response["data"]["response"];
+        assert_eq!(conversation["flow"], "Default");
+        assert_eq!(conversation["step"], "ask");
+        assert_eq!(conversation["status"], "OPEN");
+        assert_eq!(conversation["hold"]["secure"], false);
+    }
+
+    #[tokio::test]
+    async fn it_should_list_conversations_with_filters() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+        let client = |channel_id: &str, user_id: &str| Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: channel_id.to_owned(),
+            user_id: user_id.to_owned(),
+        };
+        crate::db::conversation::create("Default", "start", &client("signal", "ada"), None, &pool)
+            .await
+            .unwrap();
+        let closed = crate::db::conversation::create(
+            "Default",
+            "end",
+            &client("signal", "grace"),
+            None,
+            &pool,
+        )
+        .await
+        .unwrap();
+        crate::db::conversation::set_status_by_id(&closed, "CLOSED", &pool)
+            .await
+            .unwrap();
+        crate::db::conversation::create("Other", "ask", &client("web", "ada"), None, &pool)
+            .await
+            .unwrap();
+        let other_bot = Client {
+            bot_id: "other_bot".to_owned(),
+            ..client("signal", "ada")
+        };
+        crate::db::conversation::create("Default", "start", &other_bot, None, &pool)
+            .await
+            .unwrap();
+
+        socket
+            .send_json(&json!({
+                "message_type": "ListConversations",
+                "data": { "bot_id": "bot_id" }
+            }))
+            .await;
+        let res = socket.receive_json::<Value>().await;
+        assert_eq!(res["data"]["response"].as_array().unwrap().len(), 3);
+
+        socket
+            .send_json(&json!({
+                "message_type": "ListConversations",
+                "data": { "bot_id": "bot_id", "channel_id": "signal", "status": "OPEN" }
+            }))
+            .await;
+        let res = socket.receive_json::<Value>().await;
+        let conversations = res["data"]["response"].as_array().unwrap();
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(conversations[0]["user_id"], "ada");
+        assert_eq!(conversations[0]["flow_id"], "Default");
+        assert_eq!(conversations[0]["step_id"], "start");
+        assert!(conversations[0]["created_at"].is_string());
+
+        socket
+            .send_json(&json!({
+                "message_type": "ListConversations",
+                "data": { "bot_id": "bot_id", "user_id": "ada", "limit": 1 }
+            }))
+            .await;
+        let res = socket.receive_json::<Value>().await;
+        assert_eq!(res["data"]["response"].as_array().unwrap().len(), 1);
+
+        socket
+            .send_json(&json!({
+                "message_type": "ListConversations",
+                "data": { "bot_id": "bot_id", "status": "PAUSED" }
+            }))
+            .await;
+        socket
+            .assert_receive_text_contains("expected OPEN, CLOSED or HANDOFF")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_unknown_step() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Hello").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "SetConversationStep",
+                "data": {
+                    "client": {
+                        "user_id": "user_id",
+                        "channel_id": "channel_id",
+                        "bot_id": "bot_id"
+                    },
+                    "flow": "Default",
+                    "step": "missing",
+                }
+            }))
+            .await;
+
+        socket
+            .assert_receive_text_contains("Step 'missing' does not exist in flow 'Default'")
+            .await
+    }
+}
@@ -17,6 +17,7 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 
 use bitpart_common::db::Pool;
 use tokio::sync::Mutex;
@@ -26,15 +27,23 @@ use crate::channels::signal;
 
 pub mod bot;
 pub mod channel;
+pub mod conversation;
 pub mod request;
 
 pub use bot::{
-    create_bot, delete_bot, delete_bot_version, get_bot_diff, get_bot_version, get_bot_versions,
-    list_bots, read_bot, touch_bot_version,
+    create_bot, delete_bot, delete_bot_version, delete_flow, get_bot_diff, get_bot_version,
+    get_bot_versions, list_bot_summaries, list_bots, read_bot, recompile_bots, rollback_bot,
+    upsert_flow,
 };
 pub use channel::{
-    create_channel, delete_channel, link_channel, list_channels, read_channel, reset_channel,
-    start_channel,
+    create_channel, delete_channel, delete_contact, delete_group, describe_channel, in_maintenance,
+    link_channel, list_channels, read_channel, reset_channel, set_maintenance, set_presence,
+    start_channel, start_online_channels, stop_channel, store_stats,
+};
+pub use conversation::{
+    create_memories, end_handoff, forget_user, get_conversation, get_memories, list_conversations,
+    list_failed_callbacks, list_failed_sends, patch_conversation, query_messages, replay_callbacks,
+    resend_recent, send_message, set_conversation_step,
 };
 pub use request::process_request;
 
@@ -47,4 +56,5 @@ pub struct ApiState {
     pub tracker: TaskTracker,
     pub attachments_dir: PathBuf,
     pub manager: Arc<dyn signal::ChannelBackend>,
+    pub maintenance: Arc<AtomicBool>,
 }
@@ -18,10 +18,17 @@ use bitpart_common::{csml::Request, db::Pool, error::Result};
 
 use crate::csml::conversation;
 
+/**
+ * Handle a request. A `dry_run` request is handled without writing anything
+ * to the database, see `conversation::dry_run`.
+ */
 pub async fn process_request(
     body: &Request,
     pool: &Pool,
 ) -> Result<serde_json::Map<String, serde_json::Value>> {
+    if body.dry_run {
+        return conversation::dry_run(body, pool).await;
+    }
     match conversation::start(body, pool).await {
         Ok(res) => Ok(res),
         Err(err) => Err(err),
@@ -30,7 +37,9 @@ pub async fn process_request(
 
 #[cfg(test)]
 mod test_request {
-    use crate::utils::get_test_socket;
+    use crate::db;
+    use crate::utils::{get_test_socket, get_test_socket_with_pool, start_test_receiver};
+    use csml_interpreter::data::Client;
     use serde_json::{Value, json};
 
     #[tokio::test]
@@ -84,4 +93,1527 @@ mod test_request {
 
         socket.assert_receive_text_contains("Hello").await
     }
+
+    #[tokio::test]
+    async fn it_should_name_the_bot_in_the_response() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "Helpdesk",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+        socket.assert_receive_text_contains("Hello").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "ChatRequest",
+                "data": {
+                    "bot_id": "bot_id",
+                    "event": {
+                        "id": "request_id",
+                        "client": {
+                            "user_id": "user_id",
+                            "channel_id": "channel_id",
+                            "bot_id": "bot_id"
+                        },
+                        "payload": {
+                          "content_type": "text" ,
+                          "content": {
+                            "text": "hi"
+                          }
+                        },
+                        "metadata": Value::Null,
+                    }
+                }
+            }))
+            .await;
+
+        let res = socket.receive_json::<Value>().await;
+        assert_eq!(res["data"]["response"]["bot_name"], "Helpdesk");
+        assert_eq!(res["data"]["response"]["client"]["bot_id"], "bot_id");
+    }
+
+    #[tokio::test]
+    async fn it_should_start_flows_at_their_entry_step() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Start\" goto end\nwelcome: say \"Welcome\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                    "env": {
+                        "entry_steps": { "default": "welcome" }
+                    },
+                }
+            }))
+            .await;
+        let res = socket.receive_json::<Value>().await;
+        assert_eq!(res["message_type"], "CreateBot");
+
+        socket
+            .send_json(&json!({
+                "message_type": "ChatRequest",
+                "data": {
+                    "bot_id": "bot_id",
+                    "event": {
+                        "id": "request_id",
+                        "client": {
+                            "user_id": "user_id",
+                            "channel_id": "channel_id",
+                            "bot_id": "bot_id"
+                        },
+                        "payload": {
+                          "content_type": "text" ,
+                          "content": {
+                            "text": "hi"
+                          }
+                        },
+                        "metadata": Value::Null,
+                    }
+                }
+            }))
+            .await;
+
+        let res = socket.receive_json::<Value>().await;
+        let messages = res["data"]["response"]["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["payload"]["content"]["text"], "Welcome");
+    }
+
+    #[tokio::test]
+    async fn it_should_run_the_fallback_flow_when_nothing_matches() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      },
+                      {
+                        "id": "Help",
+                        "name": "Help",
+                        "content": "start: say \"Help\" goto end",
+                        "commands": ["/help"],
+                      },
+                      {
+                        "id": "Fallback",
+                        "name": "Fallback",
+                        "content": "start: say \"Sorry, try /help\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                    "env": {
+                        "fallback_flow": "Fallback"
+                    },
+                }
+            }))
+            .await;
+        let res = socket.receive_json::<Value>().await;
+        assert_eq!(res["message_type"], "CreateBot");
+
+        for (content_type, payload) in [("payload", "/unknown"), ("regex", "^/nothing$")] {
+            socket
+                .send_json(&json!({
+                    "message_type": "ChatRequest",
+                    "data": {
+                        "bot_id": "bot_id",
+                        "event": {
+                            "id": "request_id",
+                            "client": {
+                                "user_id": "user_id",
+                                "channel_id": "channel_id",
+                                "bot_id": "bot_id"
+                            },
+                            "payload": {
+                              "content_type": content_type,
+                              "content": {
+                                "payload": payload
+                              }
+                            },
+                            "metadata": Value::Null,
+                        }
+                    }
+                }))
+                .await;
+
+            let res = socket.receive_json::<Value>().await;
+            let messages = res["data"]["response"]["messages"].as_array().unwrap();
+            assert_eq!(messages.len(), 1);
+            assert_eq!(
+                messages[0]["payload"]["content"]["text"],
+                "Sorry, try /help"
+            );
+        }
+
+        // command-like text is meant to pick a flow too, other text isn't
+        for (text, expected) in [
+            ("/unknown", "Sorry, try /help"),
+            ("!unknown", "Sorry, try /help"),
+            ("hello", "Hello"),
+        ] {
+            socket
+                .send_json(&json!({
+                    "message_type": "ChatRequest",
+                    "data": {
+                        "bot_id": "bot_id",
+                        "event": {
+                            "id": "request_id",
+                            "client": {
+                                "user_id": "user_id",
+                                "channel_id": "channel_id",
+                                "bot_id": "bot_id"
+                            },
+                            "payload": {
+                              "content_type": "text",
+                              "content": {
+                                "text": text
+                              }
+                            },
+                            "metadata": Value::Null,
+                        }
+                    }
+                }))
+                .await;
+
+            let res = socket.receive_json::<Value>().await;
+            let messages = res["data"]["response"]["messages"].as_array().unwrap();
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0]["payload"]["content"]["text"], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_forward_the_event_on_switch_bot() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "target_bot",
+                    "name": "target",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"You said {{event}}\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+        let res = socket.receive_json::<Value>().await;
+        assert_eq!(res["message_type"], "Response");
+
+        for (forward, user_id) in [(true, "forwarded"), (false, "triggered")] {
+            socket
+                .send_json(&json!({
+                    "message_type": "CreateBot",
+                    "data": {
+                        "id": "bot_id",
+                        "name": "test",
+                        "flows": [
+                          {
+                            "id": "Default",
+                            "name": "Default",
+                            "content": "start: goto @@target_bot",
+                            "commands": [],
+                          }
+                        ],
+                        "default_flow": "Default",
+                        "multibot": [{ "id": "target_bot" }],
+                        "env": {
+                            "switch_bot_forward_event": forward
+                        },
+                    }
+                }))
+                .await;
+            let res = socket.receive_json::<Value>().await;
+            assert_eq!(res["message_type"], "Response");
+
+            socket
+                .send_json(&json!({
+                    "message_type": "ChatRequest",
+                    "data": {
+                        "bot_id": "bot_id",
+                        "event": {
+                            "id": "request_id",
+                            "client": {
+                                "user_id": user_id,
+                                "channel_id": "channel_id",
+                                "bot_id": "bot_id"
+                            },
+                            "payload": {
+                              "content_type": "text" ,
+                              "content": {
+                                "text": "I need help with billing"
+                              }
+                            },
+                            "metadata": Value::Null,
+                        }
+                    }
+                }))
+                .await;
+
+            let res = socket.receive_json::<Value>().await;
+            let messages = res["data"]["response"]["messages"].as_array().unwrap();
+            let said = messages
+                .iter()
+                .filter_map(|message| message["payload"]["content"]["text"].as_str())
+                .find(|text| text.starts_with("You said"))
+                .unwrap();
+            assert_eq!(
+                said == "You said I need help with billing",
+                forward,
+                "{said}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_turn_away_requests_in_maintenance() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+        socket.assert_receive_text_contains("Hello").await;
+
+        let request = json!({
+            "message_type": "ChatRequest",
+            "data": {
+                "bot_id": "bot_id",
+                "event": {
+                    "id": "request_id",
+                    "client": {
+                        "user_id": "user_id",
+                        "channel_id": "channel_id",
+                        "bot_id": "bot_id"
+                    },
+                    "payload": {
+                      "content_type": "text" ,
+                      "content": {
+                        "text": "hi"
+                      }
+                    },
+                    "metadata": Value::Null,
+                }
+            }
+        });
+
+        socket
+            .send_json(&json!({
+                "message_type": "SetMaintenance",
+                "data": { "enabled": true }
+            }))
+            .await;
+        socket.assert_receive_text_contains("SetMaintenance").await;
+
+        socket.send_json(&request).await;
+        socket
+            .assert_receive_text_contains("Server is in maintenance")
+            .await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "SetMaintenance",
+                "data": { "enabled": false }
+            }))
+            .await;
+        socket.assert_receive_text_contains("SetMaintenance").await;
+
+        socket.send_json(&request).await;
+        socket.assert_receive_text_contains("Hello").await;
+    }
+
+    #[tokio::test]
+    async fn it_should_answer_the_socket_and_the_library_alike() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" say \"Bye\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+        socket.assert_receive_text_contains("Hello").await;
+
+        let request = |user_id: &str| {
+            json!({
+                "bot_id": "bot_id",
+                "event": {
+                    "id": "request_id",
+                    "client": {
+                        "user_id": user_id,
+                        "channel_id": "channel_id",
+                        "bot_id": "bot_id"
+                    },
+                    "payload": {
+                      "content_type": "text" ,
+                      "content": {
+                        "text": "hi"
+                      }
+                    },
+                    "metadata": Value::Null,
+                }
+            })
+        };
+
+        socket
+            .send_json(&json!({
+                "message_type": "ChatRequest",
+                "data": request("socket_user"),
+            }))
+            .await;
+        let res = socket.receive_json::<Value>().await;
+        let from_socket = &res["data"]["response"]["messages"];
+
+        let body = serde_json::from_value(request("library_user")).unwrap();
+        let res = bitpart::csml::conversation::start(&body, &pool)
+            .await
+            .unwrap();
+        let from_library = &res["messages"];
+
+        let payloads = |messages: &Value| -> Vec<Value> {
+            messages
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|message| message["payload"].clone())
+                .collect()
+        };
+        assert_eq!(payloads(from_socket).len(), 2);
+        assert_eq!(payloads(from_socket), payloads(from_library));
+    }
+
+    #[tokio::test]
+    async fn it_should_handle_a_repeated_idempotency_key_once() {
+        let mut socket = get_test_socket().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Name?\" hold say \"Thanks\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+        socket.assert_receive_text_contains("Name?").await;
+
+        for (key, reply) in [("first", "Name?"), ("first", "Name?"), ("second", "Thanks")] {
+            socket
+                .send_json(&json!({
+                    "message_type": "ChatRequest",
+                    "data": {
+                        "bot_id": "bot_id",
+                        "idempotency_key": key,
+                        "event": {
+                            "id": "request_id",
+                            "client": {
+                                "user_id": "user_id",
+                                "channel_id": "channel_id",
+                                "bot_id": "bot_id"
+                            },
+                            "payload": {
+                              "content_type": "text" ,
+                              "content": {
+                                "text": "Alice"
+                              }
+                            },
+                            "metadata": Value::Null,
+                        }
+                    }
+                }))
+                .await;
+
+            let res = socket.receive_json::<Value>().await;
+            let messages = res["data"]["response"]["messages"].as_array().unwrap();
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0]["payload"]["content"]["text"], reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_resume_hold_without_bot_ast() {
+        let mut socket = get_test_socket().await;
+
+        // An inline bot is never stored, so it arrives without a pre-built ast.
+        let bot = json!({
+            "id": "bot_id",
+            "name": "test",
+            "flows": [
+              {
+                "id": "Default",
+                "name": "Default",
+                "content": "start: say \"Name?\" hold say \"Hi {{event}}\" goto end",
+                "commands": [],
+              }
+            ],
+            "default_flow": "Default",
+            "bot_ast": Value::Null,
+        });
+
+        for (text, expected) in [("hello", vec!["Name?"]), ("Alice", vec!["Hi Alice"])] {
+            socket
+                .send_json(&json!({
+                    "message_type": "ChatRequest",
+                    "data": {
+                        "bot": bot,
+                        "event": {
+                            "id": "request_id",
+                            "client": {
+                                "user_id": "user_id",
+                                "channel_id": "channel_id",
+                                "bot_id": "bot_id"
+                            },
+                            "payload": {
+                              "content_type": "text" ,
+                              "content": {
+                                "text": text
+                              }
+                            },
+                            "metadata": Value::Null,
+                        }
+                    }
+                }))
+                .await;
+
+            // the resumed turn carries on after the hold rather than restarting
+            let res = socket.receive_json::<Value>().await;
+            let said: Vec<&str> = res["data"]["response"]["messages"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter_map(|message| message["payload"]["content"]["text"].as_str())
+                .collect();
+            assert_eq!(said, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_restart_a_held_step_that_changed() {
+        let mut socket = get_test_socket().await;
+
+        let create_bot = |content: &str| {
+            json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": content,
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            })
+        };
+        let chat = |text: &str| {
+            json!({
+                "message_type": "ChatRequest",
+                "data": {
+                    "bot_id": "bot_id",
+                    "event": {
+                        "id": "request_id",
+                        "client": {
+                            "user_id": "user_id",
+                            "channel_id": "channel_id",
+                            "bot_id": "bot_id"
+                        },
+                        "payload": {
+                          "content_type": "text" ,
+                          "content": {
+                            "text": text
+                          }
+                        },
+                        "metadata": Value::Null,
+                    }
+                }
+            })
+        };
+
+        socket
+            .send_json(&create_bot(
+                "start: say \"Name?\" hold say \"Hi {{event}}\" goto end",
+            ))
+            .await;
+        socket.assert_receive_text_contains("Name?").await;
+        socket.send_json(&chat("hello")).await;
+        socket.assert_receive_text_contains("Name?").await;
+
+        // the held step changes before the user answers, so it starts over
+        socket
+            .send_json(&create_bot(
+                "start: say \"Your name?\" hold say \"Hello {{event}}\" goto end",
+            ))
+            .await;
+        socket.assert_receive_text_contains("Your name?").await;
+        for reply in ["Your name?", "Hello Alice"] {
+            socket.send_json(&chat("Alice")).await;
+            let res = socket.receive_json::<Value>().await;
+            let messages = res["data"]["response"]["messages"].as_array().unwrap();
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0]["payload"]["content"]["text"], reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_not_persist_secure_messages() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"PIN?\" hold_secure say \"Thanks\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("PIN?").await;
+
+        for text in ["hi", "1234"] {
+            socket
+                .send_json(&json!({
+                    "message_type": "ChatRequest",
+                    "data": {
+                        "bot_id": "bot_id",
+                        "event": {
+                            "id": "request_id",
+                            "client": {
+                                "user_id": "user_id",
+                                "channel_id": "channel_id",
+                                "bot_id": "bot_id"
+                            },
+                            "payload": {
+                              "content_type": "text" ,
+                              "content": {
+                                "text": text
+                              }
+                            },
+                            "metadata": Value::Null,
+                            "low_data_mode": false,
+                        }
+                    }
+                }))
+                .await;
+        }
+
+        socket.assert_receive_text_contains("PIN?").await;
+        socket.assert_receive_text_contains("Thanks").await;
+
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "channel_id".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let messages = db::message::get_by_client(&client, None, None, &pool)
+            .await
+            .unwrap();
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.direction == "RECEIVE" && m.payload.contains("hi"))
+        );
+        assert!(messages.iter().all(|m| !m.payload.contains("1234")));
+    }
+
+    #[tokio::test]
+    async fn it_should_stop_replying_once_handed_off() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: remember _handoff = \"asked for a person\" say \"Someone will be with you soon\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+        socket.assert_receive_text_contains("Someone").await;
+
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "channel_id".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let chat = |text: &str| {
+            json!({
+                "message_type": "ChatRequest",
+                "data": {
+                    "bot_id": "bot_id",
+                    "event": {
+                        "id": "request_id",
+                        "client": {
+                            "user_id": "user_id",
+                            "channel_id": "channel_id",
+                            "bot_id": "bot_id"
+                        },
+                        "payload": {
+                          "content_type": "text" ,
+                          "content": {
+                            "text": text
+                          }
+                        },
+                        "metadata": Value::Null,
+                        "low_data_mode": false,
+                    }
+                }
+            })
+        };
+
+        socket.send_json(&chat("I need help")).await;
+        socket
+            .assert_receive_text_contains("Someone will be with you soon")
+            .await;
+        let conversation = db::conversation::get_latest_by_client(&client, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conversation.status, "HANDOFF");
+
+        // the bot stays quiet, but the message is kept for the operator
+        socket.send_json(&chat("hello?")).await;
+        let res = socket.receive_json::<Value>().await;
+        assert!(res["data"]["response"]["messages"].is_null());
+        let messages = db::message::get_by_client(&client, None, None, &pool)
+            .await
+            .unwrap();
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.direction == "RECEIVE" && m.payload.contains("hello?"))
+        );
+
+        socket
+            .send_json(&json!({
+                "message_type": "EndHandoff",
+                "data": { "client": client }
+            }))
+            .await;
+        let res = socket.receive_json::<Value>().await;
+        assert_eq!(res["data"]["response"], conversation.id);
+
+        socket.send_json(&chat("thanks")).await;
+        socket
+            .assert_receive_text_contains("Someone will be with you soon")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn it_should_not_persist_handed_off_messages_in_no_persist_flows() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: remember _handoff = \"asked for a person\" say \"Someone will be with you soon\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                    "env": { "no_persist": ["Default"] },
+                }
+            }))
+            .await;
+        socket.assert_receive_text_contains("Someone").await;
+
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "channel_id".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        for text in ["I need help", "hello?"] {
+            socket
+                .send_json(&json!({
+                    "message_type": "ChatRequest",
+                    "data": {
+                        "bot_id": "bot_id",
+                        "event": {
+                            "id": "request_id",
+                            "client": {
+                                "user_id": "user_id",
+                                "channel_id": "channel_id",
+                                "bot_id": "bot_id"
+                            },
+                            "payload": {
+                              "content_type": "text" ,
+                              "content": {
+                                "text": text
+                              }
+                            },
+                            "metadata": Value::Null,
+                            "low_data_mode": false,
+                        }
+                    }
+                }))
+                .await;
+            socket.receive_json::<Value>().await;
+        }
+
+        let conversation = db::conversation::get_latest_by_client(&client, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conversation.status, "HANDOFF");
+        let messages = db::message::get_by_client(&client, None, None, &pool)
+            .await
+            .unwrap();
+        assert!(messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_should_not_persist_secure_conversations() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"PIN?\" hold say \"Thanks\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("PIN?").await;
+
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "channel_id".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        // only the first event is marked secure, which covers the conversation
+        for (text, secure) in [("hi", true), ("1234", false)] {
+            socket
+                .send_json(&json!({
+                    "message_type": "ChatRequest",
+                    "data": {
+                        "bot_id": "bot_id",
+                        "event": {
+                            "id": "request_id",
+                            "client": {
+                                "user_id": "user_id",
+                                "channel_id": "channel_id",
+                                "bot_id": "bot_id"
+                            },
+                            "payload": {
+                              "content_type": "text" ,
+                              "content": {
+                                "text": text
+                              },
+                              "secure": secure
+                            },
+                            "metadata": Value::Null,
+                            "low_data_mode": false,
+                        }
+                    }
+                }))
+                .await;
+            if secure {
+                socket.assert_receive_text_contains("PIN?").await;
+                let conversation = db::conversation::get_latest_open_by_client(&client, &pool)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert!(
+                    db::state::get(&client, "secure", &conversation.id, &pool)
+                        .await
+                        .is_ok()
+                );
+            }
+        }
+
+        socket.assert_receive_text_contains("Thanks").await;
+
+        let messages = db::message::get_by_client(&client, None, None, &pool)
+            .await
+            .unwrap();
+        // the turns are kept, only redacted
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.direction == "RECEIVE" && m.payload.contains("secure"))
+        );
+        assert!(messages.iter().all(|m| !m.payload.contains("1234")));
+        // the flag is cleared once the conversation ends
+        assert!(
+            db::state::get_by_client(&client, &pool)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_fire_conversation_lifecycle_webhooks() {
+        let (url, received) = start_test_receiver().await;
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                    "env": {
+                        "conversation_start_webhook": url,
+                        "conversation_end_webhook": url,
+                    },
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Hello").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "ChatRequest",
+                "data": {
+                    "bot_id": "bot_id",
+                    "event": {
+                        "id": "request_id",
+                        "client": {
+                            "user_id": "user_id",
+                            "channel_id": "channel_id",
+                            "bot_id": "bot_id"
+                        },
+                        "payload": {
+                          "content_type": "text" ,
+                          "content": {
+                            "text": "hi"
+                          }
+                        },
+                        "metadata": Value::Null,
+                    }
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Hello").await;
+
+        // both webhooks go to the same url, and one drain delivers them in order
+        assert_eq!(crate::csml::callback::drain(&pool).await.unwrap(), 2);
+
+        let received = received.lock().unwrap();
+        let events: Vec<&str> = received
+            .iter()
+            .map(|body| body["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(events, vec!["conversation_start", "conversation_end"]);
+        assert_eq!(received[0]["reason"], "new");
+        assert_eq!(received[1]["reason"], "end");
+        assert_eq!(received[1]["client"]["user_id"], "user_id");
+    }
+
+    #[tokio::test]
+    async fn it_should_not_persist_no_persist_flows() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Intake",
+                        "name": "Intake",
+                        "content": "start: say \"Your address?\" hold say \"Noted\" goto end",
+                        "commands": [],
+                      },
+                      {
+                        "id": "Open",
+                        "name": "Open",
+                        "content": "start: say \"Opening hours\" goto end",
+                        "commands": ["hours"],
+                      }
+                    ],
+                    "default_flow": "Intake",
+                    "env": {
+                        "no_persist": ["Intake"],
+                    },
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Your address?").await;
+
+        for text in ["hi", "1 Main St", "hours"] {
+            socket
+                .send_json(&json!({
+                    "message_type": "ChatRequest",
+                    "data": {
+                        "bot_id": "bot_id",
+                        "event": {
+                            "id": "request_id",
+                            "client": {
+                                "user_id": "user_id",
+                                "channel_id": "channel_id",
+                                "bot_id": "bot_id"
+                            },
+                            "payload": {
+                              "content_type": "text" ,
+                              "content": {
+                                "text": text
+                              }
+                            },
+                            "metadata": Value::Null,
+                            "low_data_mode": false,
+                        }
+                    }
+                }))
+                .await;
+        }
+
+        socket.assert_receive_text_contains("Your address?").await;
+        socket.assert_receive_text_contains("Noted").await;
+        socket.assert_receive_text_contains("Opening hours").await;
+
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "channel_id".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let messages = db::message::get_by_client(&client, None, None, &pool)
+            .await
+            .unwrap();
+        assert!(
+            messages
+                .iter()
+                .all(|m| !m.payload.contains("1 Main St") && !m.payload.contains("address"))
+        );
+        // the other flow is persisted as usual
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.direction == "RECEIVE" && m.payload.contains("hours"))
+        );
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.direction == "SEND" && m.payload.contains("Opening hours"))
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_stop_flows_that_loop_forever() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: goto again\nagain: goto start",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("goto again").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "ChatRequest",
+                "data": {
+                    "bot_id": "bot_id",
+                    "event": {
+                        "id": "request_id",
+                        "client": {
+                            "user_id": "user_id",
+                            "channel_id": "channel_id",
+                            "bot_id": "bot_id"
+                        },
+                        "payload": {
+                          "content_type": "text" ,
+                          "content": {
+                            "text": "hi"
+                          }
+                        },
+                        "metadata": Value::Null,
+                        "step_limit": 10,
+                    }
+                }
+            }))
+            .await;
+
+        socket
+            .assert_receive_text_contains("step limit of 10")
+            .await;
+
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "channel_id".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let conversation = db::conversation::get_latest_by_client(&client, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conversation.status, "CLOSED");
+    }
+
+    #[tokio::test]
+    async fn it_should_not_write_anything_on_a_dry_run() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: remember greeted = true say \"Hello\" hold say \"Bye\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Hello").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "ChatRequest",
+                "data": {
+                    "bot_id": "bot_id",
+                    "dry_run": true,
+                    "event": {
+                        "id": "request_id",
+                        "client": {
+                            "user_id": "user_id",
+                            "channel_id": "channel_id",
+                            "bot_id": "bot_id"
+                        },
+                        "payload": {
+                          "content_type": "text" ,
+                          "content": {
+                            "text": "hi"
+                          }
+                        },
+                        "metadata": Value::Null,
+                        "low_data_mode": false,
+                    }
+                }
+            }))
+            .await;
+
+        let res = socket.receive_json::<Value>().await;
+        let response = &res["data"]["response"];
+        assert_eq!(response["request_id"], "request_id");
+        assert_eq!(response["client"]["user_id"], "user_id");
+        assert!(response["messages"].to_string().contains("Hello"));
+
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "channel_id".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        assert!(
+            db::conversation::get_latest_by_client(&client, &pool)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            db::memory::get_by_client(&client, None, None, &pool)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            db::message::get_by_client(&client, None, None, &pool)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            db::state::get_by_client(&client, &pool)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_continue_the_open_conversation_on_a_dry_run() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: remember greeted = \"yes\" say \"Hello\" hold say \"Greeted {{greeted}}\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Hello").await;
+
+        for (text, dry_run) in [("hi", false), ("again", true)] {
+            socket
+                .send_json(&json!({
+                    "message_type": "ChatRequest",
+                    "data": {
+                        "bot_id": "bot_id",
+                        "dry_run": dry_run,
+                        "event": {
+                            "id": "request_id",
+                            "client": {
+                                "user_id": "user_id",
+                                "channel_id": "channel_id",
+                                "bot_id": "bot_id"
+                            },
+                            "payload": {
+                              "content_type": "text" ,
+                              "content": {
+                                "text": text
+                              }
+                            },
+                            "metadata": Value::Null,
+                            "low_data_mode": false,
+                        }
+                    }
+                }))
+                .await;
+
+            let res = socket.receive_json::<Value>().await;
+            let messages = res["data"]["response"]["messages"].to_string();
+            if dry_run {
+                // carried on from the hold, with the user's memories
+                assert!(messages.contains("Greeted yes"));
+            } else {
+                assert!(messages.contains("Hello"));
+            }
+        }
+
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "channel_id".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let conversation = db::conversation::get_latest_by_client(&client, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(conversation.status, "OPEN");
+        assert!(
+            db::state::get(&client, "hold", "position", &pool)
+                .await
+                .is_ok()
+        );
+        let messages = db::message::get_by_client(&client, None, None, &pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|message| message.payload)
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(messages.contains("hi"));
+        assert!(!messages.contains("Greeted"));
+        assert!(!messages.contains("again"));
+    }
+
+    #[tokio::test]
+    async fn it_should_store_received_messages_scrubbed() {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                    "env": {
+                        "scrub_patterns": [r"\+?\d[\d ]{7,}\d"],
+                    },
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Hello").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "ChatRequest",
+                "data": {
+                    "bot_id": "bot_id",
+                    "event": {
+                        "id": "request_id",
+                        "client": {
+                            "user_id": "user_id",
+                            "channel_id": "channel_id",
+                            "bot_id": "bot_id"
+                        },
+                        "payload": {
+                          "content_type": "text" ,
+                          "content": {
+                            "text": "Call me on +1 201 555 0123 please"
+                          }
+                        },
+                        "metadata": Value::Null,
+                        "low_data_mode": false,
+                    }
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Hello").await;
+
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "channel_id".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let messages = db::message::get_by_client(&client, None, None, &pool)
+            .await
+            .unwrap();
+        assert!(messages.iter().any(
+            |m| m.direction == "RECEIVE" && m.payload.contains("Call me on [scrubbed] please")
+        ));
+        assert!(messages.iter().all(|m| !m.payload.contains("555 0123")));
+    }
+
+    async fn chat_with_low_data_mode(low_data_mode: bool) -> Vec<db::message::Model> {
+        let (mut socket, pool) = get_test_socket_with_pool().await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "CreateBot",
+                "data": {
+                    "id": "bot_id",
+                    "name": "test",
+                    "flows": [
+                      {
+                        "id": "Default",
+                        "name": "Default",
+                        "content": "start: say \"Hello\" goto end",
+                        "commands": [],
+                      }
+                    ],
+                    "default_flow": "Default",
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Hello").await;
+
+        socket
+            .send_json(&json!({
+                "message_type": "ChatRequest",
+                "data": {
+                    "bot_id": "bot_id",
+                    "event": {
+                        "id": "request_id",
+                        "client": {
+                            "user_id": "user_id",
+                            "channel_id": "channel_id",
+                            "bot_id": "bot_id"
+                        },
+                        "payload": {
+                          "content_type": "text" ,
+                          "content": {
+                            "text": "hi"
+                          }
+                        },
+                        "metadata": Value::Null,
+                        "low_data_mode": low_data_mode,
+                    }
+                }
+            }))
+            .await;
+
+        socket.assert_receive_text_contains("Hello").await;
+
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "channel_id".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        db::message::get_by_client(&client, None, None, &pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_should_not_persist_messages_in_low_data_mode() {
+        let messages = chat_with_low_data_mode(true).await;
+        assert!(
+            messages
+                .iter()
+                .all(|m| m.direction != "RECEIVE" && m.direction != "SEND")
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_persist_messages_outside_low_data_mode() {
+        let messages = chat_with_low_data_mode(false).await;
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.direction == "RECEIVE" && m.payload.contains("hi"))
+        );
+        assert!(
+            messages
+                .iter()
+                .any(|m| m.direction == "SEND" && m.payload.contains("Hello"))
+        );
+    }
 }
@@ -0,0 +1,176 @@
+// Bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Retention of the attachments received by channels, which are otherwise
+//! kept in the attachments directory forever.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// How often the attachments directory is pruned
+pub const DEFAULT_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Limits on the attachments kept. Either, both or neither can be set.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Total size in bytes of the attachments kept
+    pub max_bytes: Option<u64>,
+    /// How long an attachment is kept after it was written
+    pub max_age: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_bytes.is_none() && self.max_age.is_none()
+    }
+}
+
+struct Saved {
+    path: PathBuf,
+    len: u64,
+    modified: SystemTime,
+}
+
+/// The attachments written by channels, which are all named `bitpart-*`,
+/// oldest first
+fn saved_attachments(dir: &Path) -> std::io::Result<Vec<Saved>> {
+    let mut saved = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with("bitpart-") {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        saved.push(Saved {
+            path: entry.path(),
+            len: metadata.len(),
+            modified: metadata.modified()?,
+        });
+    }
+    saved.sort_by_key(|attachment| attachment.modified);
+    Ok(saved)
+}
+
+/**
+ * Delete the attachments in `dir` older than the policy's `max_age`, then the
+ * oldest of the rest until they fit in `max_bytes`. Returns the number of
+ * attachments deleted.
+ */
+pub fn prune(dir: &Path, policy: &RetentionPolicy) -> std::io::Result<usize> {
+    let saved = saved_attachments(dir)?;
+    let now = SystemTime::now();
+    let mut total: u64 = saved.iter().map(|attachment| attachment.len).sum();
+    let mut deleted = 0;
+    for attachment in saved {
+        let expired = policy.max_age.is_some_and(|max_age| {
+            now.duration_since(attachment.modified)
+                .is_ok_and(|age| age > max_age)
+        });
+        let over = policy.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+        if !expired && !over {
+            // what is left is newer, and fits
+            break;
+        }
+        match std::fs::remove_file(&attachment.path) {
+            Ok(_) => {
+                total -= attachment.len;
+                deleted += 1;
+            }
+            Err(error) => {
+                warn!(file_path =% attachment.path.display(), %error, "failed to delete attachment")
+            }
+        }
+    }
+    Ok(deleted)
+}
+
+/**
+ * Prune the attachments directory every `interval` until `token` is
+ * cancelled.
+ */
+pub async fn run(
+    dir: PathBuf,
+    policy: RetentionPolicy,
+    interval: Duration,
+    token: CancellationToken,
+) {
+    loop {
+        let pruned = {
+            let dir = dir.clone();
+            let policy = policy.clone();
+            tokio::task::spawn_blocking(move || prune(&dir, &policy)).await
+        };
+        match pruned {
+            Ok(Ok(0)) => {}
+            Ok(Ok(deleted)) => info!("deleted {} old attachments", deleted),
+            Ok(Err(error)) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Ok(Err(error)) => error!("attachments pruning: {}", error),
+            Err(error) => error!("attachments pruning: {}", error),
+        }
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, len: usize, age: Duration) {
+        let path = dir.join(name);
+        std::fs::write(&path, vec![0u8; len]).unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    #[test]
+    fn it_should_prune_the_oldest_attachments() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let hour = Duration::from_secs(60 * 60);
+        write(dir.path(), "bitpart-old.png", 10, hour * 48);
+        write(dir.path(), "bitpart-older.png", 10, hour * 72);
+        write(dir.path(), "bitpart-recent.png", 10, hour * 2);
+        write(dir.path(), "bitpart-new.png", 10, hour);
+        write(dir.path(), "other.db", 100, hour * 96);
+
+        assert_eq!(prune(dir.path(), &RetentionPolicy::default()).unwrap(), 0);
+
+        let by_age = RetentionPolicy {
+            max_age: Some(hour * 24),
+            ..Default::default()
+        };
+        assert_eq!(prune(dir.path(), &by_age).unwrap(), 2);
+        assert!(!dir.path().join("bitpart-older.png").exists());
+        assert!(!dir.path().join("bitpart-old.png").exists());
+
+        let by_size = RetentionPolicy {
+            max_bytes: Some(15),
+            ..Default::default()
+        };
+        assert_eq!(prune(dir.path(), &by_size).unwrap(), 1);
+        assert!(!dir.path().join("bitpart-recent.png").exists());
+        assert!(dir.path().join("bitpart-new.png").exists());
+        // only attachments are ever deleted
+        assert!(dir.path().join("other.db").exists());
+    }
+}
@@ -1 +1,2 @@
+pub mod attachments;
 pub mod signal;
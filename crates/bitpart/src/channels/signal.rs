@@ -17,6 +17,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use base64::prelude::*;
 use bitpart_common::{
     csml::{Request, SerializedEvent},
     error::{BitpartErrorKind, Result},
@@ -25,34 +26,41 @@ use chrono::Local;
 use csml_interpreter::data::Client;
 use futures::StreamExt;
 use futures::{channel::oneshot, pin_mut};
+use phonenumber::PhoneNumber;
 use presage::libsignal_service::configuration::SignalServers;
 use presage::libsignal_service::content::Reaction;
-use presage::libsignal_service::prelude::Uuid;
 use presage::libsignal_service::proto::data_message::Quote;
 use presage::libsignal_service::proto::sync_message::Sent;
 use presage::libsignal_service::protocol::ServiceId;
+use presage::libsignal_service::sender::AttachmentSpec;
 use presage::libsignal_service::zkgroup::GroupMasterKeyBytes;
 use presage::model::identity::OnNewIdentity;
 use presage::model::messages::Received;
+use presage::proto::AttachmentPointer;
 use presage::proto::EditMessage;
 use presage::proto::ReceiptMessage;
 use presage::proto::SyncMessage;
+use presage::proto::TypingMessage;
 use presage::proto::receipt_message;
+use presage::proto::typing_message;
 use presage::store::ContentExt;
 use presage::{
     Manager,
     libsignal_service::content::{Content, ContentBody, DataMessage, GroupContextV2},
     manager::Registered,
-    store::{Store, Thread},
+    store::{ContentsStore, StateStore, Store, Thread},
 };
-use presage_store_bitpart::BitpartStore;
+use presage_store_bitpart::{BitpartStore, seen_message_key};
 use sanitise_file_name::sanitise;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::time::UNIX_EPOCH;
 use std::{
-    cell::Cell,
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    ops::Range,
     path::{Path, PathBuf},
+    sync::OnceLock,
 };
 use tokio::{
     fs,
@@ -63,7 +71,7 @@ use tokio::{
 };
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::warn;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, instrument};
 use uuid;
 
 use crate::api;
@@ -84,6 +92,16 @@ pub enum ChannelMessageContents {
     ResetSessions {
         id: String,
     },
+    /// Stop the channel's receiver by cancelling the message's token, which
+    /// must be the channel's own
+    StopChannel {
+        id: String,
+    },
+    SendMessages {
+        id: String,
+        user_id: String,
+        messages: Vec<serde_json::Value>,
+    },
 }
 
 pub struct ChannelMessage {
@@ -154,6 +172,45 @@ impl ChannelBackend for SignalManager {
 pub struct ChannelState {
     id: String,
     pool: bitpart_common::db::Pool,
+    /// The channel's `apps_endpoint`, used instead of the bot's when set
+    apps_endpoint: Option<String>,
+    /// The last message received from each contact, by user id, so replies
+    /// can quote it
+    last_received: RefCell<HashMap<String, ReceivedMessage>>,
+    /// The messages sent to each user, so replies can edit them
+    sent: SentMessages,
+}
+
+/// A received message that a reply can quote
+#[derive(Debug, Clone)]
+struct ReceivedMessage {
+    timestamp: u64,
+    author: ServiceId,
+}
+
+/// How many of the last messages sent to each user can be edited
+const MAX_EDITABLE_MESSAGES: usize = 100;
+
+/// The timestamps of the last messages sent to each user, by user id
+#[derive(Debug, Default)]
+struct SentMessages(RefCell<HashMap<String, VecDeque<u64>>>);
+
+impl SentMessages {
+    fn record(&self, user_id: &str, timestamp: u64) {
+        let mut sent = self.0.borrow_mut();
+        let timestamps = sent.entry(user_id.to_owned()).or_default();
+        if timestamps.len() == MAX_EDITABLE_MESSAGES {
+            timestamps.pop_front();
+        }
+        timestamps.push_back(timestamp);
+    }
+
+    fn contains(&self, user_id: &str, timestamp: u64) -> bool {
+        self.0
+            .borrow()
+            .get(user_id)
+            .is_some_and(|timestamps| timestamps.contains(&timestamp))
+    }
 }
 
 // === device linking ===
@@ -170,6 +227,9 @@ async fn start_channel_recv(
     let state = ChannelState {
         id: channel.bot_id,
         pool,
+        apps_endpoint: channel.apps_endpoint,
+        last_received: RefCell::default(),
+        sent: SentMessages::default(),
     };
     receive(manager, &attachments_dir, &state).await?;
     Ok(())
@@ -189,7 +249,7 @@ async fn process_channel_message(msg: ChannelMessage) -> Result<()> {
             attachments_dir,
             device_name,
         } => {
-            let config_store = BitpartStore::open(&id, &pool, OnNewIdentity::Trust).await?;
+            let config_store = open_store(&id, &pool).await?;
             let (provisioning_link_tx, provisioning_link_rx) = oneshot::channel();
 
             spawn_local(async move {
@@ -234,12 +294,18 @@ async fn process_channel_message(msg: ChannelMessage) -> Result<()> {
             id,
             attachments_dir,
         } => {
-            let store = BitpartStore::open(&id, &pool, OnNewIdentity::Trust).await?;
-
             spawn_local(async move {
                 tokio::select! {
                     _ = async {
-                        match Manager::load_registered(store).await {
+                        let backoff = Backoff::from_limits(limits());
+                        let loaded = match check_registered(&id, &pool).await {
+                            Ok(()) => {
+                                with_backoff(&backoff, "load channel", || load_manager(&id, &pool))
+                                    .await
+                            }
+                            Err(err) => Err(err),
+                        };
+                        match loaded {
                             Ok(manager) => {
                                 let mut manager_ref = Cell::new(manager);
                                 let res =
@@ -252,7 +318,7 @@ async fn process_channel_message(msg: ChannelMessage) -> Result<()> {
 
                             },
                             Err(err) => {
-                                error!("Skipping startup of unregistered channel: {:?}", err);
+                                error!("Skipping startup of channel: {:?}", err);
 
                             }
                         }
@@ -265,8 +331,15 @@ async fn process_channel_message(msg: ChannelMessage) -> Result<()> {
                 .send("".to_owned())
                 .map_err(BitpartErrorKind::Signal)?)
         }
+        ChannelMessageContents::StopChannel { id } => {
+            info!(channel_id = id, "stopping channel");
+            token.cancel();
+            Ok(sender
+                .send("".to_owned())
+                .map_err(BitpartErrorKind::Signal)?)
+        }
         ChannelMessageContents::ResetSessions { id } => {
-            let store = BitpartStore::open(&id, &pool, OnNewIdentity::Trust).await?;
+            let store = open_store(&id, &pool).await?;
 
             match Manager::load_registered(store).await {
                 Ok(mut manager) => {
@@ -296,77 +369,803 @@ async fn process_channel_message(msg: ChannelMessage) -> Result<()> {
                 }
             }
         }
+        ChannelMessageContents::SendMessages {
+            id,
+            user_id,
+            messages,
+        } => {
+            let store = open_store(&id, &pool).await?;
+            let mut manager = match Manager::load_registered(store).await {
+                Ok(manager) => manager,
+                Err(err) => {
+                    return Ok(sender
+                        .send(format!("Channel is not registered: {err}"))
+                        .map_err(BitpartErrorKind::Signal)?);
+                }
+            };
+            // only messages in this batch can be edited, as the channel's own
+            // record of what it sent stays with its receiver
+            let failed = send_replies(
+                &mut manager,
+                &messages,
+                &user_id,
+                None,
+                &SentMessages::default(),
+                send_timeout(),
+                &pool,
+            )
+            .await;
+            let res = if failed > 0 {
+                format!(
+                    "{} of {} messages could not be sent",
+                    failed,
+                    messages.len()
+                )
+            } else {
+                "".to_owned()
+            };
+            Ok(sender.send(res).map_err(BitpartErrorKind::Signal)?)
+        }
     }
 }
 
 // === outbound send ===
 
+#[derive(Clone)]
 enum Recipient {
-    Contact(Uuid),
+    Contact(ServiceId),
     Group(GroupMasterKeyBytes),
+    PhoneNumber(PhoneNumber),
 }
 
-async fn send<S: Store>(
+fn format_e164(number: &PhoneNumber) -> String {
+    number.format().mode(phonenumber::Mode::E164).to_string()
+}
+
+/// A bare uuid is taken to be an ACI, but some contacts can only be reached by
+/// their PNI; ask the store which one we actually have sessions with. Groups
+/// must be ones the bot is a member of. Phone numbers are looked up among the
+/// contacts synced from the primary device, which keep each contact's number.
+async fn resolve_recipient(recipient: Recipient, store: &BitpartStore) -> Result<Recipient> {
+    match recipient {
+        Recipient::PhoneNumber(number) => {
+            for contact in store.contacts().await? {
+                let contact = contact?;
+                if contact.phone_number.as_ref() == Some(&number) {
+                    return Ok(Recipient::Contact(
+                        store.service_id_for(contact.uuid).await?,
+                    ));
+                }
+            }
+            Err(BitpartErrorKind::Signal(format!(
+                "no Signal contact has phone number {}",
+                format_e164(&number)
+            ))
+            .into())
+        }
+        Recipient::Contact(service_id @ ServiceId::Aci(_)) => Ok(Recipient::Contact(
+            store.service_id_for(service_id.raw_uuid()).await?,
+        )),
+        Recipient::Group(master_key) => match store.group(master_key).await? {
+            Some(_) => Ok(Recipient::Group(master_key)),
+            None => Err(BitpartErrorKind::Signal(format!(
+                "bot is not a member of group {}",
+                hex::encode(master_key)
+            ))
+            .into()),
+        },
+        recipient => Ok(recipient),
+    }
+}
+
+/// Build the `DataMessage` for a reply. An attachment and text go out together
+/// as one message, with the text as the attachment's caption.
+fn build_data_message(
+    body: String,
+    attachments: Vec<AttachmentPointer>,
+    group: Option<&GroupMasterKeyBytes>,
+    timestamp: u64,
+) -> DataMessage {
+    let body = if body.is_empty() && !attachments.is_empty() {
+        None
+    } else {
+        Some(body)
+    };
+    DataMessage {
+        body,
+        attachments,
+        group_v2: group.map(|master_key| GroupContextV2 {
+            master_key: Some(master_key.to_vec()),
+            revision: Some(0),
+            ..Default::default()
+        }),
+        timestamp: Some(timestamp),
+        ..Default::default()
+    }
+}
+
+/// Default maximum number of attachments in one message, see `Limits`
+const DEFAULT_MAX_ATTACHMENTS: usize = 32;
+/// Default maximum size in bytes of one attachment, see `Limits`
+const DEFAULT_MAX_ATTACHMENT_SIZE: usize = 100 * 1024 * 1024;
+/// Default maximum size in bytes of all the attachments of one message, see
+/// `Limits`
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 100 * 1024 * 1024;
+
+/// Limits on what channels send and receive, and how they reconnect, from the
+/// server configuration. Unset limits take their defaults.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Maximum number of attachments in one message, more are split across
+    /// several messages
+    pub max_attachments: Option<usize>,
+    /// Maximum size in bytes of one attachment, sent or received
+    pub max_attachment_size: Option<usize>,
+    /// Maximum size in bytes of all the attachments of one message
+    pub max_message_size: Option<usize>,
+    /// How long fetching one outgoing attachment over http(s) may take
+    pub attachment_timeout: Option<Duration>,
+    /// Number of a message's attachments downloaded at once
+    pub attachment_downloads: Option<usize>,
+    /// How long a transcription may take
+    pub transcription_timeout: Option<Duration>,
+    /// How long a single reply may take to send
+    pub send_timeout: Option<Duration>,
+    /// Delay after the first failed attempt to reach Signal
+    pub reconnect_delay: Option<Duration>,
+    /// Longest delay between attempts to reach Signal
+    pub reconnect_max_delay: Option<Duration>,
+    /// Attempts to reach Signal before giving up, 0 for no limit
+    pub reconnect_attempts: Option<u32>,
+}
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/**
+ * Set the limits channels work within from the server configuration. Only
+ * the first call has any effect.
+ */
+pub fn set_limits(limits: Limits) {
+    let _ = LIMITS.set(limits);
+}
+
+fn limits() -> &'static Limits {
+    LIMITS.get_or_init(Limits::default)
+}
+
+fn max_attachment_size() -> usize {
+    limits()
+        .max_attachment_size
+        .unwrap_or(DEFAULT_MAX_ATTACHMENT_SIZE)
+}
+
+/// Check an attachment against the size limit
+fn check_attachment(spec: &AttachmentSpec, max_size: usize) -> Result<()> {
+    if spec.length > max_size {
+        return Err(BitpartErrorKind::Signal(format!(
+            "attachment is {} bytes, over the limit of {} bytes",
+            spec.length, max_size
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Whether an attachment can share a message with others: Signal shows
+/// several attachments as an album, which only holds images and videos.
+fn is_album_media(spec: &AttachmentSpec) -> bool {
+    spec.content_type.starts_with("image/") || spec.content_type.starts_with("video/")
+}
+
+/// Split attachments, given as their sizes and whether they can join an
+/// album, into batches that each fit in one message, keeping their order. A
+/// batch is closed when it reaches `max_count` attachments or when the next
+/// one would take it over `max_total` bytes. Anything other than an image or
+/// video is sent in a batch of its own.
+fn batch_attachments(
+    attachments: &[(usize, bool)],
+    max_count: usize,
+    max_total: usize,
+) -> Vec<Range<usize>> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut total = 0;
+    for (i, (size, album)) in attachments.iter().enumerate() {
+        if i > start
+            && (i - start >= max_count
+                || total + size > max_total
+                || !album
+                || !attachments[start].1)
+        {
+            batches.push(start..i);
+            start = i;
+            total = 0;
+        }
+        total += size;
+    }
+    if start < attachments.len() {
+        batches.push(start..attachments.len());
+    }
+    batches
+}
+
+/// Default seconds fetching one attachment over http(s) may take, see `Limits`
+const DEFAULT_ATTACHMENT_TIMEOUT: u64 = 30;
+
+/// Where the media for outgoing attachments may be loaded from
+#[derive(Debug, Clone, Default)]
+struct AttachmentSources {
+    /// Local files are only sent from under this directory, and not at all
+    /// without it
+    dir: Option<PathBuf>,
+    /// Hosts attachments may be fetched from over http(s), none by default
+    hosts: Vec<String>,
+    /// How long fetching one attachment may take
+    timeout: Duration,
+}
+
+static ATTACHMENT_SOURCES: OnceLock<(PathBuf, Vec<String>)> = OnceLock::new();
+
+/**
+ * Set where outgoing attachments may be loaded from: local files under `dir`,
+ * and http(s) urls on one of `hosts`. Without it, attachments can't be sent
+ * from either. Only the first call has any effect.
+ */
+pub fn set_attachment_sources(dir: PathBuf, hosts: Vec<String>) {
+    let _ = ATTACHMENT_SOURCES.set((dir, hosts));
+}
+
+fn attachment_sources() -> AttachmentSources {
+    let (dir, hosts) = match ATTACHMENT_SOURCES.get() {
+        Some((dir, hosts)) => (Some(dir.clone()), hosts.clone()),
+        None => (None, vec![]),
+    };
+    AttachmentSources {
+        dir,
+        hosts,
+        timeout: limits()
+            .attachment_timeout
+            .unwrap_or(Duration::from_secs(DEFAULT_ATTACHMENT_TIMEOUT)),
+    }
+}
+
+/// The HTTP client attachments are fetched with. It doesn't follow redirects,
+/// which could lead off the allowed hosts.
+fn attachment_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap_or_default()
+    })
+}
+
+/// Load and check every attachment of a message. Attachments that can't be
+/// sent are left out and their errors returned alongside the rest.
+async fn load_attachments(
+    locations: &[String],
+    sources: &AttachmentSources,
+) -> (Vec<(AttachmentSpec, Vec<u8>)>, Vec<String>) {
+    let max_size = max_attachment_size();
+
+    let mut loaded = Vec::new();
+    let mut errors = Vec::new();
+    for location in locations {
+        let checked = match load_attachment(location, sources, max_size).await {
+            Ok((spec, data)) => check_attachment(&spec, max_size).map(|_| (spec, data)),
+            Err(err) => Err(err),
+        };
+        match checked {
+            Ok(attachment) => loaded.push(attachment),
+            Err(err) => errors.push(format!("{location}: {err}")),
+        }
+    }
+    (loaded, errors)
+}
+
+fn over_size_limit(max_size: usize) -> BitpartErrorKind {
+    BitpartErrorKind::Signal(format!("attachment is over the limit of {max_size} bytes"))
+}
+
+/// Read a local file to attach, which has to be under the attachments
+/// directory once symlinks and `..` are resolved
+async fn read_local_attachment(
+    path: &Path,
+    sources: &AttachmentSources,
+    max_size: usize,
+) -> Result<Vec<u8>> {
+    let dir = sources
+        .dir
+        .as_ref()
+        .ok_or_else(|| BitpartErrorKind::Signal("local attachments are not allowed".to_owned()))?;
+    let dir = fs::canonicalize(dir).await?;
+    let canonical = fs::canonicalize(path).await?;
+    if !canonical.starts_with(&dir) {
+        return Err(BitpartErrorKind::Signal(format!(
+            "{} is outside the attachments directory",
+            path.display()
+        ))
+        .into());
+    }
+    if fs::metadata(&canonical).await?.len() > max_size as u64 {
+        return Err(over_size_limit(max_size).into());
+    }
+    Ok(fs::read(&canonical).await?)
+}
+
+/// Fetch an attachment from one of the allowed hosts, giving up once it takes
+/// longer than the timeout or grows over `max_size`
+async fn fetch_attachment(
+    url: &url::Url,
+    sources: &AttachmentSources,
+    max_size: usize,
+) -> Result<Vec<u8>> {
+    let host = url.host_str().unwrap_or_default();
+    if !sources
+        .hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        return Err(
+            BitpartErrorKind::Signal(format!("attachments can't be fetched from {host}")).into(),
+        );
+    }
+
+    let http_err = |e: reqwest::Error| BitpartErrorKind::Signal(e.to_string());
+    let mut res = attachment_client()
+        .get(url.as_str())
+        .timeout(sources.timeout)
+        .send()
+        .await
+        .map_err(http_err)?;
+    if !res.status().is_success() {
+        return Err(BitpartErrorKind::Signal(format!(
+            "fetching attachment failed with {}",
+            res.status()
+        ))
+        .into());
+    }
+    if res
+        .content_length()
+        .is_some_and(|len| len > max_size as u64)
+    {
+        return Err(over_size_limit(max_size).into());
+    }
+    let mut data = Vec::new();
+    while let Some(chunk) = res.chunk().await.map_err(http_err)? {
+        if data.len() + chunk.len() > max_size {
+            return Err(over_size_limit(max_size).into());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Fetch the media for an outgoing attachment from an http(s) url on an allowed
+/// host, or from a `file://` url or local path under the attachments directory.
+async fn load_attachment(
+    location: &str,
+    sources: &AttachmentSources,
+    max_size: usize,
+) -> Result<(AttachmentSpec, Vec<u8>)> {
+    let (path, data) = match url::Url::parse(location) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+            let data = fetch_attachment(&url, sources, max_size).await?;
+            (PathBuf::from(url.path()), data)
+        }
+        Ok(url) if url.scheme() == "file" => {
+            let path = url.to_file_path().map_err(|_| {
+                BitpartErrorKind::Signal(format!("Invalid attachment path: {location}"))
+            })?;
+            let data = read_local_attachment(&path, sources, max_size).await?;
+            (path, data)
+        }
+        _ => {
+            let path = PathBuf::from(location);
+            let data = read_local_attachment(&path, sources, max_size).await?;
+            (path, data)
+        }
+    };
+
+    let spec = AttachmentSpec {
+        content_type: mime_guess::from_path(&path)
+            .first_or_octet_stream()
+            .to_string(),
+        length: data.len(),
+        file_name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned()),
+        preview: None,
+        voice_note: None,
+        borderless: None,
+        width: None,
+        height: None,
+        caption: None,
+        blur_hash: None,
+    };
+    Ok((spec, data))
+}
+
+/// The timestamp to send a message with: the one it was given, so that
+/// replayed or scheduled messages are ordered as intended, or else now. Signal
+/// stores the message under the same timestamp, so receipts can be matched.
+fn send_timestamp(timestamp: Option<u64>) -> u64 {
+    timestamp.unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_millis() as u64
+    })
+}
+
+/// Send a message built for the recipient
+async fn deliver<S: Store>(
     manager: &mut Manager<S, Registered>,
     recipient: Recipient,
-    msg: String,
+    data_message: impl Into<ContentBody>,
+    timestamp: u64,
 ) -> Result<()> {
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or(Duration::ZERO)
-        .as_millis() as u64;
-
     match recipient {
-        Recipient::Contact(uuid) => {
-            info!(recipient =% uuid, "sending message to contact");
-            let mut data_message: ContentBody = DataMessage {
-                body: Some(msg),
-                ..Default::default()
-            }
-            .into();
-            if let ContentBody::DataMessage(d) = &mut data_message {
-                d.timestamp = Some(timestamp);
-            }
+        Recipient::Contact(service_id) => {
+            info!(recipient =% service_id.service_id_string(), "sending message to contact");
             manager
-                .send_message(ServiceId::Aci(uuid.into()), data_message, timestamp)
+                .send_message(service_id, data_message, timestamp)
                 .await
                 .map_err(|e| BitpartErrorKind::PresageStore(e.to_string()))?;
         }
+        Recipient::PhoneNumber(number) => {
+            return Err(BitpartErrorKind::Signal(format!(
+                "phone number {} was not resolved to a contact",
+                format_e164(&number)
+            ))
+            .into());
+        }
         Recipient::Group(master_key) => {
             info!("sending message to group");
-            let mut data_message: ContentBody = DataMessage {
-                body: Some(msg),
-                group_v2: Some(GroupContextV2 {
-                    master_key: Some(master_key.to_vec()),
-                    revision: Some(0),
-                    ..Default::default()
-                }),
-                ..Default::default()
-            }
-            .into();
-            if let ContentBody::DataMessage(d) = &mut data_message {
-                d.timestamp = Some(timestamp);
-            }
             manager
                 .send_message_to_group(&master_key, data_message, timestamp)
                 .await
                 .map_err(|e| BitpartErrorKind::PresageStore(e.to_string()))?;
         }
     }
+    Ok(())
+}
+
+/// Send a message with its attachments. When they don't fit in one Signal
+/// message they are split across several, sent in order, with the text and
+/// quote on the first. Returns the timestamp of the first.
+async fn send<S: Store>(
+    manager: &mut Manager<S, Registered>,
+    recipient: Recipient,
+    msg: String,
+    attachments: Vec<String>,
+    timestamp: Option<u64>,
+    quote: Option<Quote>,
+) -> Result<u64> {
+    let timestamp = send_timestamp(timestamp);
+    let group = match &recipient {
+        Recipient::Group(master_key) => Some(*master_key),
+        _ => None,
+    };
+
+    let attachments_count = attachments.len();
+    let (mut loaded, mut errors) = load_attachments(&attachments, &attachment_sources()).await;
+    let sizes: Vec<(usize, bool)> = loaded
+        .iter()
+        .map(|(spec, _)| (spec.length, is_album_media(spec)))
+        .collect();
+    let batches = batch_attachments(
+        &sizes,
+        limits()
+            .max_attachments
+            .unwrap_or(DEFAULT_MAX_ATTACHMENTS)
+            .max(1),
+        limits()
+            .max_message_size
+            .unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+    );
+
+    let mut msg = Some(msg);
+    let mut quote = quote;
+    let mut sent = 0;
+    // a message without attachments is still sent once, for its text
+    let batches = if batches.is_empty() {
+        vec![0..0]
+    } else {
+        batches
+    };
+    for batch in batches {
+        let batch: Vec<_> = loaded.drain(..batch.len()).collect();
+        let mut pointers = Vec::new();
+        if !batch.is_empty() {
+            info!(count = batch.len(), "uploading attachments");
+            let uploaded = manager
+                .upload_attachments(batch)
+                .await
+                .map_err(|e| BitpartErrorKind::PresageStore(e.to_string()))?;
+            for upload in uploaded {
+                match upload {
+                    Ok(pointer) => pointers.push(pointer),
+                    Err(err) => errors.push(BitpartErrorKind::from(err).to_string()),
+                }
+            }
+        }
+        let text = msg.take().unwrap_or_default();
+        if pointers.is_empty() && text.is_empty() && (sent > 0 || !errors.is_empty()) {
+            continue;
+        }
+        // every message needs its own timestamp
+        let timestamp = timestamp + sent;
+        let data_message = DataMessage {
+            quote: quote.take(),
+            ..build_data_message(text, pointers, group.as_ref(), timestamp)
+        };
+        deliver(manager, recipient.clone(), data_message, timestamp).await?;
+        sent += 1;
+    }
+
+    if !errors.is_empty() && sent == 0 {
+        return Err(BitpartErrorKind::Signal(format!(
+            "no attachments could be sent: {}",
+            errors.join("; ")
+        ))
+        .into());
+    }
+    if !errors.is_empty() {
+        return Err(BitpartErrorKind::Signal(format!(
+            "{} of {} attachments could not be sent: {}",
+            errors.len(),
+            attachments_count,
+            errors.join("; ")
+        ))
+        .into());
+    }
+    Ok(timestamp)
+}
+
+/// Build the `DataMessage` reacting to a message. In a contact's thread the
+/// message reacted to is theirs unless the reaction names its author; in a
+/// group the author must be named.
+fn build_reaction_message(
+    reaction: ReplyReaction,
+    recipient: &Recipient,
+    timestamp: u64,
+) -> Result<DataMessage> {
+    let author = match (&reaction.target_author, recipient) {
+        (Some(author), _) => ServiceId::parse_from_service_id_string(author).ok_or_else(|| {
+            BitpartErrorKind::Signal(format!("invalid reaction target author: {author}"))
+        })?,
+        (None, Recipient::Contact(service_id)) => *service_id,
+        (None, _) => {
+            return Err(BitpartErrorKind::Signal(
+                "a reaction in a group must name the target_author".to_owned(),
+            )
+            .into());
+        }
+    };
+    let group = match recipient {
+        Recipient::Group(master_key) => Some(master_key),
+        _ => None,
+    };
+    Ok(DataMessage {
+        body: None,
+        reaction: Some(Reaction {
+            emoji: Some(reaction.emoji),
+            remove: Some(reaction.remove),
+            target_author_aci: Some(author.raw_uuid().to_string()),
+            target_sent_timestamp: Some(reaction.target_sent_timestamp),
+            ..Default::default()
+        }),
+        ..build_data_message(String::new(), vec![], group, timestamp)
+    })
+}
+
+async fn send_reaction<S: Store>(
+    manager: &mut Manager<S, Registered>,
+    recipient: Recipient,
+    reaction: ReplyReaction,
+) -> Result<()> {
+    let timestamp = send_timestamp(None);
+    let data_message = build_reaction_message(reaction, &recipient, timestamp)?;
+    deliver(manager, recipient, data_message, timestamp).await
+}
+
+/// Build the `EditMessage` replacing the text of the message sent at the
+/// edit's target timestamp
+fn build_edit_message(edit: ReplyEdit, recipient: &Recipient, timestamp: u64) -> EditMessage {
+    let group = match recipient {
+        Recipient::Group(master_key) => Some(master_key),
+        _ => None,
+    };
+    EditMessage {
+        target_sent_timestamp: Some(edit.target_sent_timestamp),
+        data_message: Some(build_data_message(edit.text, vec![], group, timestamp)),
+    }
+}
+
+async fn send_edit<S: Store>(
+    manager: &mut Manager<S, Registered>,
+    recipient: Recipient,
+    edit: ReplyEdit,
+) -> Result<()> {
+    let timestamp = send_timestamp(None);
+    let edit_message = build_edit_message(edit, &recipient, timestamp);
+    deliver(
+        manager,
+        recipient,
+        ContentBody::EditMessage(edit_message),
+        timestamp,
+    )
+    .await
+}
 
+/// Longest a `typing` reply shows the typing indicator for. Signal clients
+/// hide it by themselves after 15 seconds.
+const MAX_TYPING_DURATION: Duration = Duration::from_secs(10);
+
+/// Show or hide the typing indicator in a contact's thread. Typing in a group
+/// needs the group's id rather than its master key, so groups are skipped.
+async fn send_typing<S: Store>(
+    manager: &mut Manager<S, Registered>,
+    recipient: Recipient,
+    started: bool,
+) -> Result<()> {
+    let Recipient::Contact(service_id) = recipient else {
+        debug!("not sending typing indicator to a group");
+        return Ok(());
+    };
+    let timestamp = send_timestamp(None);
+    let action = if started {
+        typing_message::Action::Started
+    } else {
+        typing_message::Action::Stopped
+    };
+    let typing = TypingMessage {
+        timestamp: Some(timestamp),
+        action: Some(action as i32),
+        group_id: None,
+    };
+    manager
+        .send_message(service_id, ContentBody::TypingMessage(typing), timestamp)
+        .await
+        .map_err(|e| BitpartErrorKind::PresageStore(e.to_string()))?;
     Ok(())
 }
 
+// === receipts ===
+
+/// Bot `env` key that, when true, has the bot send read receipts as well as
+/// delivery receipts for the messages it handles
+const READ_RECEIPTS: &str = "read_receipts";
+
+/// Whether the bot has opted in to sending read receipts with `read_receipts`.
+/// Only delivery receipts are sent by default, so senders don't learn when
+/// their messages were handled.
+fn read_receipts_enabled(env: Option<&serde_json::Value>) -> bool {
+    env.and_then(|env| env.get(READ_RECEIPTS)?.as_bool())
+        .unwrap_or(false)
+}
+
+/// The receipts to send for a handled message
+fn receipt_types(read: bool) -> Vec<receipt_message::Type> {
+    let mut types = vec![receipt_message::Type::Delivery];
+    if read {
+        types.push(receipt_message::Type::Read);
+    }
+    types
+}
+
+fn build_receipt(receipt_type: receipt_message::Type, timestamp: u64) -> ReceiptMessage {
+    ReceiptMessage {
+        r#type: Some(receipt_type as i32),
+        timestamp: vec![timestamp],
+    }
+}
+
+/// Tell the sender of a message sent at `timestamp` that the bot has it.
+/// Failures are logged rather than returned, since the message itself has
+/// already been handled.
+async fn acknowledge(
+    manager: &mut Manager<BitpartStore, Registered>,
+    sender: ServiceId,
+    timestamp: u64,
+    env: Option<&serde_json::Value>,
+) {
+    for receipt_type in receipt_types(read_receipts_enabled(env)) {
+        let receipt = build_receipt(receipt_type, timestamp);
+        if let Err(err) = manager
+            .send_message(
+                sender,
+                ContentBody::ReceiptMessage(receipt),
+                send_timestamp(None),
+            )
+            .await
+        {
+            warn!("Failed to send {:?} receipt: {:?}", receipt_type, err);
+        }
+    }
+}
+
 // === message formatting ===
 
-async fn process_signal_message<S: Store>(
-    manager: &mut Manager<S, Registered>,
+/// The bot's `env`, loaded once for each incoming message so that all of its
+/// settings come from the same version of the bot
+async fn bot_env(state: &ChannelState) -> Option<serde_json::Value> {
+    match crate::db::bot::get_latest_by_bot_id(&state.id, &state.pool).await {
+        Ok(version) => version.and_then(|v| v.bot.env),
+        Err(err) => {
+            warn!("Failed to look up bot settings: {:?}", err);
+            None
+        }
+    }
+}
+
+async fn process_signal_message(
+    manager: &mut Manager<BitpartStore, Registered>,
+    attachments_dir: &Path,
+    content: &Content,
+    state: &ChannelState,
+) -> Result<()> {
+    // Signal can deliver a message again after a reconnect, which must not
+    // get a second reply. If the check fails, the message is handled anyway.
+    // A message is only recorded once it has been handled, so one that failed
+    // is handled again if it is delivered again.
+    let key = seen_message_key(&content.metadata);
+    match manager.store().seen(&key).await {
+        Ok(false) => {}
+        Ok(true) => {
+            debug!(key, "skipping message received before");
+            return Ok(());
+        }
+        Err(err) => warn!(
+            "Failed to check whether message was received before: {:?}",
+            err
+        ),
+    }
+
+    handle_signal_message(manager, attachments_dir, content, state).await?;
+
+    if let Err(err) = manager.store().first_seen(&key).await {
+        warn!("Failed to record received message: {:?}", err);
+    }
+    Ok(())
+}
+
+async fn handle_signal_message(
+    manager: &mut Manager<BitpartStore, Registered>,
     attachments_dir: &Path,
     content: &Content,
     state: &ChannelState,
 ) -> Result<()> {
     let thread = Thread::try_from(content).map_err(|e| BitpartErrorKind::Signal(e.to_string()))?;
 
+    if let Err(err) = crate::db::channel::set_last_received("signal", &state.id, &state.pool).await
+    {
+        error!("Failed to record last received message: {:?}", err);
+    }
+    let env = bot_env(state).await;
+    let env = env.as_ref();
+
+    // Save attachments first, so the events sent to the bot can say where
+    // they are.
+    let saved = match &content.body {
+        ContentBody::DataMessage(DataMessage { attachments, .. }) => {
+            let limit = limits()
+                .attachment_downloads
+                .unwrap_or(DEFAULT_ATTACHMENT_DOWNLOADS);
+            let fetcher = &*manager;
+            download_attachments(attachments, attachments_dir, limit, |pointer| async move {
+                fetcher
+                    .get_attachment(pointer)
+                    .await
+                    .map_err(|e| BitpartErrorKind::Signal(e.to_string()).into())
+            })
+            .await
+        }
+        _ => Vec::new(),
+    };
+
     async fn format_data_message<S: Store>(
         thread: &Thread,
         data_message: &DataMessage,
@@ -522,10 +1321,35 @@ async fn process_signal_message<S: Store>(
             }
             Msg::Replyable(Thread::Contact(sender), body) => {
                 let contact = format_contact(sender, manager).await;
-                if let Err(err) =
-                    reply(sender.raw_uuid().to_string(), body.clone(), state, manager).await
+                let mut metadata = sender_metadata(sender, manager.store()).await;
+                // lets flows react to the message
+                metadata["timestamp"] = json!(ts);
+                metadata["attachments"] = attachments_metadata(&saved);
+                state.last_received.borrow_mut().insert(
+                    sender.service_id_string(),
+                    ReceivedMessage {
+                        timestamp: ts,
+                        author: *sender,
+                    },
+                );
+                let payload = json!({
+                    "content_type": "text",
+                    "content": {
+                        "text": body
+                    }
+                });
+                match reply(
+                    sender.service_id_string(),
+                    payload,
+                    metadata,
+                    env,
+                    state,
+                    manager,
+                )
+                .await
                 {
-                    warn!("Problem with replying to message: {:?}", err);
+                    Ok(()) => acknowledge(manager, *sender, ts, env).await,
+                    Err(err) => warn!("Problem with replying to message: {:?}", err),
                 }
                 (format!("From {contact} @ {ts}: "), body)
             }
@@ -539,7 +1363,34 @@ async fn process_signal_message<S: Store>(
                 (format!("From {sender} to group {group} @ {ts}: "), body)
             }
             Msg::Replyable(Thread::Group(key), body) => {
-                let sender = format_contact(&content.metadata.sender, manager).await;
+                let sender_id = &content.metadata.sender;
+                let known = matches!(manager.store().group(*key).await, Ok(Some(_)));
+                if answers_group(key, known, env, state).await {
+                    let mut metadata = sender_metadata(sender_id, manager.store()).await;
+                    metadata["timestamp"] = json!(ts);
+                    metadata["attachments"] = attachments_metadata(&saved);
+                    metadata[GROUP_METADATA] = json!(hex::encode(key));
+                    let payload = json!({
+                        "content_type": "text",
+                        "content": {
+                            "text": body
+                        }
+                    });
+                    match reply(
+                        sender_id.service_id_string(),
+                        payload,
+                        metadata,
+                        env,
+                        state,
+                        manager,
+                    )
+                    .await
+                    {
+                        Ok(()) => acknowledge(manager, *sender_id, ts, env).await,
+                        Err(err) => warn!("Problem with replying to group message: {:?}", err),
+                    }
+                }
+                let sender = format_contact(sender_id, manager).await;
                 let group = format_group(*key, manager).await;
                 (format!("From {sender} to group {group} @ {ts}: "), body)
             }
@@ -552,120 +1403,832 @@ async fn process_signal_message<S: Store>(
         debug!("{prefix} / REDACTED");
     }
 
-    let sender = content.metadata.sender.raw_uuid();
-    if let ContentBody::DataMessage(DataMessage { attachments, .. }) = &content.body {
-        for attachment_pointer in attachments {
-            let Ok(attachment_data) = manager.get_attachment(attachment_pointer).await else {
-                warn!("failed to fetch attachment");
-                continue;
+    if let ContentBody::DataMessage(DataMessage { body: None, .. }) = &content.body
+        && let Thread::Contact(sender) = &thread
+    {
+        let attachments = attachments_metadata(&saved);
+        let mut handled = false;
+        for attachment in saved {
+            // Attachments sent without a body are passed on to the bot as
+            // events of their own. Voice notes are transcribed if the bot has
+            // an endpoint for it.
+            let payload = if attachment.content_type.starts_with("audio/") {
+                let transcript = match transcription_endpoint(env) {
+                    Some(endpoint) => {
+                        transcribe(
+                            endpoint,
+                            &attachment.content_type,
+                            attachment.data,
+                            transcription_timeout(),
+                        )
+                        .await
+                    }
+                    None => None,
+                };
+                audio_payload(&attachment.path, transcript)
+            } else {
+                attachment_payload(&attachment.path, &attachment.content_type)
             };
-
-            let extensions = mime_guess::get_mime_extensions_str(
-                attachment_pointer
-                    .content_type
-                    .as_deref()
-                    .unwrap_or("application/octet-stream"),
-            );
-            let extension = extensions.and_then(|e| e.first()).unwrap_or(&"bin");
-            let filename = sanitise(
-                &attachment_pointer
-                    .file_name
-                    .clone()
-                    .unwrap_or_else(|| Local::now().format("%Y-%m-%d-%H-%M-%s").to_string()),
-            );
-            let file_path = attachments_dir.join(format!("bitpart-{filename}.{extension}",));
-            match fs::write(&file_path, &attachment_data).await {
-                Ok(_) => info!(%sender, file_path =% file_path.display(), "saved attachment"),
-                Err(error) => error!(
-                    %sender,
-                    file_path =% file_path.display(),
-                    %error,
-                    "failed to write attachment"
-                ),
+            let mut metadata = sender_metadata(sender, manager.store()).await;
+            metadata["attachments"] = attachments.clone();
+            match reply(
+                sender.service_id_string(),
+                payload,
+                metadata,
+                env,
+                state,
+                manager,
+            )
+            .await
+            {
+                Ok(()) => handled = true,
+                Err(err) => warn!("Problem with replying to attachment: {:?}", err),
             }
         }
+        if handled {
+            acknowledge(manager, *sender, content.timestamp(), env).await;
+        }
+    }
+
+    if let ContentBody::DataMessage(data_message) = &content.body
+        && let Some(master_key) = group_update(data_message)
+        && let Err(err) =
+            joined_group(master_key, &content.metadata.sender, env, state, manager).await
+    {
+        warn!("Problem with handling group update: {:?}", err);
     }
     Ok(())
 }
 
-// === message listener ===
+// === group invites ===
 
-async fn reply<S: Store>(
-    user_id: String,
-    body: String,
-    state: &ChannelState,
-    manager: &mut Manager<S, Registered>,
-) -> Result<()> {
-    let payload = json!({
-        "content_type": "text",
-        "content": {
-            "text": body
-        }
-    });
+/// Bot `env` key that, when true, has the bot greet groups it is added to
+const ACCEPT_GROUPS: &str = "accept_groups";
 
-    let client = Client {
-        bot_id: state.id.clone(),
-        channel_id: "signal".to_owned(),
-        user_id: user_id.clone(),
-    };
+/// Bot `env` key listing the service ids allowed to add the bot to groups
+const GROUP_ALLOWLIST: &str = "group_allowlist";
 
-    let event = SerializedEvent {
-        id: uuid::Uuid::new_v4().to_string(),
-        client,
-        metadata: serde_json::Value::Null,
-        payload,
-        step_limit: None,
-        callback_url: None,
-    };
+/// What to do on being added to a group
+#[derive(Debug, PartialEq)]
+enum GroupPolicy {
+    /// Stay in the group without telling the bot, as before `accept_groups`
+    Ignore,
+    /// Tell the bot, so a flow can greet the group
+    Accept,
+    /// Forget the group, so the bot never answers it
+    Reject,
+}
+
+/// The group a data message announces a change to, if it is a group update
+/// rather than a message to the group.
+fn group_update(data_message: &DataMessage) -> Option<GroupMasterKeyBytes> {
+    match data_message {
+        DataMessage {
+            body: None,
+            group_v2:
+                Some(GroupContextV2 {
+                    master_key: Some(master_key),
+                    group_change: Some(_),
+                    ..
+                }),
+            ..
+        } => master_key.as_slice().try_into().ok(),
+        _ => None,
+    }
+}
+
+/// Event metadata key holding the hex-encoded master key of the group a
+/// message was sent in. Replies to the event go back to that group.
+const GROUP_METADATA: &str = "group";
+
+/// Whether the bot has opted in to hearing from groups with `accept_groups`
+fn groups_accepted(env: Option<&serde_json::Value>) -> bool {
+    env.and_then(|env| env.get(ACCEPT_GROUPS)?.as_bool())
+        .unwrap_or(false)
+}
+
+/// Where the replies to an event go unless they name a recipient: the group
+/// the event came from, or else the user.
+fn reply_default_recipient(user_id: &str, metadata: &serde_json::Value) -> String {
+    match metadata
+        .get(GROUP_METADATA)
+        .and_then(|group| group.as_str())
+    {
+        Some(group) => group.to_owned(),
+        None => user_id.to_owned(),
+    }
+}
+
+fn group_policy(env: Option<&serde_json::Value>, added_by: &ServiceId) -> GroupPolicy {
+    let Some(env) = env else {
+        return GroupPolicy::Ignore;
+    };
+    if !env
+        .get(ACCEPT_GROUPS)
+        .and_then(|accept| accept.as_bool())
+        .unwrap_or(false)
+    {
+        return GroupPolicy::Ignore;
+    }
+    let Some(allowed) = env.get(GROUP_ALLOWLIST).and_then(|list| list.as_array()) else {
+        return GroupPolicy::Accept;
+    };
+    let service_id = added_by.service_id_string();
+    let uuid = added_by.raw_uuid().to_string();
+    if allowed
+        .iter()
+        .filter_map(|id| id.as_str())
+        .any(|id| id == service_id || id == uuid)
+    {
+        GroupPolicy::Accept
+    } else {
+        GroupPolicy::Reject
+    }
+}
+
+fn group_added_payload(
+    master_key: &GroupMasterKeyBytes,
+    title: &str,
+    added_by: &ServiceId,
+) -> serde_json::Value {
+    json!({
+        "content_type": "group_added",
+        "content": {
+            "group": hex::encode(master_key),
+            "title": title,
+            "added_by": added_by.service_id_string(),
+        }
+    })
+}
+
+/// State type under which the groups the bot was added to and rejected are
+/// recorded, keyed by `REJECTED` for the group's hex-encoded master key
+const GROUP_STATE: &str = "group";
+const REJECTED: &str = "rejected";
+
+fn group_client(master_key: &GroupMasterKeyBytes, state: &ChannelState) -> Client {
+    Client {
+        bot_id: state.id.clone(),
+        channel_id: "signal".to_owned(),
+        user_id: hex::encode(master_key),
+    }
+}
+
+async fn group_rejected(master_key: &GroupMasterKeyBytes, state: &ChannelState) -> bool {
+    let client = group_client(master_key, state);
+    match crate::db::state::get_unexpired(&client, GROUP_STATE, REJECTED, &state.pool).await {
+        Ok(rejected) => rejected.is_some(),
+        Err(err) => {
+            warn!("Failed to look up rejected group: {:?}", err);
+            // drop the message rather than answer a group that may be rejected
+            true
+        }
+    }
+}
+
+/// Whether a message to a group is passed on to the bot: only if the bot
+/// hears from groups, the group is `known` to the store, and the bot didn't
+/// reject it when it was added. Presage saves a group again whenever a message
+/// to it arrives, so being known isn't enough.
+async fn answers_group(
+    master_key: &GroupMasterKeyBytes,
+    known: bool,
+    env: Option<&serde_json::Value>,
+    state: &ChannelState,
+) -> bool {
+    known && groups_accepted(env) && !group_rejected(master_key, state).await
+}
+
+/// Presage has already fetched and saved the group by the time its update
+/// reaches us. Only the first update in a group's thread is the bot joining
+/// it; later ones are changes to a group it was already in, and are ignored.
+/// A group that is rejected is forgotten, and recorded so that its messages
+/// are dropped from then on.
+async fn join_group_policy(
+    master_key: GroupMasterKeyBytes,
+    added_by: &ServiceId,
+    env: Option<&serde_json::Value>,
+    state: &ChannelState,
+    store: &BitpartStore,
+) -> Result<GroupPolicy> {
+    let thread = Thread::Group(master_key);
+    if store.messages(&thread, ..).await?.count() > 1 {
+        return Ok(GroupPolicy::Ignore);
+    }
+
+    let policy = group_policy(env, added_by);
+    if policy == GroupPolicy::Reject {
+        warn!(
+            group = hex::encode(master_key),
+            "added to a group by someone not in the group allowlist, forgetting it"
+        );
+        crate::db::state::set(
+            &group_client(&master_key, state),
+            GROUP_STATE,
+            REJECTED,
+            &json!(true),
+            None,
+            &state.pool,
+        )
+        .await?;
+        store.delete_group(master_key).await?;
+    }
+    Ok(policy)
+}
+
+async fn joined_group(
+    master_key: GroupMasterKeyBytes,
+    added_by: &ServiceId,
+    env: Option<&serde_json::Value>,
+    state: &ChannelState,
+    manager: &mut Manager<BitpartStore, Registered>,
+) -> Result<()> {
+    match join_group_policy(master_key, added_by, env, state, manager.store()).await? {
+        GroupPolicy::Ignore | GroupPolicy::Reject => Ok(()),
+        GroupPolicy::Accept => {
+            // Invitations leave the bot a pending member, which can't read
+            // the group until it is accepted from another device.
+            let Some(group) = manager.store().group(master_key).await? else {
+                info!(
+                    group = hex::encode(master_key),
+                    "invited to a group that can't be read yet"
+                );
+                return Ok(());
+            };
+            let payload = group_added_payload(&master_key, &group.title, added_by);
+            let metadata = sender_metadata(added_by, manager.store()).await;
+            reply(
+                hex::encode(master_key),
+                payload,
+                metadata,
+                env,
+                state,
+                manager,
+            )
+            .await
+        }
+    }
+}
+
+// === incoming attachments ===
+
+/// Default number of a message's attachments downloaded at once, see `Limits`
+const DEFAULT_ATTACHMENT_DOWNLOADS: usize = 4;
+
+/// An incoming attachment, written to the attachments directory
+struct SavedAttachment {
+    content_type: String,
+    path: PathBuf,
+    data: Vec<u8>,
+}
+
+/// Where to write an incoming attachment. Unnamed attachments are named after
+/// the time they arrived, and their position in the message.
+fn attachment_path(attachments_dir: &Path, pointer: &AttachmentPointer, index: usize) -> PathBuf {
+    let content_type = pointer
+        .content_type
+        .as_deref()
+        .unwrap_or("application/octet-stream");
+    let extensions = mime_guess::get_mime_extensions_str(content_type);
+    let extension = extensions.and_then(|e| e.first()).unwrap_or(&"bin");
+    let filename = sanitise(
+        &pointer
+            .file_name
+            .clone()
+            .unwrap_or_else(|| format!("{}-{index}", Local::now().format("%Y-%m-%d-%H-%M-%s"))),
+    );
+    attachments_dir.join(format!("bitpart-{filename}.{extension}",))
+}
 
-    let request = Request {
+/// Download a message's attachments, at most `limit` at a time, writing each
+/// one as soon as it arrives. Attachments over the size limit are skipped.
+/// Returns the attachments that were saved.
+async fn download_attachments<'a, F, Fut>(
+    pointers: &'a [AttachmentPointer],
+    attachments_dir: &Path,
+    limit: usize,
+    fetch: F,
+) -> Vec<SavedAttachment>
+where
+    F: Fn(&'a AttachmentPointer) -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<u8>>>,
+{
+    let max_size = max_attachment_size();
+    let downloads = futures::stream::iter(pointers.iter().enumerate())
+        .filter(|(_, pointer)| {
+            let size = pointer.size.unwrap_or_default() as usize;
+            if size > max_size {
+                warn!(size, max_size, "skipping oversized attachment");
+            }
+            futures::future::ready(size <= max_size)
+        })
+        .map(|(index, pointer)| {
+            let download = fetch(pointer);
+            async move { (index, pointer, download.await) }
+        })
+        .buffer_unordered(limit.max(1));
+    pin_mut!(downloads);
+
+    let mut saved = Vec::new();
+    while let Some((index, pointer, data)) = downloads.next().await {
+        let data = match data {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("failed to fetch attachment: {:?}", err);
+                continue;
+            }
+        };
+        let path = attachment_path(attachments_dir, pointer, index);
+        match fs::write(&path, &data).await {
+            Ok(_) => info!(file_path =% path.display(), "saved attachment"),
+            Err(error) => {
+                error!(file_path =% path.display(), %error, "failed to write attachment");
+                continue;
+            }
+        }
+        saved.push(SavedAttachment {
+            content_type: pointer
+                .content_type
+                .clone()
+                .unwrap_or_else(|| "application/octet-stream".to_owned()),
+            path,
+            data,
+        });
+    }
+    saved
+}
+
+// === transcription ===
+
+/// Bot `env` key holding the url incoming voice notes are transcribed by
+const TRANSCRIPTION_ENDPOINT: &str = "transcription_endpoint";
+
+fn transcription_endpoint(env: Option<&serde_json::Value>) -> Option<&str> {
+    env?.get(TRANSCRIPTION_ENDPOINT)?.as_str()
+}
+
+/// Default seconds a transcription may take, see `Limits`
+const DEFAULT_TRANSCRIPTION_TIMEOUT: u64 = 30;
+
+/// How long a transcription may take before the audio is delivered without one
+fn transcription_timeout() -> Duration {
+    limits()
+        .transcription_timeout
+        .unwrap_or(Duration::from_secs(DEFAULT_TRANSCRIPTION_TIMEOUT))
+}
+
+async fn request_transcript(
+    endpoint: &str,
+    content_type: &str,
+    audio: Vec<u8>,
+    timeout: Duration,
+) -> std::result::Result<String, String> {
+    let res: serde_json::Value = crate::http::client()
+        .post(endpoint)
+        .timeout(timeout)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .header(reqwest::header::CONTENT_TYPE, content_type)
+        .body(audio)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+    res.get("text")
+        .and_then(|text| text.as_str())
+        .map(|text| text.to_owned())
+        .ok_or_else(|| "response has no text".to_owned())
+}
+
+/// POST the audio to the transcription endpoint, which should answer with a
+/// JSON `{"text": "..."}` within `timeout`. Fails open: any error is logged
+/// and no transcript is returned, so the audio event is still delivered.
+async fn transcribe(
+    endpoint: &str,
+    content_type: &str,
+    audio: Vec<u8>,
+    timeout: Duration,
+) -> Option<String> {
+    match request_transcript(endpoint, content_type, audio, timeout).await {
+        Ok(transcript) => Some(transcript),
+        Err(err) => {
+            warn!(%err, "failed to transcribe audio");
+            None
+        }
+    }
+}
+
+/// The CSML event for an image, video or other file sent without a body
+fn attachment_payload(path: &Path, content_type: &str) -> serde_json::Value {
+    let kind = match content_type.split('/').next() {
+        Some("image") => "image",
+        Some("video") => "video",
+        _ => "file",
+    };
+    json!({
+        "content_type": kind,
+        "content": {
+            "url": path.display().to_string(),
+            "content_type": content_type,
+        },
+    })
+}
+
+/// Event metadata listing where each saved attachment is, and its type. The
+/// attachment data itself is never included.
+fn attachments_metadata(saved: &[SavedAttachment]) -> serde_json::Value {
+    saved
+        .iter()
+        .map(|attachment| {
+            json!({
+                "path": attachment.path.display().to_string(),
+                "content_type": attachment.content_type,
+            })
+        })
+        .collect()
+}
+
+fn audio_payload(path: &Path, transcript: Option<String>) -> serde_json::Value {
+    let mut content = json!({ "url": path.display().to_string() });
+    if let Some(transcript) = transcript {
+        content["transcript"] = json!(transcript);
+    }
+    json!({
+        "content_type": "audio",
+        "content": content,
+    })
+}
+
+// === message listener ===
+
+/// Event metadata describing who sent a message, so flows can greet the user
+/// by their Signal profile name. `name` is null for unknown or unnamed
+/// contacts, while `display_name` falls back to their uuid.
+fn format_sender_metadata(service_id: &ServiceId, name: Option<String>) -> serde_json::Value {
+    let uuid = service_id.raw_uuid().to_string();
+    json!({
+        "sender": {
+            "display_name": name.clone().unwrap_or_else(|| uuid.clone()),
+            "uuid": uuid,
+            "service_id": service_id.service_id_string(),
+            "name": name,
+        }
+    })
+}
+
+async fn sender_metadata(service_id: &ServiceId, store: &BitpartStore) -> serde_json::Value {
+    let name = match store.contact_by_id(service_id).await {
+        Ok(contact) => contact.map(|c| c.name).filter(|name| !name.is_empty()),
+        Err(err) => {
+            warn!("Failed to look up sender's contact: {:?}", err);
+            None
+        }
+    };
+    format_sender_metadata(service_id, name)
+}
+
+/// Bot `env` key giving the number of days Signal conversations are kept
+const TTL_DURATION: &str = "ttl_duration";
+
+fn ttl_duration(env: Option<&serde_json::Value>) -> Option<i64> {
+    env?.get(TTL_DURATION)?.as_i64()
+}
+
+/// Bot `env` key that, when false, has messages in Signal conversations
+/// written to the `message` table
+const LOW_DATA_MODE: &str = "low_data_mode";
+
+fn low_data_mode(env: Option<&serde_json::Value>) -> Option<bool> {
+    env?.get(LOW_DATA_MODE)?.as_bool()
+}
+
+/// The request for the bot to handle an incoming Signal message
+fn reply_request(
+    user_id: String,
+    payload: serde_json::Value,
+    metadata: serde_json::Value,
+    env: Option<&serde_json::Value>,
+    state: &ChannelState,
+) -> Request {
+    let client = Client {
+        bot_id: state.id.clone(),
+        channel_id: "signal".to_owned(),
+        user_id,
+    };
+
+    let event = SerializedEvent {
+        id: uuid::Uuid::new_v4().to_string(),
+        client,
+        metadata,
+        payload,
+        step_limit: None,
+        callback_url: None,
+        ttl_duration: ttl_duration(env),
+        low_data_mode: low_data_mode(env),
+    };
+
+    Request {
         bot: None,
         bot_id: Some(state.id.clone()),
         version_id: None,
-        apps_endpoint: None,
+        apps_endpoint: state.apps_endpoint.clone(),
         multibot: None,
         event,
-    };
+        idempotency_key: None,
+        dry_run: false,
+    }
+}
+
+async fn reply(
+    user_id: String,
+    payload: serde_json::Value,
+    metadata: serde_json::Value,
+    env: Option<&serde_json::Value>,
+    state: &ChannelState,
+    manager: &mut Manager<BitpartStore, Registered>,
+) -> Result<()> {
+    let recipient = reply_default_recipient(&user_id, &metadata);
+    let request = reply_request(user_id.clone(), payload, metadata, env, state);
+    crate::metrics::message_received(&state.id, "signal");
 
     let res = api::process_request(&request, &state.pool).await?;
     if let Some(messages) = res.get("messages") {
-        for i in messages
-            .as_array()
-            .ok_or(BitpartErrorKind::Signal(
-                "Got invalid message from interpreter".to_owned(),
-            ))?
-            .iter()
-        {
-            send(
-                manager,
-                try_user_id_to_recipient(&reply_get_user_id(i, &user_id))?,
-                reply_get_text(i),
-            )
-            .await
-            .map_err(|err| BitpartErrorKind::Signal(err.to_string()))?;
+        let messages = messages.as_array().ok_or(BitpartErrorKind::Signal(
+            "Got invalid message from interpreter".to_owned(),
+        ))?;
+        // only the user's own thread is remembered for quoting
+        let quote = if recipient == user_id && messages.iter().any(reply_wants_quote) {
+            reply_quote(manager, &user_id, state).await
+        } else {
+            None
+        };
+        let failed = send_replies(
+            manager,
+            messages,
+            &recipient,
+            quote.as_ref(),
+            &state.sent,
+            send_timeout(),
+            &state.pool,
+        )
+        .await;
+        if failed > 0 {
+            return Err(BitpartErrorKind::Signal(format!(
+                "{} of {} replies could not be sent",
+                failed,
+                messages.len()
+            ))
+            .into());
         }
     }
 
     Ok(())
 }
 
-fn unescape(input: &str) -> String {
-    input
-        .trim_matches(|c| c == '\"' || c == '\'')
-        .replace("\\n", "\n")
-        .replace("\\t", "\t")
-        .replace("\\\"", "\"")
-        .replace("\\\\", "\\")
+/// Something replies can be delivered through, so delivery can be exercised
+/// without a registered Signal account.
+trait ReplySender {
+    /// Returns the timestamp of the message sent, if it can be edited later
+    async fn send_reply(
+        &mut self,
+        reply: &serde_json::Value,
+        user_id: &str,
+        quote: Option<&Quote>,
+    ) -> Result<Option<u64>>;
+    async fn send_typing(
+        &mut self,
+        reply: &serde_json::Value,
+        user_id: &str,
+        started: bool,
+    ) -> Result<()>;
 }
 
-fn try_user_id_to_recipient(user_id: &str) -> Result<Recipient> {
-    match Uuid::try_parse(user_id) {
-        Ok(uuid) => Ok(Recipient::Contact(uuid)),
-        Err(_) => {
-            let key: [u8; 32] = user_id.as_bytes().try_into()?;
-            Ok(Recipient::Group(key))
+async fn reply_recipient(
+    manager: &Manager<BitpartStore, Registered>,
+    reply: &serde_json::Value,
+    user_id: &str,
+) -> Result<Recipient> {
+    let user_id = reply_get_user_id(reply, user_id);
+    let self_aci = manager.registration_data().service_ids.aci;
+    match try_note_to_self(&user_id, self_aci) {
+        Some(recipient) => {
+            info!("sending note to self");
+            Ok(recipient)
+        }
+        None => resolve_recipient(try_user_id_to_recipient(&user_id)?, manager.store()).await,
+    }
+}
+
+impl ReplySender for Manager<BitpartStore, Registered> {
+    async fn send_reply(
+        &mut self,
+        reply: &serde_json::Value,
+        user_id: &str,
+        quote: Option<&Quote>,
+    ) -> Result<Option<u64>> {
+        let recipient = reply_recipient(self, reply, user_id).await?;
+        if let Some(reaction) = reply_get_reaction(reply)? {
+            send_reaction(self, recipient, reaction).await?;
+            return Ok(None);
+        }
+        if let Some(edit) = reply_get_edit(reply)? {
+            send_edit(self, recipient, edit).await?;
+            return Ok(None);
+        }
+        let timestamp = send(
+            self,
+            recipient,
+            reply_get_text(reply),
+            reply_get_attachments(reply),
+            reply_get_timestamp(reply),
+            quote.cloned(),
+        )
+        .await?;
+        Ok(Some(timestamp))
+    }
+
+    async fn send_typing(
+        &mut self,
+        reply: &serde_json::Value,
+        user_id: &str,
+        started: bool,
+    ) -> Result<()> {
+        let recipient = reply_recipient(self, reply, user_id).await?;
+        send_typing(self, recipient, started).await
+    }
+}
+
+const DEFAULT_SEND_TIMEOUT: u64 = 60;
+
+/// How long a single reply may take to send before it is given up on, see
+/// `Limits`
+fn send_timeout() -> Duration {
+    limits()
+        .send_timeout
+        .unwrap_or(Duration::from_secs(DEFAULT_SEND_TIMEOUT))
+}
+
+/// Send each reply in turn. A reply that fails, or takes longer than
+/// `timeout`, is logged and recorded, and the rest are still sent. Returns the
+/// number of replies that failed.
+///
+/// A `typing` reply shows the typing indicator for its duration instead. The
+/// next message sent clears it on the user's devices; if none is, it is
+/// stopped once the replies are done. Replies asking to quote the message
+/// they answer are sent with `quote`, when there is one.
+///
+/// The messages sent are recorded in `sent`, and an `edit` reply fails unless
+/// the message it edits is there.
+async fn send_replies<R: ReplySender>(
+    sender: &mut R,
+    messages: &[serde_json::Value],
+    user_id: &str,
+    quote: Option<&Quote>,
+    sent: &SentMessages,
+    timeout: Duration,
+    pool: &bitpart_common::db::Pool,
+) -> usize {
+    let mut failed = 0;
+    let mut typing = None;
+    for (order, i) in messages.iter().enumerate() {
+        if let Some(duration) = reply_get_typing(i) {
+            match sender.send_typing(i, user_id, true).await {
+                Ok(()) => typing = Some(i),
+                Err(err) => warn!("Failed to send typing indicator: {:?}", err),
+            }
+            sleep(duration).await;
+            continue;
+        }
+        let quote = quote.filter(|_| reply_wants_quote(i));
+        let to = reply_get_user_id(i, user_id);
+        let res = match check_edit_target(i, &to, sent) {
+            Err(err) => Err(err),
+            Ok(()) => {
+                match tokio::time::timeout(timeout, sender.send_reply(i, user_id, quote)).await {
+                    Ok(res) => res,
+                    Err(_) => Err(BitpartErrorKind::Signal(format!(
+                        "send timed out after {timeout:?}"
+                    ))
+                    .into()),
+                }
+            }
+        };
+        match res {
+            Ok(timestamp) => {
+                typing = None;
+                if let Some(timestamp) = timestamp {
+                    sent.record(&to, timestamp);
+                }
+            }
+            Err(err) => {
+                error!("Failed to send reply {}: {:?}", order, err);
+                record_failed_send(i, order, &err.to_string(), pool).await;
+                failed += 1;
+            }
         }
     }
+    if let Some(reply) = typing
+        && let Err(err) = sender.send_typing(reply, user_id, false).await
+    {
+        warn!("Failed to stop typing indicator: {:?}", err);
+    }
+    failed
+}
+
+/// Keep a record of an undelivered reply on its conversation so operators can
+/// tell that the user never received it.
+async fn record_failed_send(
+    res: &serde_json::Value,
+    order: usize,
+    error: &str,
+    pool: &bitpart_common::db::Pool,
+) {
+    let Some(conversation_id) = res.get("conversation_id").and_then(|id| id.as_str()) else {
+        return;
+    };
+    let interaction_order = res
+        .get("interaction_order")
+        .and_then(|o| o.as_i64())
+        .unwrap_or_default() as i32;
+    if let Err(err) = crate::db::message::create_failed(
+        conversation_id,
+        &res["payload"]["content_type"],
+        error,
+        interaction_order,
+        order as i32,
+        pool,
+    )
+    .await
+    {
+        error!("Failed to record undelivered message: {:?}", err);
+    }
+}
+
+/// The text of a JSON string, or the JSON itself for any other value.
+fn value_to_string(value: &serde_json::Value) -> String {
+    match value.as_str() {
+        Some(text) => text.to_owned(),
+        None => value.to_string(),
+    }
+}
+
+/// `user_id` addressing the account's own "Note to Self" thread
+const NOTE_TO_SELF: &str = "self";
+
+/// Replies addressed to `NOTE_TO_SELF`, or to the account's own uuid, go to
+/// its "Note to Self" thread, e.g. for audit logs.
+fn try_note_to_self(user_id: &str, self_aci: uuid::Uuid) -> Option<Recipient> {
+    if user_id == NOTE_TO_SELF || uuid::Uuid::parse_str(user_id).is_ok_and(|id| id == self_aci) {
+        Some(Recipient::Contact(ServiceId::Aci(self_aci.into())))
+    } else {
+        None
+    }
+}
+
+/// Prefix for addressing a group by its base64-encoded master key
+const GROUP_PREFIX: &str = "group:";
+
+fn group_from_base64(encoded: &str) -> Result<Recipient> {
+    let key = BASE64_STANDARD.decode(encoded.trim()).map_err(|err| {
+        BitpartErrorKind::Signal(format!("invalid base64 group master key: {err}"))
+    })?;
+    let len = key.len();
+    let master_key: GroupMasterKeyBytes = key.try_into().map_err(|_| {
+        BitpartErrorKind::Signal(format!(
+            "group master key must be 32 bytes, got {len}: {encoded}"
+        ))
+    })?;
+    Ok(Recipient::Group(master_key))
+}
+
+/// Accepts a bare (ACI) uuid, a "PNI:<uuid>" service id string, an E.164
+/// phone number, or a group master key, either hex-encoded or as
+/// `group:<base64>`.
+fn try_user_id_to_recipient(user_id: &str) -> Result<Recipient> {
+    if let Some(encoded) = user_id.strip_prefix(GROUP_PREFIX) {
+        return group_from_base64(encoded);
+    }
+    if user_id.starts_with('+') {
+        return match phonenumber::parse(None, user_id) {
+            Ok(number) if phonenumber::is_valid(&number) => Ok(Recipient::PhoneNumber(number)),
+            _ => Err(BitpartErrorKind::Signal(format!("invalid phone number: {user_id}")).into()),
+        };
+    }
+    if let Some(service_id) = ServiceId::parse_from_service_id_string(user_id) {
+        return Ok(Recipient::Contact(service_id));
+    }
+    match hex::decode(user_id)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+    {
+        Some(master_key) => Ok(Recipient::Group(master_key)),
+        None => Err(BitpartErrorKind::Signal(format!(
+            "not a uuid, phone number or group master key: {user_id}"
+        ))
+        .into()),
+    }
 }
 
 fn reply_get_user_id(res: &serde_json::Value, default_user_id: &str) -> String {
@@ -674,21 +2237,344 @@ fn reply_get_user_id(res: &serde_json::Value, default_user_id: &str) -> String {
         && let Some(client) = content.get("client")
         && let Some(user_id) = client.get("user_id")
     {
-        return unescape(&user_id.to_string()).to_string();
+        return value_to_string(user_id);
     }
     default_user_id.to_string()
 }
 
+/// Whether a reply asks to quote the message it answers, with `"quote": true`
+fn reply_wants_quote(res: &serde_json::Value) -> bool {
+    res["payload"]["content"]["quote"].as_bool() == Some(true)
+}
+
+/**
+ * Build a quote of the last message received from this user, looking its text
+ * up in the store. If it is no longer there, the reply goes out unquoted.
+ */
+async fn reply_quote(
+    manager: &Manager<BitpartStore, Registered>,
+    user_id: &str,
+    state: &ChannelState,
+) -> Option<Quote> {
+    let received = state.last_received.borrow().get(user_id).cloned()?;
+    let thread = Thread::Contact(received.author);
+    let message = match manager.store().message(&thread, received.timestamp).await {
+        Ok(message) => message,
+        Err(err) => {
+            warn!(%thread, "Failed to look up message to quote: {:?}", err);
+            None
+        }
+    };
+    let quote = build_quote(&received, message.as_ref().map(|m| &m.body));
+    if quote.is_none() {
+        warn!(%thread, sent_at = received.timestamp, "message to quote is no longer stored");
+    }
+    quote
+}
+
+fn build_quote(received: &ReceivedMessage, body: Option<&ContentBody>) -> Option<Quote> {
+    let Some(ContentBody::DataMessage(DataMessage {
+        body: Some(text), ..
+    })) = body
+    else {
+        return None;
+    };
+    Some(Quote {
+        id: Some(received.timestamp),
+        author_aci: Some(received.author.raw_uuid().to_string()),
+        text: Some(text.clone()),
+        ..Default::default()
+    })
+}
+
 fn reply_get_text(res: &serde_json::Value) -> String {
     if let Some(payload) = res.get("payload")
         && let Some(content) = payload.get("content")
         && let Some(text) = content.get("text")
     {
-        return unescape(&text.to_string()).to_string();
+        return value_to_string(text);
     }
     "".to_owned()
 }
 
+/// A reply can set the timestamp it is sent with, in milliseconds since the
+/// epoch, in `content.timestamp`
+fn reply_get_timestamp(res: &serde_json::Value) -> Option<u64> {
+    res.get("payload")?
+        .get("content")?
+        .get("timestamp")?
+        .as_u64()
+}
+
+/// A reply with `content_type` `reaction` reacts with `content.emoji` to the
+/// message sent at `content.target_sent_timestamp`, by `content.target_author`
+/// if given. `content.remove` takes the reaction back.
+#[derive(Debug, PartialEq)]
+struct ReplyReaction {
+    emoji: String,
+    target_sent_timestamp: u64,
+    target_author: Option<String>,
+    remove: bool,
+}
+
+fn reply_get_reaction(res: &serde_json::Value) -> Result<Option<ReplyReaction>> {
+    let payload = &res["payload"];
+    if payload["content_type"].as_str() != Some("reaction") {
+        return Ok(None);
+    }
+    let content = &payload["content"];
+    match (
+        content["emoji"].as_str(),
+        content["target_sent_timestamp"].as_u64(),
+    ) {
+        (Some(emoji), Some(target_sent_timestamp)) => Ok(Some(ReplyReaction {
+            emoji: emoji.to_owned(),
+            target_sent_timestamp,
+            target_author: content["target_author"].as_str().map(|a| a.to_owned()),
+            remove: content["remove"].as_bool().unwrap_or(false),
+        })),
+        _ => Err(BitpartErrorKind::Signal(
+            "a reaction needs an emoji and a target_sent_timestamp".to_owned(),
+        )
+        .into()),
+    }
+}
+
+/// A reply with `content_type` `edit` replaces the text of the message sent
+/// to the same user at `content.target_sent_timestamp` with `content.text`
+#[derive(Debug, PartialEq)]
+struct ReplyEdit {
+    target_sent_timestamp: u64,
+    text: String,
+}
+
+fn reply_get_edit(res: &serde_json::Value) -> Result<Option<ReplyEdit>> {
+    let payload = &res["payload"];
+    if payload["content_type"].as_str() != Some("edit") {
+        return Ok(None);
+    }
+    let content = &payload["content"];
+    match (
+        content["target_sent_timestamp"].as_u64(),
+        content["text"].as_str(),
+    ) {
+        (Some(target_sent_timestamp), Some(text)) if !text.is_empty() => Ok(Some(ReplyEdit {
+            target_sent_timestamp,
+            text: text.to_owned(),
+        })),
+        _ => Err(BitpartErrorKind::Signal(
+            "an edit needs a target_sent_timestamp and a text".to_owned(),
+        )
+        .into()),
+    }
+}
+
+/// An edit can only be sent for a message this channel sent to the user
+fn check_edit_target(res: &serde_json::Value, user_id: &str, sent: &SentMessages) -> Result<()> {
+    match reply_get_edit(res)? {
+        Some(edit) if !sent.contains(user_id, edit.target_sent_timestamp) => {
+            Err(BitpartErrorKind::Signal(format!(
+                "no message sent at {} to edit",
+                edit.target_sent_timestamp
+            ))
+            .into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// CSML `Typing` replies show the typing indicator for `content.duration`
+/// milliseconds, up to `MAX_TYPING_DURATION`
+fn reply_get_typing(res: &serde_json::Value) -> Option<Duration> {
+    let payload = res.get("payload")?;
+    if payload["content_type"].as_str() != Some("typing") {
+        return None;
+    }
+    let duration = payload["content"]["duration"].as_u64().unwrap_or_default();
+    Some(Duration::from_millis(duration).min(MAX_TYPING_DURATION))
+}
+
+/// Media messages (`Image`, `File`, `Audio`, `Video`) carry the attachment's
+/// url or path in `content.url`. Any message can also carry several in
+/// `content.attachments`, as urls or objects with a `url`.
+fn reply_get_attachments(res: &serde_json::Value) -> Vec<String> {
+    let Some(payload) = res.get("payload") else {
+        return vec![];
+    };
+    let content = &payload["content"];
+    let mut attachments = Vec::new();
+    if let Some("image" | "file" | "audio" | "video") = payload["content_type"].as_str()
+        && let Some(url) = content["url"].as_str()
+    {
+        attachments.push(url.to_owned());
+    }
+    if let Some(list) = content["attachments"].as_array() {
+        attachments.extend(
+            list.iter()
+                .filter_map(|attachment| attachment.as_str().or(attachment["url"].as_str()))
+                .map(|url| url.to_owned()),
+        );
+    }
+    attachments
+}
+
+// === reconnection ===
+
+const DEFAULT_RECONNECT_DELAY: u64 = 1;
+const DEFAULT_RECONNECT_MAX_DELAY: u64 = 300;
+
+/// How a channel keeps trying to reach Signal: the delay doubles after each
+/// failed attempt, up to `max_delay`, and it gives up after `max_attempts`
+/// (never, if unset).
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Backoff {
+    delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+}
+
+impl Backoff {
+    /// From the reconnect limits in `Limits`
+    fn from_limits(limits: &Limits) -> Self {
+        Self {
+            delay: limits
+                .reconnect_delay
+                .unwrap_or(Duration::from_secs(DEFAULT_RECONNECT_DELAY)),
+            max_delay: limits
+                .reconnect_max_delay
+                .unwrap_or(Duration::from_secs(DEFAULT_RECONNECT_MAX_DELAY)),
+            max_attempts: limits.reconnect_attempts.filter(|n| *n > 0),
+        }
+    }
+
+    /// How long to wait after the given (1-based) failed attempt
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    fn gives_up(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt >= max)
+    }
+}
+
+/// Call `connect` until it succeeds, waiting between attempts as `backoff`
+/// says. Each failed attempt is logged.
+async fn with_backoff<T, F, Fut>(backoff: &Backoff, what: &str, mut connect: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match connect().await {
+            Ok(connected) => return Ok(connected),
+            Err(err) if backoff.gives_up(attempt) => {
+                error!(attempt, "Giving up trying to {what}: {:?}", err);
+                return Err(err);
+            }
+            Err(err) => {
+                let delay = backoff.delay(attempt);
+                warn!(attempt, ?delay, "Failed to {what}, retrying: {:?}", err);
+                sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// How changed identities of contacts are treated: whether they are rejected
+/// rather than trusted, and for how long after linking they are trusted anyway
+static IDENTITY_POLICY: OnceLock<(bool, Option<Duration>)> = OnceLock::new();
+
+/**
+ * Set how channels treat a contact whose identity changed, e.g. because they
+ * moved to a new phone. By default it is trusted. With `reject`, it is only
+ * trusted within `trust_window` of the channel being linked. Only the first
+ * call has any effect.
+ */
+pub fn set_identity_policy(reject: bool, trust_window: Option<Duration>) {
+    let _ = IDENTITY_POLICY.set((reject, trust_window));
+}
+
+/// Open the store a channel sends and receives with, under the identity policy
+pub(crate) async fn open_store(id: &str, pool: &bitpart_common::db::Pool) -> Result<BitpartStore> {
+    let (reject, trust_window) = IDENTITY_POLICY.get().copied().unwrap_or_default();
+    let on_new_identity = if reject {
+        OnNewIdentity::Reject
+    } else {
+        OnNewIdentity::Trust
+    };
+    let store = BitpartStore::open(id, pool, on_new_identity).await?;
+    Ok(match trust_window {
+        Some(window) => store.with_trust_window(window),
+        None => store,
+    })
+}
+
+/// Check that a channel was registered or linked before trying to load it.
+/// Retrying can't help a channel that never was, or a store that can't be
+/// read, so these fail at once rather than with backoff.
+async fn check_registered(id: &str, pool: &bitpart_common::db::Pool) -> Result<()> {
+    let store = open_store(id, pool).await?;
+    match store.load_registration_data().await? {
+        Some(_) => Ok(()),
+        None => Err(BitpartErrorKind::Signal(format!("channel {id} is not registered")).into()),
+    }
+}
+
+async fn load_manager(
+    id: &str,
+    pool: &bitpart_common::db::Pool,
+) -> Result<Manager<BitpartStore, Registered>> {
+    let store = open_store(id, pool).await?;
+    Ok(Manager::load_registered(store).await?)
+}
+
+/**
+ * Report that the Signal receive stream for this channel has ended, so that
+ * monitoring can spot a channel going dark before it is reconnected.
+ */
+async fn receive_stream_ended(state: &ChannelState) -> Result<()> {
+    let channel = crate::db::channel::get("signal", &state.id, &state.pool).await?;
+    let last_received_at = channel.and_then(|c| c.last_received_at);
+    warn!(
+        monotonic_counter.bitpart_receive_stream_ended = 1,
+        bot_id = state.id,
+        channel_id = "signal",
+        last_received_at = last_received_at.as_deref().unwrap_or("never"),
+        "receive stream ended"
+    );
+    if let Some(version) = crate::db::bot::get_latest_by_bot_id(&state.id, &state.pool).await? {
+        crate::csml::callback::channel_down(
+            &version.bot,
+            "signal",
+            last_received_at.as_deref(),
+            "stream_ended",
+            &state.pool,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Presage saves the contacts synced from the primary device through
+/// `BitpartStore::save_contact` before announcing them, so they can be looked
+/// up by `contact_by_id`; report how many there are now.
+async fn contacts_synced(store: &BitpartStore) {
+    match store.contacts().await {
+        Ok(contacts) => info!(count = contacts.count(), "synchronized contacts"),
+        Err(err) => warn!("Failed to read synchronized contacts: {:?}", err),
+    }
+}
+
+/**
+ * Receive messages for this channel until it is cancelled. Whenever the
+ * stream from Signal fails or ends, the manager is reloaded and resubscribed
+ * after an exponential backoff. The caller selects on the channel's
+ * cancellation token, so a shutdown also cuts short a pending reconnect.
+ */
+#[instrument(skip_all, fields(bot_id = %state.id, channel_id = "signal"))]
 async fn receive(
     manager_ref: &mut Cell<Manager<BitpartStore, Registered>>,
     attachments_dir: &Path,
@@ -699,54 +2585,1137 @@ async fn receive(
         "attachments will be stored"
     );
 
+    let backoff = Backoff::from_limits(limits());
+    let mut attempt = 0;
     loop {
-        'inner: loop {
-            tokio::time::sleep(Duration::from_millis(2)).await;
-            let manager = manager_ref.get_mut();
-            match manager.receive_messages().await {
-                Ok(messages) => {
-                    pin_mut!(messages);
-                    while let Some(content) = messages.next().await {
-                        match content {
-                            Received::QueueEmpty => debug!("done with synchronization"),
-                            Received::Contacts => debug!("got contacts synchronization"),
-                            Received::Content(content) => {
-                                if let Err(err) = process_signal_message(
-                                    manager,
-                                    attachments_dir,
-                                    &content,
-                                    state,
-                                )
-                                .await
-                                {
-                                    warn!("Failed to extract message thread: {:?}", err);
-                                }
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let manager = manager_ref.get_mut();
+        match manager.receive_messages().await {
+            Ok(messages) => {
+                pin_mut!(messages);
+                while let Some(content) = messages.next().await {
+                    attempt = 0;
+                    match content {
+                        Received::QueueEmpty => debug!("done with synchronization"),
+                        Received::Contacts => contacts_synced(manager.store()).await,
+                        Received::Content(content) => {
+                            if let Err(err) =
+                                process_signal_message(manager, attachments_dir, &content, state)
+                                    .await
+                            {
+                                warn!("Failed to extract message thread: {:?}", err);
                             }
                         }
                     }
                 }
-                Err(err) => {
-                    error!("Failed to receive messages: {:?}", err);
-                    sleep(Duration::from_secs(30)).await;
-                    break 'inner;
+                if let Err(err) = receive_stream_ended(state).await {
+                    error!("Failed to report end of receive stream: {:?}", err);
                 }
+                attempt += 1;
+                if backoff.gives_up(attempt) {
+                    error!(attempt, "Giving up receiving messages: stream keeps ending");
+                    return Err(BitpartErrorKind::Signal("Receive stream ended".to_owned()).into());
+                }
+                let delay = backoff.delay(attempt);
+                warn!(attempt, ?delay, "Receive stream ended, reconnecting");
+                sleep(delay).await;
+            }
+            Err(err) => {
+                attempt += 1;
+                if backoff.gives_up(attempt) {
+                    error!(attempt, "Giving up receiving messages: {:?}", err);
+                    return Err(err.into());
+                }
+                let delay = backoff.delay(attempt);
+                error!(attempt, ?delay, "Failed to receive messages: {:?}", err);
+                sleep(delay).await;
             }
         }
-        let store = BitpartStore::open(&state.id, &state.pool, OnNewIdentity::Trust).await?;
-        // if let Ok(manager) = Manager::load_registered(store).await {
-        //     warn!("Replacing manager!");
-        //     manager_ref.replace(manager);
-        // } else {
-        //     warn!("Failed to reload manager.");
-        // }
-        match Manager::load_registered(store).await {
-            Ok(manager) => {
-                warn!("Replacing manager!");
-                manager_ref.replace(manager);
+        let manager = with_backoff(&backoff, "reload manager", || {
+            load_manager(&state.id, &state.pool)
+        })
+        .await?;
+        info!(attempt, "Reconnected to Signal");
+        manager_ref.replace(manager);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::get_test_pool;
+
+    #[test]
+    fn it_should_back_off_exponentially() {
+        let backoff = Backoff {
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            max_attempts: Some(3),
+        };
+        let delays: Vec<u64> = (1..=4).map(|n| backoff.delay(n).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5]);
+        assert!(!backoff.gives_up(2));
+        assert!(backoff.gives_up(3));
+    }
+
+    #[tokio::test]
+    async fn it_should_not_retry_loading_an_unregistered_channel() {
+        let pool = get_test_pool().await;
+        let err = check_registered("channel", &pool).await.unwrap_err();
+        assert!(err.to_string().contains("not registered"));
+    }
+
+    #[tokio::test]
+    async fn it_should_retry_a_failed_connection() {
+        let backoff = Backoff {
+            delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+            max_attempts: Some(3),
+        };
+        let mut attempts = 0;
+        let connected = with_backoff(&backoff, "connect", || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt == 1 {
+                    Err(BitpartErrorKind::Signal("connection refused".to_owned()).into())
+                } else {
+                    Ok(attempt)
+                }
             }
-            Err(err) => {
-                warn!("Failed to reload manager. {:?}", err);
+        })
+        .await
+        .unwrap();
+        assert_eq!(connected, 2);
+
+        let mut attempts = 0;
+        let res: Result<()> = with_backoff(&backoff, "connect", || {
+            attempts += 1;
+            async { Err(BitpartErrorKind::Signal("connection refused".to_owned()).into()) }
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn it_should_parse_aci_and_pni_recipients() {
+        let uuid = "a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d";
+
+        let Ok(Recipient::Contact(aci)) = try_user_id_to_recipient(uuid) else {
+            panic!("expected a contact recipient");
+        };
+        assert!(matches!(aci, ServiceId::Aci(_)));
+        assert_eq!(aci.raw_uuid().to_string(), uuid);
+
+        let Ok(Recipient::Contact(pni)) = try_user_id_to_recipient(&format!("PNI:{uuid}")) else {
+            panic!("expected a contact recipient");
+        };
+        assert!(matches!(pni, ServiceId::Pni(_)));
+        assert_eq!(pni.raw_uuid().to_string(), uuid);
+    }
+
+    #[test]
+    fn it_should_send_with_an_explicit_timestamp() {
+        let reply = json!({
+            "payload": {
+                "content_type": "text",
+                "content": {
+                    "text": "Reminder",
+                    "timestamp": 1_700_000_000_000u64
+                }
+            }
+        });
+        let timestamp = send_timestamp(reply_get_timestamp(&reply));
+        assert_eq!(timestamp, 1_700_000_000_000);
+        let message = build_data_message(reply_get_text(&reply), vec![], None, timestamp);
+        assert_eq!(message.timestamp, Some(1_700_000_000_000));
+
+        let reply = json!({
+            "payload": {
+                "content_type": "text",
+                "content": { "text": "Now" }
+            }
+        });
+        assert_eq!(reply_get_timestamp(&reply), None);
+        assert!(send_timestamp(None) > 1_700_000_000_000);
+    }
+
+    #[test]
+    fn it_should_split_attachments_over_the_limits_in_order() {
+        let media = |sizes: &[usize]| -> Vec<(usize, bool)> {
+            sizes.iter().map(|size| (*size, true)).collect()
+        };
+        assert_eq!(batch_attachments(&[], 32, 100), vec![]);
+        assert_eq!(batch_attachments(&media(&[10; 5]), 32, 100), vec![0..5]);
+        // by count
+        assert_eq!(
+            batch_attachments(&media(&[1; 5]), 2, 100),
+            vec![0..2, 2..4, 4..5]
+        );
+        // by size, with an attachment over the total sent on its own
+        assert_eq!(
+            batch_attachments(&media(&[40, 40, 40, 150, 10]), 32, 100),
+            vec![0..2, 2..3, 3..4, 4..5]
+        );
+        // anything but images and videos goes on its own
+        assert_eq!(
+            batch_attachments(
+                &[(1, true), (1, true), (1, false), (1, false), (1, true)],
+                32,
+                100
+            ),
+            vec![0..2, 2..3, 3..4, 4..5]
+        );
+    }
+
+    #[test]
+    fn it_should_caption_attachments_in_one_message() {
+        let reply = json!({
+            "payload": {
+                "content_type": "image",
+                "content": {
+                    "url": "https://example.org/cat.png",
+                    "text": "A cat"
+                }
+            }
+        });
+        assert_eq!(
+            reply_get_attachments(&reply),
+            vec!["https://example.org/cat.png"]
+        );
+
+        let pointer = AttachmentPointer {
+            content_type: Some("image/png".to_owned()),
+            ..Default::default()
+        };
+        let message = build_data_message(reply_get_text(&reply), vec![pointer.clone()], None, 1);
+        assert_eq!(message.body.as_deref(), Some("A cat"));
+        assert_eq!(message.attachments, vec![pointer.clone()]);
+        assert!(message.group_v2.is_none());
+
+        let master_key = [7u8; 32];
+        let message = build_data_message(
+            reply_get_text(&reply),
+            vec![pointer.clone()],
+            Some(&master_key),
+            1,
+        );
+        assert_eq!(message.body.as_deref(), Some("A cat"));
+        assert_eq!(message.attachments, vec![pointer]);
+        assert_eq!(
+            message.group_v2.and_then(|group| group.master_key),
+            Some(master_key.to_vec())
+        );
+    }
+
+    struct MockReplySender {
+        sent: Vec<String>,
+    }
+
+    impl ReplySender for MockReplySender {
+        async fn send_reply(
+            &mut self,
+            reply: &serde_json::Value,
+            _user_id: &str,
+            quote: Option<&Quote>,
+        ) -> Result<Option<u64>> {
+            let mut text = reply_get_text(reply);
+            if text == "fail" {
+                return Err(BitpartErrorKind::Signal("unregistered user".to_owned()).into());
+            }
+            if text == "stall" {
+                sleep(Duration::from_secs(3600)).await;
+            }
+            if let Some(quote) = quote {
+                text = format!("{text} (quoting {})", quote.id.unwrap_or_default());
+            }
+            self.sent.push(text);
+            // like the real sender, an edit isn't a message that can be edited
+            if reply_get_edit(reply)?.is_some() {
+                return Ok(None);
+            }
+            // stands in for the timestamp the message was sent with
+            Ok(Some(self.sent.len() as u64))
+        }
+
+        async fn send_typing(
+            &mut self,
+            _reply: &serde_json::Value,
+            _user_id: &str,
+            started: bool,
+        ) -> Result<()> {
+            self.sent.push(format!("typing {started}"));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn it_should_stop_typing_when_no_message_follows() {
+        let pool = get_test_pool().await;
+
+        let typing = json!({"payload": {"content_type": "typing", "content": {"duration": 0}}});
+        let text = json!({"payload": {"content_type": "text", "content": {"text": "hello"}}});
+        let messages = vec![typing.clone(), text, typing];
+        let mut sender = MockReplySender { sent: vec![] };
+
+        let failed = send_replies(
+            &mut sender,
+            &messages,
+            "user_id",
+            None,
+            &SentMessages::default(),
+            send_timeout(),
+            &pool,
+        )
+        .await;
+
+        assert_eq!(failed, 0);
+        assert_eq!(
+            sender.sent,
+            vec!["typing true", "hello", "typing true", "typing false"]
+        );
+        assert_eq!(
+            reply_get_typing(
+                &json!({"payload": {"content_type": "typing", "content": {"duration": 60000}}})
+            ),
+            Some(MAX_TYPING_DURATION)
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_sending_after_a_failed_reply() {
+        let pool = get_test_pool().await;
+
+        let messages: Vec<serde_json::Value> = ["first", "fail", "third"]
+            .iter()
+            .map(|text| json!({"payload": {"content_type": "text", "content": {"text": text}}}))
+            .collect();
+        let mut sender = MockReplySender { sent: vec![] };
+
+        let failed = send_replies(
+            &mut sender,
+            &messages,
+            "user_id",
+            None,
+            &SentMessages::default(),
+            send_timeout(),
+            &pool,
+        )
+        .await;
+
+        assert_eq!(failed, 1);
+        assert_eq!(sender.sent, vec!["first", "third"]);
+    }
+
+    #[tokio::test]
+    async fn it_should_quote_only_replies_that_ask_to() {
+        let pool = get_test_pool().await;
+
+        let author = ServiceId::Aci(uuid::Uuid::new_v4().into());
+        let received = ReceivedMessage {
+            timestamp: 1234,
+            author,
+        };
+        let body = ContentBody::DataMessage(DataMessage {
+            body: Some("what time is it?".to_owned()),
+            ..Default::default()
+        });
+        let quote = build_quote(&received, Some(&body)).unwrap();
+        assert_eq!(quote.id, Some(1234));
+        assert_eq!(quote.author_aci, Some(author.raw_uuid().to_string()));
+        assert_eq!(quote.text.as_deref(), Some("what time is it?"));
+        assert!(build_quote(&received, None).is_none());
+
+        let messages = vec![
+            json!({ "payload": { "content": { "text": "noon", "quote": true } } }),
+            json!({ "payload": { "content": { "text": "bye" } } }),
+        ];
+        let mut sender = MockReplySender { sent: vec![] };
+        let failed = send_replies(
+            &mut sender,
+            &messages,
+            "user_id",
+            Some(&quote),
+            &SentMessages::default(),
+            send_timeout(),
+            &pool,
+        )
+        .await;
+
+        assert_eq!(failed, 0);
+        assert_eq!(sender.sent, vec!["noon (quoting 1234)", "bye"]);
+    }
+
+    #[tokio::test]
+    async fn it_should_only_edit_messages_it_sent() {
+        let pool = get_test_pool().await;
+
+        let edit = |target: u64, text: &str| {
+            json!({"payload": {"content_type": "edit", "content": {
+                "target_sent_timestamp": target,
+                "text": text
+            }}})
+        };
+        assert_eq!(
+            reply_get_edit(&edit(1, "fixed")).unwrap(),
+            Some(ReplyEdit {
+                target_sent_timestamp: 1,
+                text: "fixed".to_owned()
+            })
+        );
+        assert!(reply_get_edit(&edit(1, "")).is_err());
+        let message = build_edit_message(
+            reply_get_edit(&edit(1, "fixed")).unwrap().unwrap(),
+            &Recipient::Group([7u8; 32]),
+            5,
+        );
+        assert_eq!(message.target_sent_timestamp, Some(1));
+        let data_message = message.data_message.unwrap();
+        assert_eq!(data_message.body.as_deref(), Some("fixed"));
+        assert_eq!(data_message.timestamp, Some(5));
+        assert!(data_message.group_v2.is_some());
+
+        let messages = vec![
+            json!({"payload": {"content_type": "text", "content": {"text": "typo"}}}),
+            edit(1, "fixed"),
+            edit(99, "never sent"),
+        ];
+        let sent = SentMessages::default();
+        let mut sender = MockReplySender { sent: vec![] };
+        let failed = send_replies(
+            &mut sender,
+            &messages,
+            "user_id",
+            None,
+            &sent,
+            send_timeout(),
+            &pool,
+        )
+        .await;
+
+        assert_eq!(failed, 1);
+        assert_eq!(sender.sent, vec!["typo", "fixed"]);
+        assert!(sent.contains("user_id", 1));
+        assert!(!sent.contains("user_id", 2));
+        assert!(!sent.contains("other_user", 1));
+    }
+
+    #[tokio::test]
+    async fn it_should_record_a_stalled_send_as_failed() {
+        let pool = get_test_pool().await;
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "signal".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let conversation_id =
+            crate::db::conversation::create("Default", "start", &client, None, &pool)
+                .await
+                .unwrap();
+
+        let messages: Vec<serde_json::Value> = ["stall", "second"]
+            .iter()
+            .map(|text| {
+                json!({
+                    "conversation_id": conversation_id,
+                    "payload": {"content_type": "text", "content": {"text": text}}
+                })
+            })
+            .collect();
+        let mut sender = MockReplySender { sent: vec![] };
+
+        let failed = send_replies(
+            &mut sender,
+            &messages,
+            "user_id",
+            None,
+            &SentMessages::default(),
+            Duration::from_millis(10),
+            &pool,
+        )
+        .await;
+
+        assert_eq!(failed, 1);
+        assert_eq!(sender.sent, vec!["second"]);
+        let dead = crate::db::message::get_failed("bot_id", None, None, &pool)
+            .await
+            .unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].user_id, "user_id");
+        assert!(dead[0].error.contains("timed out"));
+    }
+
+    #[test]
+    fn it_should_build_a_reaction_to_the_users_message() {
+        let uuid = "a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d";
+        let reply = json!({
+            "payload": {
+                "content_type": "reaction",
+                "content": {
+                    "emoji": "👍",
+                    "target_sent_timestamp": 1_700_000_000_000u64,
+                    "client": { "user_id": uuid }
+                }
+            }
+        });
+
+        let recipient = try_user_id_to_recipient(&reply_get_user_id(&reply, "other")).unwrap();
+        let reaction = reply_get_reaction(&reply).unwrap().unwrap();
+        let message = build_reaction_message(reaction, &recipient, 1).unwrap();
+
+        let reaction = message.reaction.unwrap();
+        assert_eq!(reaction.emoji.as_deref(), Some("👍"));
+        assert_eq!(reaction.remove, Some(false));
+        assert_eq!(reaction.target_author_aci.as_deref(), Some(uuid));
+        assert_eq!(reaction.target_sent_timestamp, Some(1_700_000_000_000));
+        assert_eq!(message.body, None);
+        assert_eq!(message.group_v2, None);
+
+        let group = try_user_id_to_recipient(&hex::encode([7u8; 32])).unwrap();
+        let reaction = reply_get_reaction(&reply).unwrap().unwrap();
+        assert!(build_reaction_message(reaction, &group, 1).is_err());
+        let invalid = json!({"payload": {"content_type": "reaction", "content": {"emoji": "👍"}}});
+        assert!(reply_get_reaction(&invalid).is_err());
+    }
+
+    #[test]
+    fn it_should_put_the_sender_name_in_metadata() {
+        let uuid = "a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d";
+        let Ok(Recipient::Contact(service_id)) = try_user_id_to_recipient(uuid) else {
+            panic!("expected a contact recipient");
+        };
+
+        let metadata = format_sender_metadata(&service_id, Some("Alice".to_owned()));
+        assert_eq!(metadata["sender"]["name"], "Alice");
+        assert_eq!(metadata["sender"]["display_name"], "Alice");
+        assert_eq!(metadata["sender"]["uuid"], uuid);
+
+        let metadata = format_sender_metadata(&service_id, None);
+        assert!(metadata["sender"]["name"].is_null());
+        assert_eq!(metadata["sender"]["display_name"], uuid);
+    }
+
+    #[tokio::test]
+    async fn it_should_report_when_the_receive_stream_ends() {
+        let (url, received) = crate::utils::start_test_receiver().await;
+        let pool = get_test_pool().await;
+
+        let bot: csml_interpreter::data::CsmlBot = serde_json::from_value(json!({
+            "id": "bot_id",
+            "name": "test",
+            "flows": [],
+            "default_flow": "Default",
+            "env": { "channel_down_webhook": url },
+        }))
+        .unwrap();
+        crate::db::bot::create(bot, &pool).await.unwrap();
+        crate::db::channel::create("signal", "bot_id", &pool)
+            .await
+            .unwrap();
+        crate::db::channel::set_last_received("signal", "bot_id", &pool)
+            .await
+            .unwrap();
+
+        let state = ChannelState {
+            id: "bot_id".to_owned(),
+            pool: pool.clone(),
+            apps_endpoint: None,
+            last_received: RefCell::default(),
+            sent: SentMessages::default(),
+        };
+        receive_stream_ended(&state).await.unwrap();
+        crate::csml::callback::drain(&pool).await.unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["event"], "channel_down");
+        assert_eq!(received[0]["bot_id"], "bot_id");
+        assert_eq!(received[0]["channel_id"], "signal");
+        assert_eq!(received[0]["reason"], "stream_ended");
+        assert!(received[0]["last_received_at"].is_string());
+    }
+
+    #[tokio::test]
+    async fn it_should_expire_signal_conversations_after_the_bot_ttl() {
+        let pool = get_test_pool().await;
+
+        let bot: csml_interpreter::data::CsmlBot = serde_json::from_value(json!({
+            "id": "bot_id",
+            "name": "test",
+            "flows": [
+              {
+                "id": "Default",
+                "name": "Default",
+                "content": "start: say \"Name?\" hold goto end",
+                "commands": [],
+              }
+            ],
+            "default_flow": "Default",
+            "env": { "ttl_duration": 7 },
+        }))
+        .unwrap();
+        crate::db::bot::create(bot, &pool).await.unwrap();
+
+        let state = ChannelState {
+            id: "bot_id".to_owned(),
+            pool: pool.clone(),
+            apps_endpoint: None,
+            last_received: RefCell::default(),
+            sent: SentMessages::default(),
+        };
+        let payload = json!({ "content_type": "text", "content": { "text": "hi" } });
+        let env = bot_env(&state).await;
+        let request = reply_request(
+            "user_id".to_owned(),
+            payload,
+            json!({}),
+            env.as_ref(),
+            &state,
+        );
+        assert_eq!(request.event.ttl_duration, Some(7));
+        api::process_request(&request, &pool).await.unwrap();
+
+        let conversation =
+            crate::db::conversation::get_latest_open_by_client(&request.event.client, &pool)
+                .await
+                .unwrap()
+                .unwrap();
+        let expires_at = conversation.expires_at.expect("conversation should expire");
+        let expires_at =
+            chrono::NaiveDateTime::parse_from_str(&expires_at, "%Y-%m-%d %H:%M:%S%.f").unwrap();
+        let days = (expires_at - chrono::Utc::now().naive_utc()).num_hours() as f64 / 24.0;
+        assert!((6.9..=7.0).contains(&days), "expires in {days} days");
+    }
+
+    async fn transcribe_stub(body: axum::body::Bytes) -> axum::Json<serde_json::Value> {
+        axum::Json(json!({ "text": format!("{} bytes of audio", body.len()) }))
+    }
+
+    #[tokio::test]
+    async fn it_should_attach_a_transcript_to_audio() {
+        let app = axum::Router::new().route("/", axum::routing::post(transcribe_stub));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let transcript =
+            transcribe(&endpoint, "audio/aac", vec![0; 16], Duration::from_secs(5)).await;
+        assert_eq!(transcript.as_deref(), Some("16 bytes of audio"));
+
+        let payload = audio_payload(Path::new("/tmp/note.aac"), transcript);
+        assert_eq!(payload["content_type"], "audio");
+        assert_eq!(payload["content"]["url"], "/tmp/note.aac");
+        assert_eq!(payload["content"]["transcript"], "16 bytes of audio");
+    }
+
+    #[tokio::test]
+    async fn it_should_deliver_audio_without_a_transcript_on_failure() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+
+        let transcript =
+            transcribe(&endpoint, "audio/aac", vec![0; 16], Duration::from_secs(5)).await;
+        assert_eq!(transcript, None);
+
+        let payload = audio_payload(Path::new("/tmp/note.aac"), transcript);
+        assert_eq!(payload["content_type"], "audio");
+        assert!(payload["content"].get("transcript").is_none());
+    }
+
+    #[tokio::test]
+    async fn it_should_give_up_on_a_hung_transcription_endpoint() {
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::post(|| async {
+                sleep(Duration::from_secs(60)).await;
+                axum::Json(json!({ "text": "too late" }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let started = std::time::Instant::now();
+        let transcript = transcribe(
+            &endpoint,
+            "audio/aac",
+            vec![0; 16],
+            Duration::from_millis(200),
+        )
+        .await;
+        assert_eq!(transcript, None);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn it_should_describe_saved_attachments_without_their_data() {
+        let saved = vec![
+            SavedAttachment {
+                content_type: "image/jpeg".to_owned(),
+                path: PathBuf::from("/tmp/bitpart-photo.jpg"),
+                data: vec![0xff; 16],
+            },
+            SavedAttachment {
+                content_type: "application/pdf".to_owned(),
+                path: PathBuf::from("/tmp/bitpart-doc.pdf"),
+                data: vec![0x25; 16],
+            },
+        ];
+        let metadata = attachments_metadata(&saved);
+        assert_eq!(
+            metadata,
+            json!([
+                { "path": "/tmp/bitpart-photo.jpg", "content_type": "image/jpeg" },
+                { "path": "/tmp/bitpart-doc.pdf", "content_type": "application/pdf" },
+            ])
+        );
+
+        let image = attachment_payload(&saved[0].path, &saved[0].content_type);
+        assert_eq!(image["content_type"], "image");
+        assert_eq!(image["content"]["url"], "/tmp/bitpart-photo.jpg");
+        let file = attachment_payload(&saved[1].path, &saved[1].content_type);
+        assert_eq!(file["content_type"], "file");
+    }
+
+    #[tokio::test]
+    async fn it_should_resolve_phone_number_recipients() {
+        let Err(err) = try_user_id_to_recipient("+1 555") else {
+            panic!("expected an invalid phone number to be rejected");
+        };
+        assert!(err.to_string().contains("invalid phone number: +1 555"));
+
+        let Ok(Recipient::PhoneNumber(number)) = try_user_id_to_recipient("+1 201 555 0123") else {
+            panic!("expected a phone number recipient");
+        };
+        assert_eq!(format_e164(&number), "+12015550123");
+
+        let pool = get_test_pool().await;
+        let store = BitpartStore::open("channel", &pool, OnNewIdentity::Trust)
+            .await
+            .unwrap();
+        let Err(err) = resolve_recipient(Recipient::PhoneNumber(number), &store).await else {
+            panic!("expected a number without a contact to be rejected");
+        };
+        assert!(
+            err.to_string()
+                .contains("no Signal contact has phone number +12015550123")
+        );
+    }
+
+    #[test]
+    fn it_should_only_send_read_receipts_when_asked_to() {
+        assert_eq!(receipt_types(false), vec![receipt_message::Type::Delivery]);
+        assert_eq!(
+            receipt_types(true),
+            vec![receipt_message::Type::Delivery, receipt_message::Type::Read]
+        );
+
+        let receipt = build_receipt(receipt_message::Type::Delivery, 1700000000000);
+        assert_eq!(receipt.r#type, Some(receipt_message::Type::Delivery as i32));
+        assert_eq!(receipt.timestamp, vec![1700000000000]);
+    }
+
+    #[test]
+    fn it_should_read_channel_settings_from_the_bot_env() {
+        assert!(!read_receipts_enabled(None));
+        assert!(!groups_accepted(None));
+        assert_eq!(ttl_duration(None), None);
+
+        let env = json!({
+            "read_receipts": true,
+            "accept_groups": true,
+            "ttl_duration": 7,
+            "low_data_mode": false,
+            "transcription_endpoint": "http://localhost:9000",
+        });
+        assert!(read_receipts_enabled(Some(&env)));
+        assert!(groups_accepted(Some(&env)));
+        assert_eq!(ttl_duration(Some(&env)), Some(7));
+        assert_eq!(low_data_mode(Some(&env)), Some(false));
+        assert_eq!(
+            transcription_endpoint(Some(&env)),
+            Some("http://localhost:9000")
+        );
+    }
+
+    #[test]
+    fn it_should_reply_to_the_group_a_message_came_from() {
+        let sender = "a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d";
+        let master_key = [7u8; 32];
+        let metadata = json!({ "group": hex::encode(master_key) });
+
+        let default = reply_default_recipient(sender, &metadata);
+        let Ok(Recipient::Group(key)) = try_user_id_to_recipient(&default) else {
+            panic!("expected a group recipient");
+        };
+        assert_eq!(key, master_key);
+        assert_eq!(reply_default_recipient(sender, &json!({})), sender);
+
+        let reply = json!({
+            "payload": {
+                "content": {
+                    "text": "psst",
+                    "client": { "user_id": sender }
+                }
             }
+        });
+        assert_eq!(reply_get_user_id(&reply, &default), sender);
+    }
+
+    #[test]
+    fn it_should_accept_groups_from_the_allowlist() {
+        let master_key = [7u8; 32];
+        let added = DataMessage {
+            group_v2: Some(GroupContextV2 {
+                master_key: Some(master_key.to_vec()),
+                revision: Some(1),
+                group_change: Some(vec![1, 2, 3]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(group_update(&added), Some(master_key));
+
+        let message = DataMessage {
+            body: Some("hello".to_owned()),
+            ..added.clone()
+        };
+        assert_eq!(group_update(&message), None);
+
+        let friend = ServiceId::Aci(
+            uuid::Uuid::parse_str("a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d")
+                .unwrap()
+                .into(),
+        );
+        let stranger = ServiceId::Aci(
+            uuid::Uuid::parse_str("0e1f2a3b-4c5d-4a7b-8c9d-a1b2c3d4e5f6")
+                .unwrap()
+                .into(),
+        );
+        assert_eq!(group_policy(None, &friend), GroupPolicy::Ignore);
+        assert_eq!(
+            group_policy(Some(&json!({ "accept_groups": true })), &stranger),
+            GroupPolicy::Accept
+        );
+
+        let env = json!({
+            "accept_groups": true,
+            "group_allowlist": ["a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d"],
+        });
+        assert_eq!(group_policy(Some(&env), &friend), GroupPolicy::Accept);
+        assert_eq!(group_policy(Some(&env), &stranger), GroupPolicy::Reject);
+
+        let payload = group_added_payload(&master_key, "Friends", &friend);
+        assert_eq!(payload["content_type"], "group_added");
+        assert_eq!(payload["content"]["group"], hex::encode(master_key));
+        assert_eq!(payload["content"]["title"], "Friends");
+    }
+
+    #[tokio::test]
+    async fn it_should_drop_messages_to_rejected_groups() {
+        let pool = get_test_pool().await;
+        let bot: csml_interpreter::data::CsmlBot = serde_json::from_value(json!({
+            "id": "bot_id",
+            "name": "test",
+            "flows": [],
+            "default_flow": "Default",
+            "env": {
+                "accept_groups": true,
+                "group_allowlist": ["a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d"],
+            },
+        }))
+        .unwrap();
+        crate::db::bot::create(bot, &pool).await.unwrap();
+        let store = BitpartStore::open("channel", &pool, OnNewIdentity::Trust)
+            .await
+            .unwrap();
+        let state = ChannelState {
+            id: "bot_id".to_owned(),
+            pool: pool.clone(),
+            apps_endpoint: None,
+            last_received: RefCell::default(),
+            sent: SentMessages::default(),
+        };
+
+        let env = bot_env(&state).await;
+        let master_key = [7u8; 32];
+        let other_key = [8u8; 32];
+        assert!(answers_group(&master_key, true, env.as_ref(), &state).await);
+
+        let stranger = ServiceId::Aci(
+            uuid::Uuid::parse_str("0e1f2a3b-4c5d-4a7b-8c9d-a1b2c3d4e5f6")
+                .unwrap()
+                .into(),
+        );
+        let policy = join_group_policy(master_key, &stranger, env.as_ref(), &state, &store)
+            .await
+            .unwrap();
+        assert_eq!(policy, GroupPolicy::Reject);
+
+        // presage saves the group again when a message to it arrives
+        assert!(!answers_group(&master_key, true, env.as_ref(), &state).await);
+        assert!(answers_group(&other_key, true, env.as_ref(), &state).await);
+        assert!(!answers_group(&other_key, false, env.as_ref(), &state).await);
+    }
+
+    #[test]
+    fn it_should_parse_group_master_keys() {
+        let master_key = [7u8; 32];
+        let Ok(Recipient::Group(key)) = try_user_id_to_recipient(&hex::encode(master_key)) else {
+            panic!("expected a group recipient");
+        };
+        assert_eq!(key, master_key);
+        assert!(try_user_id_to_recipient("not a recipient").is_err());
+
+        let encoded = format!("group:{}", BASE64_STANDARD.encode(master_key));
+        let Ok(Recipient::Group(key)) = try_user_id_to_recipient(&encoded) else {
+            panic!("expected a group recipient");
+        };
+        assert_eq!(key, master_key);
+
+        let short = format!("group:{}", BASE64_STANDARD.encode([7u8; 16]));
+        let Err(err) = try_user_id_to_recipient(&short) else {
+            panic!("expected a short key to be rejected");
+        };
+        assert!(err.to_string().contains("must be 32 bytes, got 16"));
+        assert!(try_user_id_to_recipient("group:not base64!").is_err());
+    }
+
+    #[tokio::test]
+    async fn it_should_reject_groups_the_bot_is_not_in() {
+        let pool = get_test_pool().await;
+        let store = BitpartStore::open("channel", &pool, OnNewIdentity::Trust)
+            .await
+            .unwrap();
+
+        let master_key = [7u8; 32];
+        let Err(err) = resolve_recipient(Recipient::Group(master_key), &store).await else {
+            panic!("expected an unknown group to be rejected");
+        };
+        assert!(err.to_string().contains(&format!(
+            "bot is not a member of group {}",
+            hex::encode(master_key)
+        )));
+    }
+
+    #[test]
+    fn it_should_decode_reply_text_and_user_id() {
+        let reply: serde_json::Value = serde_json::from_str(
+            r#"{
+                "payload": {
+                    "content_type": "text",
+                    "content": {
+                        "text": "She said \"hi\" \u00e9t\u00e9 \ud83d\udc4b\nbye\\n",
+                        "client": { "user_id": "caf\u00e9 \"quoted\"" }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(reply_get_text(&reply), "She said \"hi\" été 👋\nbye\\n");
+        assert_eq!(reply_get_user_id(&reply, "default"), "café \"quoted\"");
+
+        let reply = json!({"payload": {"content": {"text": 42}}});
+        assert_eq!(reply_get_text(&reply), "42");
+        assert_eq!(reply_get_user_id(&reply, "default"), "default");
+    }
+
+    #[tokio::test]
+    async fn it_should_send_several_images_in_one_message() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let cat = dir.path().join("cat.png");
+        let dog = dir.path().join("dog.jpg");
+        let notes = dir.path().join("notes.txt");
+        for path in [&cat, &dog, &notes] {
+            std::fs::write(path, b"data").unwrap();
         }
+
+        let reply = json!({
+            "payload": {
+                "content_type": "text",
+                "content": {
+                    "text": "Our pets",
+                    "attachments": [
+                        cat.display().to_string(),
+                        { "url": dog.display().to_string() },
+                    ]
+                }
+            }
+        });
+        let locations = reply_get_attachments(&reply);
+        assert_eq!(locations.len(), 2);
+
+        let sources = AttachmentSources {
+            dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let (loaded, errors) = load_attachments(&locations, &sources).await;
+        assert!(errors.is_empty());
+        let types: Vec<&str> = loaded
+            .iter()
+            .map(|(spec, _)| spec.content_type.as_str())
+            .collect();
+        assert_eq!(types, vec!["image/png", "image/jpeg"]);
+
+        let pointers: Vec<AttachmentPointer> = loaded
+            .iter()
+            .map(|(spec, _)| AttachmentPointer {
+                content_type: Some(spec.content_type.clone()),
+                ..Default::default()
+            })
+            .collect();
+        let message = build_data_message(reply_get_text(&reply), pointers.clone(), None, 1);
+        assert_eq!(message.body.as_deref(), Some("Our pets"));
+        assert_eq!(message.attachments, pointers);
+
+        // a text file can't join an album, so it is sent in a message of its
+        // own, while a missing file can't be sent at all
+        let missing = dir.path().join("missing.png").display().to_string();
+        let locations = vec![
+            cat.display().to_string(),
+            notes.display().to_string(),
+            missing,
+        ];
+        let (loaded, errors) = load_attachments(&locations, &sources).await;
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("missing.png"));
+        let sizes: Vec<(usize, bool)> = loaded
+            .iter()
+            .map(|(spec, _)| (spec.length, is_album_media(spec)))
+            .collect();
+        assert_eq!(batch_attachments(&sizes, 32, 100), vec![0..1, 1..2]);
+    }
+
+    #[tokio::test]
+    async fn it_should_only_send_local_attachments_from_the_attachments_dir() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let outside = tempfile::tempdir().expect("tempdir");
+        let cat = dir.path().join("cat.png");
+        let secret = outside.path().join("secret.png");
+        for path in [&cat, &secret] {
+            std::fs::write(path, b"data").unwrap();
+        }
+        let linked = dir.path().join("linked.png");
+        std::os::unix::fs::symlink(&secret, &linked).unwrap();
+        let escaped = dir
+            .path()
+            .join("..")
+            .join(outside.path().file_name().unwrap())
+            .join("secret.png");
+
+        let sources = AttachmentSources {
+            dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let (loaded, errors) = load_attachments(
+            &[
+                format!("file://{}", cat.display()),
+                secret.display().to_string(),
+                linked.display().to_string(),
+                escaped.display().to_string(),
+            ],
+            &sources,
+        )
+        .await;
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(errors.len(), 3);
+        assert!(
+            errors
+                .iter()
+                .all(|err| err.contains("outside the attachments directory"))
+        );
+
+        // without an attachments directory no local file can be sent
+        let (loaded, errors) =
+            load_attachments(&[cat.display().to_string()], &AttachmentSources::default()).await;
+        assert!(loaded.is_empty());
+        assert!(errors[0].contains("not allowed"));
+    }
+
+    #[tokio::test]
+    async fn it_should_only_fetch_attachments_from_allowed_hosts() {
+        let app = axum::Router::new()
+            .route("/cat.png", axum::routing::get(|| async { vec![0u8; 16] }))
+            .route("/big.png", axum::routing::get(|| async { vec![0u8; 4096] }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let cat = url::Url::parse(&format!("http://{addr}/cat.png")).unwrap();
+        let big = url::Url::parse(&format!("http://{addr}/big.png")).unwrap();
+        let mut sources = AttachmentSources {
+            timeout: Duration::from_secs(5),
+            ..Default::default()
+        };
+
+        let err = fetch_attachment(&cat, &sources, 1024).await.unwrap_err();
+        assert!(err.to_string().contains("can't be fetched from 127.0.0.1"));
+
+        sources.hosts = vec!["127.0.0.1".to_owned()];
+        assert_eq!(
+            fetch_attachment(&cat, &sources, 1024).await.unwrap().len(),
+            16
+        );
+        let err = fetch_attachment(&big, &sources, 1024).await.unwrap_err();
+        assert!(err.to_string().contains("over the limit of 1024 bytes"));
+    }
+
+    #[tokio::test]
+    async fn it_should_write_every_attachment_of_a_message() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let pointers: Vec<AttachmentPointer> = ["one.png", "two.png", "three.png"]
+            .iter()
+            .map(|name| AttachmentPointer {
+                content_type: Some("image/png".to_owned()),
+                file_name: Some(name.to_string()),
+                size: Some(4),
+                ..Default::default()
+            })
+            .chain([
+                AttachmentPointer {
+                    content_type: Some("image/png".to_owned()),
+                    size: Some(4),
+                    ..Default::default()
+                },
+                AttachmentPointer {
+                    content_type: Some("image/png".to_owned()),
+                    size: Some(4),
+                    ..Default::default()
+                },
+            ])
+            .collect();
+
+        let saved = download_attachments(&pointers, dir.path(), 2, |pointer| async move {
+            Ok(pointer.file_name.clone().unwrap_or_default().into_bytes())
+        })
+        .await;
+
+        assert_eq!(saved.len(), 5);
+        let written = std::fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(written, 5);
+        let one = saved
+            .iter()
+            .find(|a| a.path.ends_with("bitpart-one.png"))
+            .unwrap();
+        assert_eq!(std::fs::read(&one.path).unwrap(), b"one.png");
+    }
+
+    #[test]
+    fn it_should_send_notes_to_self_to_the_own_thread() {
+        let self_aci = uuid::Uuid::parse_str("a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d").unwrap();
+        let own_thread = Thread::Contact(ServiceId::Aci(self_aci.into()));
+
+        for user_id in [NOTE_TO_SELF, "a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d"] {
+            let Some(Recipient::Contact(service_id)) = try_note_to_self(user_id, self_aci) else {
+                panic!("expected {user_id} to address the note to self thread");
+            };
+            assert_eq!(Thread::Contact(service_id), own_thread);
+        }
+
+        assert!(try_note_to_self("0e1f2a3b-4c5d-4a7b-8c9d-a1b2c3d4e5f6", self_aci).is_none());
     }
 }
@@ -0,0 +1,640 @@
+// Bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Persistent delivery queue for `callback_url` messages. Messages are written
+//! to the `callback_queue` table and delivered by a background worker, so
+//! pending callbacks survive a restart. Those that still fail after
+//! `max_attempts` are kept as dead letters until they are replayed.
+//!
+//! Callbacks to one url are delivered one at a time, in the order they were
+//! queued, so a conversation's messages arrive in interaction order. Callbacks
+//! to different urls are delivered concurrently, so one slow endpoint doesn't
+//! hold up the others.
+//!
+//! When the bot or the server has a secret, each callback is signed so that
+//! the receiver can check it came from Bitpart: `X-Bitpart-Timestamp` holds
+//! the time it was sent, in seconds since the epoch, and `X-Bitpart-Signature`
+//! holds `sha256=` followed by the hex-encoded HMAC-SHA256, keyed with the
+//! secret, of the timestamp, a `.` and the exact request body.
+
+use bitpart_common::db::Pool;
+use bitpart_common::error::Result;
+use chrono::{SecondsFormat, Utc};
+use csml_interpreter::data::{Client, CsmlBot};
+use futures::future::join_all;
+use hmac::{Hmac, Mac};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
+
+use crate::db;
+
+/// Maximum number of pending callbacks before new ones are dropped
+pub const MAX_QUEUE_DEPTH: u64 = 10_000;
+/// Default delivery attempts before an entry is moved to the dead-letter
+/// state, see `max_attempts`
+pub const DEFAULT_MAX_ATTEMPTS: i32 = 10;
+/// Default seconds a delivery attempt may take, see `timeout`
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+/// Number of entries delivered per drain
+const BATCH_SIZE: u64 = 100;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const BACKOFF_BASE_SECS: i64 = 2;
+const BACKOFF_MAX_SECS: i64 = 3600;
+
+fn backoff_secs(attempts: i32) -> i64 {
+    BACKOFF_BASE_SECS
+        .saturating_pow(attempts.max(1) as u32)
+        .min(BACKOFF_MAX_SECS)
+}
+
+static MAX_ATTEMPTS: OnceLock<i32> = OnceLock::new();
+static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/**
+ * Set the delivery attempts before an entry is moved to the dead-letter
+ * state from the server configuration. Only the first call has any effect,
+ * and one that isn't positive has none.
+ */
+pub fn set_max_attempts(attempts: i32) {
+    if attempts > 0 {
+        let _ = MAX_ATTEMPTS.set(attempts);
+    }
+}
+
+/**
+ * Set how long a delivery attempt may take from the server configuration.
+ * Only the first call has any effect.
+ */
+pub fn set_timeout(timeout: Duration) {
+    let _ = TIMEOUT.set(timeout);
+}
+
+/**
+ * Delivery attempts before an entry is moved to the dead-letter state, see
+ * `set_max_attempts`.
+ */
+pub fn max_attempts() -> i32 {
+    MAX_ATTEMPTS.get().copied().unwrap_or(DEFAULT_MAX_ATTEMPTS)
+}
+
+/**
+ * How long a delivery attempt may take before it counts as failed, see
+ * `set_timeout`.
+ */
+pub fn timeout() -> Duration {
+    TIMEOUT
+        .get()
+        .copied()
+        .unwrap_or(Duration::from_secs(DEFAULT_TIMEOUT_SECS))
+}
+
+/// Header holding the time a callback was signed at, in seconds since the epoch
+pub const TIMESTAMP_HEADER: &str = "X-Bitpart-Timestamp";
+/// Header holding a callback's signature, see `sign`
+pub const SIGNATURE_HEADER: &str = "X-Bitpart-Signature";
+/// Bot `env` key holding the secret its callbacks are signed with, instead of
+/// the server's. It is taken out of the `env` when the bot is stored, see
+/// `db::bot::create`.
+pub const CALLBACK_SECRET: &str = "callback_secret";
+
+static DEFAULT_SECRET: OnceLock<String> = OnceLock::new();
+
+/**
+ * Set the secret callbacks are signed with for bots that don't have their
+ * own. Only the first call has any effect.
+ */
+pub fn set_default_secret(secret: String) {
+    let _ = DEFAULT_SECRET.set(secret);
+}
+
+/**
+ * The signature of a callback body sent at `timestamp`: `sha256=` and the
+ * hex-encoded HMAC-SHA256 of `{timestamp}.{body}`. The timestamp is signed
+ * too, so that a receiver rejecting old timestamps can't be sent a replayed
+ * callback.
+ */
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// The bot's own secret, or else the server's
+async fn secret_for(bot_id: Option<&str>, pool: &Pool) -> Option<String> {
+    if let Some(bot_id) = bot_id {
+        match db::bot::get_callback_secret(bot_id, pool).await {
+            Ok(Some(secret)) => return Some(secret),
+            Ok(None) => {}
+            Err(err) => warn!(bot_id, "Failed to look up callback secret: {}", err),
+        }
+    }
+    DEFAULT_SECRET.get().cloned()
+}
+
+async fn post(
+    url: &str,
+    payload: &Value,
+    secret: Option<&str>,
+    timeout: Duration,
+) -> std::result::Result<(), String> {
+    let body = serde_json::to_vec(payload).map_err(|e| e.to_string())?;
+    let mut request = crate::http::client()
+        .post(url)
+        .timeout(timeout)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+    if let Some(secret) = secret {
+        let timestamp = Utc::now().timestamp();
+        request = request
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(SIGNATURE_HEADER, sign(secret, timestamp, &body));
+    }
+    request
+        .body(body)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+async fn report_depth(pool: &Pool) -> Result<u64> {
+    let depth = db::callback::count_by_status(db::callback::STATUS_PENDING, pool).await?;
+    debug!(
+        histogram.bitpart_callback_queue_depth = depth,
+        "callback queue depth"
+    );
+    Ok(depth)
+}
+
+/**
+ * Queue a message from `bot_id` for delivery to `url`. When the queue is full
+ * the message is dropped and logged rather than blocking the conversation.
+ */
+pub async fn enqueue(url: &str, msg: &Value, bot_id: &str, pool: &Pool) -> Result<()> {
+    let depth = report_depth(pool).await?;
+    if depth >= MAX_QUEUE_DEPTH {
+        error!(url, depth, "callback queue is full, dropping message");
+        return Ok(());
+    }
+    db::callback::create(url, msg, bot_id, pool).await?;
+    Ok(())
+}
+
+/// Bot `env` key holding the url notified when a conversation starts
+pub const START_WEBHOOK: &str = "conversation_start_webhook";
+/// Bot `env` key holding the url notified when a conversation ends
+pub const END_WEBHOOK: &str = "conversation_end_webhook";
+/// Bot `env` key holding the url notified when one of its channels stops receiving
+pub const CHANNEL_DOWN_WEBHOOK: &str = "channel_down_webhook";
+/// Bot `env` key holding the url notified when a conversation is handed off
+/// to a human operator
+pub const HANDOFF_WEBHOOK: &str = "handoff_webhook";
+
+/**
+ * Look up one of the conversation lifecycle webhooks in the bot's `env`.
+ */
+pub fn lifecycle_webhook(bot: &CsmlBot, key: &str) -> Option<String> {
+    bot.env
+        .as_ref()
+        .and_then(|env| env.get(key))
+        .and_then(|url| url.as_str())
+        .map(|url| url.to_owned())
+}
+
+async fn notify_lifecycle(
+    url: &str,
+    event: &str,
+    conversation_id: &str,
+    client: &Client,
+    flow: &str,
+    reason: &str,
+    pool: &Pool,
+) -> Result<()> {
+    let payload = json!({
+        "event": event,
+        "conversation_id": conversation_id,
+        "client": {
+            "bot_id": client.bot_id,
+            "user_id": client.user_id,
+            "channel_id": client.channel_id,
+        },
+        "flow": flow,
+        "reason": reason,
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+    });
+    enqueue(url, &payload, &client.bot_id, pool).await
+}
+
+/**
+ * Queue the bot's conversation start webhook, if it has one.
+ */
+pub async fn conversation_started(
+    bot: &CsmlBot,
+    conversation_id: &str,
+    client: &Client,
+    flow: &str,
+    reason: &str,
+    pool: &Pool,
+) -> Result<()> {
+    match lifecycle_webhook(bot, START_WEBHOOK) {
+        Some(url) => {
+            notify_lifecycle(
+                &url,
+                "conversation_start",
+                conversation_id,
+                client,
+                flow,
+                reason,
+                pool,
+            )
+            .await
+        }
+        None => Ok(()),
+    }
+}
+
+/**
+ * Queue a conversation end webhook to `url`, if set.
+ */
+pub async fn conversation_ended(
+    url: Option<&str>,
+    conversation_id: &str,
+    client: &Client,
+    flow: &str,
+    reason: &str,
+    pool: &Pool,
+) -> Result<()> {
+    match url {
+        Some(url) => {
+            notify_lifecycle(
+                url,
+                "conversation_end",
+                conversation_id,
+                client,
+                flow,
+                reason,
+                pool,
+            )
+            .await
+        }
+        None => Ok(()),
+    }
+}
+
+/**
+ * Queue the bot's handoff webhook, if it has one, so that an operator can pick
+ * up the conversation.
+ */
+pub async fn conversation_handed_off(
+    bot: &CsmlBot,
+    conversation_id: &str,
+    client: &Client,
+    flow: &str,
+    reason: &str,
+    pool: &Pool,
+) -> Result<()> {
+    match lifecycle_webhook(bot, HANDOFF_WEBHOOK) {
+        Some(url) => {
+            notify_lifecycle(
+                &url,
+                "conversation_handoff",
+                conversation_id,
+                client,
+                flow,
+                reason,
+                pool,
+            )
+            .await
+        }
+        None => Ok(()),
+    }
+}
+
+/**
+ * Queue the bot's channel down webhook, if it has one.
+ */
+pub async fn channel_down(
+    bot: &CsmlBot,
+    channel_id: &str,
+    last_received_at: Option<&str>,
+    reason: &str,
+    pool: &Pool,
+) -> Result<()> {
+    let Some(url) = lifecycle_webhook(bot, CHANNEL_DOWN_WEBHOOK) else {
+        return Ok(());
+    };
+    let payload = json!({
+        "event": "channel_down",
+        "bot_id": bot.id,
+        "channel_id": channel_id,
+        "last_received_at": last_received_at,
+        "reason": reason,
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+    });
+    enqueue(&url, &payload, &bot.id, pool).await
+}
+
+/**
+ * Deliver due entries until none are left, or every endpoint with one due has
+ * failed. Each round attempts the oldest due entry of every url at once.
+ * Returns the number delivered.
+ */
+pub async fn drain(pool: &Pool) -> Result<usize> {
+    let mut delivered = 0;
+    let max_attempts = max_attempts();
+    let timeout = timeout();
+    let mut secrets: HashMap<Option<String>, Option<String>> = HashMap::new();
+    loop {
+        let due = db::callback::get_due(Some(BATCH_SIZE), pool).await?;
+        if due.is_empty() {
+            break;
+        }
+        let payloads = due
+            .iter()
+            .map(|entry| serde_json::from_str(&entry.payload))
+            .collect::<std::result::Result<Vec<Value>, _>>()?;
+        for entry in &due {
+            if !secrets.contains_key(&entry.bot_id) {
+                let secret = secret_for(entry.bot_id.as_deref(), pool).await;
+                secrets.insert(entry.bot_id.clone(), secret);
+            }
+        }
+        let results = join_all(due.iter().zip(&payloads).map(|(entry, payload)| {
+            let secret = secrets.get(&entry.bot_id).cloned().flatten();
+            async move { post(&entry.url, payload, secret.as_deref(), timeout).await }
+        }))
+        .await;
+
+        let mut progressed = false;
+        for (entry, res) in due.iter().zip(results) {
+            match res {
+                Ok(()) => {
+                    db::callback::delete(&entry.id, pool).await?;
+                    delivered += 1;
+                    progressed = true;
+                }
+                Err(err) => {
+                    let attempts = entry.attempts + 1;
+                    if attempts >= max_attempts {
+                        error!("callback to {} failed, giving up: {}", entry.url, err);
+                        db::callback::set_failed(&entry.id, &err, None, pool).await?;
+                    } else {
+                        warn!("callback to {} failed, retrying: {}", entry.url, err);
+                        let retry_in = backoff_secs(attempts);
+                        db::callback::set_failed(&entry.id, &err, Some(retry_in), pool).await?;
+                    }
+                }
+            }
+        }
+        if !progressed {
+            break;
+        }
+    }
+    report_depth(pool).await?;
+    Ok(delivered)
+}
+
+/**
+ * Queue the dead letters for delivery again, from the first attempt, e.g.
+ * once their endpoint is back. Only those to `url` are replayed, if given.
+ * Returns the number queued.
+ */
+pub async fn replay(url: Option<&str>, pool: &Pool) -> Result<usize> {
+    let replayed = db::callback::requeue_dead(url, pool).await?;
+    report_depth(pool).await?;
+    Ok(replayed)
+}
+
+/**
+ * Drain the queue until `token` is cancelled.
+ */
+pub async fn run(pool: Pool, token: CancellationToken) {
+    loop {
+        if let Err(err) = drain(&pool).await {
+            error!("callback queue: {}", err);
+        }
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{get_test_pool, open_test_pool, start_test_receiver};
+    use axum::{Router, body::Bytes, extract::State, http::HeaderMap};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn it_should_resume_pending_callbacks_after_restart() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("test.sqlite");
+        let (url, received) = start_test_receiver().await;
+
+        let pool = open_test_pool(&path).await;
+        for i in 0..3 {
+            enqueue(&url, &json!({"n": i}), "bot_id", &pool)
+                .await
+                .unwrap();
+        }
+        assert_eq!(report_depth(&pool).await.unwrap(), 3);
+        drop(pool);
+
+        // Simulated restart: reopen the same database and drain it
+        let pool = open_test_pool(&path).await;
+        assert_eq!(report_depth(&pool).await.unwrap(), 3);
+        assert_eq!(drain(&pool).await.unwrap(), 3);
+        assert_eq!(report_depth(&pool).await.unwrap(), 0);
+
+        let received = received.lock().unwrap();
+        assert_eq!(
+            *received,
+            vec![json!({"n": 0}), json!({"n": 1}), json!({"n": 2})]
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_deliver_every_callback_in_order() {
+        let pool = get_test_pool().await;
+        let (first_url, first) = start_test_receiver().await;
+        let (second_url, second) = start_test_receiver().await;
+
+        for i in 0..20 {
+            enqueue(&first_url, &json!({"n": i}), "bot_id", &pool)
+                .await
+                .unwrap();
+            enqueue(&second_url, &json!({"n": i}), "bot_id", &pool)
+                .await
+                .unwrap();
+        }
+        assert_eq!(drain(&pool).await.unwrap(), 40);
+        assert_eq!(report_depth(&pool).await.unwrap(), 0);
+
+        let expected: Vec<Value> = (0..20).map(|i| json!({"n": i})).collect();
+        assert_eq!(*first.lock().unwrap(), expected);
+        assert_eq!(*second.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn it_should_stop_draining_when_no_endpoint_answers() {
+        let pool = get_test_pool().await;
+        // Nothing listens on port 9 of localhost
+        for i in 0..3 {
+            enqueue("http://127.0.0.1:9/", &json!({"n": i}), "bot_id", &pool)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(drain(&pool).await.unwrap(), 0);
+        assert_eq!(report_depth(&pool).await.unwrap(), 3);
+        // the oldest entry is backed off, and the rest wait behind it
+        assert!(db::callback::get_due(None, &pool).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_should_dead_letter_after_max_attempts() {
+        let pool = get_test_pool().await;
+        // Nothing listens on port 9 of localhost
+        let id = db::callback::create("http://127.0.0.1:9/", &json!({}), "bot_id", &pool)
+            .await
+            .unwrap();
+
+        for _ in 1..max_attempts() {
+            db::callback::set_failed(&id, "refused", Some(0), &pool)
+                .await
+                .unwrap();
+        }
+        assert_eq!(drain(&pool).await.unwrap(), 0);
+
+        assert_eq!(report_depth(&pool).await.unwrap(), 0);
+        assert_eq!(
+            db::callback::count_by_status(db::callback::STATUS_DEAD, &pool)
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_replay_dead_callbacks() {
+        let pool = get_test_pool().await;
+        let (url, received) = start_test_receiver().await;
+        let dead = db::callback::create(&url, &json!({"n": 0}), "bot_id", &pool)
+            .await
+            .unwrap();
+        db::callback::set_failed(&dead, "refused", None, &pool)
+            .await
+            .unwrap();
+        let elsewhere = db::callback::create("http://127.0.0.1:9/", &json!({}), "bot_id", &pool)
+            .await
+            .unwrap();
+        db::callback::set_failed(&elsewhere, "refused", None, &pool)
+            .await
+            .unwrap();
+
+        let failed = db::callback::get_dead(None, None, &pool).await.unwrap();
+        assert_eq!(failed.len(), 2);
+        assert_eq!(failed[0].last_error.as_deref(), Some("refused"));
+
+        assert_eq!(replay(Some(&url), &pool).await.unwrap(), 1);
+        assert_eq!(drain(&pool).await.unwrap(), 1);
+        assert_eq!(*received.lock().unwrap(), vec![json!({"n": 0})]);
+        let failed = db::callback::get_dead(None, None, &pool).await.unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].id, elsewhere);
+    }
+
+    #[test]
+    fn it_should_sign_the_timestamp_and_body() {
+        assert_eq!(
+            sign("whsec_test", 1_700_000_000, br#"{"n":1}"#),
+            "sha256=c9510dc724d87352d318a3fdc598547c646cffdcb130f43f814037318725be9e"
+        );
+        assert_ne!(
+            sign("whsec_test", 1_700_000_001, br#"{"n":1}"#),
+            sign("whsec_test", 1_700_000_000, br#"{"n":1}"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn it_should_sign_callbacks_with_the_bot_secret() {
+        let pool = get_test_pool().await;
+        let bot: CsmlBot = serde_json::from_value(json!({
+            "id": "signed_bot",
+            "name": "signed",
+            "flows": [],
+            "default_flow": "Default",
+            "env": { "callback_secret": "whsec_test" },
+        }))
+        .unwrap();
+        let created = db::bot::create(bot, &pool).await.unwrap();
+        // the secret isn't kept with the bot, where flows and ReadBot would see it
+        assert_eq!(created.bot.env, Some(json!({})));
+        let stored = db::bot::get_latest_by_bot_id("signed_bot", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.bot.env, Some(json!({})));
+
+        let received = Arc::new(Mutex::new(vec![]));
+        let app = Router::new()
+            .route(
+                "/callback",
+                axum::routing::post(
+                    |State(received): State<Arc<Mutex<Vec<(HeaderMap, Bytes)>>>>,
+                     headers: HeaderMap,
+                     body: Bytes| async move {
+                        received.lock().unwrap().push((headers, body));
+                    },
+                ),
+            )
+            .with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let url = format!("http://{}/callback", addr);
+
+        enqueue(&url, &json!({"n": 1}), "signed_bot", &pool)
+            .await
+            .unwrap();
+        assert_eq!(drain(&pool).await.unwrap(), 1);
+
+        let received = received.lock().unwrap();
+        let (headers, body) = &received[0];
+        let timestamp: i64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        assert!((Utc::now().timestamp() - timestamp).abs() < 60);
+        assert_eq!(
+            headers[SIGNATURE_HEADER].to_str().unwrap(),
+            sign("whsec_test", timestamp, body)
+        );
+        assert_eq!(
+            serde_json::from_slice::<Value>(body).unwrap(),
+            json!({"n": 1})
+        );
+    }
+}
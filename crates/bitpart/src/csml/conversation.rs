@@ -26,16 +26,19 @@ use bitpart_common::{
 };
 use chrono::Utc;
 use csml_interpreter::data::{
-    ApiInfo, Client, Context, CsmlBot, CsmlFlow, CsmlResult, Event, Hold, IndexInfo, Message,
-    PreviousBot,
+    ApiInfo, Client, Context, CsmlBot, CsmlFlow, CsmlResult, Event, Hold, IndexInfo, Literal,
+    Message, PreviousBot,
     ast::Flow,
     context::{ContextStepInfo, get_hashmap_from_json, get_hashmap_from_mem},
 };
 use csml_interpreter::{load_components, search_for_modules, validate_bot};
 use serde_json::{Value, json};
 use std::collections::HashMap;
+use std::sync::OnceLock;
+use tracing::warn;
 
-use super::data::{ConversationData, SwitchBot, search_bot};
+use super::callback;
+use super::data::{ConversationData, SwitchBot, persists_at, search_bot};
 use super::interpret;
 use super::utils;
 use crate::db;
@@ -48,9 +51,12 @@ async fn create_new_conversation<'a>(
     ttl: Option<chrono::Duration>,
     pool: &Pool,
 ) -> Result<String> {
-    let (flow, step) = match flow_found {
-        Some((flow, step)) => (flow, step),
-        None => (utils::get_default_flow(bot)?, "start".to_owned()),
+    let (flow, step, reason) = match flow_found {
+        Some((flow, step)) => (flow, step, "flow_trigger"),
+        None => {
+            let flow = utils::get_default_flow(bot)?;
+            (flow, utils::get_entry_step(bot, flow), "new")
+        }
     };
 
     let conversation_id = db::conversation::create(
@@ -61,6 +67,7 @@ async fn create_new_conversation<'a>(
         pool,
     )
     .await?;
+    callback::conversation_started(bot, &conversation_id, client, &flow.name, reason, pool).await?;
 
     context.step = ContextStepInfo::UnknownFlow(step);
     context.flow = flow.name.to_owned();
@@ -90,6 +97,15 @@ async fn get_or_create_conversation<'a>(
                             // if flow id exist in db but not in bot close conversation
                             db::conversation::set_status_by_id(&conversation.id, "CLOSED", pool)
                                 .await?;
+                            callback::conversation_ended(
+                                callback::lifecycle_webhook(bot, callback::END_WEBHOOK).as_deref(),
+                                &conversation.id,
+                                client,
+                                &conversation.flow_id,
+                                "flow_not_found",
+                                pool,
+                            )
+                            .await?;
                             // start new conversation at default flow
                             return create_new_conversation(
                                 context, bot, flow_found, client, ttl, pool,
@@ -116,17 +132,46 @@ async fn get_previous_bot(client: &Client, pool: &Pool) -> Option<PreviousBot> {
     }
 }
 
+/// Server-wide `apps_endpoint`, from the server configuration
+static DEFAULT_APPS_ENDPOINT: OnceLock<String> = OnceLock::new();
+
+/**
+ * Set the `apps_endpoint` used by requests and bots that don't name their
+ * own. Only the first call has any effect.
+ */
+pub fn set_default_apps_endpoint(apps_endpoint: String) {
+    let _ = DEFAULT_APPS_ENDPOINT.set(apps_endpoint);
+}
+
+/**
+ * An `apps_endpoint` given in the request takes precedence over the bot's,
+ * which takes precedence over the server default.
+ */
+fn resolve_apps_endpoint(
+    request: Option<&String>,
+    bot: Option<&String>,
+    default: Option<&String>,
+) -> Option<String> {
+    request.or(bot).or(default).cloned()
+}
+
 async fn init_context(
     flow: String,
     client: Client,
-    apps_endpoint: &Option<String>,
+    request_apps_endpoint: Option<&String>,
+    bot_apps_endpoint: Option<&String>,
     pool: &Pool,
 ) -> Context {
     let previous_bot = get_previous_bot(&client, pool).await;
 
-    let api_info = apps_endpoint.as_ref().map(|value| ApiInfo {
+    let api_info = resolve_apps_endpoint(
+        request_apps_endpoint,
+        bot_apps_endpoint,
+        DEFAULT_APPS_ENDPOINT.get(),
+    )
+    .map(|apps_endpoint| ApiInfo {
         client,
-        apps_endpoint: value.to_owned(),
+        apps_endpoint,
     });
 
     Context {
@@ -140,10 +185,30 @@ async fn init_context(
     }
 }
 
+/**
+ * The client's memories, as the variables of a context in `flow`. Of several
+ * memories with the same key, the most recent is kept.
+ */
+async fn current_memories(
+    client: &Client,
+    flow: &str,
+    pool: &Pool,
+) -> Result<HashMap<String, Literal>> {
+    let memories = db::memory::get_by_client(client, None, None, pool).await?;
+    let mut map = serde_json::Map::new();
+    for mem in memories {
+        if !map.contains_key(&mem.key) {
+            map.insert(mem.key, serde_json::json!(mem.value));
+        }
+    }
+    Ok(get_hashmap_from_mem(&serde_json::json!(map), flow))
+}
+
 async fn init_conversation_data<'a>(
     default_flow: String,
     event: &Event,
     request: &'a SerializedEvent,
+    apps_endpoint: Option<&String>,
     bot: &'a CsmlBot,
     pool: &Pool,
 ) -> Result<ConversationData> {
@@ -153,12 +218,13 @@ async fn init_conversation_data<'a>(
     let mut context = init_context(
         default_flow,
         request.client.clone(),
-        &bot.apps_endpoint,
+        apps_endpoint,
+        bot.apps_endpoint.as_ref(),
         pool,
     )
     .await;
     let ttl = utils::get_ttl_duration_value(Some(event));
-    // let low_data = utils::get_low_data_mode_value(event); // We're always in low_data mode
+    let low_data = utils::get_low_data_mode_value(event);
 
     // Do we have a flow matching the request? If the user is requesting a flow in one way
     // or another, this takes precedence over any previously open conversation
@@ -171,15 +237,7 @@ async fn init_conversation_data<'a>(
             .await?;
 
     context.metadata = get_hashmap_from_json(&request.metadata, &context.flow);
-    let memories = db::memory::get_by_client(&request.client, None, None, pool).await?;
-    let mut map = serde_json::Map::new();
-    for mem in memories {
-        if !map.contains_key(&mem.key) {
-            map.insert(mem.key, serde_json::json!(mem.value));
-        }
-    }
-
-    context.current = get_hashmap_from_mem(&serde_json::json!(map), &context.flow);
+    context.current = current_memories(&request.client, &context.flow, pool).await?;
 
     let data = ConversationData {
         conversation_id,
@@ -190,7 +248,14 @@ async fn init_conversation_data<'a>(
         client: request.client.clone(),
         messages: vec![],
         ttl,
-        low_data: true,
+        low_data,
+        secure: event.secure,
+        end_webhook: callback::lifecycle_webhook(bot, callback::END_WEBHOOK),
+        no_persist: utils::get_no_persist(bot),
+        bot_name: bot.name.clone(),
+        scrub_patterns: utils::get_scrub_patterns(bot),
+        handed_off: false,
+        dry_run: false,
     };
 
     let flow = data.context.flow.to_owned();
@@ -212,7 +277,7 @@ async fn init_conversation_data<'a>(
 /**
  * Initialize the bot
  */
-fn init_bot(bot: &mut CsmlBot) -> Result<()> {
+pub fn init_bot(bot: &mut CsmlBot) -> Result<()> {
     // load native components into the bot
     bot.native_components = match load_components() {
         Ok(components) => Some(components),
@@ -319,7 +384,7 @@ async fn switch_bot(
             // save message
             data.messages.push(message.clone());
             // send message
-            utils::send_msg_to_callback_url(data, vec![message], 0, false);
+            utils::send_msg_to_callback_url(data, vec![message], 0, false, pool).await?;
 
             // setting default step && flow
             data.context.step = ContextStepInfo::Normal("start".to_owned());
@@ -332,13 +397,16 @@ async fn switch_bot(
         }
     };
 
-    // update event to flow trigger
-    event.content_type = "flow_trigger".to_owned();
-    event.content = serde_json::json!({
-            "flow_id": flow.id,
-            "step_id": step
-        }
-    );
+    // update event to flow trigger, unless the bot asked for the user's event
+    // to be delivered to the new bot as it is
+    if !next_bot.forward_event {
+        event.content_type = "flow_trigger".to_owned();
+        event.content = serde_json::json!({
+                "flow_id": flow.id,
+                "step_id": step
+            }
+        );
+    }
 
     // create new conversation for the new client
     data.conversation_id = db::conversation::create(
@@ -349,18 +417,23 @@ async fn switch_bot(
         pool,
     )
     .await?;
-
-    let memories = db::memory::get_by_client(&data.client, None, None, pool).await?;
-    let mut map = serde_json::Map::new();
-    for mem in memories {
-        if !map.contains_key(&mem.key) {
-            map.insert(mem.key, serde_json::json!(mem.value));
-        }
-    }
+    data.end_webhook = callback::lifecycle_webhook(bot, callback::END_WEBHOOK);
+    data.no_persist = utils::get_no_persist(bot);
+    data.bot_name = bot.name.clone();
+    data.scrub_patterns = utils::get_scrub_patterns(bot);
+    callback::conversation_started(
+        bot,
+        &data.conversation_id,
+        &data.client,
+        &flow.name,
+        "switch_bot",
+        pool,
+    )
+    .await?;
 
     // and get memories of the new bot from db,
     // clearing the permanent memories from scope of the previous bot
-    data.context.current = get_hashmap_from_mem(&serde_json::json!(map), &data.context.flow);
+    data.context.current = current_memories(&data.client, &data.context.flow, pool).await?;
 
     Ok(())
 }
@@ -417,13 +490,19 @@ async fn check_switch_bot(
 
 async fn check_for_hold(
     data: &mut ConversationData,
-    bot: &CsmlBot,
+    bot: &mut CsmlBot,
     event: &mut Event,
     pool: &Pool,
 ) -> Result<()> {
     if let Ok(hold) = db::state::get(&data.client, "hold", "position", pool).await {
         match hold.get("hash") {
             Some(hash_value) => {
+                // the hold hash is computed from the bot ast, so rebuild it if this
+                // bot version was never fully initialized rather than failing
+                if bot.bot_ast.is_none() {
+                    warn!(bot_id = %bot.id, "bot ast missing while resuming hold, rebuilding");
+                    init_bot(bot)?;
+                }
                 let flow_hash = utils::get_current_step_hash(&data.context, bot)?;
                 // cleanup the current hold and restart flow
                 if flow_hash != *hash_value {
@@ -437,7 +516,9 @@ async fn check_for_hold(
         let index = match serde_json::from_value::<IndexInfo>(hold["index"].clone()) {
             Ok(index) => index,
             Err(_) => {
-                db::state::delete(&data.client, "hold", "position", pool).await?;
+                if !data.dry_run {
+                    db::state::delete(&data.client, "hold", "position", pool).await?;
+                }
                 return Ok(());
             }
         };
@@ -458,15 +539,33 @@ async fn check_for_hold(
             secure: secure_hold,
         });
 
-        db::state::delete(&data.client, "hold", "position", pool).await?;
+        if !data.dry_run {
+            db::state::delete(&data.client, "hold", "position", pool).await?;
+        }
     };
     Ok(())
 }
 
+/// State type under which the responses to requests with an idempotency key
+/// are kept, keyed by the idempotency key
+const IDEMPOTENCY: &str = "idempotency";
+
+/// How long a response is kept for requests repeating its idempotency key
+const IDEMPOTENCY_TTL_HOURS: i64 = 24;
+
 pub async fn start(
     body: &Request,
     pool: &Pool,
 ) -> Result<serde_json::Map<String, serde_json::Value>> {
+    // a retried request gets the response to the first one, without running
+    // the bot again
+    if let Some(key) = &body.idempotency_key
+        && let Some(Value::Object(response)) =
+            db::state::get_unexpired(&body.event.client, IDEMPOTENCY, key, pool).await?
+    {
+        return Ok(response);
+    }
+
     let mut request = body.event.to_owned();
 
     let mut bot_opt: BotOpt = match body.try_into() {
@@ -483,18 +582,57 @@ pub async fn start(
     let mut formatted_event = Event::try_from(&request)?;
 
     let mut bot = search_bot(&bot_opt, pool).await?;
+
+    // a handed off conversation belongs to the operator: keep what the user
+    // sends for them, unless it isn't persisted, but don't run the bot
+    if let Some(conversation) =
+        db::conversation::get_latest_by_client(&request.client, pool).await?
+        && conversation.status == interpret::HANDOFF
+    {
+        let flow = match utils::get_flow_by_id(&conversation.flow_id, &bot.flows) {
+            Ok(flow) => flow.name.as_str(),
+            Err(_) => conversation.flow_id.as_str(),
+        };
+        if !persists_at(
+            utils::get_low_data_mode_value(&formatted_event),
+            &utils::get_no_persist(&bot),
+            flow,
+            &conversation.step_id,
+        ) {
+            return Ok(serde_json::Map::new());
+        }
+        let payload = if request.is_secure()
+            || utils::is_secure_conversation(&request.client, &conversation.id, pool).await
+        {
+            utils::secure_placeholder()
+        } else {
+            utils::scrub(&request.payload, &utils::get_scrub_patterns(&bot))
+        };
+        db::message::create_in_conversation(&conversation.id, "RECEIVE", &payload, pool).await?;
+        return Ok(serde_json::Map::new());
+    }
+
     init_bot(&mut bot)?;
 
     let mut data = init_conversation_data(
         utils::get_default_flow(&bot)?.name.to_owned(),
         &formatted_event,
         &request,
+        body.apps_endpoint.as_ref(),
         &bot,
         pool,
     )
     .await?;
 
-    check_for_hold(&mut data, &bot, &mut formatted_event, pool).await?;
+    check_for_hold(&mut data, &mut bot, &mut formatted_event, pool).await?;
+    // a secure event from the channel flags its whole conversation secure
+    if request.is_secure() {
+        utils::set_secure_conversation(&data, pool).await?;
+    } else if utils::is_secure_conversation(&data.client, &data.conversation_id, pool).await {
+        formatted_event.secure = true;
+    }
+    // a secure hold marks the event as secure, which covers the rest of this turn
+    data.secure = formatted_event.secure;
 
     /////////// block user event if delay variable si on and delay_time is bigger than current time
     if let Some(delay) = bot.no_interruption_delay {
@@ -525,23 +663,23 @@ pub async fn start(
     //////////////////////////////////////
 
     // save event in db as message RECEIVE
-    match (data.low_data, formatted_event.secure) {
-        (false, true) => {
-            let msgs = vec![serde_json::json!({"content_type": "secure"})];
+    match (data.persists(), formatted_event.secure) {
+        (true, true) => {
+            let msgs = vec![utils::secure_placeholder()];
 
             db::message::create(&data, &msgs, 0, "RECEIVE", None, pool).await?;
         }
-        (false, false) => {
-            let msgs = vec![request.payload.to_owned()];
+        (true, false) => {
+            let msgs = vec![utils::scrub(&request.payload, &data.scrub_patterns)];
 
             db::message::create(&data, &msgs, 0, "RECEIVE", None, pool).await?;
         }
-        (true, _) => {}
+        (false, _) => {}
     }
 
     let result = interpret::step(&mut data, formatted_event.to_owned(), &bot, pool).await;
 
-    check_switch_bot(
+    let response = check_switch_bot(
         result,
         &mut data,
         &mut bot,
@@ -549,5 +687,182 @@ pub async fn start(
         &mut formatted_event,
         pool,
     )
-    .await
+    .await?;
+
+    // secure responses are never written to the database, so a retried
+    // secure request is handled again
+    if let Some(key) = &body.idempotency_key
+        && !data.secure
+    {
+        db::state::set(
+            &body.event.client,
+            IDEMPOTENCY,
+            key,
+            &Value::Object(response.clone()),
+            Some(Utc::now().naive_utc() + chrono::Duration::hours(IDEMPOTENCY_TTL_HOURS)),
+            pool,
+        )
+        .await?;
+    }
+
+    Ok(response)
+}
+
+/**
+ * Run the bot for a request without writing anything to the database. The
+ * turn carries on from the user's open conversation, with their memories,
+ * and whatever it changes is thrown away: no conversation, message, memory or
+ * state is written, and neither `callback_url` nor the bot's webhooks are
+ * called. A `switch_bot` ends the turn rather than running the other bot.
+ */
+pub async fn dry_run(
+    body: &Request,
+    pool: &Pool,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let mut request = body.event.to_owned();
+    let bot_opt: BotOpt = match body.try_into() {
+        Ok(bot_opt) => bot_opt,
+        _ => return Err(BitpartErrorKind::Interpreter("Bad Request".to_owned()).into()),
+    };
+    request.metadata = match request.metadata {
+        Value::Null => json!({}),
+        val => val,
+    };
+    let mut event = Event::try_from(&request)?;
+
+    let mut bot = search_bot(&bot_opt, pool).await?;
+    if let Some(conversation) =
+        db::conversation::get_latest_by_client(&request.client, pool).await?
+        && conversation.status == interpret::HANDOFF
+    {
+        return Ok(serde_json::Map::new());
+    }
+    init_bot(&mut bot)?;
+
+    let mut context = init_context(
+        utils::get_default_flow(&bot)?.name.to_owned(),
+        request.client.clone(),
+        body.apps_endpoint.as_ref(),
+        bot.apps_endpoint.as_ref(),
+        pool,
+    )
+    .await;
+    // as in `get_or_create_conversation`, but a conversation that would be
+    // created is only made up
+    let flow_found = utils::find_flow(&event, &bot).ok();
+    let replaces_hold = flow_found.is_some() || event.content_type == "flow_trigger";
+    let open = db::conversation::get_latest_open_by_client(&request.client, pool)
+        .await?
+        .filter(|conversation| utils::get_flow_by_id(&conversation.flow_id, &bot.flows).is_ok());
+    let (flow, step) = match (flow_found, &open) {
+        (Some((flow, step)), _) => (flow, step),
+        (None, Some(conversation)) => (
+            utils::get_flow_by_id(&conversation.flow_id, &bot.flows)?,
+            conversation.step_id.to_owned(),
+        ),
+        (None, None) => {
+            let flow = utils::get_default_flow(&bot)?;
+            (flow, utils::get_entry_step(&bot, flow))
+        }
+    };
+    context.step = ContextStepInfo::UnknownFlow(step);
+    context.flow = flow.name.to_owned();
+    context.metadata = get_hashmap_from_json(&request.metadata, &context.flow);
+    context.current = current_memories(&request.client, &context.flow, pool).await?;
+
+    let conversation_id = match &open {
+        Some(conversation) => conversation.id.clone(),
+        None => uuid::Uuid::new_v4().to_string(),
+    };
+    let mut data = ConversationData {
+        conversation_id,
+        context,
+        metadata: request.metadata.clone(),
+        request_id: request.id.clone(),
+        callback_url: request.callback_url.clone(),
+        client: request.client.clone(),
+        messages: vec![],
+        ttl: utils::get_ttl_duration_value(Some(&event)),
+        low_data: utils::get_low_data_mode_value(&event),
+        secure: event.secure,
+        end_webhook: None,
+        no_persist: utils::get_no_persist(&bot),
+        bot_name: bot.name.clone(),
+        scrub_patterns: utils::get_scrub_patterns(&bot),
+        handed_off: false,
+        dry_run: true,
+    };
+
+    // a new flow replaces the hold, as in `search_flow`
+    if !replaces_hold {
+        check_for_hold(&mut data, &mut bot, &mut event, pool).await?;
+    }
+    if request.is_secure()
+        || utils::is_secure_conversation(&data.client, &data.conversation_id, pool).await
+    {
+        event.secure = true;
+    }
+    data.secure = event.secure;
+
+    let (messages, _) = interpret::step(&mut data, event, &bot, pool).await?;
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_scrub_received_payloads() {
+        let bot: CsmlBot = serde_json::from_value(json!({
+            "id": "bot_id",
+            "name": "test",
+            "flows": [],
+            "default_flow": "Default",
+            "env": {
+                "scrub_patterns": [r"\+?\d[\d ]{7,}\d", "("],
+            },
+        }))
+        .unwrap();
+        let patterns = utils::get_scrub_patterns(&bot);
+        assert_eq!(patterns.len(), 1);
+
+        let payload = json!({
+            "content_type": "text",
+            "content": {
+                "text": "Call me on +1 201 555 0123 please",
+            },
+        });
+        assert_eq!(
+            utils::scrub(&payload, &patterns),
+            json!({
+                "content_type": "text",
+                "content": {
+                    "text": "Call me on [scrubbed] please",
+                },
+            })
+        );
+        assert_eq!(utils::scrub(&payload, &[]), payload);
+    }
+
+    #[test]
+    fn it_should_fall_back_to_the_configured_apps_endpoint() {
+        let config = "http://config".to_owned();
+        let bot = "http://bot".to_owned();
+        let request = "http://request".to_owned();
+
+        assert_eq!(
+            resolve_apps_endpoint(None, None, Some(&config)),
+            Some(config.clone())
+        );
+        assert_eq!(
+            resolve_apps_endpoint(None, Some(&bot), Some(&config)),
+            Some(bot.clone())
+        );
+        assert_eq!(
+            resolve_apps_endpoint(Some(&request), Some(&bot), Some(&config)),
+            Some(request)
+        );
+        assert_eq!(resolve_apps_endpoint(None, None, None), None);
+    }
 }
@@ -23,7 +23,10 @@ use bitpart_common::{
     error::{BitpartErrorKind, Result},
 };
 use csml_interpreter::data::{Client, Context, CsmlBot, Message};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use tracing::warn;
 
 use crate::db;
 
@@ -33,6 +36,9 @@ pub struct SwitchBot {
     pub version_id: Option<String>,
     pub flow: Option<String>,
     pub step: String,
+    /// Deliver the event that led to the switch to the new bot, rather than a
+    /// `flow_trigger`, see `utils::FORWARD_EVENT_ON_SWITCH`
+    pub forward_event: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -53,6 +59,53 @@ pub struct ConversationData {
     pub messages: Vec<Message>,
     pub ttl: Option<chrono::Duration>,
     pub low_data: bool,
+    /// Set when the incoming event is secure (see `hold_secure`). Nothing from
+    /// a secure turn is written to the `message` table verbatim, and callbacks
+    /// receive redacted payloads.
+    pub secure: bool,
+    /// The bot's conversation end webhook, see `callback::END_WEBHOOK`
+    pub end_webhook: Option<String>,
+    /// Flows and steps whose messages are never persisted, see `utils::NO_PERSIST`
+    pub no_persist: Vec<String>,
+    /// Human readable name of the bot handling the conversation
+    pub bot_name: String,
+    /// Scrubbed from received messages before they are persisted, see
+    /// `utils::SCRUB_PATTERNS`
+    pub scrub_patterns: Vec<Regex>,
+    /// Set once the flow hands the conversation off to a human operator, see
+    /// `interpret::HANDOFF`
+    pub handed_off: bool,
+    /// Set for a dry run, whose turn writes nothing to the database and queues
+    /// no callbacks, see `conversation::dry_run`
+    pub dry_run: bool,
+}
+
+impl ConversationData {
+    /**
+     * Whether messages at the current flow and step may be written to the
+     * `message` table.
+     */
+    pub fn persists(&self) -> bool {
+        !self.dry_run
+            && persists_at(
+                self.low_data,
+                &self.no_persist,
+                &self.context.flow,
+                &self.context.step.get_step(),
+            )
+    }
+}
+
+/**
+ * Whether messages at `flow` and `step` may be written to the `message` table,
+ * outside of low data mode and the bot's `no_persist` list.
+ */
+pub fn persists_at(low_data: bool, no_persist: &[String], flow: &str, step: &str) -> bool {
+    let step = format!("{}.{}", flow, step);
+    !low_data
+        && !no_persist
+            .iter()
+            .any(|entry| entry == flow || *entry == step)
 }
 
 pub async fn search_bot(bot: &BotOpt, pool: &Pool) -> Result<Box<CsmlBot>> {
@@ -69,6 +122,7 @@ pub async fn search_bot(bot: &BotOpt, pool: &Pool) -> Result<Box<CsmlBot>> {
                 Some(bot_version) => {
                     // bot_version.bot.apps_endpoint = apps_endpoint.to_owned();
                     // bot_version.bot.multibot = multibot.to_owned();
+                    check_engine_version(&bot_version, engine_version_check())?;
                     Ok(Box::new(bot_version.bot))
                 }
                 None => Err(BitpartErrorKind::Interpreter(format!(
@@ -90,6 +144,7 @@ pub async fn search_bot(bot: &BotOpt, pool: &Pool) -> Result<Box<CsmlBot>> {
                 Some(bot_version) => {
                     // bot_version.bot.apps_endpoint = apps_endpoint.to_owned();
                     // bot_version.bot.multibot = multibot.to_owned();
+                    check_engine_version(&bot_version, engine_version_check())?;
                     Ok(Box::new(bot_version.bot))
                 }
                 None => Err(BitpartErrorKind::Interpreter(format!(
@@ -101,3 +156,117 @@ pub async fn search_bot(bot: &BotOpt, pool: &Pool) -> Result<Box<CsmlBot>> {
         }
     }
 }
+
+/// What to do when a stored bot was saved by an incompatible engine version
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EngineVersionCheck {
+    #[default]
+    Warn,
+    Refuse,
+}
+
+static ENGINE_VERSION_CHECK: OnceLock<EngineVersionCheck> = OnceLock::new();
+
+/**
+ * Set what to do with bots saved by an incompatible engine version from the
+ * server configuration. Only the first call has any effect.
+ */
+pub fn set_engine_version_check(check: EngineVersionCheck) {
+    let _ = ENGINE_VERSION_CHECK.set(check);
+}
+
+fn engine_version_check() -> EngineVersionCheck {
+    ENGINE_VERSION_CHECK.get().copied().unwrap_or_default()
+}
+
+/**
+ * Whether a bot saved by engine `version` can run on this one. Versions are
+ * compatible when their major versions match, and, before 1.0, their minor
+ * versions too.
+ */
+pub fn is_compatible_engine(version: &str) -> bool {
+    fn major_minor(version: &str) -> Option<(u64, u64)> {
+        let mut parts = version.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        Some((major, minor))
+    }
+
+    match (major_minor(version), major_minor(env!("CARGO_PKG_VERSION"))) {
+        (Some((0, minor)), Some((0, current_minor))) => minor == current_minor,
+        (Some((major, _)), Some((current_major, _))) => major == current_major,
+        _ => false,
+    }
+}
+
+/**
+ * Warn about, or refuse, a bot version saved by an incompatible engine, so
+ * that it gets re-validated and saved again. Returns whether it is compatible.
+ */
+pub fn check_engine_version(bot_version: &BotVersion, policy: EngineVersionCheck) -> Result<bool> {
+    if is_compatible_engine(&bot_version.engine_version) {
+        return Ok(true);
+    }
+    let message = format!(
+        "bot {} version {} was saved by engine {}, which is incompatible with engine {}; \
+         re-save it to re-validate",
+        bot_version.bot.id,
+        bot_version.version_id,
+        bot_version.engine_version,
+        env!("CARGO_PKG_VERSION")
+    );
+    match policy {
+        EngineVersionCheck::Warn => {
+            warn!("{}", message);
+            Ok(false)
+        }
+        EngineVersionCheck::Refuse => Err(BitpartErrorKind::Interpreter(message).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::get_test_pool;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn it_should_flag_bots_saved_by_an_older_engine() {
+        let pool = get_test_pool().await;
+
+        let bot: CsmlBot = serde_json::from_value(json!({
+            "id": "bot_id",
+            "name": "test",
+            "flows": [],
+            "default_flow": "Default",
+        }))
+        .unwrap();
+        let created = db::bot::create(bot, &pool).await.unwrap();
+        assert!(check_engine_version(&created, EngineVersionCheck::Refuse).unwrap());
+
+        let version_id = created.version_id.clone();
+        pool.get()
+            .await
+            .unwrap()
+            .interact(move |conn| {
+                conn.execute(
+                    "UPDATE bot SET engine_version = '0.0.1' WHERE id = ?",
+                    [version_id],
+                )
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let loaded = db::bot::get_latest_by_bot_id("bot_id", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.engine_version, "0.0.1");
+        assert!(!check_engine_version(&loaded, EngineVersionCheck::Warn).unwrap());
+
+        let err = check_engine_version(&loaded, EngineVersionCheck::Refuse).unwrap_err();
+        assert!(err.to_string().contains("saved by engine 0.0.1"));
+    }
+}
@@ -17,7 +17,10 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use bitpart_common::{db::Pool, error::Result};
+use bitpart_common::{
+    db::Pool,
+    error::{BitpartError, BitpartErrorKind, Result},
+};
 use chrono::Utc;
 use csml_interpreter::csml_logs::LogLvl;
 use csml_interpreter::data::{
@@ -27,16 +30,59 @@ use csml_interpreter::data::{
 use csml_interpreter::interpret;
 use serde_json::{Value, map::Map};
 use std::collections::HashMap;
+use std::sync::OnceLock;
 use std::sync::mpsc as std_mpsc;
 use tokio::sync::mpsc as tokio_mpsc;
-use tracing::{debug, error, info, instrument, trace, warn};
+use tracing::level_filters::LevelFilter;
+use tracing::{Level, Span, debug, error, info, instrument, trace, warn};
 
+use super::callback;
 use super::data::{ConversationData, SwitchBot};
+use super::pool::interpreter_pool;
 use super::utils::{
-    get_current_step_hash, get_flow_by_id, messages_formatter, send_msg_to_callback_url,
-    update_current_context,
+    clear_secure_conversation, get_current_step_hash, get_flow_by_id, get_forward_event_on_switch,
+    messages_formatter, secure_placeholder, send_msg_to_callback_url, update_current_context,
 };
-use crate::db;
+use crate::{db, metrics};
+
+/// Target the bots' own `Log` events are emitted under, so that they can be
+/// filtered separately from the server's logs
+pub const INTERPRETER_LOG_TARGET: &str = "csml";
+
+/// Most verbose level of the bots' `Log` events, from the server configuration
+static INTERPRETER_LOG_LEVEL: OnceLock<LevelFilter> = OnceLock::new();
+
+/**
+ * Set the most verbose level of the bots' `Log` events that is passed on. By
+ * default they are all passed on, and only the server's verbosity applies.
+ * Only the first call has any effect.
+ */
+pub fn set_interpreter_log_level(level: LevelFilter) {
+    let _ = INTERPRETER_LOG_LEVEL.set(level);
+}
+
+fn interpreter_log_enabled(log_lvl: &LogLvl, max: LevelFilter) -> bool {
+    let level = match log_lvl {
+        LogLvl::Error => Level::ERROR,
+        LogLvl::Warn => Level::WARN,
+        LogLvl::Info => Level::INFO,
+        LogLvl::Debug => Level::DEBUG,
+        LogLvl::Trace => Level::TRACE,
+    };
+    level <= max
+}
+
+/// Status of a conversation handed off to a human operator. The bot no longer
+/// answers it until the operator ends the handoff.
+pub const HANDOFF: &str = "HANDOFF";
+
+/// Memory a flow remembers to hand its conversation off, with the reason as
+/// its value, e.g. `remember _handoff = "asked for a person"`
+pub const HANDOFF_MEMORY: &str = "_handoff";
+
+/// Most `goto`s a single request may take when its event sets no
+/// `step_limit`, so that a flow that loops forever is stopped
+pub const DEFAULT_STEP_LIMIT: usize = 100;
 
 #[derive(Debug, Clone)]
 enum InterpreterReturn {
@@ -45,6 +91,55 @@ enum InterpreterReturn {
     SwitchBot(SwitchBot),
 }
 
+/**
+ * Mark the conversation closed and queue the bot's conversation end webhook.
+ * A handed off conversation is left to the operator.
+ */
+async fn close_conversation(data: &ConversationData, reason: &str, pool: &Pool) -> Result<()> {
+    if data.handed_off || data.dry_run {
+        return Ok(());
+    }
+    db::conversation::set_status_by_id(&data.conversation_id, "CLOSED", pool).await?;
+    clear_secure_conversation(&data.client, &data.conversation_id, pool).await?;
+    callback::conversation_ended(
+        data.end_webhook.as_deref(),
+        &data.conversation_id,
+        &data.client,
+        &data.context.flow,
+        reason,
+        pool,
+    )
+    .await
+}
+
+/**
+ * Mark the conversation handed off to a human operator and queue the bot's
+ * handoff webhook.
+ */
+async fn hand_off(
+    data: &mut ConversationData,
+    reason: &Value,
+    bot: &CsmlBot,
+    pool: &Pool,
+) -> Result<()> {
+    let reason = reason.as_str().unwrap_or("escalate");
+    info!(reason, "conversation handed off");
+    data.handed_off = true;
+    if data.dry_run {
+        return Ok(());
+    }
+    db::conversation::set_status_by_id(&data.conversation_id, HANDOFF, pool).await?;
+    callback::conversation_handed_off(
+        bot,
+        &data.conversation_id,
+        &data.client,
+        &data.context.flow,
+        reason,
+        pool,
+    )
+    .await
+}
+
 #[instrument(
     name = "csml.step",
     skip_all,
@@ -53,6 +148,7 @@ enum InterpreterReturn {
         user_id = %data.client.user_id,
         channel_id = %data.client.channel_id,
         flow = %data.context.flow,
+        step = %data.context.step.get_step(),
     ),
 )]
 pub async fn step(
@@ -62,21 +158,34 @@ pub async fn step(
     pool: &Pool,
 ) -> Result<(Map<String, Value>, Option<SwitchBot>)> {
     let mut current_flow: &CsmlFlow = get_flow_by_id(&data.context.flow, &bot.flows)?;
+    // a turn that starts or ends in a no-persist flow or step is not persisted
+    let persists = data.persists();
     let mut interaction_order = 0;
     let mut conversation_end = false;
     let (interpret_sender, interpret_receiver) = std_mpsc::channel::<MSG>();
     let (sender, mut receiver) = tokio_mpsc::channel::<MSG>(32);
     let context = data.context.clone();
     let mut switch_bot = None;
+    // the interpreter is told the limit too, so it can stop on its own
+    let step_limit = event.step_limit.unwrap_or(DEFAULT_STEP_LIMIT);
+    let event = Event {
+        step_limit: Some(step_limit),
+        ..event
+    };
+    let mut steps = 0;
     info!("interpreter: start interpretations of bot {:?}", bot.id);
     debug!(
         "interpreter: start interpretations of bot {:?}, with ",
         bot.id
     );
     let new_bot = bot.clone();
-    tokio::task::spawn_blocking(move || {
+    // the interpreter runs on its own thread, outside of this span unless it
+    // is entered there
+    let span = Span::current();
+    interpreter_pool().spawn(move || {
+        let _entered = span.enter();
         interpret(new_bot, context, event, Some(interpret_sender));
-    });
+    })?;
     tokio::task::spawn_blocking(move || {
         while let Ok(msg) = interpret_receiver.recv() {
             if sender.blocking_send(msg).is_err() {
@@ -89,31 +198,42 @@ pub async fn step(
 
     while let Some(received) = receiver.recv().await {
         match received {
+            MSG::Remember(mem) if mem.key == HANDOFF_MEMORY => {
+                hand_off(data, &mem.value, bot, pool).await?;
+            }
             MSG::Remember(mem) => {
                 memories.insert(mem.key.clone(), mem);
             }
             MSG::Forget(mem) => match mem {
                 ForgetMemory::ALL => {
                     memories.clear();
-                    db::memory::delete_by_client(&data.client, pool).await?;
+                    if !data.dry_run {
+                        db::memory::delete_by_client(&data.client, pool).await?;
+                    }
                 }
                 ForgetMemory::SINGLE(memory) => {
                     memories.remove(&memory.ident);
-                    db::memory::delete(&data.client, &memory.ident, pool).await?;
+                    if !data.dry_run {
+                        db::memory::delete(&data.client, &memory.ident, pool).await?;
+                    }
                 }
                 ForgetMemory::LIST(mem_list) => {
                     for mem in mem_list.iter() {
                         memories.remove(&mem.ident);
-                        db::memory::delete(&data.client, &mem.ident, pool).await?;
+                        if !data.dry_run {
+                            db::memory::delete(&data.client, &mem.ident, pool).await?;
+                        }
                     }
                 }
             },
             MSG::Message(msg) => {
                 info!("sending message");
                 debug!("sending message {:?}", msg);
+                metrics::message_sent(&data.client.bot_id, &data.client.channel_id);
 
                 debug!("CONTEXT {:?}", data.context);
-                send_msg_to_callback_url(data, vec![msg.clone()], interaction_order, false);
+                send_msg_to_callback_url(data, vec![msg.clone()], interaction_order, false, pool)
+                    .await?;
                 data.messages.push(msg);
             }
             MSG::Shout(msg) => {
@@ -122,7 +242,8 @@ pub async fn step(
 
                 debug!("CONTEXT {:?}", data.context);
 
-                send_msg_to_callback_url(data, vec![msg.clone()], interaction_order, false);
+                send_msg_to_callback_url(data, vec![msg.clone()], interaction_order, false, pool)
+                    .await?;
 
                 let convos =
                     db::conversation::get_open_by_bot_id(&data.client.bot_id, None, None, pool)
@@ -149,7 +270,8 @@ pub async fn step(
 
                 debug!("CONTEXT {:?}", data.context);
 
-                send_msg_to_callback_url(data, vec![msg.clone()], interaction_order, false);
+                send_msg_to_callback_url(data, vec![msg.clone()], interaction_order, false, pool)
+                    .await?;
 
                 let clients = db::memory::get_by_memory("_whisperable", &data.client.bot_id, pool)
                     .await?
@@ -178,8 +300,10 @@ pub async fn step(
 
                 debug!("CONTEXT {:?}", data.context);
 
-                db::conversation::delete_by_client(&data.client, pool).await?;
-                db::memory::delete_by_client(&data.client, pool).await?;
+                if !data.dry_run {
+                    db::conversation::delete_by_client(&data.client, pool).await?;
+                    db::memory::delete_by_client(&data.client, pool).await?;
+                }
             }
             MSG::Log {
                 flow,
@@ -187,15 +311,32 @@ pub async fn step(
                 message,
                 log_lvl,
             } => {
+                let max = INTERPRETER_LOG_LEVEL
+                    .get()
+                    .copied()
+                    .unwrap_or(LevelFilter::TRACE);
+                if !interpreter_log_enabled(&log_lvl, max) {
+                    continue;
+                }
                 // Note: `flow` here is the CSML script's own flow identifier,
                 // logged as `csml_flow` to disambiguate from the span's `flow`
                 // field which comes from `data.context.flow`.
                 match log_lvl {
-                    LogLvl::Error => error!(csml_flow = flow, line, message),
-                    LogLvl::Warn => warn!(csml_flow = flow, line, message),
-                    LogLvl::Info => info!(csml_flow = flow, line, message),
-                    LogLvl::Debug => debug!(csml_flow = flow, line, message),
-                    LogLvl::Trace => trace!(csml_flow = flow, line, message),
+                    LogLvl::Error => {
+                        error!(target: INTERPRETER_LOG_TARGET, csml_flow = flow, line, message)
+                    }
+                    LogLvl::Warn => {
+                        warn!(target: INTERPRETER_LOG_TARGET, csml_flow = flow, line, message)
+                    }
+                    LogLvl::Info => {
+                        info!(target: INTERPRETER_LOG_TARGET, csml_flow = flow, line, message)
+                    }
+                    LogLvl::Debug => {
+                        debug!(target: INTERPRETER_LOG_TARGET, csml_flow = flow, line, message)
+                    }
+                    LogLvl::Trace => {
+                        trace!(target: INTERPRETER_LOG_TARGET, csml_flow = flow, line, message)
+                    }
                 };
             }
             MSG::Hold(Hold {
@@ -217,15 +358,17 @@ pub async fn step(
                 info!("hold bot");
                 debug!("hold bot, state_hold {:?}", state_hold);
 
-                db::state::set(
-                    &data.client,
-                    "hold",
-                    "position",
-                    &state_hold,
-                    data.ttl.map(|t| Utc::now().naive_utc() + t),
-                    pool,
-                )
-                .await?;
+                if !data.dry_run {
+                    db::state::set(
+                        &data.client,
+                        "hold",
+                        "position",
+                        &state_hold,
+                        data.ttl.map(|t| Utc::now().naive_utc() + t),
+                        pool,
+                    )
+                    .await?;
+                }
                 data.context.hold = Some(Hold {
                     index,
                     step_vars,
@@ -240,6 +383,12 @@ pub async fn step(
                 step,
                 bot: None,
             } => {
+                steps += 1;
+                if steps > step_limit {
+                    return Err(
+                        step_limit_exceeded(data, step_limit, interaction_order, pool).await,
+                    );
+                }
                 if let Ok(InterpreterReturn::End) = manage_internal_goto(
                     data,
                     &mut conversation_end,
@@ -281,26 +430,42 @@ pub async fn step(
             MSG::Error(err_msg) => {
                 conversation_end = true;
                 error!("interpreter error: {:?}", err_msg);
+                metrics::interpreter_error(&data.client.bot_id, &data.client.channel_id);
 
-                send_msg_to_callback_url(data, vec![err_msg.clone()], interaction_order, true);
+                send_msg_to_callback_url(
+                    data,
+                    vec![err_msg.clone()],
+                    interaction_order,
+                    true,
+                    pool,
+                )
+                .await?;
                 data.messages.push(err_msg);
-                db::conversation::set_status_by_id(&data.conversation_id, "CLOSED", pool).await?;
+                close_conversation(data, "error", pool).await?;
             }
         }
     }
 
-    if !data.low_data {
+    if persists && data.persists() {
         // save in db
         let msgs: Vec<serde_json::Value> = data
             .messages
             .iter()
-            .map(|var| var.clone().message_to_json())
+            .map(|var| {
+                if data.secure {
+                    secure_placeholder()
+                } else {
+                    var.clone().message_to_json()
+                }
+            })
             .collect();
 
         db::message::create(data, &msgs, interaction_order, "SEND", None, pool).await?;
     }
 
-    db::memory::create_many(&data.client, &memories, None, pool).await?;
+    if !data.dry_run {
+        db::memory::create_many(&data.client, &memories, None, pool).await?;
+    }
 
     Ok((
         messages_formatter(
@@ -321,6 +486,7 @@ pub async fn step(
         user_id = %data.client.user_id,
         channel_id = %data.client.channel_id,
         flow = %data.context.flow,
+        step = %data.context.step.get_step(),
         target_bot = %target_bot,
     ),
 )]
@@ -365,7 +531,9 @@ async fn manage_switch_bot(
                 }],
                 *interaction_order,
                 true,
-            );
+                pool,
+            )
+            .await?;
 
             error!(message = error_message);
             return Ok(InterpreterReturn::End);
@@ -395,11 +563,11 @@ async fn manage_switch_bot(
     // save message
     data.messages.push(message.clone());
     // send message switch bot
-    send_msg_to_callback_url(data, vec![message], *interaction_order, true);
+    send_msg_to_callback_url(data, vec![message], *interaction_order, true, pool).await?;
 
     info!("switch bot");
 
-    db::conversation::set_status_by_id(&data.conversation_id, "CLOSED", pool).await?;
+    close_conversation(data, "switch_bot", pool).await?;
 
     let previous_bot: Value = serde_json::json!({
         "bot": data.client.bot_id,
@@ -407,25 +575,28 @@ async fn manage_switch_bot(
         "step": data.context.step,
     });
 
-    db::state::set(
-        &Client::new(
-            next_bot.id.to_owned(),
-            data.client.channel_id.clone(),
-            data.client.user_id.clone(),
-        ),
-        "bot",
-        "previous",
-        &previous_bot,
-        data.ttl.map(|t| Utc::now().naive_utc() + t),
-        pool,
-    )
-    .await?;
+    if !data.dry_run {
+        db::state::set(
+            &Client::new(
+                next_bot.id.to_owned(),
+                data.client.channel_id.clone(),
+                data.client.user_id.clone(),
+            ),
+            "bot",
+            "previous",
+            &previous_bot,
+            data.ttl.map(|t| Utc::now().naive_utc() + t),
+            pool,
+        )
+        .await?;
+    }
 
     Ok(InterpreterReturn::SwitchBot(SwitchBot {
         bot_id: next_bot.id.to_owned(),
         version_id: next_bot.version_id.to_owned(),
         flow,
         step: step.get_step(),
+        forward_event: get_forward_event_on_switch(bot),
     }))
 }
 
@@ -438,6 +609,7 @@ async fn manage_switch_bot(
         user_id = %data.client.user_id,
         channel_id = %data.client.channel_id,
         flow = %data.context.flow,
+        step = %data.context.step.get_step(),
     ),
 )]
 async fn manage_internal_goto<'a>(
@@ -481,9 +653,55 @@ async fn manage_internal_goto<'a>(
     Ok(InterpreterReturn::Continue)
 }
 
+/**
+ * Stop a request whose flow took more than `step_limit` `goto`s: the error is
+ * sent to the callback URL and the conversation is closed, so that the next
+ * message starts afresh rather than looping again.
+ */
+async fn step_limit_exceeded(
+    data: &mut ConversationData,
+    step_limit: usize,
+    interaction_order: i32,
+    pool: &Pool,
+) -> BitpartError {
+    let error_message = format!(
+        "step limit of {} exceeded in flow {}, step {}",
+        step_limit,
+        data.context.flow,
+        data.context.step.get_step()
+    );
+    error!(message = error_message);
+    metrics::interpreter_error(&data.client.bot_id, &data.client.channel_id);
+    let message = Message {
+        content_type: "error".to_owned(),
+        content: serde_json::json!({ "error": error_message.clone() }),
+    };
+    if let Err(err) =
+        send_msg_to_callback_url(data, vec![message.clone()], interaction_order, true, pool).await
+    {
+        warn!("Failed to send step limit error: {:?}", err);
+    }
+    data.messages.push(message);
+    if let Err(err) = close_conversation(data, "error", pool).await {
+        warn!("Failed to close looping conversation: {:?}", err);
+    }
+    BitpartErrorKind::Interpreter(error_message).into()
+}
+
 /**
  * CSML `goto flow` action
  */
+#[instrument(
+    name = "csml.goto_flow",
+    skip_all,
+    fields(
+        bot_id = %data.client.bot_id,
+        user_id = %data.client.user_id,
+        channel_id = %data.client.channel_id,
+        flow = %nextflow,
+        step = %nextstep.get_step(),
+    ),
+)]
 async fn goto_flow<'a>(
     data: &mut ConversationData,
     interaction_order: &mut i32,
@@ -497,13 +715,15 @@ async fn goto_flow<'a>(
     data.context.flow = nextflow;
     data.context.step = nextstep;
 
-    db::conversation::update(
-        &data.conversation_id,
-        Some(current_flow.id.clone()),
-        Some(data.context.step.get_step()),
-        pool,
-    )
-    .await?;
+    if !data.dry_run {
+        db::conversation::update(
+            &data.conversation_id,
+            Some(current_flow.id.clone()),
+            Some(data.context.step.get_step()),
+            pool,
+        )
+        .await?;
+    }
 
     *interaction_order += 1;
 
@@ -524,22 +744,39 @@ async fn goto_step(
         *conversation_end = true;
 
         // send end of conversation
-        send_msg_to_callback_url(data, vec![], *interaction_order, *conversation_end);
-        db::conversation::set_status_by_id(&data.conversation_id, "CLOSED", pool).await?;
+        send_msg_to_callback_url(data, vec![], *interaction_order, *conversation_end, pool).await?;
+        close_conversation(data, "end", pool).await?;
 
         // break interpret_step loop
         return Ok(*conversation_end);
     } else {
         data.context.step = nextstep;
-        db::conversation::update(
-            &data.conversation_id,
-            None,
-            Some(data.context.step.get_step()),
-            pool,
-        )
-        .await?;
+        if !data.dry_run {
+            db::conversation::update(
+                &data.conversation_id,
+                None,
+                Some(data.context.step.get_step()),
+                pool,
+            )
+            .await?;
+        }
     }
 
     *interaction_order += 1;
     Ok(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_filter_bot_logs_by_the_interpreter_level() {
+        assert!(!interpreter_log_enabled(&LogLvl::Debug, LevelFilter::INFO));
+        assert!(interpreter_log_enabled(&LogLvl::Info, LevelFilter::INFO));
+        assert!(interpreter_log_enabled(&LogLvl::Error, LevelFilter::INFO));
+        assert!(interpreter_log_enabled(&LogLvl::Debug, LevelFilter::DEBUG));
+        assert!(!interpreter_log_enabled(&LogLvl::Trace, LevelFilter::DEBUG));
+        assert!(!interpreter_log_enabled(&LogLvl::Error, LevelFilter::OFF));
+    }
+}
@@ -17,7 +17,9 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+pub mod callback;
 pub mod conversation;
 pub mod data;
 pub mod interpret;
+pub mod pool;
 pub mod utils;
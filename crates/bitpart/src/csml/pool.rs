@@ -0,0 +1,211 @@
+// Bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Fixed set of long-lived threads that run the CSML interpreter. Interpreting
+//! a step is synchronous and can recurse deeply, so it gets dedicated threads
+//! with a larger stack instead of a new thread per request.
+
+use bitpart_common::error::{BitpartErrorKind, Result};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Mutex, OnceLock, mpsc};
+use std::thread;
+use tracing::error;
+
+/// Default interpreter thread stack size, see `set_interpreter_pool`
+pub const DEFAULT_STACK_SIZE: usize = 4 * 1024 * 1024;
+/// Default number of steps that may wait for a free interpreter thread, see
+/// `set_interpreter_pool`
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct InterpreterPool {
+    sender: mpsc::SyncSender<Job>,
+}
+
+impl InterpreterPool {
+    pub fn new(threads: usize, stack_size: usize, queue_size: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("bitpart-interpreter-{i}"))
+                .stack_size(stack_size)
+                .spawn(move || {
+                    loop {
+                        let job = match receiver.lock() {
+                            Ok(receiver) => receiver.recv(),
+                            Err(_) => break,
+                        };
+                        let Ok(job) = job else {
+                            break;
+                        };
+                        if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            error!("CSML interpreter panicked");
+                        }
+                    }
+                })
+                .expect("Failed to spawn interpreter thread");
+        }
+
+        Self { sender }
+    }
+
+    /**
+     * Queue `job` to run on the next free interpreter thread. Fails rather
+     * than waiting when the queue is full, so a burst of requests is turned
+     * away instead of piling up behind steps that run for a long time.
+     */
+    pub fn spawn(&self, job: impl FnOnce() + Send + 'static) -> Result<()> {
+        match self.sender.try_send(Box::new(job)) {
+            Ok(()) => Ok(()),
+            Err(mpsc::TrySendError::Full(_)) => Err(BitpartErrorKind::Interpreter(
+                "CSML interpreter queue is full".to_owned(),
+            )
+            .into()),
+            Err(mpsc::TrySendError::Disconnected(_)) => Err(BitpartErrorKind::Interpreter(
+                "CSML interpreter pool has shut down".to_owned(),
+            )
+            .into()),
+        }
+    }
+}
+
+static POOL: OnceLock<InterpreterPool> = OnceLock::new();
+
+/**
+ * Size the shared interpreter pool from the server configuration: the number
+ * of threads (one per CPU by default), their stack size in bytes and how many
+ * steps may wait for a free thread. Only the first call, or the first use of
+ * the pool, has any effect.
+ */
+pub fn set_interpreter_pool(
+    threads: Option<usize>,
+    stack_size: Option<usize>,
+    queue_size: Option<usize>,
+) {
+    let _ = POOL.set(InterpreterPool::new(
+        threads.unwrap_or_else(default_threads),
+        stack_size.unwrap_or(DEFAULT_STACK_SIZE),
+        queue_size.unwrap_or(DEFAULT_QUEUE_SIZE),
+    ));
+}
+
+fn default_threads() -> usize {
+    thread::available_parallelism().map_or(4, |n| n.get())
+}
+
+/**
+ * The shared interpreter pool, see `set_interpreter_pool`.
+ */
+pub fn interpreter_pool() -> &'static InterpreterPool {
+    POOL.get_or_init(|| {
+        InterpreterPool::new(default_threads(), DEFAULT_STACK_SIZE, DEFAULT_QUEUE_SIZE)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    #[test]
+    fn it_should_run_more_jobs_than_threads() {
+        let pool = InterpreterPool::new(2, DEFAULT_STACK_SIZE, DEFAULT_QUEUE_SIZE);
+        let (done, results) = mpsc::channel();
+
+        for i in 0..50 {
+            let done = done.clone();
+            pool.spawn(move || {
+                thread::sleep(Duration::from_millis(2));
+                let _ = done.send((i, thread::current().id()));
+            })
+            .unwrap();
+        }
+        drop(done);
+
+        let results: Vec<_> = results.iter().collect();
+        assert_eq!(results.len(), 50);
+        let threads: HashSet<_> = results.iter().map(|(_, id)| *id).collect();
+        assert!(threads.len() <= 2);
+    }
+
+    #[test]
+    fn it_should_survive_a_panicking_job() {
+        let pool = InterpreterPool::new(1, DEFAULT_STACK_SIZE, DEFAULT_QUEUE_SIZE);
+        let (done, result) = mpsc::channel();
+
+        pool.spawn(|| panic!("interpreter error")).unwrap();
+        pool.spawn(move || {
+            let _ = done.send(());
+        })
+        .unwrap();
+
+        assert!(result.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn it_should_turn_away_jobs_when_the_queue_is_full() {
+        let pool = InterpreterPool::new(1, DEFAULT_STACK_SIZE, 1);
+        let (started, running) = mpsc::channel();
+        let (release, wait) = mpsc::channel::<()>();
+
+        // occupy the only thread, then fill the queue behind it
+        pool.spawn(move || {
+            let _ = started.send(());
+            let _ = wait.recv();
+        })
+        .unwrap();
+        running.recv_timeout(Duration::from_secs(5)).unwrap();
+        pool.spawn(|| {}).unwrap();
+
+        let err = pool.spawn(|| {}).unwrap_err();
+        assert!(err.to_string().contains("queue is full"));
+
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn it_should_run_short_jobs_quickly() {
+        let pool = InterpreterPool::new(2, DEFAULT_STACK_SIZE, DEFAULT_QUEUE_SIZE);
+        let (done, results) = mpsc::channel();
+        let jobs = 10_000;
+
+        let start = std::time::Instant::now();
+        let mut sent = 0;
+        while sent < jobs {
+            let done = done.clone();
+            if pool.spawn(move || done.send(()).unwrap()).is_ok() {
+                sent += 1;
+            } else {
+                thread::yield_now();
+            }
+        }
+        drop(done);
+        assert_eq!(results.iter().count(), jobs);
+
+        // the pool adds a few microseconds per step at most, far below the
+        // time the interpreter itself takes
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed < Duration::from_secs(5),
+            "{jobs} jobs took {elapsed:?}"
+        );
+    }
+}
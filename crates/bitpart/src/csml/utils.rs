@@ -37,7 +37,7 @@ use regex::Regex;
 use serde_json::{Value, json, map::Map};
 use std::collections::HashMap;
 use std::env;
-use tracing::debug;
+use tracing::{debug, info, warn};
 
 use super::data::ConversationData;
 use crate::db;
@@ -82,44 +82,186 @@ pub fn messages_formatter(
     map_client.insert("channel_id".to_owned(), json!(data.client.channel_id));
 
     map.insert("client".to_owned(), Value::Object(map_client));
+    map.insert("bot_name".to_owned(), json!(data.bot_name));
 
     map
 }
 
-fn format_and_transfer(callback_url: &str, msg: serde_json::Value) {
-    let mut request = ureq::post(callback_url);
+/**
+ * If a callback_url is defined, we must send each message to its endpoint as it comes.
+ * Messages are queued and delivered in the background, see `csml::callback`.
+ * Otherwise, just continue!
+ */
+async fn send_to_callback_url(
+    data: &mut ConversationData,
+    msg: serde_json::Value,
+    pool: &Pool,
+) -> Result<()> {
+    let callback_url = match &data.callback_url {
+        Some(callback_url) if !data.dry_run => callback_url,
+        _ => return Ok(()),
+    };
 
-    request = request
-        .set("Accept", "application/json")
-        .set("Content-Type", "application/json");
+    super::callback::enqueue(callback_url, &msg, &data.client.bot_id, pool).await
+}
 
-    let response = request.send_json(msg);
+/**
+ * Stand-in for any payload produced during a secure turn. It is what gets
+ * persisted to the `message` table and what callbacks receive instead of the
+ * real content.
+ */
+pub fn secure_placeholder() -> Value {
+    json!({"content_type": "secure"})
+}
 
-    if let Err(err) = response {
-        eprintln!("callback_url call failed: {:?}", err.to_string());
-    }
+/// State type under which secure conversations are flagged, keyed by
+/// conversation id
+const SECURE_CONVERSATION: &str = "secure";
+
+/**
+ * Flag the current conversation secure, so that every later event in it is
+ * treated as secure too. The flag is cleared when the conversation closes.
+ */
+pub async fn set_secure_conversation(data: &ConversationData, pool: &Pool) -> Result<()> {
+    db::state::set(
+        &data.client,
+        SECURE_CONVERSATION,
+        &data.conversation_id,
+        &json!(true),
+        data.ttl.map(|t| Utc::now().naive_utc() + t),
+        pool,
+    )
+    .await
+}
+
+pub async fn is_secure_conversation(client: &Client, conversation_id: &str, pool: &Pool) -> bool {
+    db::state::get(client, SECURE_CONVERSATION, conversation_id, pool)
+        .await
+        .is_ok_and(|secure| secure.as_bool().unwrap_or(false))
 }
 
+pub async fn clear_secure_conversation(
+    client: &Client,
+    conversation_id: &str,
+    pool: &Pool,
+) -> Result<()> {
+    db::state::delete(client, SECURE_CONVERSATION, conversation_id, pool).await
+}
+
+/// Bot `env` key listing the flows (`"flow"`) and steps (`"flow.step"`) whose
+/// messages are never written to the `message` table
+pub const NO_PERSIST: &str = "no_persist";
+
 /**
- * If a callback_url is defined, we must send each message to its endpoint as it comes.
- * Otherwise, just continue!
+ * Read the bot's `no_persist` list. Invalid entries are ignored.
  */
-fn send_to_callback_url(data: &mut ConversationData, msg: serde_json::Value) {
-    let callback_url = match &data.callback_url {
-        Some(callback_url) => callback_url,
-        None => return,
-    };
+pub fn get_no_persist(bot: &CsmlBot) -> Vec<String> {
+    bot.env
+        .as_ref()
+        .and_then(|env| env.get(NO_PERSIST))
+        .and_then(|list| list.as_array())
+        .map(|list| {
+            list.iter()
+                .filter_map(|entry| entry.as_str())
+                .map(|entry| entry.to_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Bot `env` key listing regular expressions whose matches are scrubbed from
+/// received messages before they are written to the `message` table
+pub const SCRUB_PATTERNS: &str = "scrub_patterns";
+
+/// What scrubbed matches are replaced with
+const SCRUBBED: &str = "[scrubbed]";
+
+/**
+ * Read and compile the bot's `scrub_patterns`. Invalid patterns are logged
+ * and ignored.
+ */
+pub fn get_scrub_patterns(bot: &CsmlBot) -> Vec<Regex> {
+    bot.env
+        .as_ref()
+        .and_then(|env| env.get(SCRUB_PATTERNS))
+        .and_then(|list| list.as_array())
+        .map(|list| {
+            list.iter()
+                .filter_map(|entry| entry.as_str())
+                .filter_map(|pattern| match Regex::new(pattern) {
+                    Ok(regex) => Some(regex),
+                    Err(err) => {
+                        warn!(bot_id = %bot.id, pattern, "invalid scrub pattern: {}", err);
+                        None
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/**
+ * Replace every match of the patterns in the strings of a payload. Object
+ * keys are left as they are.
+ */
+pub fn scrub(value: &Value, patterns: &[Regex]) -> Value {
+    match value {
+        Value::String(text) => {
+            Value::String(patterns.iter().fold(text.to_owned(), |text, pattern| {
+                pattern.replace_all(&text, SCRUBBED).into_owned()
+            }))
+        }
+        Value::Array(values) => Value::Array(values.iter().map(|v| scrub(v, patterns)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, v)| (key.to_owned(), scrub(v, patterns)))
+                .collect(),
+        ),
+        other => other.to_owned(),
+    }
+}
 
-    format_and_transfer(callback_url, msg)
+/// Bot env key mapping a flow id or name to the step it starts at instead of
+/// `start`
+pub const ENTRY_STEPS: &str = "entry_steps";
+
+/**
+ * The step a flow starts at: its entry in the bot's `entry_steps`, matched on
+ * the flow id or name ignoring case, or `start`.
+ */
+pub fn get_entry_step(bot: &CsmlBot, flow: &CsmlFlow) -> String {
+    bot.env
+        .as_ref()
+        .and_then(|env| env.get(ENTRY_STEPS))
+        .and_then(|steps| steps.as_object())
+        .and_then(|steps| {
+            steps.iter().find_map(|(key, step)| {
+                let key = key.to_ascii_lowercase();
+                (key == flow.id.to_ascii_lowercase() || key == flow.name.to_ascii_lowercase())
+                    .then(|| step.as_str())
+                    .flatten()
+            })
+        })
+        .unwrap_or("start")
+        .to_owned()
 }
 
-pub fn send_msg_to_callback_url(
+pub async fn send_msg_to_callback_url(
     data: &mut ConversationData,
     msg: Vec<Message>,
     interaction_order: i32,
     end: bool,
-) {
-    let messages = messages_formatter(data, msg, interaction_order, end);
+    pool: &Pool,
+) -> Result<()> {
+    let mut messages = messages_formatter(data, msg, interaction_order, end);
+
+    if data.secure
+        && let Some(Value::Array(msgs)) = messages.get_mut("messages")
+    {
+        for msg in msgs.iter_mut() {
+            msg["payload"] = secure_placeholder();
+        }
+    }
 
     debug!(
         bot_id = data.client.bot_id.to_string(),
@@ -130,7 +272,7 @@ pub fn send_msg_to_callback_url(
         messages["conversation_end"]
     );
 
-    send_to_callback_url(data, serde_json::json!(messages))
+    send_to_callback_url(data, serde_json::json!(messages), pool).await
 }
 
 pub fn update_current_context(
@@ -184,7 +326,9 @@ pub fn get_default_flow(bot: &CsmlBot) -> Result<&CsmlFlow> {
 }
 
 pub async fn clean_hold_and_restart(data: &mut ConversationData, pool: &Pool) -> Result<()> {
-    db::state::delete(&data.client, "hold", "position", pool).await?;
+    if !data.dry_run {
+        db::state::delete(&data.client, "hold", "position", pool).await?;
+    }
     data.context.hold = None;
     Ok(())
 }
@@ -302,6 +446,24 @@ pub fn get_current_step_hash(context: &Context, bot: &CsmlBot) -> Result<String>
     Ok(format!("{:x}", hash.finalize()))
 }
 
+/**
+ * Check whether a flow of an initialized bot (one with a `bot_ast`) has a step
+ * with the given name.
+ */
+pub fn step_exists(bot: &CsmlBot, flow: &CsmlFlow, step: &str) -> Result<bool> {
+    let ast = match &bot.bot_ast {
+        Some(ast) => ast,
+        None => return Err(BitpartErrorKind::Interpreter("not valid ast".to_string()).into()),
+    };
+    let base64decoded = BASE64_STANDARD.decode(ast)?;
+    let csml_bot: HashMap<String, Flow> = bincode::deserialize(&base64decoded[..])?;
+
+    Ok(csml_bot.get(&flow.name).is_some_and(|flow| {
+        flow.flow_instructions
+            .contains_key(&InstructionScope::StepScope(step.to_owned()))
+    }))
+}
+
 pub fn get_ttl_duration_value(event: Option<&Event>) -> Option<chrono::Duration> {
     if let Some(event) = event
         && let Some(ttl) = event.ttl_duration
@@ -318,109 +480,331 @@ pub fn get_ttl_duration_value(event: Option<&Event>) -> Option<chrono::Duration>
     None
 }
 
-// pub fn get_low_data_mode_value(event: &Event) -> bool {
-//     if let Some(low_data) = event.low_data_mode {
-//         return low_data;
-//     }
+/**
+ * Whether the conversation is in low_data mode, where no messages are written
+ * to the `message` table. Set by `low_data_mode` on the event, or else by the
+ * `LOW_DATA_MODE` environment variable. Defaults to on, so that nothing is
+ * stored unless asked for.
+ */
+pub fn get_low_data_mode_value(event: &Event) -> bool {
+    if let Some(low_data) = event.low_data_mode {
+        return low_data;
+    }
 
-//     if let Ok(low_data) = env::var("LOW_DATA_MODE") {
-//         if let Ok(low_data) = low_data.parse::<bool>() {
-//             return low_data;
-//         }
-//     }
+    if let Ok(low_data) = env::var("LOW_DATA_MODE")
+        && let Ok(low_data) = low_data.parse::<bool>()
+    {
+        return low_data;
+    }
 
-//     false
-// }
+    true
+}
 
-pub async fn search_flow<'a>(
+/// A flow picked by one of its commands
+#[derive(Debug)]
+struct CommandMatch<'a> {
+    flow: &'a CsmlFlow,
+    /// The command of the flow that matched the event
+    command: &'a str,
+    /// How many flows had a matching command, one of which was picked
+    candidates: usize,
+}
+
+/// Each flow with a command matching the regex, along with that command. The
+/// regex is compiled once for all the flows' commands.
+fn regex_command_matches<'a>(
+    pattern: &str,
+    flows: &'a [CsmlFlow],
+) -> Result<Vec<(&'a CsmlFlow, &'a str)>> {
+    let action = Regex::new(pattern)
+        .map_err(|e| BitpartErrorKind::Interpreter(format!("invalid regex {pattern}: {e}")))?;
+    Ok(flows
+        .iter()
+        .filter_map(|flow| {
+            flow.commands
+                .iter()
+                .find(|cmd| action.is_match(cmd))
+                .map(|cmd| (flow, cmd.as_str()))
+        })
+        .collect())
+}
+
+/// Each flow with a command equal to the text, ignoring case, along with that
+/// command
+fn command_matches<'a>(text: &str, flows: &'a [CsmlFlow]) -> Vec<(&'a CsmlFlow, &'a str)> {
+    let text = text.to_lowercase();
+    flows
+        .iter()
+        .filter_map(|flow| {
+            flow.commands
+                .iter()
+                .find(|cmd| cmd.to_lowercase() == text)
+                .map(|cmd| (flow, cmd.as_str()))
+        })
+        .collect()
+}
+
+/// Pick one of the flows whose command matched at random
+fn pick_command_match<'a>(matches: Vec<(&'a CsmlFlow, &'a str)>) -> Option<CommandMatch<'a>> {
+    if matches.is_empty() {
+        return None;
+    }
+    let candidates = matches.len();
+    let (flow, command) = matches[thread_rng().gen_range(0..candidates)];
+    Some(CommandMatch {
+        flow,
+        command,
+        candidates,
+    })
+}
+
+/// Log which command picked the flow, and out of how many, to help debug
+/// misrouted events
+fn log_command_match(found: &CommandMatch) {
+    info!(
+        matched_command = found.command,
+        candidates = found.candidates,
+        flow = %found.flow.id,
+        "flow picked by command"
+    );
+}
+
+/// Bot `env` key that, when true, has a `switch_bot` deliver the user's event
+/// to the new bot instead of a bare `flow_trigger`, so it can act on what the
+/// user said
+pub const FORWARD_EVENT_ON_SWITCH: &str = "switch_bot_forward_event";
+
+/**
+ * Whether the bot forwards the triggering event on a `switch_bot`.
+ */
+pub fn get_forward_event_on_switch(bot: &CsmlBot) -> bool {
+    bot.env
+        .as_ref()
+        .and_then(|env| env.get(FORWARD_EVENT_ON_SWITCH))
+        .and_then(|forward| forward.as_bool())
+        .unwrap_or(false)
+}
+
+/// Bot `env` key naming the flow run when an event asks for a flow that
+/// nothing matches, instead of the default flow
+pub const FALLBACK_FLOW: &str = "fallback_flow";
+
+/**
+ * The bot's fallback flow, if it names one that exists.
+ */
+pub fn get_fallback_flow(bot: &CsmlBot) -> Option<&CsmlFlow> {
+    let flow_id = bot.env.as_ref()?.get(FALLBACK_FLOW)?.as_str()?;
+    match get_flow_by_id(flow_id, &bot.flows) {
+        Ok(flow) => Some(flow),
+        Err(_) => {
+            warn!(bot_id = %bot.id, flow_id, "fallback flow does not exist");
+            None
+        }
+    }
+}
+
+/// Bot `env` key listing the prefixes that mark free text as a command, `/`
+/// and `!` unless set
+pub const COMMAND_PREFIXES: &str = "command_prefixes";
+
+const DEFAULT_COMMAND_PREFIXES: [&str; 2] = ["/", "!"];
+
+/**
+ * Whether free text looks like a command, by starting with one of the bot's
+ * command prefixes. Such text that matches no flow goes to the fallback flow
+ * rather than answering the current conversation.
+ */
+pub fn is_command_like(bot: &CsmlBot, text: &str) -> bool {
+    let text = text.trim_start();
+    match bot
+        .env
+        .as_ref()
+        .and_then(|env| env.get(COMMAND_PREFIXES))
+        .and_then(|prefixes| prefixes.as_array())
+    {
+        Some(prefixes) => prefixes
+            .iter()
+            .filter_map(|prefix| prefix.as_str())
+            .any(|prefix| !prefix.is_empty() && text.starts_with(prefix)),
+        None => DEFAULT_COMMAND_PREFIXES
+            .iter()
+            .any(|prefix| text.starts_with(prefix)),
+    }
+}
+
+fn start_fallback_flow<'a>(
     event: &Event,
-    bot: &'a CsmlBot,
-    client: &Client,
-    pool: &Pool,
-) -> Result<(&'a CsmlFlow, String)> {
+    bot: &CsmlBot,
+    flow: &'a CsmlFlow,
+) -> (&'a CsmlFlow, String) {
+    info!(
+        content_type = event.content_type,
+        flow = %flow.id,
+        "no flow matched, starting the fallback flow"
+    );
+    (flow, get_entry_step(bot, flow))
+}
+
+/**
+ * The flow and step an event asks for, if any. Unlike `search_flow`, this
+ * leaves any hold in place.
+ */
+pub fn find_flow<'a>(event: &Event, bot: &'a CsmlBot) -> Result<(&'a CsmlFlow, String)> {
     match event {
         event if event.content_type == "flow_trigger" => {
-            db::state::delete(client, "hold", "position", pool).await?;
-
             let flow_trigger: FlowTrigger = serde_json::from_str(&event.content_value)?;
 
             match get_flow_by_id(&flow_trigger.flow_id, &bot.flows) {
                 Ok(flow) => match flow_trigger.step_id {
                     Some(step_id) => Ok((flow, step_id)),
-                    None => Ok((flow, "start".to_owned())),
+                    None => Ok((flow, get_entry_step(bot, flow))),
                 },
-                Err(_) => Ok((
-                    get_flow_by_id(&bot.default_flow, &bot.flows)?,
-                    "start".to_owned(),
-                )),
+                Err(_) => {
+                    let flow = match get_fallback_flow(bot) {
+                        Some(flow) => flow,
+                        None => get_flow_by_id(&bot.default_flow, &bot.flows)?,
+                    };
+                    Ok((flow, get_entry_step(bot, flow)))
+                }
             }
         }
         event if event.content_type == "regex" => {
-            let mut random_flows = vec![];
-
-            for flow in bot.flows.iter() {
-                let contains_command = flow.commands.iter().any(|cmd| {
-                    if let Ok(action) = Regex::new(&event.content_value) {
-                        action.is_match(cmd)
-                    } else {
-                        false
-                    }
-                });
-
-                if contains_command {
-                    random_flows.push(flow)
+            match pick_command_match(regex_command_matches(&event.content_value, &bot.flows)?) {
+                Some(found) => {
+                    log_command_match(&found);
+                    Ok((found.flow, get_entry_step(bot, found.flow)))
                 }
-            }
-
-            // gen_range will panic if range is empty
-            let random = if !random_flows.is_empty() {
-                thread_rng().gen_range(0..random_flows.len())
-            } else {
-                0
-            };
-            match random_flows.get(random) {
-                Some(flow) => {
-                    db::state::delete(client, "hold", "position", pool).await?;
-                    Ok((flow, "start".to_owned()))
-                }
-                None => Err(BitpartErrorKind::Interpreter(format!(
-                    "no match found for regex: {}",
-                    event.content_value
-                ))
-                .into()),
+                None => match get_fallback_flow(bot) {
+                    Some(flow) => Ok(start_fallback_flow(event, bot, flow)),
+                    None => Err(BitpartErrorKind::Interpreter(format!(
+                        "no match found for regex: {}",
+                        event.content_value
+                    ))
+                    .into()),
+                },
             }
         }
-        event => {
-            let mut random_flows = vec![];
-
-            for flow in bot.flows.iter() {
-                let contains_command = flow
-                    .commands
-                    .iter()
-                    .any(|cmd| cmd.as_str().to_lowercase() == event.content_value.to_lowercase());
-
-                if contains_command {
-                    random_flows.push(flow)
-                }
+        event => match pick_command_match(command_matches(&event.content_value, &bot.flows)) {
+            Some(found) => {
+                log_command_match(&found);
+                Ok((found.flow, get_entry_step(bot, found.flow)))
             }
-
-            // gen_range will panic if range is empty
-            let random = if !random_flows.is_empty() {
-                thread_rng().gen_range(0..random_flows.len())
-            } else {
-                0
-            };
-            match random_flows.get(random) {
-                Some(flow) => {
-                    db::state::delete(client, "hold", "position", pool).await?;
-                    Ok((flow, "start".to_owned()))
+            // free text that isn't a command answers the current conversation,
+            // but a button payload or a command was meant to pick a flow
+            None => match get_fallback_flow(bot) {
+                Some(flow)
+                    if event.content_type == "payload"
+                        || (event.content_type == "text"
+                            && is_command_like(bot, &event.content_value)) =>
+                {
+                    Ok(start_fallback_flow(event, bot, flow))
                 }
-                None => Err(BitpartErrorKind::Interpreter(format!(
+                _ => Err(BitpartErrorKind::Interpreter(format!(
                     "Flow '{}' does not exist",
                     event.content_value
                 ))
                 .into()),
-            }
-        }
+            },
+        },
+    }
+}
+
+/**
+ * The flow and step an event asks for, if any. A new flow replaces whatever
+ * the conversation was holding on, as does any `flow_trigger`.
+ */
+pub async fn search_flow<'a>(
+    event: &Event,
+    bot: &'a CsmlBot,
+    client: &Client,
+    pool: &Pool,
+) -> Result<(&'a CsmlFlow, String)> {
+    let found = find_flow(event, bot);
+    if found.is_ok() || event.content_type == "flow_trigger" {
+        db::state::delete(client, "hold", "position", pool).await?;
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flows() -> Vec<CsmlFlow> {
+        serde_json::from_value(json!([
+            {
+                "id": "Greeting",
+                "name": "Greeting",
+                "content": "start: goto end",
+                "commands": ["/hello", "/hi"],
+            },
+            {
+                "id": "Help",
+                "name": "Help",
+                "content": "start: goto end",
+                "commands": ["/help"],
+            },
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn it_should_report_the_matched_command() {
+        let flows = flows();
+
+        let found = pick_command_match(command_matches("/HI", &flows)).unwrap();
+        assert_eq!(found.flow.id, "Greeting");
+        assert_eq!(found.command, "/hi");
+        assert_eq!(found.candidates, 1);
+
+        let found = pick_command_match(regex_command_matches("^/h", &flows).unwrap()).unwrap();
+        assert_eq!(found.candidates, 2);
+        assert!(["/hello", "/help"].contains(&found.command));
+
+        assert!(pick_command_match(command_matches("/bye", &flows)).is_none());
+    }
+
+    #[test]
+    fn it_should_recognise_command_like_text() {
+        let mut bot: CsmlBot = serde_json::from_value(json!({
+            "id": "bot_id",
+            "name": "test",
+            "flows": [],
+            "default_flow": "Default",
+        }))
+        .unwrap();
+        assert!(is_command_like(&bot, "/unknown"));
+        assert!(is_command_like(&bot, "  !nope"));
+        assert!(!is_command_like(&bot, "hello /there"));
+        assert!(!is_command_like(&bot, "#tag"));
+
+        bot.env = Some(json!({ COMMAND_PREFIXES: ["#", ""] }));
+        assert!(is_command_like(&bot, "#tag"));
+        assert!(!is_command_like(&bot, "/unknown"));
+        assert!(!is_command_like(&bot, "hello"));
+    }
+
+    #[test]
+    fn it_should_match_a_regex_against_many_flows() {
+        let flows: Vec<CsmlFlow> = (0..1000)
+            .map(|i| {
+                serde_json::from_value(json!({
+                    "id": format!("Flow{i}"),
+                    "name": format!("Flow{i}"),
+                    "content": "start: goto end",
+                    "commands": [format!("/flow{i}"), format!("/other{i}")],
+                }))
+                .unwrap()
+            })
+            .collect();
+
+        let matches = regex_command_matches(r"^/flow\d*7$", &flows).unwrap();
+        assert_eq!(matches.len(), 100);
+        assert!(matches.iter().all(|(_, command)| command.ends_with('7')));
+
+        let Err(err) = regex_command_matches("(", &flows) else {
+            panic!("expected an invalid regex to be an error");
+        };
+        assert!(err.to_string().contains("invalid regex ("));
     }
 }
@@ -22,7 +22,7 @@ use serde::{Deserialize, Serialize};
 use std::env;
 use uuid::Uuid;
 
-use crate::csml::data::BotVersion;
+use crate::csml::{callback::CALLBACK_SECRET, data::BotVersion};
 
 fn pool_err(e: impl std::fmt::Display) -> BitpartErrorKind {
     BitpartErrorKind::Pool(e.to_string())
@@ -82,6 +82,7 @@ struct BotRow {
     id: String,
     bot_id: String,
     bot_json: String,
+    engine_version: String,
 }
 
 impl BotRow {
@@ -91,7 +92,7 @@ impl BotRow {
         Ok(BotVersion {
             version_id: row_id,
             bot: bot.into(),
-            engine_version: env!("CARGO_PKG_VERSION").to_owned(),
+            engine_version: self.engine_version,
         })
     }
 
@@ -100,7 +101,7 @@ impl BotRow {
         Ok(BotVersion {
             version_id: bot.id.clone(),
             bot: bot.into(),
-            engine_version: env!("CARGO_PKG_VERSION").to_owned(),
+            engine_version: self.engine_version,
         })
     }
 }
@@ -129,6 +130,87 @@ pub async fn list(limit: Option<u64>, offset: Option<u64>, db: &Pool) -> Result<
     Ok(res)
 }
 
+/// Summary of the latest version of a bot, as returned by a detailed `ListBots`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BotSummary {
+    pub id: String,
+    pub name: String,
+    pub version_id: String,
+    pub engine_version: String,
+    pub flow_count: u64,
+}
+
+pub async fn list_summaries(
+    limit: Option<u64>,
+    offset: Option<u64>,
+    db: &Pool,
+) -> Result<Vec<BotSummary>> {
+    let obj = db.get().await.map_err(pool_err)?;
+    let res = obj
+        .interact(move |conn| -> rusqlite::Result<Vec<BotSummary>> {
+            let lim: i64 = limit.map(|n| n as i64).unwrap_or(-1);
+            let off: i64 = offset.map(|n| n as i64).unwrap_or(0);
+            let mut stmt = conn.prepare(
+                "SELECT b.bot_id, json_extract(b.bot, '$.name'), b.id, b.engine_version, \
+                        json_array_length(b.bot, '$.flows') \
+                 FROM (SELECT DISTINCT bot_id FROM bot) AS ids \
+                 JOIN bot AS b ON b.rowid = ( \
+                     SELECT latest.rowid FROM bot AS latest \
+                     WHERE latest.bot_id = ids.bot_id \
+                     ORDER BY latest.updated_at DESC, latest.rowid DESC \
+                     LIMIT 1) \
+                 ORDER BY b.created_at DESC \
+                 LIMIT ? OFFSET ?",
+            )?;
+            let rows = stmt.query_map(params![lim, off], |r| {
+                Ok(BotSummary {
+                    id: r.get(0)?,
+                    name: r.get(1)?,
+                    version_id: r.get(2)?,
+                    engine_version: r.get(3)?,
+                    flow_count: r.get(4)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(res)
+}
+
+/**
+ * The latest version of every bot.
+ */
+pub async fn list_latest(db: &Pool) -> Result<Vec<BotVersion>> {
+    let obj = db.get().await.map_err(pool_err)?;
+    let rows = obj
+        .interact(move |conn| -> rusqlite::Result<Vec<BotRow>> {
+            let mut stmt = conn.prepare(
+                "SELECT b.id, b.bot_id, b.bot, b.engine_version \
+                 FROM (SELECT DISTINCT bot_id FROM bot) AS ids \
+                 JOIN bot AS b ON b.rowid = ( \
+                     SELECT latest.rowid FROM bot AS latest \
+                     WHERE latest.bot_id = ids.bot_id \
+                     ORDER BY latest.updated_at DESC, latest.rowid DESC \
+                     LIMIT 1) \
+                 ORDER BY b.created_at DESC",
+            )?;
+            let rows = stmt.query_map([], |r| {
+                Ok(BotRow {
+                    id: r.get(0)?,
+                    bot_id: r.get(1)?,
+                    bot_json: r.get(2)?,
+                    engine_version: r.get(3)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+        .map_err(pool_err)??;
+
+    rows.into_iter().map(|r| r.into_version_row_id()).collect()
+}
+
 pub async fn get(
     bot_id: &str,
     limit: Option<u64>,
@@ -142,9 +224,9 @@ pub async fn get(
             let lim: i64 = limit.map(|n| n as i64).unwrap_or(-1);
             let off: i64 = offset.map(|n| n as i64).unwrap_or(0);
             let mut stmt = conn.prepare(
-                "SELECT id, bot_id, bot FROM bot \
+                "SELECT id, bot_id, bot, engine_version FROM bot \
                  WHERE bot_id = ? \
-                 ORDER BY updated_at DESC \
+                 ORDER BY updated_at DESC, rowid DESC \
                  LIMIT ? OFFSET ?",
             )?;
             let rows = stmt.query_map(params![bot_id, lim, off], |r| {
@@ -152,6 +234,7 @@ pub async fn get(
                     id: r.get(0)?,
                     bot_id: r.get(1)?,
                     bot_json: r.get(2)?,
+                    engine_version: r.get(3)?,
                 })
             })?;
             let mut out = Vec::new();
@@ -174,13 +257,15 @@ pub async fn get_by_id(id: &str, db: &Pool) -> Result<Option<BotVersion>> {
     let obj = db.get().await.map_err(pool_err)?;
     let row = obj
         .interact(move |conn| -> rusqlite::Result<Option<BotRow>> {
-            let mut stmt = conn.prepare("SELECT id, bot_id, bot FROM bot WHERE id = ?")?;
+            let mut stmt =
+                conn.prepare("SELECT id, bot_id, bot, engine_version FROM bot WHERE id = ?")?;
             let row = stmt
                 .query_row(params![id], |r| {
                     Ok(BotRow {
                         id: r.get(0)?,
                         bot_id: r.get(1)?,
                         bot_json: r.get(2)?,
+                        engine_version: r.get(3)?,
                     })
                 })
                 .optional()?;
@@ -201,9 +286,9 @@ pub async fn get_latest_by_bot_id(bot_id: &str, db: &Pool) -> Result<Option<BotV
     let row = obj
         .interact(move |conn| -> rusqlite::Result<Option<BotRow>> {
             let mut stmt = conn.prepare(
-                "SELECT id, bot_id, bot FROM bot \
+                "SELECT id, bot_id, bot, engine_version FROM bot \
                  WHERE bot_id = ? \
-                 ORDER BY updated_at DESC \
+                 ORDER BY updated_at DESC, rowid DESC \
                  LIMIT 1",
             )?;
             let row = stmt
@@ -212,6 +297,7 @@ pub async fn get_latest_by_bot_id(bot_id: &str, db: &Pool) -> Result<Option<BotV
                         id: r.get(0)?,
                         bot_id: r.get(1)?,
                         bot_json: r.get(2)?,
+                        engine_version: r.get(3)?,
                     })
                 })
                 .optional()?;
@@ -226,13 +312,71 @@ pub async fn get_latest_by_bot_id(bot_id: &str, db: &Pool) -> Result<Option<BotV
     }
 }
 
+/**
+ * The secret the latest version of a bot signs its callbacks with, if it has
+ * its own.
+ */
+pub async fn get_callback_secret(bot_id: &str, db: &Pool) -> Result<Option<String>> {
+    let bot_id = bot_id.to_owned();
+    let obj = db.get().await.map_err(pool_err)?;
+    let secret = obj
+        .interact(move |conn| -> rusqlite::Result<Option<String>> {
+            conn.query_row(
+                "SELECT callback_secret FROM bot \
+                 WHERE bot_id = ? \
+                 ORDER BY updated_at DESC, rowid DESC \
+                 LIMIT 1",
+                params![bot_id],
+                |r| r.get(0),
+            )
+            .optional()
+            .map(Option::flatten)
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(secret)
+}
+
+/**
+ * The secret one version of a bot signs its callbacks with, if it has its
+ * own.
+ */
+pub async fn get_callback_secret_by_id(id: &str, db: &Pool) -> Result<Option<String>> {
+    let id = id.to_owned();
+    let obj = db.get().await.map_err(pool_err)?;
+    let secret = obj
+        .interact(move |conn| -> rusqlite::Result<Option<String>> {
+            conn.query_row(
+                "SELECT callback_secret FROM bot WHERE id = ?",
+                params![id],
+                |r| r.get(0),
+            )
+            .optional()
+            .map(Option::flatten)
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(secret)
+}
+
 // =====================================================================
 // Write functions
 // =====================================================================
 
-pub async fn create(bot: CsmlBot, db: &Pool) -> Result<BotVersion> {
+/**
+ * Store a new version of a bot. Its callback secret, if its `env` has one, is
+ * taken out and kept separately, so that it isn't returned with the bot or
+ * visible to its flows, see `get_callback_secret`.
+ */
+pub async fn create(mut bot: CsmlBot, db: &Pool) -> Result<BotVersion> {
     let row_id = Uuid::new_v4().to_string();
     let bot_id = bot.id.clone();
+    let callback_secret = bot
+        .env
+        .as_mut()
+        .and_then(|env| env.as_object_mut())
+        .and_then(|env| env.remove(CALLBACK_SECRET))
+        .and_then(|secret| secret.as_str().map(|secret| secret.to_owned()));
     let bot_json = bot.to_json().to_string();
     let engine_version = env!("CARGO_PKG_VERSION").to_owned();
 
@@ -245,8 +389,9 @@ pub async fn create(bot: CsmlBot, db: &Pool) -> Result<BotVersion> {
             // future-proofs against schema drift. `created_at`/`updated_at`
             // get their `CURRENT_TIMESTAMP` defaults.
             conn.execute(
-                "INSERT INTO bot (id, bot_id, bot, engine_version) VALUES (?, ?, ?, ?)",
-                params![row_id, bot_id, bot_json, engine_version],
+                "INSERT INTO bot (id, bot_id, bot, engine_version, callback_secret) \
+                 VALUES (?, ?, ?, ?, ?)",
+                params![row_id, bot_id, bot_json, engine_version, callback_secret],
             )?;
             Ok(bot_json)
         })
@@ -262,41 +407,25 @@ pub async fn create(bot: CsmlBot, db: &Pool) -> Result<BotVersion> {
     })
 }
 
-pub async fn touch(id: &str, version_id: &str, db: &Pool) -> Result<Option<BotVersion>> {
-    let id = id.to_owned();
+/**
+ * Overwrite a stored bot version in place, stamping it with the running
+ * engine version.
+ */
+pub async fn update(version_id: &str, bot: &CsmlBot, db: &Pool) -> Result<()> {
     let version_id = version_id.to_owned();
+    let bot_json = bot.to_json().to_string();
+    let engine_version = env!("CARGO_PKG_VERSION").to_owned();
 
     let obj = db.get().await.map_err(pool_err)?;
-    let row = obj
-        .interact(move |conn| -> rusqlite::Result<Option<BotRow>> {
-            let mut stmt =
-                conn.prepare("SELECT id, bot_id, bot FROM bot WHERE id = ? AND bot_id = ?")?;
-            let row = stmt
-                .query_row(params![version_id, id], |r| {
-                    Ok(BotRow {
-                        id: r.get(0)?,
-                        bot_id: r.get(1)?,
-                        bot_json: r.get(2)?,
-                    })
-                })
-                .optional()?;
-            if let Some(ref r) = row {
-                // Fire the AFTER UPDATE trigger. The SET is a no-op on
-                // column values; SQLite still treats the row as updated.
-                conn.execute(
-                    "UPDATE bot SET engine_version = engine_version WHERE id = ?",
-                    params![r.id],
-                )?;
-            }
-            Ok(row)
-        })
-        .await
-        .map_err(pool_err)??;
-
-    match row {
-        Some(r) => Ok(Some(r.into_version_row_id()?)),
-        None => Ok(None),
-    }
+    obj.interact(move |conn| -> rusqlite::Result<usize> {
+        conn.execute(
+            "UPDATE bot SET bot = ?, engine_version = ? WHERE id = ?",
+            params![bot_json, engine_version, version_id],
+        )
+    })
+    .await
+    .map_err(pool_err)??;
+    Ok(())
 }
 
 pub async fn delete_by_bot_id(bot_id: &str, db: &Pool) -> Result<()> {
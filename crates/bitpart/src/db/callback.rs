@@ -0,0 +1,220 @@
+// Bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use bitpart_common::db::Pool;
+use bitpart_common::error::{BitpartErrorKind, Result};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+fn pool_err(e: impl std::fmt::Display) -> BitpartErrorKind {
+    BitpartErrorKind::Pool(e.to_string())
+}
+
+pub const STATUS_PENDING: &str = "PENDING";
+pub const STATUS_DEAD: &str = "DEAD";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Model {
+    pub id: String,
+    pub url: String,
+    pub payload: String,
+    /// The bot the callback was queued for, unset for those queued before
+    /// bots were recorded
+    pub bot_id: Option<String>,
+    pub attempts: i32,
+    pub status: String,
+    pub last_error: Option<String>,
+    pub next_attempt_at: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+const SELECT_COLS: &str = "id, url, payload, bot_id, attempts, status, last_error, \
+                          next_attempt_at, created_at, updated_at";
+
+fn row_to_model(r: &rusqlite::Row<'_>) -> rusqlite::Result<Model> {
+    Ok(Model {
+        id: r.get("id")?,
+        url: r.get("url")?,
+        payload: r.get("payload")?,
+        bot_id: r.get("bot_id")?,
+        attempts: r.get("attempts")?,
+        status: r.get("status")?,
+        last_error: r.get("last_error")?,
+        next_attempt_at: r.get("next_attempt_at")?,
+        created_at: r.get("created_at")?,
+        updated_at: r.get("updated_at")?,
+    })
+}
+
+pub async fn create(url: &str, payload: &Value, bot_id: &str, db: &Pool) -> Result<String> {
+    let id = Uuid::new_v4().to_string();
+    let url = url.to_owned();
+    let payload = payload.to_string();
+    let bot_id = bot_id.to_owned();
+
+    let obj = db.get().await.map_err(pool_err)?;
+    let id_clone = id.clone();
+    obj.interact(move |conn| -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT INTO callback_queue (id, url, payload, bot_id, status) \
+             VALUES (?, ?, ?, ?, ?)",
+            params![id_clone, url, payload, bot_id, STATUS_PENDING],
+        )?;
+        Ok(())
+    })
+    .await
+    .map_err(pool_err)??;
+    Ok(id)
+}
+
+pub async fn count_by_status(status: &str, db: &Pool) -> Result<u64> {
+    let status = status.to_owned();
+    let obj = db.get().await.map_err(pool_err)?;
+    let count = obj
+        .interact(move |conn| -> rusqlite::Result<i64> {
+            conn.query_row(
+                "SELECT COUNT(*) FROM callback_queue WHERE status = ?",
+                params![status],
+                |r| r.get(0),
+            )
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(count as u64)
+}
+
+/**
+ * Pending entries whose next attempt is due, oldest first. An entry is held
+ * back while an older entry for the same url is still pending, so callbacks
+ * to one endpoint are always delivered in order.
+ */
+pub async fn get_due(limit: Option<u64>, db: &Pool) -> Result<Vec<Model>> {
+    let obj = db.get().await.map_err(pool_err)?;
+    let rows = obj
+        .interact(move |conn| -> rusqlite::Result<Vec<Model>> {
+            let lim: i64 = limit.map(|n| n as i64).unwrap_or(-1);
+            let sql = format!(
+                "SELECT {SELECT_COLS} FROM callback_queue AS q \
+                 WHERE q.status = ?1 AND q.next_attempt_at <= CURRENT_TIMESTAMP \
+                   AND NOT EXISTS ( \
+                     SELECT 1 FROM callback_queue AS older \
+                     WHERE older.url = q.url AND older.status = ?1 \
+                       AND older.rowid < q.rowid) \
+                 ORDER BY q.rowid LIMIT ?2"
+            );
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(params![STATUS_PENDING, lim], row_to_model)?;
+            rows.collect()
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(rows)
+}
+
+/**
+ * Record a failed delivery attempt. With `retry_in_secs` the entry is retried
+ * after that delay, without it the entry is moved to the dead-letter state.
+ */
+pub async fn set_failed(
+    id: &str,
+    error: &str,
+    retry_in_secs: Option<i64>,
+    db: &Pool,
+) -> Result<()> {
+    let id = id.to_owned();
+    let error = error.to_owned();
+    let obj = db.get().await.map_err(pool_err)?;
+    obj.interact(move |conn| -> rusqlite::Result<()> {
+        match retry_in_secs {
+            Some(secs) => conn.execute(
+                "UPDATE callback_queue \
+                 SET attempts = attempts + 1, last_error = ?, \
+                     next_attempt_at = datetime('now', ?) \
+                 WHERE id = ?",
+                params![error, format!("+{secs} seconds"), id],
+            )?,
+            None => conn.execute(
+                "UPDATE callback_queue \
+                 SET attempts = attempts + 1, last_error = ?, status = ? \
+                 WHERE id = ?",
+                params![error, STATUS_DEAD, id],
+            )?,
+        };
+        Ok(())
+    })
+    .await
+    .map_err(pool_err)??;
+    Ok(())
+}
+
+/**
+ * Entries that were given up on, oldest first, with the last error each
+ * failed with.
+ */
+pub async fn get_dead(limit: Option<u64>, offset: Option<u64>, db: &Pool) -> Result<Vec<Model>> {
+    let obj = db.get().await.map_err(pool_err)?;
+    let rows = obj
+        .interact(move |conn| -> rusqlite::Result<Vec<Model>> {
+            let lim: i64 = limit.map(|n| n as i64).unwrap_or(-1);
+            let off: i64 = offset.map(|n| n as i64).unwrap_or(0);
+            let sql = format!(
+                "SELECT {SELECT_COLS} FROM callback_queue \
+                 WHERE status = ? ORDER BY rowid LIMIT ? OFFSET ?"
+            );
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(params![STATUS_DEAD, lim, off], row_to_model)?;
+            rows.collect()
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(rows)
+}
+
+/**
+ * Move dead entries, or only those to `url`, back to pending with their
+ * attempts reset, so they are delivered again. Returns the number moved.
+ */
+pub async fn requeue_dead(url: Option<&str>, db: &Pool) -> Result<usize> {
+    let url = url.map(|u| u.to_owned());
+    let obj = db.get().await.map_err(pool_err)?;
+    let requeued = obj
+        .interact(move |conn| -> rusqlite::Result<usize> {
+            conn.execute(
+                "UPDATE callback_queue \
+                 SET status = ?1, attempts = 0, next_attempt_at = CURRENT_TIMESTAMP \
+                 WHERE status = ?2 AND (?3 IS NULL OR url = ?3)",
+                params![STATUS_PENDING, STATUS_DEAD, url],
+            )
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(requeued)
+}
+
+pub async fn delete(id: &str, db: &Pool) -> Result<()> {
+    let id = id.to_owned();
+    let obj = db.get().await.map_err(pool_err)?;
+    obj.interact(move |conn| -> rusqlite::Result<()> {
+        conn.execute("DELETE FROM callback_queue WHERE id = ?", params![id])?;
+        Ok(())
+    })
+    .await
+    .map_err(pool_err)??;
+    Ok(())
+}
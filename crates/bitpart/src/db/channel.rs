@@ -26,6 +26,10 @@ pub struct Model {
     pub id: String,
     pub bot_id: String,
     pub channel_id: String,
+    pub last_received_at: Option<String>,
+    pub online: bool,
+    /// Overrides the bot's `apps_endpoint` for requests from this channel
+    pub apps_endpoint: Option<String>,
     pub updated_at: String,
     pub created_at: String,
 }
@@ -35,6 +39,9 @@ fn row_to_model(r: &rusqlite::Row<'_>) -> rusqlite::Result<Model> {
         id: r.get("id")?,
         bot_id: r.get("bot_id")?,
         channel_id: r.get("channel_id")?,
+        last_received_at: r.get("last_received_at")?,
+        online: r.get("online")?,
+        apps_endpoint: r.get("apps_endpoint")?,
         updated_at: r.get("updated_at")?,
         created_at: r.get("created_at")?,
     })
@@ -76,7 +83,8 @@ pub async fn list(limit: Option<u64>, offset: Option<u64>, db: &Pool) -> Result<
             let lim: i64 = limit.map(|n| n as i64).unwrap_or(-1);
             let off: i64 = offset.map(|n| n as i64).unwrap_or(0);
             let mut stmt = conn.prepare(
-                "SELECT id, bot_id, channel_id, updated_at, created_at FROM channel \
+                "SELECT id, bot_id, channel_id, last_received_at, online, apps_endpoint, updated_at, created_at \
+                 FROM channel \
                  ORDER BY created_at DESC \
                  LIMIT ? OFFSET ?",
             )?;
@@ -99,7 +107,8 @@ pub async fn get(channel_id: &str, bot_id: &str, db: &Pool) -> Result<Option<Mod
     let row = obj
         .interact(move |conn| -> rusqlite::Result<Option<Model>> {
             let mut stmt = conn.prepare(
-                "SELECT id, bot_id, channel_id, updated_at, created_at FROM channel \
+                "SELECT id, bot_id, channel_id, last_received_at, online, apps_endpoint, updated_at, created_at \
+                 FROM channel \
                  WHERE bot_id = ? AND channel_id = ? LIMIT 1",
             )?;
             stmt.query_row(params![bot_id, channel_id], row_to_model)
@@ -116,7 +125,8 @@ pub async fn get_by_id(id: &str, db: &Pool) -> Result<Option<Model>> {
     let row = obj
         .interact(move |conn| -> rusqlite::Result<Option<Model>> {
             let mut stmt = conn.prepare(
-                "SELECT id, bot_id, channel_id, updated_at, created_at FROM channel \
+                "SELECT id, bot_id, channel_id, last_received_at, online, apps_endpoint, updated_at, created_at \
+                 FROM channel \
                  WHERE id = ?",
             )?;
             stmt.query_row(params![id], row_to_model).optional()
@@ -132,7 +142,8 @@ pub async fn get_by_bot_id(bot_id: &str, db: &Pool) -> Result<Vec<Model>> {
     let rows = obj
         .interact(move |conn| -> rusqlite::Result<Vec<Model>> {
             let mut stmt = conn.prepare(
-                "SELECT id, bot_id, channel_id, updated_at, created_at FROM channel \
+                "SELECT id, bot_id, channel_id, last_received_at, online, apps_endpoint, updated_at, created_at \
+                 FROM channel \
                  WHERE bot_id = ?",
             )?;
             let rows = stmt.query_map(params![bot_id], row_to_model)?;
@@ -147,6 +158,61 @@ pub async fn get_by_bot_id(bot_id: &str, db: &Pool) -> Result<Vec<Model>> {
     Ok(rows)
 }
 
+/**
+ * Record that the channel has just received a message.
+ */
+pub async fn set_last_received(channel_id: &str, bot_id: &str, db: &Pool) -> Result<()> {
+    let channel_id = channel_id.to_owned();
+    let bot_id = bot_id.to_owned();
+    let obj = db.get().await.map_err(pool_err)?;
+    obj.interact(move |conn| -> rusqlite::Result<usize> {
+        conn.execute(
+            "UPDATE channel SET last_received_at = CURRENT_TIMESTAMP \
+             WHERE bot_id = ? AND channel_id = ?",
+            params![bot_id, channel_id],
+        )
+    })
+    .await
+    .map_err(pool_err)??;
+    Ok(())
+}
+
+pub async fn set_online(channel_id: &str, bot_id: &str, online: bool, db: &Pool) -> Result<()> {
+    let channel_id = channel_id.to_owned();
+    let bot_id = bot_id.to_owned();
+    let obj = db.get().await.map_err(pool_err)?;
+    obj.interact(move |conn| -> rusqlite::Result<usize> {
+        conn.execute(
+            "UPDATE channel SET online = ? WHERE bot_id = ? AND channel_id = ?",
+            params![online, bot_id, channel_id],
+        )
+    })
+    .await
+    .map_err(pool_err)??;
+    Ok(())
+}
+
+pub async fn set_apps_endpoint(
+    channel_id: &str,
+    bot_id: &str,
+    apps_endpoint: Option<&str>,
+    db: &Pool,
+) -> Result<()> {
+    let channel_id = channel_id.to_owned();
+    let bot_id = bot_id.to_owned();
+    let apps_endpoint = apps_endpoint.map(str::to_owned);
+    let obj = db.get().await.map_err(pool_err)?;
+    obj.interact(move |conn| -> rusqlite::Result<usize> {
+        conn.execute(
+            "UPDATE channel SET apps_endpoint = ? WHERE bot_id = ? AND channel_id = ?",
+            params![apps_endpoint, bot_id, channel_id],
+        )
+    })
+    .await
+    .map_err(pool_err)??;
+    Ok(())
+}
+
 pub async fn delete(channel_id: &str, bot_id: &str, db: &Pool) -> Result<()> {
     let channel_id_owned = channel_id.to_owned();
     let bot_id_owned = bot_id.to_owned();
@@ -191,3 +257,38 @@ pub async fn delete_by_id(id: &str, db: &Pool) -> Result<()> {
     .map_err(pool_err)??;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::get_test_pool;
+
+    #[tokio::test]
+    async fn it_should_record_last_received() {
+        let pool = get_test_pool().await;
+        create("signal", "bot_id", &pool).await.unwrap();
+
+        let channel = get("signal", "bot_id", &pool).await.unwrap().unwrap();
+        assert_eq!(channel.last_received_at, None);
+
+        set_last_received("signal", "bot_id", &pool).await.unwrap();
+
+        let channel = get("signal", "bot_id", &pool).await.unwrap().unwrap();
+        assert!(channel.last_received_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn it_should_keep_an_apps_endpoint() {
+        let pool = get_test_pool().await;
+        create("signal", "bot_id", &pool).await.unwrap();
+
+        let channel = get("signal", "bot_id", &pool).await.unwrap().unwrap();
+        assert_eq!(channel.apps_endpoint, None);
+
+        set_apps_endpoint("signal", "bot_id", Some("http://apps"), &pool)
+            .await
+            .unwrap();
+        let channel = get("signal", "bot_id", &pool).await.unwrap().unwrap();
+        assert_eq!(channel.apps_endpoint.as_deref(), Some("http://apps"));
+    }
+}
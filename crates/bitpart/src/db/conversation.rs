@@ -161,6 +161,27 @@ pub async fn get_latest_open_by_client(client: &Client, db: &Pool) -> Result<Opt
     Ok(row)
 }
 
+pub async fn get_latest_by_client(client: &Client, db: &Pool) -> Result<Option<Model>> {
+    let bot_id = client.bot_id.clone();
+    let channel_id = client.channel_id.clone();
+    let user_id = client.user_id.clone();
+    let obj = db.get().await.map_err(pool_err)?;
+    let row = obj
+        .interact(move |conn| -> rusqlite::Result<Option<Model>> {
+            let sql = format!(
+                "SELECT {SELECT_COLS} FROM conversation \
+                 WHERE bot_id = ? AND channel_id = ? AND user_id = ? \
+                 ORDER BY created_at DESC LIMIT 1"
+            );
+            let mut stmt = conn.prepare(&sql)?;
+            stmt.query_row(params![bot_id, channel_id, user_id], row_to_model)
+                .optional()
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(row)
+}
+
 pub async fn get_by_client(
     client: &Client,
     limit: Option<u64>,
@@ -170,6 +191,7 @@ pub async fn get_by_client(
     let bot_id = client.bot_id.clone();
     let channel_id = client.channel_id.clone();
     let user_id = client.user_id.clone();
+    let now = super::expiry::now();
     let obj = db.get().await.map_err(pool_err)?;
     let rows = obj
         .interact(move |conn| -> rusqlite::Result<Vec<Model>> {
@@ -178,11 +200,14 @@ pub async fn get_by_client(
             let sql = format!(
                 "SELECT {SELECT_COLS} FROM conversation \
                  WHERE bot_id = ? AND channel_id = ? AND user_id = ? \
+                   AND (expires_at IS NULL OR expires_at > ?) \
                  LIMIT ? OFFSET ?"
             );
             let mut stmt = conn.prepare(&sql)?;
-            let rows =
-                stmt.query_map(params![bot_id, channel_id, user_id, lim, off], row_to_model)?;
+            let rows = stmt.query_map(
+                params![bot_id, channel_id, user_id, now, lim, off],
+                row_to_model,
+            )?;
             let mut out = Vec::new();
             for row in rows {
                 out.push(row?);
@@ -224,6 +249,75 @@ pub async fn get_open_by_bot_id(
     Ok(rows)
 }
 
+/**
+ * The number of open, unexpired conversations of each bot on each channel,
+ * as `(bot_id, channel_id, count)`.
+ */
+pub async fn count_open(db: &Pool) -> Result<Vec<(String, String, i64)>> {
+    let now = super::expiry::now();
+    let obj = db.get().await.map_err(pool_err)?;
+    let rows = obj
+        .interact(
+            move |conn| -> rusqlite::Result<Vec<(String, String, i64)>> {
+                let mut stmt = conn.prepare(
+                    "SELECT bot_id, channel_id, COUNT(*) FROM conversation \
+                 WHERE status = 'OPEN' AND (expires_at IS NULL OR expires_at > ?) \
+                 GROUP BY bot_id, channel_id",
+                )?;
+                let rows =
+                    stmt.query_map(params![now], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?;
+                rows.collect()
+            },
+        )
+        .await
+        .map_err(pool_err)??;
+    Ok(rows)
+}
+
+/**
+ * A bot's unexpired conversations, most recently active first, optionally
+ * only those on one channel, with one user or in one status.
+ */
+pub async fn query(
+    bot_id: &str,
+    channel_id: Option<&str>,
+    user_id: Option<&str>,
+    status: Option<&str>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    db: &Pool,
+) -> Result<Vec<Model>> {
+    let bot_id = bot_id.to_owned();
+    let channel_id = channel_id.map(|c| c.to_owned());
+    let user_id = user_id.map(|u| u.to_owned());
+    let status = status.map(|s| s.to_owned());
+    let now = super::expiry::now();
+    let obj = db.get().await.map_err(pool_err)?;
+    let rows = obj
+        .interact(move |conn| -> rusqlite::Result<Vec<Model>> {
+            let lim: i64 = limit.map(|n| n as i64).unwrap_or(-1);
+            let off: i64 = offset.map(|n| n as i64).unwrap_or(0);
+            let sql = format!(
+                "SELECT {SELECT_COLS} FROM conversation \
+                 WHERE bot_id = ?1 \
+                 AND (?2 IS NULL OR channel_id = ?2) \
+                 AND (?3 IS NULL OR user_id = ?3) \
+                 AND (?4 IS NULL OR status = ?4) \
+                 AND (expires_at IS NULL OR expires_at > ?7) \
+                 ORDER BY last_interaction_at DESC, rowid DESC LIMIT ?5 OFFSET ?6"
+            );
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(
+                params![bot_id, channel_id, user_id, status, lim, off, now],
+                row_to_model,
+            )?;
+            rows.collect()
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(rows)
+}
+
 pub async fn update(
     id: &str,
     flow_id: Option<String>,
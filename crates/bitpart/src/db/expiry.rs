@@ -0,0 +1,169 @@
+// Bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use bitpart_common::db::Pool;
+use bitpart_common::error::{BitpartErrorKind, Result};
+use chrono::Utc;
+use rusqlite::params;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+fn pool_err(e: impl std::fmt::Display) -> BitpartErrorKind {
+    BitpartErrorKind::Pool(e.to_string())
+}
+
+/// How often expired rows are deleted unless configured otherwise
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/**
+ * The current time, rendered the way `expires_at` columns are written, so
+ * that expired rows can be found by comparing the text.
+ */
+pub fn now() -> String {
+    Utc::now().naive_utc().to_string()
+}
+
+/**
+ * Delete every memory, state, conversation and message whose `expires_at` has
+ * passed, along with the messages of expired conversations. Returns the
+ * number of rows deleted.
+ */
+pub async fn sweep(db: &Pool) -> Result<usize> {
+    let now = now();
+    let obj = db.get().await.map_err(pool_err)?;
+    let deleted = obj
+        .interact(move |conn| -> rusqlite::Result<usize> {
+            let tx = conn.transaction()?;
+            let mut deleted = tx.execute(
+                "DELETE FROM message \
+                 WHERE expires_at <= ?1 \
+                    OR conversation_id IN (SELECT id FROM conversation WHERE expires_at <= ?1)",
+                params![now],
+            )?;
+            for table in ["conversation", "memory", "state"] {
+                deleted += tx.execute(
+                    &format!("DELETE FROM {table} WHERE expires_at <= ?"),
+                    params![now],
+                )?;
+            }
+            tx.commit()?;
+            Ok(deleted)
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(deleted)
+}
+
+/**
+ * Sweep expired rows every `interval` until `token` is cancelled.
+ */
+pub async fn run(pool: Pool, interval: Duration, token: CancellationToken) {
+    loop {
+        match sweep(&pool).await {
+            Ok(0) => {}
+            Ok(deleted) => info!("deleted {} expired rows", deleted),
+            Err(err) => error!("expiry sweep: {}", err),
+        }
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::test_utils::get_test_pool;
+    use csml_interpreter::data::Client;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn it_should_hide_and_sweep_expired_rows() {
+        let pool = get_test_pool().await;
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "signal".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let past = Some(Utc::now().naive_utc() - chrono::Duration::days(1));
+        let future = Some(Utc::now().naive_utc() + chrono::Duration::days(1));
+
+        let expired = db::conversation::create("Default", "start", &client, past, &pool)
+            .await
+            .unwrap();
+        db::message::create_failed(&expired, &json!("text"), "gone", 0, 0, &pool)
+            .await
+            .unwrap();
+        db::conversation::create("Default", "start", &client, future, &pool)
+            .await
+            .unwrap();
+        db::memory::create(&client, "old", &json!("stale"), past, &pool)
+            .await
+            .unwrap();
+        db::memory::create(&client, "new", &json!("fresh"), future, &pool)
+            .await
+            .unwrap();
+        db::state::set(&client, "hold", "position", &json!({}), past, &pool)
+            .await
+            .unwrap();
+
+        // expired rows are never read, even before they are swept
+        let memories = db::memory::get_by_client(&client, None, None, &pool)
+            .await
+            .unwrap();
+        assert_eq!(memories.len(), 1);
+        assert_eq!(memories[0].key, "new");
+        assert!(
+            db::memory::get(&client, "old", &pool)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            db::state::get_by_client(&client, &pool)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            db::conversation::get_by_client(&client, None, None, &pool)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(
+            db::message::get_by_client(&client, None, None, &pool)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // the message, conversation, memory and state
+        assert_eq!(sweep(&pool).await.unwrap(), 4);
+        assert_eq!(sweep(&pool).await.unwrap(), 0);
+        assert_eq!(
+            db::memory::get_by_client(&client, None, None, &pool)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
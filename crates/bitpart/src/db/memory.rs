@@ -181,15 +181,17 @@ pub async fn get(client: &Client, key: &str, db: &Pool) -> Result<Option<Model>>
     let channel_id = client.channel_id.clone();
     let user_id = client.user_id.clone();
     let key = key.to_owned();
+    let now = super::expiry::now();
     let obj = db.get().await.map_err(pool_err)?;
     let row = obj
         .interact(move |conn| -> rusqlite::Result<Option<Model>> {
             let sql = format!(
                 "SELECT {SELECT_COLS} FROM memory \
-                 WHERE bot_id = ? AND channel_id = ? AND user_id = ? AND key = ? LIMIT 1"
+                 WHERE bot_id = ? AND channel_id = ? AND user_id = ? AND key = ? \
+                   AND (expires_at IS NULL OR expires_at > ?) LIMIT 1"
             );
             let mut stmt = conn.prepare(&sql)?;
-            stmt.query_row(params![bot_id, channel_id, user_id, key], row_to_model)
+            stmt.query_row(params![bot_id, channel_id, user_id, key, now], row_to_model)
                 .optional()
         })
         .await
@@ -206,6 +208,7 @@ pub async fn get_by_client(
     let bot_id = client.bot_id.clone();
     let channel_id = client.channel_id.clone();
     let user_id = client.user_id.clone();
+    let now = super::expiry::now();
     let obj = db.get().await.map_err(pool_err)?;
     let rows = obj
         .interact(move |conn| -> rusqlite::Result<Vec<Model>> {
@@ -214,11 +217,14 @@ pub async fn get_by_client(
             let sql = format!(
                 "SELECT {SELECT_COLS} FROM memory \
                  WHERE bot_id = ? AND channel_id = ? AND user_id = ? \
+                   AND (expires_at IS NULL OR expires_at > ?) \
                  LIMIT ? OFFSET ?"
             );
             let mut stmt = conn.prepare(&sql)?;
-            let rows =
-                stmt.query_map(params![bot_id, channel_id, user_id, lim, off], row_to_model)?;
+            let rows = stmt.query_map(
+                params![bot_id, channel_id, user_id, now, lim, off],
+                row_to_model,
+            )?;
             let mut out = Vec::new();
             for row in rows {
                 out.push(row?);
@@ -230,15 +236,61 @@ pub async fn get_by_client(
     Ok(rows)
 }
 
+/**
+ * A user's unexpired memories whose keys start with `prefix`, in key order,
+ * e.g. all their `pref_*` memories.
+ */
+pub async fn get_by_client_prefix(
+    client: &Client,
+    prefix: &str,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    db: &Pool,
+) -> Result<Vec<Model>> {
+    let bot_id = client.bot_id.clone();
+    let channel_id = client.channel_id.clone();
+    let user_id = client.user_id.clone();
+    let prefix = prefix.to_owned();
+    let now = super::expiry::now();
+    let obj = db.get().await.map_err(pool_err)?;
+    let rows = obj
+        .interact(move |conn| -> rusqlite::Result<Vec<Model>> {
+            let lim: i64 = limit.map(|n| n as i64).unwrap_or(-1);
+            let off: i64 = offset.map(|n| n as i64).unwrap_or(0);
+            // compared as a substring, as `_` and `%` are common in keys and
+            // would be wildcards in a LIKE pattern
+            let sql = format!(
+                "SELECT {SELECT_COLS} FROM memory \
+                 WHERE bot_id = ?1 AND channel_id = ?2 AND user_id = ?3 \
+                   AND substr(key, 1, length(?4)) = ?4 \
+                   AND (expires_at IS NULL OR expires_at > ?5) \
+                 ORDER BY key LIMIT ?6 OFFSET ?7"
+            );
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(
+                params![bot_id, channel_id, user_id, prefix, now, lim, off],
+                row_to_model,
+            )?;
+            rows.collect()
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(rows)
+}
+
 pub async fn get_by_memory(key: &str, bot_id: &str, db: &Pool) -> Result<Vec<Model>> {
     let key = key.to_owned();
     let bot_id = bot_id.to_owned();
+    let now = super::expiry::now();
     let obj = db.get().await.map_err(pool_err)?;
     let rows = obj
         .interact(move |conn| -> rusqlite::Result<Vec<Model>> {
-            let sql = format!("SELECT {SELECT_COLS} FROM memory WHERE key = ? AND bot_id = ?");
+            let sql = format!(
+                "SELECT {SELECT_COLS} FROM memory WHERE key = ? AND bot_id = ? \
+                 AND (expires_at IS NULL OR expires_at > ?)"
+            );
             let mut stmt = conn.prepare(&sql)?;
-            let rows = stmt.query_map(params![key, bot_id], row_to_model)?;
+            let rows = stmt.query_map(params![key, bot_id, now], row_to_model)?;
             let mut out = Vec::new();
             for row in rows {
                 out.push(row?);
@@ -294,3 +346,64 @@ pub async fn delete_by_bot_id(bot_id: &str, db: &Pool) -> Result<()> {
     .map_err(pool_err)??;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::get_test_pool;
+
+    #[tokio::test]
+    async fn it_should_get_memories_by_key_prefix() {
+        let pool = get_test_pool().await;
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "signal".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let memories: HashMap<String, CsmlMemory> = [
+            ("pref_language", Value::from("fr")),
+            ("pref_units", Value::from("metric")),
+            ("prefix", Value::from(1)),
+            ("name", Value::from("Ada")),
+            ("pref%wild", Value::from(true)),
+        ]
+        .into_iter()
+        .map(|(key, value)| {
+            (
+                key.to_owned(),
+                CsmlMemory {
+                    key: key.to_owned(),
+                    value,
+                },
+            )
+        })
+        .collect();
+        create_many(&client, &memories, None, &pool).await.unwrap();
+        let other = Client {
+            user_id: "other_user".to_owned(),
+            ..client.clone()
+        };
+        create(&other, "pref_language", &Value::from("de"), None, &pool)
+            .await
+            .unwrap();
+
+        let prefs = get_by_client_prefix(&client, "pref_", None, None, &pool)
+            .await
+            .unwrap();
+        let keys: Vec<&str> = prefs.iter().map(|m| m.key.as_str()).collect();
+        assert_eq!(keys, vec!["pref_language", "pref_units"]);
+        assert_eq!(prefs[0].value, Value::from("fr"));
+        assert_eq!(prefs[0].expires_at, None);
+
+        let page = get_by_client_prefix(&client, "pref", Some(2), Some(1), &pool)
+            .await
+            .unwrap();
+        let keys: Vec<&str> = page.iter().map(|m| m.key.as_str()).collect();
+        assert_eq!(keys, vec!["pref_language", "pref_units"]);
+
+        let all = get_by_client_prefix(&client, "", None, None, &pool)
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 5);
+    }
+}
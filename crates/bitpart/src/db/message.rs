@@ -40,11 +40,15 @@ pub struct Model {
     pub created_at: String,
     pub updated_at: String,
     pub expires_at: Option<String>,
+    pub status: Option<String>,
 }
 
+/// Status of a SEND message that a channel failed to deliver.
+pub const STATUS_FAILED: &str = "FAILED";
+
 const SELECT_COLS: &str = "id, conversation_id, flow_id, step_id, direction, payload, \
                           content_type, message_order, interaction_order, \
-                          created_at, updated_at, expires_at";
+                          created_at, updated_at, expires_at, status";
 
 fn row_to_model(r: &rusqlite::Row<'_>) -> rusqlite::Result<Model> {
     Ok(Model {
@@ -60,6 +64,7 @@ fn row_to_model(r: &rusqlite::Row<'_>) -> rusqlite::Result<Model> {
         created_at: r.get("created_at")?,
         updated_at: r.get("updated_at")?,
         expires_at: r.get("expires_at")?,
+        status: r.get("status")?,
     })
 }
 
@@ -122,6 +127,83 @@ pub async fn create(
     Ok(())
 }
 
+/**
+ * Record that a channel failed to deliver a message produced in the given
+ * conversation. Only the content type and the error are kept, never the
+ * message content itself, so this is written even in low_data mode.
+ */
+pub async fn create_failed(
+    conversation_id: &str,
+    content_type: &Value,
+    error: &str,
+    interaction_order: i32,
+    message_order: i32,
+    db: &Pool,
+) -> Result<()> {
+    let id = Uuid::new_v4().to_string();
+    let conversation_id = conversation_id.to_owned();
+    let payload = serde_json::json!({ "content_type": content_type, "error": error }).to_string();
+    let content_type = content_type.to_string();
+
+    let obj = db.get().await.map_err(pool_err)?;
+    obj.interact(move |conn| -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT INTO message \
+             (id, conversation_id, flow_id, step_id, direction, payload, content_type, \
+              message_order, interaction_order, expires_at, status) \
+             SELECT ?, id, flow_id, step_id, 'SEND', ?, ?, ?, ?, expires_at, ? \
+             FROM conversation WHERE id = ?",
+            params![
+                id,
+                payload,
+                content_type,
+                message_order,
+                interaction_order,
+                STATUS_FAILED,
+                conversation_id
+            ],
+        )?;
+        Ok(())
+    })
+    .await
+    .map_err(pool_err)??;
+    Ok(())
+}
+
+/**
+ * Record a message received or sent in a conversation handed off to an
+ * operator. The bot doesn't handle these, so this is the operator's only copy
+ * and it is written even in low_data mode.
+ */
+pub async fn create_in_conversation(
+    conversation_id: &str,
+    direction: &str,
+    payload: &Value,
+    db: &Pool,
+) -> Result<()> {
+    let id = Uuid::new_v4().to_string();
+    let conversation_id = conversation_id.to_owned();
+    let direction = direction.to_owned();
+    let content_type = payload["content_type"].to_string();
+    let payload = payload.to_string();
+
+    let obj = db.get().await.map_err(pool_err)?;
+    obj.interact(move |conn| -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT INTO message \
+             (id, conversation_id, flow_id, step_id, direction, payload, content_type, \
+              message_order, interaction_order, expires_at) \
+             SELECT ?, id, flow_id, step_id, ?, ?, ?, 0, 0, expires_at \
+             FROM conversation WHERE id = ?",
+            params![id, direction, payload, content_type, conversation_id],
+        )?;
+        Ok(())
+    })
+    .await
+    .map_err(pool_err)??;
+    Ok(())
+}
+
 pub async fn delete_by_client(client: &Client, db: &Pool) -> Result<()> {
     let convos = super::conversation::get_by_client(client, None, None, db).await?;
     if convos.is_empty() {
@@ -146,11 +228,63 @@ pub async fn get_by_client(
     offset: Option<u64>,
     db: &Pool,
 ) -> Result<Vec<Model>> {
-    let convos = super::conversation::get_by_client(client, limit, offset, db).await?;
-    if convos.is_empty() {
-        return Ok(Vec::new());
+    query(client, None, None, None, limit, offset, db).await
+}
+
+/// A stored message with its payload decoded, as returned by the API
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MessageRecord {
+    pub id: String,
+    pub conversation_id: String,
+    pub flow_id: String,
+    pub step_id: String,
+    pub direction: String,
+    pub payload: Value,
+    pub status: Option<String>,
+    pub created_at: String,
+}
+
+impl From<Model> for MessageRecord {
+    fn from(message: Model) -> Self {
+        Self {
+            payload: serde_json::from_str(&message.payload)
+                .unwrap_or(Value::String(message.payload)),
+            id: message.id,
+            conversation_id: message.conversation_id,
+            flow_id: message.flow_id,
+            step_id: message.step_id,
+            direction: message.direction,
+            status: message.status,
+            created_at: message.created_at,
+        }
     }
-    let convo_ids: Vec<String> = convos.into_iter().map(|c| c.id).collect();
+}
+
+/// Format of the `created_at` column, as written by SQLite's `CURRENT_TIMESTAMP`
+const CREATED_AT_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/**
+ * A user's messages across all their conversations, oldest first. Only those
+ * in the given `direction` (`SEND` or `RECEIVE`) are returned if it is set,
+ * and only those created in `from..to` if either bound is set: `from` is
+ * included and `to` is not.
+ */
+pub async fn query(
+    client: &Client,
+    direction: Option<&str>,
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    db: &Pool,
+) -> Result<Vec<Model>> {
+    let bot_id = client.bot_id.clone();
+    let channel_id = client.channel_id.clone();
+    let user_id = client.user_id.clone();
+    let direction = direction.map(|d| d.to_owned());
+    let from = from.map(|f| f.format(CREATED_AT_FORMAT).to_string());
+    let to = to.map(|t| t.format(CREATED_AT_FORMAT).to_string());
+    let now = super::expiry::now();
     let obj = db.get().await.map_err(pool_err)?;
     let rows = obj
         .interact(move |conn| -> rusqlite::Result<Vec<Model>> {
@@ -158,19 +292,309 @@ pub async fn get_by_client(
             let off: i64 = offset.map(|n| n as i64).unwrap_or(0);
             let sql = format!(
                 "SELECT {SELECT_COLS} FROM message \
-                 WHERE conversation_id = ? LIMIT ? OFFSET ?"
+                 WHERE conversation_id IN (SELECT id FROM conversation \
+                   WHERE bot_id = ?1 AND channel_id = ?2 AND user_id = ?3) \
+                 AND (?4 IS NULL OR direction = ?4) \
+                 AND (?5 IS NULL OR created_at >= ?5) \
+                 AND (?6 IS NULL OR created_at < ?6) \
+                 AND (expires_at IS NULL OR expires_at > ?9) \
+                 ORDER BY created_at ASC, rowid ASC LIMIT ?7 OFFSET ?8"
             );
             let mut stmt = conn.prepare(&sql)?;
-            let mut out = Vec::new();
-            for id in convo_ids {
-                let rows = stmt.query_map(params![id, lim, off], row_to_model)?;
-                for row in rows {
-                    out.push(row?);
-                }
-            }
-            Ok(out)
+            let rows = stmt.query_map(
+                params![
+                    bot_id, channel_id, user_id, direction, from, to, lim, off, now
+                ],
+                row_to_model,
+            )?;
+            rows.collect()
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(rows)
+}
+
+/// A reply a channel failed to deliver, see `create_failed`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FailedSend {
+    pub id: String,
+    pub conversation_id: String,
+    pub channel_id: String,
+    pub user_id: String,
+    pub content_type: Value,
+    pub error: String,
+    pub created_at: String,
+}
+
+/**
+ * The replies a bot's channels failed to deliver, most recent first, so that
+ * operators can follow up on them.
+ */
+pub async fn get_failed(
+    bot_id: &str,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    db: &Pool,
+) -> Result<Vec<FailedSend>> {
+    let bot_id = bot_id.to_owned();
+    let obj = db.get().await.map_err(pool_err)?;
+    let rows = obj
+        .interact(move |conn| -> rusqlite::Result<Vec<(FailedSend, String)>> {
+            let lim: i64 = limit.map(|n| n as i64).unwrap_or(-1);
+            let off: i64 = offset.map(|n| n as i64).unwrap_or(0);
+            let mut stmt = conn.prepare(
+                "SELECT m.id, m.conversation_id, c.channel_id, c.user_id, m.payload, \
+                 m.created_at FROM message m JOIN conversation c ON c.id = m.conversation_id \
+                 WHERE c.bot_id = ? AND m.status = ? \
+                 ORDER BY m.created_at DESC, m.rowid DESC LIMIT ? OFFSET ?",
+            )?;
+            let rows = stmt.query_map(params![bot_id, STATUS_FAILED, lim, off], |r| {
+                Ok((
+                    FailedSend {
+                        id: r.get(0)?,
+                        conversation_id: r.get(1)?,
+                        channel_id: r.get(2)?,
+                        user_id: r.get(3)?,
+                        content_type: Value::Null,
+                        error: String::new(),
+                        created_at: r.get(5)?,
+                    },
+                    r.get::<_, String>(4)?,
+                ))
+            })?;
+            rows.collect()
         })
         .await
         .map_err(pool_err)??;
+    Ok(rows
+        .into_iter()
+        .map(|(mut failed, payload)| {
+            let payload: Value = serde_json::from_str(&payload).unwrap_or_default();
+            failed.content_type = payload["content_type"].clone();
+            failed.error = payload["error"].as_str().unwrap_or_default().to_owned();
+            failed
+        })
+        .collect())
+}
+
+/**
+ * The last `count` messages the bot sent to a client, oldest first. Messages
+ * the channel failed to deliver and redacted secure messages are skipped, as
+ * neither holds the original content.
+ */
+pub async fn get_last_sent(client: &Client, count: u64, db: &Pool) -> Result<Vec<Model>> {
+    let bot_id = client.bot_id.clone();
+    let channel_id = client.channel_id.clone();
+    let user_id = client.user_id.clone();
+    let secure = crate::csml::utils::secure_placeholder()["content_type"].to_string();
+    let obj = db.get().await.map_err(pool_err)?;
+    let mut rows = obj
+        .interact(move |conn| -> rusqlite::Result<Vec<Model>> {
+            let sql = format!(
+                "SELECT {SELECT_COLS} FROM message \
+                 WHERE direction = 'SEND' AND status IS NULL AND content_type != ? \
+                 AND conversation_id IN (SELECT id FROM conversation \
+                   WHERE bot_id = ? AND channel_id = ? AND user_id = ?) \
+                 ORDER BY created_at DESC, rowid DESC LIMIT ?"
+            );
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(
+                params![secure, bot_id, channel_id, user_id, count as i64],
+                row_to_model,
+            )?;
+            rows.collect()
+        })
+        .await
+        .map_err(pool_err)??;
+    rows.reverse();
     Ok(rows)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::get_test_pool;
+
+    #[tokio::test]
+    async fn it_should_record_failed_sends() {
+        let pool = get_test_pool().await;
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "signal".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let conversation_id =
+            crate::db::conversation::create("Default", "start", &client, None, &pool)
+                .await
+                .unwrap();
+
+        create_failed(
+            &conversation_id,
+            &serde_json::json!("text"),
+            "unregistered user",
+            1,
+            0,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        let messages = get_by_client(&client, None, None, &pool).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].status.as_deref(), Some(STATUS_FAILED));
+        assert_eq!(messages[0].direction, "SEND");
+        assert_eq!(messages[0].flow_id, "Default");
+        assert!(messages[0].payload.contains("unregistered user"));
+    }
+
+    #[tokio::test]
+    async fn it_should_get_the_last_sent_messages() {
+        let pool = get_test_pool().await;
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "signal".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let conversation_id =
+            crate::db::conversation::create("Default", "start", &client, None, &pool)
+                .await
+                .unwrap();
+
+        let sent = [
+            serde_json::json!({"content_type": "text", "content": {"text": "one"}}),
+            serde_json::json!({"content_type": "text", "content": {"text": "two"}}),
+            crate::csml::utils::secure_placeholder(),
+            serde_json::json!({"content_type": "text", "content": {"text": "three"}}),
+        ];
+        for payload in &sent {
+            create_in_conversation(&conversation_id, "SEND", payload, &pool)
+                .await
+                .unwrap();
+        }
+
+        let messages = get_last_sent(&client, 2, &pool).await.unwrap();
+        let texts: Vec<Value> = messages
+            .iter()
+            .map(|m| serde_json::from_str::<Value>(&m.payload).unwrap()["content"]["text"].clone())
+            .collect();
+        assert_eq!(texts, vec![Value::from("two"), Value::from("three")]);
+    }
+
+    /// Store a message with the given direction and creation time
+    async fn insert_at(conversation_id: &str, direction: &str, created_at: &str, db: &Pool) {
+        let conversation_id = conversation_id.to_owned();
+        let direction = direction.to_owned();
+        let created_at = created_at.to_owned();
+        let obj = db.get().await.unwrap();
+        obj.interact(move |conn| -> rusqlite::Result<()> {
+            conn.execute(
+                "INSERT INTO message \
+                 (id, conversation_id, flow_id, step_id, direction, payload, content_type, \
+                  message_order, interaction_order, created_at) \
+                 VALUES (?, ?, 'Default', 'start', ?, ?, '\"text\"', 0, 0, ?)",
+                params![
+                    Uuid::new_v4().to_string(),
+                    conversation_id,
+                    direction,
+                    serde_json::json!({"content_type": "text", "content": {"text": created_at}})
+                        .to_string(),
+                    created_at
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    }
+
+    fn at(time: &str) -> Option<NaiveDateTime> {
+        Some(NaiveDateTime::parse_from_str(time, CREATED_AT_FORMAT).unwrap())
+    }
+
+    #[tokio::test]
+    async fn it_should_query_messages_by_direction_and_time() {
+        let pool = get_test_pool().await;
+        let client = Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "signal".to_owned(),
+            user_id: "user_id".to_owned(),
+        };
+        let other = Client {
+            user_id: "other_user".to_owned(),
+            ..client.clone()
+        };
+        let conversation_id =
+            crate::db::conversation::create("Default", "start", &client, None, &pool)
+                .await
+                .unwrap();
+        let other_id = crate::db::conversation::create("Default", "start", &other, None, &pool)
+            .await
+            .unwrap();
+        for (direction, created_at) in [
+            ("RECEIVE", "2024-03-08 10:00:00"),
+            ("SEND", "2024-03-08 10:00:10"),
+            ("RECEIVE", "2024-03-08 10:00:20"),
+            ("SEND", "2024-03-08 10:00:30"),
+            ("RECEIVE", "2024-03-08 09:00:00"),
+        ] {
+            insert_at(&conversation_id, direction, created_at, &pool).await;
+        }
+        insert_at(&other_id, "RECEIVE", "2024-03-08 10:00:00", &pool).await;
+
+        let count = |direction: Option<&'static str>,
+                     from: Option<NaiveDateTime>,
+                     to: Option<NaiveDateTime>| {
+            let (client, pool) = (client.clone(), pool.clone());
+            async move {
+                query(&client, direction, from, to, None, None, &pool)
+                    .await
+                    .unwrap()
+                    .len()
+            }
+        };
+
+        assert_eq!(count(None, None, None).await, 5);
+        assert_eq!(count(Some("RECEIVE"), None, None).await, 3);
+        assert_eq!(count(Some("SEND"), None, None).await, 2);
+
+        assert_eq!(count(None, at("2024-03-08 09:00:00"), None).await, 5);
+        assert_eq!(count(None, None, at("2024-03-08 09:00:00")).await, 0);
+        assert_eq!(count(None, None, at("2024-03-08 10:00:00")).await, 1);
+        assert_eq!(
+            count(None, at("2024-03-08 10:00:00"), at("2024-03-08 10:00:30")).await,
+            3
+        );
+        assert_eq!(
+            count(None, at("2024-03-08 10:00:00"), at("2024-03-08 10:00:31")).await,
+            4
+        );
+        assert_eq!(count(None, at("2024-03-08 10:00:30"), None).await, 1);
+        assert_eq!(count(None, at("2024-03-08 10:00:31"), None).await, 0);
+        assert_eq!(
+            count(
+                Some("RECEIVE"),
+                at("2024-03-08 10:00:00"),
+                at("2024-03-08 10:00:30")
+            )
+            .await,
+            2
+        );
+
+        let times: Vec<String> = query(&client, None, None, None, Some(2), Some(1), &pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|m| m.created_at)
+            .collect();
+        assert_eq!(times, vec!["2024-03-08 10:00:00", "2024-03-08 10:00:10"]);
+
+        let first: MessageRecord = query(&client, None, None, None, Some(1), None, &pool)
+            .await
+            .unwrap()
+            .remove(0)
+            .into();
+        assert_eq!(first.direction, "RECEIVE");
+        assert_eq!(first.payload["content"]["text"], "2024-03-08 09:00:00");
+    }
+}
@@ -15,10 +15,13 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 pub mod bot;
+pub mod callback;
 pub mod channel;
 pub mod conversation;
+pub mod expiry;
 pub mod memory;
 pub mod message;
 pub mod state;
+pub mod user;
 
 pub use bitpart_common::db::Pool;
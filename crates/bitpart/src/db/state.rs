@@ -13,7 +13,7 @@
 
 use bitpart_common::db::Pool;
 use bitpart_common::error::{BitpartErrorKind, Result};
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Utc};
 use csml_interpreter::data::Client;
 use rusqlite::{OptionalExtension, params};
 use serde_json::Value;
@@ -57,19 +57,68 @@ pub async fn get(client: &Client, r#type: &str, key: &str, db: &Pool) -> Result<
     }
 }
 
+/**
+ * Like `get`, but a value past its `expires_at` is treated as missing rather
+ * than waiting for it to be cleaned up.
+ */
+pub async fn get_unexpired(
+    client: &Client,
+    r#type: &str,
+    key: &str,
+    db: &Pool,
+) -> Result<Option<Value>> {
+    let bot_id = client.bot_id.clone();
+    let channel_id = client.channel_id.clone();
+    let user_id = client.user_id.clone();
+    let r#type = r#type.to_owned();
+    let key = key.to_owned();
+
+    let obj = db.get().await.map_err(pool_err)?;
+    let row = obj
+        .interact(
+            move |conn| -> rusqlite::Result<Option<(String, Option<String>)>> {
+                let mut stmt = conn.prepare(
+                    "SELECT value, expires_at FROM state \
+                 WHERE bot_id = ? AND channel_id = ? AND user_id = ? \
+                   AND type = ? AND key = ? \
+                 LIMIT 1",
+                )?;
+                stmt.query_row(params![bot_id, channel_id, user_id, r#type, key], |r| {
+                    Ok((r.get(0)?, r.get(1)?))
+                })
+                .optional()
+            },
+        )
+        .await
+        .map_err(pool_err)??;
+
+    let Some((value, expires_at)) = row else {
+        return Ok(None);
+    };
+    let expired = expires_at
+        .and_then(|e| NaiveDateTime::parse_from_str(&e, "%Y-%m-%d %H:%M:%S%.f").ok())
+        .is_some_and(|e| e <= Utc::now().naive_utc());
+    if expired {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&value)?))
+}
+
 pub async fn get_by_client(client: &Client, db: &Pool) -> Result<Vec<Value>> {
     let bot_id = client.bot_id.clone();
     let channel_id = client.channel_id.clone();
     let user_id = client.user_id.clone();
+    let now = super::expiry::now();
 
     let obj = db.get().await.map_err(pool_err)?;
     let values = obj
         .interact(move |conn| -> rusqlite::Result<Vec<String>> {
             let mut stmt = conn.prepare(
                 "SELECT value FROM state \
-                 WHERE bot_id = ? AND channel_id = ? AND user_id = ?",
+                 WHERE bot_id = ? AND channel_id = ? AND user_id = ? \
+                   AND (expires_at IS NULL OR expires_at > ?)",
             )?;
-            let rows = stmt.query_map(params![bot_id, channel_id, user_id], |r| {
+            let rows = stmt.query_map(params![bot_id, channel_id, user_id, now], |r| {
                 r.get::<_, String>(0)
             })?;
             let mut out = Vec::new();
@@ -0,0 +1,150 @@
+// Bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use bitpart_common::db::Pool;
+use bitpart_common::error::{BitpartErrorKind, Result};
+use csml_interpreter::data::Client;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+fn pool_err(e: impl std::fmt::Display) -> BitpartErrorKind {
+    BitpartErrorKind::Pool(e.to_string())
+}
+
+/// Number of rows removed from each table when a user is forgotten
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Forgotten {
+    pub conversations: usize,
+    pub messages: usize,
+    pub memories: usize,
+    pub states: usize,
+    /// Whether the user's message thread was also cleared from the channel's
+    /// store, which `forget` itself leaves to the caller
+    #[serde(default)]
+    pub channel_thread: bool,
+}
+
+/**
+ * Delete everything stored about a user: their conversations and the messages
+ * in them, their memories and their state. It all goes in one transaction, so
+ * a failure leaves the user's data as it was.
+ */
+pub async fn forget(client: &Client, db: &Pool) -> Result<Forgotten> {
+    let bot_id = client.bot_id.clone();
+    let channel_id = client.channel_id.clone();
+    let user_id = client.user_id.clone();
+    let obj = db.get().await.map_err(pool_err)?;
+    let forgotten = obj
+        .interact(move |conn| -> rusqlite::Result<Forgotten> {
+            let tx = conn.transaction()?;
+            let user = params![bot_id, channel_id, user_id];
+            let messages = tx.execute(
+                "DELETE FROM message WHERE conversation_id IN \
+                 (SELECT id FROM conversation \
+                  WHERE bot_id = ? AND channel_id = ? AND user_id = ?)",
+                user,
+            )?;
+            let conversations = tx.execute(
+                "DELETE FROM conversation WHERE bot_id = ? AND channel_id = ? AND user_id = ?",
+                user,
+            )?;
+            let memories = tx.execute(
+                "DELETE FROM memory WHERE bot_id = ? AND channel_id = ? AND user_id = ?",
+                user,
+            )?;
+            let states = tx.execute(
+                "DELETE FROM state WHERE bot_id = ? AND channel_id = ? AND user_id = ?",
+                user,
+            )?;
+            tx.commit()?;
+            Ok(Forgotten {
+                conversations,
+                messages,
+                memories,
+                states,
+                channel_thread: false,
+            })
+        })
+        .await
+        .map_err(pool_err)??;
+    Ok(forgotten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::get_test_pool;
+    use serde_json::json;
+
+    fn client(user_id: &str) -> Client {
+        Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "signal".to_owned(),
+            user_id: user_id.to_owned(),
+        }
+    }
+
+    async fn seed(client: &Client, pool: &Pool) {
+        let conversation_id =
+            crate::db::conversation::create("Default", "start", client, None, pool)
+                .await
+                .unwrap();
+        crate::db::message::create_failed(&conversation_id, &json!("text"), "failed", 0, 0, pool)
+            .await
+            .unwrap();
+        crate::db::memory::create(client, "name", &json!("Alice"), None, pool)
+            .await
+            .unwrap();
+        crate::db::state::set(client, "hold", "position", &json!({}), None, pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_forget_only_the_given_user() {
+        let pool = get_test_pool().await;
+        let (forget_me, keep_me) = (client("forget_me"), client("keep_me"));
+        seed(&forget_me, &pool).await;
+        seed(&keep_me, &pool).await;
+
+        let forgotten = forget(&forget_me, &pool).await.unwrap();
+        assert_eq!(
+            forgotten,
+            Forgotten {
+                conversations: 1,
+                messages: 1,
+                memories: 1,
+                states: 1,
+                channel_thread: false,
+            }
+        );
+        assert_eq!(
+            forget(&forget_me, &pool).await.unwrap(),
+            Forgotten::default()
+        );
+
+        let kept = crate::db::conversation::get_by_client(&keep_me, None, None, &pool)
+            .await
+            .unwrap();
+        assert_eq!(kept.len(), 1);
+        assert!(
+            crate::db::state::get(&keep_me, "hold", "position", &pool)
+                .await
+                .is_ok()
+        );
+        assert_eq!(forget(&keep_me, &pool).await.unwrap().messages, 1);
+    }
+}
@@ -0,0 +1,157 @@
+// Bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Unauthenticated liveness and readiness endpoints for container
+//! orchestration.
+
+use axum::{Json, extract::State, http::StatusCode};
+use bitpart_common::{
+    db::Pool,
+    error::{BitpartErrorKind, Result},
+};
+use serde::Serialize;
+use serde_json::{Value, json};
+use tracing::warn;
+
+use crate::api::{self, ApiState};
+
+/// Readiness of one configured channel
+#[derive(Debug, Serialize)]
+pub struct ChannelReadiness {
+    pub id: String,
+    pub bot_id: String,
+    pub online: bool,
+    pub registered: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub database: bool,
+    pub channels: Vec<ChannelReadiness>,
+}
+
+/// The process is up
+pub async fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+async fn check_database(pool: &Pool) -> Result<()> {
+    let obj = pool
+        .get()
+        .await
+        .map_err(|e| BitpartErrorKind::Pool(e.to_string()))?;
+    obj.interact(|conn| conn.query_row("SELECT 1", [], |r| r.get::<_, i64>(0)))
+        .await
+        .map_err(|e| BitpartErrorKind::Pool(e.to_string()))??;
+    Ok(())
+}
+
+/**
+ * The database answers a query, and every channel that should be online has
+ * a Signal registration. Channels are listed either way, so that a database
+ * failure can be told apart from a channel that needs relinking.
+ */
+pub async fn readyz(State(state): State<ApiState>) -> (StatusCode, Json<Readiness>) {
+    let database = match check_database(&state.pool).await {
+        Ok(_) => true,
+        Err(err) => {
+            warn!("Database is not ready: {}", err);
+            false
+        }
+    };
+
+    let mut channels = Vec::new();
+    if database {
+        match crate::db::channel::list(None, None, &state.pool).await {
+            Ok(list) => {
+                for channel in list {
+                    let registered =
+                        api::describe_channel(&channel.channel_id, &channel.bot_id, &state)
+                            .await
+                            .is_ok_and(|description| description.registered);
+                    channels.push(ChannelReadiness {
+                        id: channel.channel_id,
+                        bot_id: channel.bot_id,
+                        online: channel.online,
+                        registered,
+                    });
+                }
+            }
+            Err(err) => warn!("Failed to list channels: {}", err),
+        }
+    }
+
+    let ready = database
+        && channels
+            .iter()
+            .all(|channel| !channel.online || channel.registered);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(Readiness {
+            ready,
+            database,
+            channels,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{MockChannelBackend, get_test_state};
+    use axum::{Router, routing::get};
+    use axum_test::TestServer;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn it_should_report_health_and_readiness() {
+        let state = get_test_state(Arc::new(MockChannelBackend)).await;
+        let pool = state.pool.clone();
+        let app = Router::new()
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz))
+            .with_state(state);
+        let server = TestServer::new(app).unwrap();
+
+        let res = server.get("/healthz").await;
+        res.assert_status_ok();
+        res.assert_json(&json!({ "status": "ok" }));
+
+        let res = server.get("/readyz").await;
+        res.assert_status_ok();
+        res.assert_json(&json!({ "ready": true, "database": true, "channels": [] }));
+
+        // an unlinked channel that should be online
+        crate::db::channel::create("signal", "bot_id", &pool)
+            .await
+            .unwrap();
+        crate::db::channel::set_online("signal", "bot_id", true, &pool)
+            .await
+            .unwrap();
+        let res = server.get("/readyz").await;
+        res.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        let body = res.json::<Value>();
+        assert_eq!(body["database"], true);
+        assert_eq!(body["channels"][0]["id"], "signal");
+        assert_eq!(body["channels"][0]["registered"], false);
+    }
+}
@@ -16,3 +16,8 @@
 
 pub mod csml;
 pub mod db;
+pub mod http;
+pub mod metrics;
+
+#[cfg(test)]
+mod test_utils;
@@ -16,8 +16,7 @@
 
 pub mod api;
 mod channels;
-mod csml;
-pub mod db;
+mod health;
 mod socket;
 mod utils;
 
@@ -27,35 +26,40 @@ use axum::{
     http::{StatusCode, header},
     middleware::{self, Next},
     response::Response,
-    routing::any,
+    routing::{any, get},
 };
 use bitpart_common::error::{BitpartErrorKind, Result};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use clap_verbosity_flag::Verbosity;
 use directories::ProjectDirs;
 use figment::{
-    Figment,
+    Figment, Provider,
     providers::{Env, Format, Serialized, Toml},
 };
 use figment_file_provider_adapter::FileAdapter;
 use opentelemetry::trace::TracerProvider;
 use opentelemetry_sdk::{metrics::SdkMeterProvider, trace::SdkTracer};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 use subtle::ConstantTimeEq;
-use tokio::sync::Mutex;
+use tokio::{sync::Mutex, task::JoinSet};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::info;
 use tracing_log::AsTrace;
 use tracing_opentelemetry::MetricsLayer;
+use tracing_subscriber::filter::{LevelFilter, Targets};
 use tracing_subscriber::prelude::*;
+use tracing_subscriber::{Layer, registry::LookupSpan};
 
 use api::ApiState;
+use bitpart::{csml, csml::data::EngineVersionCheck, db, http, metrics};
 use bitpart_common::db::migration::migrate;
-use channels::signal;
+use channels::{attachments, signal};
 
 /// Bitpart is a messaging tool that runs on top of Signal to support activists, journalists, and human rights defenders.
 #[derive(Parser, Serialize, Deserialize)]
@@ -70,10 +74,10 @@ struct Cli {
     #[serde(skip_serializing_if = "::std::option::Option::is_none")]
     auth: Option<String>,
 
-    /// IP address and port to bind to
-    #[arg(short, long)]
+    /// IP address and port or Unix socket path to bind to (comma-separated for several)
+    #[arg(short, long, value_delimiter = ',')]
     #[serde(skip_serializing_if = "::std::option::Option::is_none")]
-    bind: Option<String>,
+    bind: Option<Vec<String>>,
 
     /// Path to sqlcipher database file
     #[arg(short, long)]
@@ -88,6 +92,146 @@ struct Cli {
     /// Enable Opentelemetry
     #[arg(short, long)]
     opentelemetry: bool,
+
+    /// Default endpoint for CSML apps, for requests and bots that don't set one
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    apps_endpoint: Option<String>,
+
+    /// Secret that callbacks are signed with, for bots that don't set their own
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    callback_secret: Option<String>,
+
+    /// Attempts to deliver a callback before giving up on it
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    callback_attempts: Option<i32>,
+
+    /// Seconds a callback delivery attempt may take before it counts as failed
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    callback_timeout: Option<u64>,
+
+    /// Most verbose level of the bots' own log messages (error, warn, info, debug, trace or off)
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    interpreter_log_level: Option<String>,
+
+    /// Number of threads that run flows, one per CPU by default
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    interpreter_threads: Option<usize>,
+
+    /// Stack size in bytes of the threads that run flows
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    interpreter_stack_size: Option<usize>,
+
+    /// Number of steps that may wait for a free interpreter thread, more are turned away
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    interpreter_queue_size: Option<usize>,
+
+    /// What to do with bots saved by an incompatible version of Bitpart
+    #[arg(long, value_enum)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    engine_version_check: Option<EngineVersionCheck>,
+
+    /// Format of log output
+    #[arg(long, value_enum)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    log_format: Option<LogFormat>,
+
+    /// Seconds between sweeps deleting expired conversations, memories and state
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    expiry_interval: Option<u64>,
+
+    /// Maximum size in bytes of a single flow, larger ones are rejected
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    max_flow_size: Option<usize>,
+
+    /// Maximum size in bytes of all of a bot's flows, larger bots are rejected
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    max_bot_size: Option<usize>,
+
+    /// Maximum size in bytes of one attachment, larger ones are skipped
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    max_attachment_size: Option<usize>,
+
+    /// Maximum number of attachments in one Signal message, more are split across several
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    max_attachments: Option<usize>,
+
+    /// Maximum size in bytes of all the attachments of one Signal message, more are split across several
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    max_message_size: Option<usize>,
+
+    /// Seconds fetching one outgoing attachment over http(s) may take
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    attachment_timeout: Option<u64>,
+
+    /// Number of a message's attachments downloaded at once
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    attachment_downloads: Option<usize>,
+
+    /// Seconds a transcription may take before the audio is delivered without one
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    transcription_timeout: Option<u64>,
+
+    /// Seconds a single reply may take to send before it is given up on
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    send_timeout: Option<u64>,
+
+    /// Seconds to wait after the first failed attempt to reach Signal, doubling after each one
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    reconnect_delay: Option<u64>,
+
+    /// Longest wait in seconds between attempts to reach Signal
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    reconnect_max_delay: Option<u64>,
+
+    /// Attempts to reach Signal before giving up, 0 for no limit
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    reconnect_attempts: Option<u32>,
+
+    /// Total size in bytes of received attachments kept, the oldest are deleted beyond it
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    attachments_max_bytes: Option<u64>,
+
+    /// Seconds received attachments are kept
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    attachments_max_age: Option<u64>,
+
+    /// Hosts that outgoing attachments may be fetched from over http(s) (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    attachment_hosts: Option<Vec<String>>,
+
+    /// Reject contacts whose identity changed instead of trusting them, outside the identity trust window
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    reject_new_identities: Option<bool>,
+
+    /// Seconds after linking a channel during which changed identities are trusted anyway
+    #[arg(long)]
+    #[serde(skip_serializing_if = "::std::option::Option::is_none")]
+    identity_trust_window: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -98,8 +242,9 @@ struct Config {
     /// API authentication token
     auth: String,
 
-    /// IP address and port to bind to
-    bind: String,
+    /// IP addresses and ports or Unix socket paths to bind to
+    #[serde(deserialize_with = "deserialize_list")]
+    bind: Vec<String>,
 
     /// Path to sqlcipher database file
     database: String,
@@ -109,6 +254,150 @@ struct Config {
 
     /// Enable Opentelemetry
     opentelemetry: bool,
+
+    /// Default endpoint for CSML apps, for requests and bots that don't set one
+    apps_endpoint: Option<String>,
+
+    /// Secret that callbacks are signed with, for bots that don't set their own
+    callback_secret: Option<String>,
+
+    /// Attempts to deliver a callback before giving up on it
+    callback_attempts: Option<i32>,
+
+    /// Seconds a callback delivery attempt may take
+    callback_timeout: Option<u64>,
+
+    /// Most verbose level of the bots' own log messages
+    interpreter_log_level: Option<String>,
+
+    /// Number of threads that run flows
+    interpreter_threads: Option<usize>,
+
+    /// Stack size in bytes of the threads that run flows
+    interpreter_stack_size: Option<usize>,
+
+    /// Number of steps that may wait for a free interpreter thread
+    interpreter_queue_size: Option<usize>,
+
+    /// What to do with bots saved by an incompatible version of Bitpart
+    #[serde(default)]
+    engine_version_check: EngineVersionCheck,
+
+    /// Format of log output
+    #[serde(default)]
+    log_format: LogFormat,
+
+    /// Seconds between sweeps deleting expired conversations, memories and state
+    expiry_interval: Option<u64>,
+
+    /// Maximum size in bytes of a single flow
+    max_flow_size: Option<usize>,
+
+    /// Maximum size in bytes of all of a bot's flows
+    max_bot_size: Option<usize>,
+
+    /// Maximum size in bytes of one attachment
+    max_attachment_size: Option<usize>,
+
+    /// Maximum number of attachments in one Signal message
+    max_attachments: Option<usize>,
+
+    /// Maximum size in bytes of all the attachments of one Signal message
+    max_message_size: Option<usize>,
+
+    /// Seconds fetching one outgoing attachment over http(s) may take
+    attachment_timeout: Option<u64>,
+
+    /// Number of a message's attachments downloaded at once
+    attachment_downloads: Option<usize>,
+
+    /// Seconds a transcription may take
+    transcription_timeout: Option<u64>,
+
+    /// Seconds a single reply may take to send
+    send_timeout: Option<u64>,
+
+    /// Seconds to wait after the first failed attempt to reach Signal
+    reconnect_delay: Option<u64>,
+
+    /// Longest wait in seconds between attempts to reach Signal
+    reconnect_max_delay: Option<u64>,
+
+    /// Attempts to reach Signal before giving up, 0 for no limit
+    reconnect_attempts: Option<u32>,
+
+    /// Total size in bytes of received attachments kept
+    attachments_max_bytes: Option<u64>,
+
+    /// Seconds received attachments are kept
+    attachments_max_age: Option<u64>,
+
+    /// Hosts that outgoing attachments may be fetched from over http(s)
+    #[serde(default, deserialize_with = "deserialize_list")]
+    attachment_hosts: Vec<String>,
+
+    /// Reject contacts whose identity changed instead of trusting them
+    #[serde(default)]
+    reject_new_identities: bool,
+
+    /// Seconds after linking a channel during which changed identities are trusted anyway
+    identity_trust_window: Option<u64>,
+}
+
+/// Logs are human-readable text by default, or one JSON object per line for
+/// log aggregators
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+fn fmt_layer<S>(format: LogFormat) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    }
+}
+
+/// Accepts either a single (possibly comma-separated) value or a list of them,
+/// so `bind` and `attachment_hosts` work the same from the CLI, the environment
+/// and config.toml.
+fn deserialize_list<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let values = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    };
+    Ok(values
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+        .collect())
+}
+
+/// The configuration from config.toml, the environment and the command line,
+/// each overriding the ones before. Only the CLI options that were given are
+/// merged, so they don't override the others with their defaults.
+fn figment(file: impl Provider, env: impl Provider, cli: Cli) -> Figment {
+    Figment::new()
+        .merge(file)
+        .merge(env)
+        .merge(Serialized::defaults(cli))
 }
 
 /// Placeholder rendered in `Debug` output in place of sensitive values.
@@ -123,6 +412,37 @@ impl std::fmt::Debug for Cli {
             .field("database", &self.database)
             .field("key", &self.key.as_ref().map(|_| REDACTED))
             .field("opentelemetry", &self.opentelemetry)
+            .field("apps_endpoint", &self.apps_endpoint)
+            .field(
+                "callback_secret",
+                &self.callback_secret.as_ref().map(|_| REDACTED),
+            )
+            .field("callback_attempts", &self.callback_attempts)
+            .field("callback_timeout", &self.callback_timeout)
+            .field("interpreter_log_level", &self.interpreter_log_level)
+            .field("interpreter_threads", &self.interpreter_threads)
+            .field("interpreter_stack_size", &self.interpreter_stack_size)
+            .field("interpreter_queue_size", &self.interpreter_queue_size)
+            .field("engine_version_check", &self.engine_version_check)
+            .field("log_format", &self.log_format)
+            .field("expiry_interval", &self.expiry_interval)
+            .field("max_flow_size", &self.max_flow_size)
+            .field("max_bot_size", &self.max_bot_size)
+            .field("max_attachment_size", &self.max_attachment_size)
+            .field("max_attachments", &self.max_attachments)
+            .field("max_message_size", &self.max_message_size)
+            .field("attachment_timeout", &self.attachment_timeout)
+            .field("attachment_downloads", &self.attachment_downloads)
+            .field("transcription_timeout", &self.transcription_timeout)
+            .field("send_timeout", &self.send_timeout)
+            .field("reconnect_delay", &self.reconnect_delay)
+            .field("reconnect_max_delay", &self.reconnect_max_delay)
+            .field("reconnect_attempts", &self.reconnect_attempts)
+            .field("attachments_max_bytes", &self.attachments_max_bytes)
+            .field("attachments_max_age", &self.attachments_max_age)
+            .field("attachment_hosts", &self.attachment_hosts)
+            .field("reject_new_identities", &self.reject_new_identities)
+            .field("identity_trust_window", &self.identity_trust_window)
             .finish()
     }
 }
@@ -136,6 +456,37 @@ impl std::fmt::Debug for Config {
             .field("database", &self.database)
             .field("key", &REDACTED)
             .field("opentelemetry", &self.opentelemetry)
+            .field("apps_endpoint", &self.apps_endpoint)
+            .field(
+                "callback_secret",
+                &self.callback_secret.as_ref().map(|_| REDACTED),
+            )
+            .field("callback_attempts", &self.callback_attempts)
+            .field("callback_timeout", &self.callback_timeout)
+            .field("interpreter_log_level", &self.interpreter_log_level)
+            .field("interpreter_threads", &self.interpreter_threads)
+            .field("interpreter_stack_size", &self.interpreter_stack_size)
+            .field("interpreter_queue_size", &self.interpreter_queue_size)
+            .field("engine_version_check", &self.engine_version_check)
+            .field("log_format", &self.log_format)
+            .field("expiry_interval", &self.expiry_interval)
+            .field("max_flow_size", &self.max_flow_size)
+            .field("max_bot_size", &self.max_bot_size)
+            .field("max_attachment_size", &self.max_attachment_size)
+            .field("max_attachments", &self.max_attachments)
+            .field("max_message_size", &self.max_message_size)
+            .field("attachment_timeout", &self.attachment_timeout)
+            .field("attachment_downloads", &self.attachment_downloads)
+            .field("transcription_timeout", &self.transcription_timeout)
+            .field("send_timeout", &self.send_timeout)
+            .field("reconnect_delay", &self.reconnect_delay)
+            .field("reconnect_max_delay", &self.reconnect_max_delay)
+            .field("reconnect_attempts", &self.reconnect_attempts)
+            .field("attachments_max_bytes", &self.attachments_max_bytes)
+            .field("attachments_max_age", &self.attachments_max_age)
+            .field("attachment_hosts", &self.attachment_hosts)
+            .field("reject_new_identities", &self.reject_new_identities)
+            .field("identity_trust_window", &self.identity_trust_window)
             .finish()
     }
 }
@@ -158,6 +509,42 @@ async fn authenticate(
     }
 }
 
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    Unix(tokio::net::UnixListener),
+}
+
+/// Bind to `bind` as a TCP address if it parses as one, otherwise as a Unix
+/// socket path (replacing any stale socket file).
+async fn bind_listener(bind: &str) -> Result<Listener> {
+    if let Ok(addr) = bind.parse::<SocketAddr>() {
+        Ok(Listener::Tcp(tokio::net::TcpListener::bind(addr).await?))
+    } else {
+        let Ok(path) = bind.parse::<PathBuf>();
+        let _ = tokio::fs::remove_file(&path).await;
+        Ok(Listener::Unix(tokio::net::UnixListener::bind(path)?))
+    }
+}
+
+async fn serve(listener: Listener, app: Router, tracker: TaskTracker) -> Result<()> {
+    match listener {
+        Listener::Tcp(listener) => {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(async move { tracker.wait().await })
+            .await?
+        }
+        Listener::Unix(listener) => {
+            axum::serve(listener, app.into_make_service())
+                .with_graceful_shutdown(async move { tracker.wait().await })
+                .await?
+        }
+    }
+    Ok(())
+}
+
 fn telemetry_tracer_init() -> Result<SdkTracer> {
     let otlp_exporter = opentelemetry_otlp::SpanExporter::builder().with_http();
 
@@ -186,29 +573,85 @@ async fn main() -> Result<()> {
     )?;
 
     // Merge the configuration from CLI, environment, files, container secrets
-    let server: Config = Figment::new()
-        .merge(FileAdapter::wrap(Toml::file(
-            proj_dirs.config_dir().join("config.toml"),
-        )))
-        .merge(FileAdapter::wrap(Env::prefixed("BITPART_")))
-        .merge(Serialized::defaults(Cli::parse()))
-        .extract()?;
-
-    // Setup logging and telemetry
+    let server: Config = figment(
+        FileAdapter::wrap(Toml::file(proj_dirs.config_dir().join("config.toml"))),
+        FileAdapter::wrap(Env::prefixed("BITPART_")),
+        Cli::parse(),
+    )
+    .extract()?;
+
+    // Setup logging and telemetry. The bots' own log messages can be more
+    // or less verbose than the server's.
+    let server_level = server.verbose.log_level_filter().as_trace();
+    let interpreter_level = match &server.interpreter_log_level {
+        Some(level) => level.parse::<LevelFilter>().map_err(|e| {
+            BitpartErrorKind::Interpreter(format!("invalid interpreter log level: {e}"))
+        })?,
+        None => server_level,
+    };
+    csml::interpret::set_interpreter_log_level(interpreter_level);
+    csml::pool::set_interpreter_pool(
+        server.interpreter_threads,
+        server.interpreter_stack_size,
+        server.interpreter_queue_size,
+    );
+    let filter = Targets::new()
+        .with_default(server_level)
+        .with_target(csml::interpret::INTERPRETER_LOG_TARGET, interpreter_level);
     if server.opentelemetry {
+        // bitpart::metrics records against the global provider, which is a
+        // no-op unless set here
+        let meter_provider = telemetry_meter_init()?;
+        opentelemetry::global::set_meter_provider(meter_provider.clone());
         tracing_subscriber::registry()
-            .with(server.verbose.log_level_filter().as_trace())
-            .with(tracing_subscriber::fmt::layer())
+            .with(filter)
+            .with(fmt_layer(server.log_format))
             .with(tracing_opentelemetry::layer().with_tracer(telemetry_tracer_init()?))
-            .with(MetricsLayer::new(telemetry_meter_init()?))
+            .with(MetricsLayer::new(meter_provider))
             .init();
     } else {
         tracing_subscriber::registry()
-            .with(server.verbose.log_level_filter().as_trace())
-            .with(tracing_subscriber::fmt::layer())
+            .with(filter)
+            .with(fmt_layer(server.log_format))
             .init();
     }
 
+    csml::data::set_engine_version_check(server.engine_version_check);
+    if let Some(apps_endpoint) = server.apps_endpoint.clone() {
+        csml::conversation::set_default_apps_endpoint(apps_endpoint);
+    }
+    if let Some(secret) = server.callback_secret.clone() {
+        csml::callback::set_default_secret(secret);
+    }
+    if let Some(attempts) = server.callback_attempts {
+        csml::callback::set_max_attempts(attempts);
+    }
+    if let Some(timeout) = server.callback_timeout {
+        csml::callback::set_timeout(Duration::from_secs(timeout));
+    }
+    if let Some(max_size) = server.max_flow_size {
+        api::bot::set_max_flow_size(max_size);
+    }
+    if let Some(max_size) = server.max_bot_size {
+        api::bot::set_max_bot_size(max_size);
+    }
+    signal::set_limits(signal::Limits {
+        max_attachments: server.max_attachments,
+        max_attachment_size: server.max_attachment_size,
+        max_message_size: server.max_message_size,
+        attachment_timeout: server.attachment_timeout.map(Duration::from_secs),
+        attachment_downloads: server.attachment_downloads,
+        transcription_timeout: server.transcription_timeout.map(Duration::from_secs),
+        send_timeout: server.send_timeout.map(Duration::from_secs),
+        reconnect_delay: server.reconnect_delay.map(Duration::from_secs),
+        reconnect_max_delay: server.reconnect_max_delay.map(Duration::from_secs),
+        reconnect_attempts: server.reconnect_attempts,
+    });
+    signal::set_identity_policy(
+        server.reject_new_identities,
+        server.identity_trust_window.map(Duration::from_secs),
+    );
+
     // Initialize database.
     let pool = bitpart_common::db::build_pool(
         std::path::Path::new(&server.database),
@@ -218,7 +661,6 @@ async fn main() -> Result<()> {
     migrate(&pool).await?;
 
     // Start incoming message channels
-    let channels = db::channel::list(None, None, &pool).await?;
     let token = CancellationToken::new();
     let tracker = TaskTracker::new();
     let tokens: HashMap<(String, String), CancellationToken> = HashMap::new();
@@ -230,16 +672,58 @@ async fn main() -> Result<()> {
         tracker: tracker.clone(),
         attachments_dir: proj_dirs.cache_dir().to_path_buf(),
         manager: Arc::new(signal::SignalManager::new()),
+        maintenance: Arc::new(AtomicBool::new(false)),
+    };
+    signal::set_attachment_sources(
+        state.attachments_dir.clone(),
+        server.attachment_hosts.clone(),
+    );
+    api::start_online_channels(&mut state).await?;
+
+    // Deliver queued callbacks, including any left pending by a previous run
+    tracker.spawn(csml::callback::run(state.pool.clone(), token.clone()));
+
+    // Delete expired data, which is otherwise only hidden from reads
+    let expiry_interval = server
+        .expiry_interval
+        .map(Duration::from_secs)
+        .unwrap_or(db::expiry::DEFAULT_SWEEP_INTERVAL);
+    tracker.spawn(db::expiry::run(
+        state.pool.clone(),
+        expiry_interval,
+        token.clone(),
+    ));
+
+    // Count open conversations for the active conversations gauge
+    if server.opentelemetry {
+        tracker.spawn(metrics::run(
+            state.pool.clone(),
+            metrics::DEFAULT_COUNT_INTERVAL,
+            token.clone(),
+        ));
+    }
+
+    // Delete old attachments, if they are limited
+    let retention = attachments::RetentionPolicy {
+        max_bytes: server.attachments_max_bytes,
+        max_age: server.attachments_max_age.map(Duration::from_secs),
     };
-    for channel in channels.iter() {
-        let res = api::start_channel(&channel.id, &channel.bot_id, &mut state).await?;
-        info!("Started channel: {}", res);
+    if !retention.is_unlimited() {
+        tracker.spawn(attachments::run(
+            state.attachments_dir.clone(),
+            retention,
+            attachments::DEFAULT_PRUNE_INTERVAL,
+            token.clone(),
+        ));
     }
 
     // Run client API
     let app = Router::new()
         .route("/ws", any(socket::handler))
         .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        // added after the authentication layer, so orchestrators can probe them
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
         .with_state(state);
 
     println!("Server is running 🤖");
@@ -255,24 +739,88 @@ async fn main() -> Result<()> {
         });
     }
 
-    if let Ok(addr) = server.bind.parse::<SocketAddr>() {
-        let listener = tokio::net::TcpListener::bind(addr)
-            .await
-            .expect("Unable to bind to address");
-        axum::serve(
-            listener,
-            app.into_make_service_with_connect_info::<SocketAddr>(),
-        )
-        .with_graceful_shutdown(async move { tracker.wait().await })
-        .await?;
-    } else {
-        let Ok(path) = server.bind.parse::<PathBuf>();
-        let _ = tokio::fs::remove_file(&path).await;
-        let listener = tokio::net::UnixListener::bind(path).expect("Unable to bind to address");
-        axum::serve(listener, app.into_make_service())
-            .with_graceful_shutdown(async move { tracker.wait().await })
-            .await?;
-    };
+    // Serve the same router on every configured address
+    let mut servers = JoinSet::new();
+    for bind in server.bind.iter() {
+        let listener = bind_listener(bind).await?;
+        info!("Listening on {}", bind);
+        servers.spawn(serve(listener, app.clone(), tracker.clone()));
+    }
+    while let Some(res) = servers.join_next().await {
+        res.map_err(|e| BitpartErrorKind::Api(e.to_string()))??;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test_main {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn get_root<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(
+        mut stream: S,
+    ) -> String {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    const REQUIRED: &str = r#"
+        auth = "auth"
+        bind = "127.0.0.1:3000"
+        database = "bitpart.sqlite"
+        key = "key"
+    "#;
+
+    #[test]
+    fn it_should_reject_new_identities_from_config_or_env() {
+        let config = |file: String, env: Env, args: &[&str]| -> Config {
+            let cli = Cli::parse_from(std::iter::once("bitpart").chain(args.iter().copied()));
+            figment(Toml::string(&file), env, cli).extract().unwrap()
+        };
+        let unset = || Env::prefixed("BITPART_TEST_UNSET_");
+
+        assert!(!config(REQUIRED.to_owned(), unset(), &[]).reject_new_identities);
+        let file = format!("{REQUIRED}\nreject_new_identities = true");
+        assert!(config(file.clone(), unset(), &[]).reject_new_identities);
+        assert!(!config(file, unset(), &["--reject-new-identities=false"]).reject_new_identities);
+        assert!(
+            config(REQUIRED.to_owned(), unset(), &["--reject-new-identities"])
+                .reject_new_identities
+        );
+
+        // SAFETY: no other test reads this variable
+        unsafe { std::env::set_var("BITPART_TEST_1908_REJECT_NEW_IDENTITIES", "true") };
+        let env = Env::prefixed("BITPART_TEST_1908_");
+        assert!(config(REQUIRED.to_owned(), env, &[]).reject_new_identities);
+    }
+
+    #[tokio::test]
+    async fn it_should_serve_tcp_and_unix_together() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("bitpart.sock");
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        let tracker = TaskTracker::new();
+
+        let tcp = bind_listener("127.0.0.1:0").await.unwrap();
+        let Listener::Tcp(ref listener) = tcp else {
+            panic!("expected a TCP listener");
+        };
+        let addr = listener.local_addr().unwrap();
+        let unix = bind_listener(path.to_str().unwrap()).await.unwrap();
+        assert!(matches!(unix, Listener::Unix(_)));
+
+        tokio::spawn(serve(tcp, app.clone(), tracker.clone()));
+        tokio::spawn(serve(unix, app, tracker));
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert!(get_root(stream).await.ends_with("ok"));
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        assert!(get_root(stream).await.ends_with("ok"));
+    }
+}
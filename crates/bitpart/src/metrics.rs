@@ -0,0 +1,175 @@
+// Bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Counters exported over OpenTelemetry. They are recorded with the global
+//! meter provider, which is only set when `opentelemetry` is enabled. Until
+//! then it is a no-op, so recording costs next to nothing.
+
+use bitpart_common::db::Pool;
+use bitpart_common::error::Result;
+use opentelemetry::{
+    KeyValue, global,
+    metrics::{Counter, ObservableGauge},
+};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+
+use crate::db;
+
+/// How often open conversations are counted for `bitpart_active_conversations`
+pub const DEFAULT_COUNT_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Metrics {
+    messages_received: Counter<u64>,
+    messages_sent: Counter<u64>,
+    interpreter_errors: Counter<u64>,
+    _active_conversations: ObservableGauge<i64>,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Open conversations of each bot on each channel, as last counted. The
+/// gauge is observed when metrics are exported, off the async runtime, so it
+/// reports this count rather than querying the database itself.
+static OPEN_CONVERSATIONS: Mutex<Vec<(String, String, i64)>> = Mutex::new(Vec::new());
+
+/// The counters, created on first use, which must come after the meter
+/// provider is set up
+fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| {
+        let meter = global::meter("bitpart");
+        Metrics {
+            messages_received: meter
+                .u64_counter("bitpart_messages_received")
+                .with_description("Messages received from users and handed to a bot")
+                .build(),
+            messages_sent: meter
+                .u64_counter("bitpart_messages_sent")
+                .with_description("Messages sent by bots")
+                .build(),
+            interpreter_errors: meter
+                .u64_counter("bitpart_interpreter_errors")
+                .with_description("Errors raised while interpreting flows")
+                .build(),
+            _active_conversations: meter
+                .i64_observable_gauge("bitpart_active_conversations")
+                .with_description("Open conversations")
+                .with_callback(|observer| {
+                    let counts = OPEN_CONVERSATIONS.lock().unwrap_or_else(|e| e.into_inner());
+                    for (bot_id, channel_id, count) in counts.iter() {
+                        observer.observe(*count, &attributes(bot_id, channel_id));
+                    }
+                })
+                .build(),
+        }
+    })
+}
+
+fn attributes(bot_id: &str, channel_id: &str) -> [KeyValue; 2] {
+    [
+        KeyValue::new("bot_id", bot_id.to_owned()),
+        KeyValue::new("channel_id", channel_id.to_owned()),
+    ]
+}
+
+pub fn message_received(bot_id: &str, channel_id: &str) {
+    metrics()
+        .messages_received
+        .add(1, &attributes(bot_id, channel_id));
+}
+
+pub fn message_sent(bot_id: &str, channel_id: &str) {
+    metrics()
+        .messages_sent
+        .add(1, &attributes(bot_id, channel_id));
+}
+
+pub fn interpreter_error(bot_id: &str, channel_id: &str) {
+    metrics()
+        .interpreter_errors
+        .add(1, &attributes(bot_id, channel_id));
+}
+
+/**
+ * Count the open conversations for `bitpart_active_conversations`. The count
+ * comes from the database, so it is right after a restart and includes
+ * conversations closed by expiring.
+ */
+pub async fn count_open_conversations(pool: &Pool) -> Result<()> {
+    let counts = db::conversation::count_open(pool).await?;
+    // the gauge is only registered once the metrics are first used
+    metrics();
+    *OPEN_CONVERSATIONS.lock().unwrap_or_else(|e| e.into_inner()) = counts;
+    Ok(())
+}
+
+/**
+ * Count open conversations every `interval` until `token` is cancelled.
+ */
+pub async fn run(pool: Pool, interval: Duration, token: CancellationToken) {
+    loop {
+        if let Err(err) = count_open_conversations(&pool).await {
+            error!("counting open conversations: {}", err);
+        }
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::get_test_pool;
+    use chrono::Utc;
+    use csml_interpreter::data::Client;
+
+    #[tokio::test]
+    async fn it_should_count_open_conversations() {
+        let pool = get_test_pool().await;
+
+        let client = |user_id: &str| Client {
+            bot_id: "bot_id".to_owned(),
+            channel_id: "signal".to_owned(),
+            user_id: user_id.to_owned(),
+        };
+        let past = Some(Utc::now().naive_utc() - chrono::Duration::days(1));
+        db::conversation::create("Default", "start", &client("a"), None, &pool)
+            .await
+            .unwrap();
+        db::conversation::create("Default", "start", &client("b"), None, &pool)
+            .await
+            .unwrap();
+        db::conversation::create("Default", "start", &client("c"), past, &pool)
+            .await
+            .unwrap();
+        let closed = db::conversation::create("Default", "start", &client("d"), None, &pool)
+            .await
+            .unwrap();
+        db::conversation::set_status_by_id(&closed, "CLOSED", &pool)
+            .await
+            .unwrap();
+
+        count_open_conversations(&pool).await.unwrap();
+        assert_eq!(
+            *OPEN_CONVERSATIONS.lock().unwrap(),
+            vec![("bot_id".to_owned(), "signal".to_owned(), 2)]
+        );
+    }
+}
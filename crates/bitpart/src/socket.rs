@@ -92,7 +92,12 @@ impl<T: Serialize> ApiResultExt for std::result::Result<T, BitpartError> {
     fn into_ws(self, response_type: &str) -> Result<Option<Message>> {
         match self {
             Ok(res) => wrap_response(response_type, &res),
-            Err(err) => wrap_error(response_type, &err.to_string()),
+            // validation errors are kept structured, so editors can show where
+            // they are
+            Err(err) => match err.inner() {
+                BitpartErrorKind::Validation(errors) => wrap_error(response_type, errors),
+                _ => wrap_error(response_type, &err.to_string()),
+            },
         }
     }
 }
@@ -118,11 +123,26 @@ async fn process_message(
                         .await
                         .into_ws("BotVersions")
                 }
+                SocketMessage::ReadBotVersion { id, version_id } => {
+                    api::get_bot_version(&id, &version_id, state)
+                        .await
+                        .into_ws("ReadBotVersion")
+                }
                 SocketMessage::RollbackBot { id, version_id } => {
-                    api::touch_bot_version(&id, &version_id, state)
+                    api::rollback_bot(&id, &version_id, state)
                         .await
                         .into_ws("RollbackBot")
                 }
+                SocketMessage::UpsertFlow { bot_id, flow } => {
+                    api::upsert_flow(&bot_id, *flow, state)
+                        .await
+                        .into_ws("UpsertFlow")
+                }
+                SocketMessage::DeleteFlow { bot_id, flow_id } => {
+                    api::delete_flow(&bot_id, &flow_id, state)
+                        .await
+                        .into_ws("DeleteFlow")
+                }
                 SocketMessage::DiffBot {
                     version_a,
                     version_b,
@@ -133,11 +153,19 @@ async fn process_message(
                     api::delete_bot(&id, state).await.into_ws("DeleteBot")
                 }
                 SocketMessage::ListBots(options) => {
-                    let (limit, offset) =
-                        options.map(|p| (p.limit, p.offset)).unwrap_or((None, None));
-                    api::list_bots(limit, offset, state)
-                        .await
-                        .into_ws("ListBots")
+                    let options = options.unwrap_or_default();
+                    if options.detailed {
+                        api::list_bot_summaries(options.limit, options.offset, state)
+                            .await
+                            .into_ws("ListBots")
+                    } else {
+                        api::list_bots(options.limit, options.offset, state)
+                            .await
+                            .into_ws("ListBots")
+                    }
+                }
+                SocketMessage::RecompileBots => {
+                    api::recompile_bots(state).await.into_ws("RecompileBots")
                 }
                 SocketMessage::CreateChannel { id, bot_id } => {
                     api::create_channel(&id, &bot_id, state)
@@ -152,6 +180,41 @@ async fn process_message(
                         .await
                         .into_ws("ResetChannel")
                 }
+                SocketMessage::SetPresence { id, bot_id, online } => {
+                    api::set_presence(&id, &bot_id, online, state)
+                        .await
+                        .into_ws("SetPresence")
+                }
+                SocketMessage::StartChannel {
+                    id,
+                    bot_id,
+                    apps_endpoint,
+                } => api::start_channel(&id, &bot_id, apps_endpoint.as_deref(), state)
+                    .await
+                    .into_ws("StartChannel"),
+                SocketMessage::StopChannel { id, bot_id } => api::stop_channel(&id, &bot_id, state)
+                    .await
+                    .into_ws("StopChannel"),
+                SocketMessage::StoreStats { id, bot_id } => api::store_stats(&id, &bot_id, state)
+                    .await
+                    .into_ws("StoreStats"),
+                SocketMessage::DescribeChannel { id, bot_id } => {
+                    api::describe_channel(&id, &bot_id, state)
+                        .await
+                        .into_ws("DescribeChannel")
+                }
+                SocketMessage::DeleteContact { id, bot_id, uuid } => {
+                    api::delete_contact(&id, &bot_id, &uuid, state)
+                        .await
+                        .into_ws("DeleteContact")
+                }
+                SocketMessage::DeleteGroup {
+                    id,
+                    bot_id,
+                    master_key,
+                } => api::delete_group(&id, &bot_id, &master_key, state)
+                    .await
+                    .into_ws("DeleteGroup"),
                 SocketMessage::ListChannels(options) => {
                     let (limit, offset) =
                         options.map(|p| (p.limit, p.offset)).unwrap_or((None, None));
@@ -164,17 +227,123 @@ async fn process_message(
                         .await
                         .into_ws("DeleteChannel")
                 }
-                SocketMessage::ChatRequest(req) => api::process_request(&req, &state.pool)
+                SocketMessage::SetConversationStep { client, flow, step } => {
+                    api::set_conversation_step(&client, &flow, &step, state)
+                        .await
+                        .into_ws("SetConversationStep")
+                }
+                SocketMessage::GetConversation { client } => api::get_conversation(&client, state)
+                    .await
+                    .into_ws("GetConversation"),
+                SocketMessage::ListConversations {
+                    bot_id,
+                    channel_id,
+                    user_id,
+                    status,
+                    limit,
+                    offset,
+                } => api::list_conversations(
+                    &bot_id,
+                    channel_id.as_deref(),
+                    user_id.as_deref(),
+                    status.as_deref(),
+                    limit,
+                    offset,
+                    state,
+                )
+                .await
+                .into_ws("ListConversations"),
+                SocketMessage::GetMemories {
+                    client,
+                    prefix,
+                    limit,
+                    offset,
+                } => api::get_memories(&client, &prefix, limit, offset, state)
+                    .await
+                    .into_ws("GetMemories"),
+                SocketMessage::ForgetUser { client } => {
+                    api::forget_user(&client, state).await.into_ws("ForgetUser")
+                }
+                SocketMessage::CreateMemories { client, memories } => {
+                    api::create_memories(&client, memories, state)
+                        .await
+                        .into_ws("CreateMemories")
+                }
+                SocketMessage::EndHandoff { client } => {
+                    api::end_handoff(&client, state).await.into_ws("EndHandoff")
+                }
+                SocketMessage::PatchConversation { client, status } => {
+                    api::patch_conversation(&client, &status, state)
+                        .await
+                        .into_ws("PatchConversation")
+                }
+                SocketMessage::GetMessages { client, options } => {
+                    let options = options.unwrap_or_default();
+                    api::query_messages(
+                        &client,
+                        options.direction.as_deref(),
+                        options.from.as_deref(),
+                        options.to.as_deref(),
+                        options.limit,
+                        options.offset,
+                        state,
+                    )
+                    .await
+                    .into_ws("GetMessages")
+                }
+                SocketMessage::Send { client, payload } => {
+                    api::send_message(&client, payload, state)
+                        .await
+                        .into_ws("Send")
+                }
+                SocketMessage::ResendRecent { client, count } => {
+                    api::resend_recent(&client, count, state)
+                        .await
+                        .into_ws("ResendRecent")
+                }
+                SocketMessage::FailedSends { bot_id, options } => {
+                    let (limit, offset) =
+                        options.map(|p| (p.limit, p.offset)).unwrap_or((None, None));
+                    api::list_failed_sends(&bot_id, limit, offset, state)
+                        .await
+                        .into_ws("FailedSends")
+                }
+                SocketMessage::FailedCallbacks(options) => {
+                    let (limit, offset) =
+                        options.map(|p| (p.limit, p.offset)).unwrap_or((None, None));
+                    api::list_failed_callbacks(limit, offset, state)
+                        .await
+                        .into_ws("FailedCallbacks")
+                }
+                SocketMessage::ReplayCallbacks { url } => {
+                    api::replay_callbacks(url.as_deref(), state)
+                        .await
+                        .into_ws("ReplayCallbacks")
+                }
+                SocketMessage::ChatRequest(req) => {
+                    if api::in_maintenance(state) {
+                        wrap_error("ChatRequest", &"Server is in maintenance")
+                    } else {
+                        api::process_request(&req, &state.pool)
+                            .await
+                            .into_ws("ChatRequest")
+                    }
+                }
+                SocketMessage::SetMaintenance { enabled } => api::set_maintenance(enabled, state)
                     .await
-                    .into_ws("ChatRequest"),
+                    .into_ws("SetMaintenance"),
                 SocketMessage::LinkChannel {
                     id,
                     bot_id,
                     device_name,
+                    format,
+                    apps_endpoint,
                 } => api::link_channel(
                     &id,
                     &bot_id,
                     &device_name,
+                    format,
+                    apps_endpoint.as_deref(),
                     state.attachments_dir.clone(),
                     state,
                 )
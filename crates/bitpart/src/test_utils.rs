@@ -0,0 +1,61 @@
+// Bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Test fixtures for the library's own tests, which can't reach the binary's
+//! `utils`. They mirror the ones there.
+
+use axum::{Json, Router, extract::State, routing::post};
+use bitpart_common::db::{Pool, build_pool, migration::migrate};
+use serde_json::Value;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// A migrated database that lasts for the rest of the test run
+pub(crate) async fn get_test_pool() -> Pool {
+    // File-backed: deadpool's `:memory:` gives each connection its own
+    // private DB.
+    let dir = Box::leak(Box::new(tempfile::tempdir().expect("tempdir")));
+    open_test_pool(&dir.path().join("bitpart-test.sqlite")).await
+}
+
+/// Open, and migrate, the test database at `path`, e.g. again to simulate a
+/// restart
+pub(crate) async fn open_test_pool(path: &Path) -> Pool {
+    let key = "bitparttestkey";
+
+    let pool = build_pool(path, key.to_owned(), 4).expect("build pool");
+    migrate(&pool).await.expect("rusqlite migrator");
+    pool
+}
+
+type Received = Arc<Mutex<Vec<Value>>>;
+
+async fn record_post(State(received): State<Received>, Json(body): Json<Value>) {
+    received.lock().unwrap().push(body);
+}
+
+/// Start an HTTP server that records the JSON bodies POSTed to it, standing in
+/// for a callback or webhook endpoint. Returns its url and the bodies received.
+pub(crate) async fn start_test_receiver() -> (String, Received) {
+    let received: Received = Default::default();
+    let app = Router::new()
+        .route("/", post(record_post))
+        .with_state(received.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (url, received)
+}
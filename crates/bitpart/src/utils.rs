@@ -19,21 +19,29 @@ use crate::channels::signal::{ChannelBackend, ChannelMessage};
 #[cfg(test)]
 use crate::{api::ApiState, socket};
 #[cfg(test)]
-use axum::{Router, routing::any};
+use axum::{
+    Json, Router,
+    extract::State,
+    routing::{any, post},
+};
 #[cfg(test)]
 use axum_test::{TestServer, TestWebSocket};
 #[cfg(test)]
 use bitpart_common::{
-    db::{build_pool, migration::migrate},
+    db::{Pool, build_pool, migration::migrate},
     error::Result,
 };
 #[cfg(test)]
+use serde_json::Value;
+#[cfg(test)]
 use std::collections::HashMap;
 #[cfg(test)]
 use std::net::SocketAddr;
 #[cfg(test)]
 use std::sync::Arc;
 #[cfg(test)]
+use std::sync::atomic::AtomicBool;
+#[cfg(test)]
 use tokio::sync::Mutex;
 #[cfg(test)]
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
@@ -52,6 +60,11 @@ impl ChannelBackend for MockChannelBackend {
 
 #[cfg(test)]
 pub async fn get_test_socket() -> TestWebSocket {
+    get_test_socket_with_pool().await.0
+}
+
+#[cfg(test)]
+pub(crate) async fn get_test_pool() -> Pool {
     // File-backed: deadpool's `:memory:` gives each connection its own
     // private DB.
     let dir = Box::leak(Box::new(tempfile::tempdir().expect("tempdir")));
@@ -60,19 +73,32 @@ pub async fn get_test_socket() -> TestWebSocket {
 
     let pool = build_pool(&path, key.to_owned(), 4).expect("build pool");
     migrate(&pool).await.expect("rusqlite migrator");
+    pool
+}
+
+#[cfg(test)]
+pub async fn get_test_state(manager: Arc<dyn ChannelBackend>) -> ApiState {
+    let pool = get_test_pool().await;
 
     let token = CancellationToken::new();
     let tracker = TaskTracker::new();
     let tokens: HashMap<(String, String), CancellationToken> = HashMap::new();
-    let state = ApiState {
+    ApiState {
         pool,
         parent_token: token.clone(),
         tokens: Arc::new(Mutex::new(tokens)),
         tracker: tracker.clone(),
         auth: "test".into(),
         attachments_dir: "/tmp".into(),
-        manager: Arc::new(MockChannelBackend),
-    };
+        manager,
+        maintenance: Arc::new(AtomicBool::new(false)),
+    }
+}
+
+#[cfg(test)]
+pub async fn get_test_socket_with_pool() -> (TestWebSocket, Pool) {
+    let state = get_test_state(Arc::new(MockChannelBackend)).await;
+    let pool = state.pool.clone();
 
     let app = Router::new()
         .route("/ws", any(socket::handler))
@@ -82,5 +108,28 @@ pub async fn get_test_socket() -> TestWebSocket {
         .http_transport()
         .build(app.into_make_service_with_connect_info::<SocketAddr>())
         .unwrap();
-    server.get_websocket("/ws").await.into_websocket().await
+    let socket = server.get_websocket("/ws").await.into_websocket().await;
+    (socket, pool)
+}
+
+#[cfg(test)]
+type Received = Arc<std::sync::Mutex<Vec<Value>>>;
+
+#[cfg(test)]
+async fn record_post(State(received): State<Received>, Json(body): Json<Value>) {
+    received.lock().unwrap().push(body);
+}
+
+/// Start an HTTP server that records the JSON bodies POSTed to it, standing in
+/// for a callback or webhook endpoint. Returns its url and the bodies received.
+#[cfg(test)]
+pub async fn start_test_receiver() -> (String, Received) {
+    let received: Received = Default::default();
+    let app = Router::new()
+        .route("/", post(record_post))
+        .with_state(received.clone());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    (url, received)
 }
@@ -204,35 +204,38 @@ impl ContentsStore for BitpartStore {
     ) -> Result<Self::MessagesIter, BitpartStoreError> {
         let thread_id = messages_thread_id(thread);
 
-        let (start_ts, end_ts) = match (range.start_bound(), range.end_bound()) {
-            (Bound::Included(start), Bound::Unbounded) => (*start as i64, i64::MAX),
-            (Bound::Included(start), Bound::Excluded(end)) => (*start as i64, (*end - 1) as i64),
-            (Bound::Included(start), Bound::Included(end)) => (*start as i64, *end as i64),
-            (Bound::Unbounded, Bound::Included(end)) => (i64::MIN, *end as i64),
-            (Bound::Unbounded, Bound::Excluded(end)) => (i64::MIN, (*end - 1) as i64),
-            (Bound::Unbounded, Bound::Unbounded) => (i64::MIN, i64::MAX),
-            (Bound::Excluded(_), _) => {
-                unreachable!("range that excludes the initial value")
-            }
+        // Only the requested window is loaded, as an inclusive range of
+        // timestamps. Timestamps are stored as i64, so larger bounds are
+        // clamped.
+        let start_ts = match range.start_bound() {
+            Bound::Included(start) => Some(*start),
+            Bound::Excluded(start) => start.checked_add(1),
+            Bound::Unbounded => Some(0),
+        };
+        let end_ts = match range.end_bound() {
+            Bound::Included(end) => Some(*end),
+            Bound::Excluded(end) => end.checked_sub(1),
+            Bound::Unbounded => Some(u64::MAX),
         };
+        let clamp = |ts: u64| i64::try_from(ts).unwrap_or(i64::MAX);
 
-        let messages_data = if start_ts == i64::MIN && end_ts == i64::MAX {
-            db::messages::get_all(&self.id, &thread_id, &self.pool).await?
-        } else {
-            db::messages::get_range(&self.id, &thread_id, start_ts, end_ts, &self.pool).await?
+        let messages_data = match (start_ts, end_ts) {
+            (Some(0), Some(u64::MAX)) => {
+                db::messages::get_all(&self.id, &thread_id, &self.pool).await?
+            }
+            (Some(start), Some(end)) if start <= end => {
+                db::messages::get_range(&self.id, &thread_id, clamp(start), clamp(end), &self.pool)
+                    .await?
+            }
+            _ => Vec::new(),
         };
 
         debug!(%thread, count = messages_data.len(), "loading message thread");
 
-        let iter: Vec<(Vec<u8>, Vec<u8>)> = messages_data
-            .into_iter()
-            .map(|(ts, data)| ((ts as u64).to_be_bytes().into(), data))
-            .collect();
-
         Ok(BitpartMessagesIter {
             start: 0,
-            end: if iter.is_empty() { 0 } else { iter.len() - 1 },
-            data: iter,
+            end: messages_data.len(),
+            data: messages_data,
         })
     }
 
@@ -411,20 +414,19 @@ impl Iterator for BitpartStickerPacksIter {
 }
 
 pub struct BitpartMessagesIter {
-    data: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Stored messages in the requested window, by timestamp
+    data: Vec<(i64, Vec<u8>)>,
+    /// Index of the next message from the front
     start: usize,
+    /// One past the index of the next message from the back
     end: usize,
 }
 
 impl BitpartMessagesIter {
-    fn decode(
-        &self,
-        elem: Result<(&Vec<u8>, &Vec<u8>), BitpartStoreError>,
-    ) -> Option<Result<Content, BitpartStoreError>> {
-        elem.and_then(|(_, value)| {
-            ContentProto::decode(&value[..]).map_err(BitpartStoreError::from)
-        })
-        .map_or_else(|e| Some(Err(e)), |p| Some(p.try_into()))
+    fn decode(value: &[u8]) -> Result<Content, BitpartStoreError> {
+        ContentProto::decode(value)
+            .map_err(BitpartStoreError::from)
+            .and_then(|proto| proto.try_into())
     }
 }
 
@@ -432,23 +434,34 @@ impl Iterator for BitpartMessagesIter {
     type Item = Result<Content, BitpartStoreError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let (key, value) = self.data.get(self.start)?;
+        if self.start >= self.end {
+            return None;
+        }
+        let (_, value) = &self.data[self.start];
         self.start += 1;
-        self.decode(Ok((key, value)))
+        Some(Self::decode(value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end - self.start;
+        (len, Some(len))
     }
 }
 
 impl DoubleEndedIterator for BitpartMessagesIter {
     fn next_back(&mut self) -> Option<Self::Item> {
-        let (key, value) = self.data.get(self.end)?;
-        if self.end > 0 {
-            self.end -= 1;
+        if self.start >= self.end {
+            return None;
         }
-        self.decode(Ok((key, value)))
+        self.end -= 1;
+        let (_, value) = &self.data[self.end];
+        Some(Self::decode(value))
     }
 }
 
-fn messages_thread_id(t: &Thread) -> String {
+impl ExactSizeIterator for BitpartMessagesIter {}
+
+pub(crate) fn messages_thread_id(t: &Thread) -> String {
     use base64::prelude::*;
     let key = match t {
         Thread::Contact(service_id) => {
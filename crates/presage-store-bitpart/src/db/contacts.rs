@@ -91,6 +91,22 @@ pub async fn get_all(
     .map_err(BitpartStoreError::from)
 }
 
+pub async fn remove(channel_id: &str, uuid: &[u8], pool: &Pool) -> Result<u64, BitpartStoreError> {
+    let conn = pool.get().await.map_err(pool_err)?;
+    let channel_id = channel_id.to_owned();
+    let uuid = uuid.to_vec();
+    conn.interact(move |c| -> rusqlite::Result<u64> {
+        let n = c.execute(
+            "DELETE FROM signal_contacts WHERE channel_id = ?1 AND uuid = ?2",
+            params![channel_id, uuid],
+        )?;
+        Ok(n as u64)
+    })
+    .await
+    .map_err(pool_err)?
+    .map_err(BitpartStoreError::from)
+}
+
 pub async fn remove_all(channel_id: &str, pool: &Pool) -> Result<u64, BitpartStoreError> {
     let conn = pool.get().await.map_err(pool_err)?;
     let channel_id = channel_id.to_owned();
@@ -165,4 +181,46 @@ mod tests {
         let retrieved = get(channel_id, uuid, &pool).await.unwrap();
         assert_eq!(retrieved, Some(b"contact2".to_vec()));
     }
+
+    #[tokio::test]
+    async fn test_remove() {
+        let pool = setup_test_pool().await;
+        let channel_id = "test_channel";
+
+        set(channel_id, b"uuid1_16bytes123", b"contact1", &pool)
+            .await
+            .unwrap();
+        set(channel_id, b"uuid2_16bytes123", b"contact2", &pool)
+            .await
+            .unwrap();
+        set("other_channel", b"uuid1_16bytes123", b"contact1", &pool)
+            .await
+            .unwrap();
+
+        let removed = remove(channel_id, b"uuid1_16bytes123", &pool)
+            .await
+            .unwrap();
+        assert_eq!(removed, 1);
+        assert_eq!(
+            get(channel_id, b"uuid1_16bytes123", &pool).await.unwrap(),
+            None
+        );
+        assert!(
+            get(channel_id, b"uuid2_16bytes123", &pool)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            get("other_channel", b"uuid1_16bytes123", &pool)
+                .await
+                .unwrap()
+                .is_some()
+        );
+
+        let removed = remove(channel_id, b"uuid1_16bytes123", &pool)
+            .await
+            .unwrap();
+        assert_eq!(removed, 0);
+    }
 }
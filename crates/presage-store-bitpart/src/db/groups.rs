@@ -91,6 +91,33 @@ pub async fn get_all_groups(
     .map_err(BitpartStoreError::from)
 }
 
+/// Removes a group and its cached avatar, returning the number of groups removed.
+pub async fn remove_group(
+    channel_id: &str,
+    master_key: &[u8],
+    pool: &Pool,
+) -> Result<u64, BitpartStoreError> {
+    let conn = pool.get().await.map_err(pool_err)?;
+    let channel_id = channel_id.to_owned();
+    let master_key = master_key.to_vec();
+    conn.interact(move |c| -> rusqlite::Result<u64> {
+        let tx = c.transaction()?;
+        let n = tx.execute(
+            "DELETE FROM signal_groups WHERE channel_id = ?1 AND master_key = ?2",
+            params![channel_id, master_key],
+        )?;
+        tx.execute(
+            "DELETE FROM signal_group_avatars WHERE channel_id = ?1 AND master_key = ?2",
+            params![channel_id, master_key],
+        )?;
+        tx.commit()?;
+        Ok(n as u64)
+    })
+    .await
+    .map_err(pool_err)?
+    .map_err(BitpartStoreError::from)
+}
+
 pub async fn remove_all_groups(channel_id: &str, pool: &Pool) -> Result<u64, BitpartStoreError> {
     let conn = pool.get().await.map_err(pool_err)?;
     let channel_id = channel_id.to_owned();
@@ -238,4 +265,41 @@ mod tests {
         let retrieved = get_group(channel_id, master_key, &pool).await.unwrap();
         assert_eq!(retrieved, Some(b"data2".to_vec()));
     }
+
+    #[tokio::test]
+    async fn test_remove_group() {
+        let pool = setup_test_pool().await;
+        let channel_id = "test_channel";
+        let master_key = b"test_master_key_32_bytes_long_ok";
+        let other_key = b"other_master_key_32_bytes_long_o";
+
+        set_group(channel_id, master_key, b"data1", &pool)
+            .await
+            .unwrap();
+        set_group_avatar(channel_id, master_key, b"avatar", &pool)
+            .await
+            .unwrap();
+        set_group(channel_id, other_key, b"data2", &pool)
+            .await
+            .unwrap();
+
+        let removed = remove_group(channel_id, master_key, &pool).await.unwrap();
+        assert_eq!(removed, 1);
+        assert_eq!(
+            get_group(channel_id, master_key, &pool).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            get_group_avatar(channel_id, master_key, &pool)
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(
+            get_group(channel_id, other_key, &pool)
+                .await
+                .unwrap()
+                .is_some()
+        );
+    }
 }
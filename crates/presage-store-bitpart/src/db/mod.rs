@@ -25,8 +25,11 @@ pub mod kyber_pre_keys;
 pub mod messages;
 pub mod pre_keys;
 pub mod profiles;
+pub mod registration;
+pub mod seen_messages;
 pub mod sender_keys;
 pub mod sessions;
 pub mod signed_pre_keys;
 pub mod state;
+pub mod stats;
 pub mod sticker_packs;
@@ -0,0 +1,73 @@
+// presage-store-bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use deadpool_sqlite::Pool;
+use rusqlite::params;
+
+use crate::error::BitpartStoreError;
+
+fn pool_err(e: impl std::fmt::Display) -> BitpartStoreError {
+    BitpartStoreError::Pool(e.to_string())
+}
+
+/// Every table cleared along with the registration: the ACI and PNI state,
+/// keys and sessions, all saved profiles, and the messages seen so far.
+const REGISTRATION_TABLES: &[&str] = &[
+    "signal_state",
+    "signal_pni_state",
+    "signal_profiles",
+    "signal_profile_keys",
+    "signal_profile_avatars",
+    "signal_pre_keys",
+    "signal_signed_pre_keys",
+    "signal_kyber_pre_keys",
+    "signal_sender_keys",
+    "signal_sessions",
+    "signal_pni_pre_keys",
+    "signal_pni_signed_pre_keys",
+    "signal_pni_kyber_pre_keys",
+    "signal_pni_sender_keys",
+    "signal_pni_sessions",
+    "signal_seen_messages",
+];
+
+/// Delete the channel's rows from each of `tables` in a single transaction,
+/// so that either all of them are cleared or none are.
+pub(crate) async fn remove_all_in(
+    channel_id: &str,
+    tables: &'static [&'static str],
+    pool: &Pool,
+) -> Result<(), BitpartStoreError> {
+    let conn = pool.get().await.map_err(pool_err)?;
+    let channel_id = channel_id.to_owned();
+    conn.interact(move |c| -> rusqlite::Result<()> {
+        let tx = c.transaction()?;
+        for table in tables {
+            tx.execute(
+                &format!("DELETE FROM {table} WHERE channel_id = ?1"),
+                params![channel_id],
+            )?;
+        }
+        tx.commit()
+    })
+    .await
+    .map_err(pool_err)?
+    .map_err(BitpartStoreError::from)
+}
+
+pub async fn remove_all(channel_id: &str, pool: &Pool) -> Result<(), BitpartStoreError> {
+    remove_all_in(channel_id, REGISTRATION_TABLES, pool).await
+}
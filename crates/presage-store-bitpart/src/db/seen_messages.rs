@@ -0,0 +1,141 @@
+// presage-store-bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use deadpool_sqlite::Pool;
+use rusqlite::params;
+
+use crate::error::BitpartStoreError;
+
+fn pool_err(e: impl std::fmt::Display) -> BitpartStoreError {
+    BitpartStoreError::Pool(e.to_string())
+}
+
+/// Record a message as seen at `now`, forgetting those seen before
+/// `expired_before`. Returns whether it was not seen already.
+pub async fn insert(
+    channel_id: &str,
+    key: &str,
+    now: i64,
+    expired_before: i64,
+    pool: &Pool,
+) -> Result<bool, BitpartStoreError> {
+    let conn = pool.get().await.map_err(pool_err)?;
+    let channel_id = channel_id.to_owned();
+    let key = key.to_owned();
+    conn.interact(move |c| -> rusqlite::Result<bool> {
+        c.execute(
+            "DELETE FROM signal_seen_messages WHERE channel_id = ?1 AND seen_at < ?2",
+            params![channel_id, expired_before],
+        )?;
+        let inserted = c.execute(
+            "INSERT OR IGNORE INTO signal_seen_messages (channel_id, key, seen_at) VALUES (?1, ?2, ?3)",
+            params![channel_id, key, now],
+        )?;
+        Ok(inserted > 0)
+    })
+    .await
+    .map_err(pool_err)?
+    .map_err(BitpartStoreError::from)
+}
+
+/// Whether a message was seen at or after `expired_before`
+pub async fn contains(
+    channel_id: &str,
+    key: &str,
+    expired_before: i64,
+    pool: &Pool,
+) -> Result<bool, BitpartStoreError> {
+    let conn = pool.get().await.map_err(pool_err)?;
+    let channel_id = channel_id.to_owned();
+    let key = key.to_owned();
+    conn.interact(move |c| -> rusqlite::Result<bool> {
+        c.query_row(
+            "SELECT EXISTS (SELECT 1 FROM signal_seen_messages \
+             WHERE channel_id = ?1 AND key = ?2 AND seen_at >= ?3)",
+            params![channel_id, key, expired_before],
+            |row| row.get(0),
+        )
+    })
+    .await
+    .map_err(pool_err)?
+    .map_err(BitpartStoreError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deadpool_sqlite::{Config, Runtime};
+
+    async fn setup_test_pool() -> Pool {
+        let config = Config::new(":memory:");
+        let pool = config.create_pool(Runtime::Tokio1).unwrap();
+
+        let conn = pool.get().await.unwrap();
+        conn.interact(|c| {
+            c.execute(
+                "CREATE TABLE signal_seen_messages (
+                    channel_id varchar NOT NULL,
+                    key varchar NOT NULL,
+                    seen_at integer NOT NULL,
+                    PRIMARY KEY (channel_id, key)
+                )",
+                [],
+            )?;
+            Ok::<(), rusqlite::Error>(())
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        pool
+    }
+
+    #[tokio::test]
+    async fn test_insert_once() {
+        let pool = setup_test_pool().await;
+
+        assert!(insert("test_channel", "guid", 100, 0, &pool).await.unwrap());
+        assert!(!insert("test_channel", "guid", 110, 0, &pool).await.unwrap());
+        assert!(
+            insert("other_channel", "guid", 110, 0, &pool)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_contains() {
+        let pool = setup_test_pool().await;
+
+        assert!(!contains("test_channel", "guid", 0, &pool).await.unwrap());
+        insert("test_channel", "guid", 100, 0, &pool).await.unwrap();
+        assert!(contains("test_channel", "guid", 0, &pool).await.unwrap());
+        assert!(!contains("test_channel", "guid", 200, &pool).await.unwrap());
+        assert!(!contains("other_channel", "guid", 0, &pool).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_forget_expired() {
+        let pool = setup_test_pool().await;
+
+        insert("test_channel", "guid", 100, 0, &pool).await.unwrap();
+        assert!(
+            insert("test_channel", "guid", 300, 200, &pool)
+                .await
+                .unwrap()
+        );
+    }
+}
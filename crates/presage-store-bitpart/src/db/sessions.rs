@@ -269,6 +269,46 @@ pub async fn remove_like_pni(
     remove_like_impl("signal_pni_sessions", channel_id, address_pattern, pool).await
 }
 
+async fn count_like_impl(
+    table: &'static str,
+    channel_id: &str,
+    address_pattern: &str,
+    pool: &Pool,
+) -> Result<u64, BitpartStoreError> {
+    let conn = pool.get().await.map_err(pool_err)?;
+    let channel_id = channel_id.to_owned();
+    let address_pattern = address_pattern.to_owned();
+    conn.interact(move |c| -> rusqlite::Result<u64> {
+        let sql = format!(
+            "SELECT COUNT(*) FROM {} WHERE channel_id = ?1 AND address LIKE ?2",
+            table
+        );
+        c.query_row(&sql, params![channel_id, address_pattern], |row| {
+            row.get::<_, i64>(0)
+        })
+        .map(|n| n as u64)
+    })
+    .await
+    .map_err(pool_err)?
+    .map_err(BitpartStoreError::from)
+}
+
+pub async fn count_like_aci(
+    channel_id: &str,
+    address_pattern: &str,
+    pool: &Pool,
+) -> Result<u64, BitpartStoreError> {
+    count_like_impl("signal_sessions", channel_id, address_pattern, pool).await
+}
+
+pub async fn count_like_pni(
+    channel_id: &str,
+    address_pattern: &str,
+    pool: &Pool,
+) -> Result<u64, BitpartStoreError> {
+    count_like_impl("signal_pni_sessions", channel_id, address_pattern, pool).await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -356,4 +396,37 @@ mod tests {
         let retrieved = get_aci(channel_id, address, &pool).await.unwrap();
         assert_eq!(retrieved, Some(b"data2".to_vec()));
     }
+
+    #[tokio::test]
+    async fn test_count_like() {
+        let pool = setup_test_pool().await;
+        let channel_id = "test_channel";
+
+        set_aci(channel_id, "PNI:addr1.1", b"data1", &pool)
+            .await
+            .unwrap();
+        set_aci(channel_id, "PNI:addr1.2", b"data2", &pool)
+            .await
+            .unwrap();
+        set_aci(channel_id, "addr2.1", b"data3", &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            count_like_aci(channel_id, "PNI:addr1.%", &pool)
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            count_like_aci(channel_id, "addr1.%", &pool).await.unwrap(),
+            0
+        );
+        assert_eq!(
+            count_like_pni(channel_id, "PNI:addr1.%", &pool)
+                .await
+                .unwrap(),
+            0
+        );
+    }
 }
@@ -0,0 +1,114 @@
+// presage-store-bitpart
+// Copyright (C) 2025 Throneless Tech
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use deadpool_sqlite::Pool;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::error::BitpartStoreError;
+
+fn pool_err(e: impl std::fmt::Display) -> BitpartStoreError {
+    BitpartStoreError::Pool(e.to_string())
+}
+
+/// Every per-channel table of the store, named after the sled-style tree it
+/// replaced, with the column holding each row's payload.
+const TREES: &[(&str, &str, &str)] = &[
+    ("identities", "signal_identities", "identity_key"),
+    ("sessions", "signal_sessions", "session_data"),
+    ("pre_keys", "signal_pre_keys", "record_data"),
+    ("signed_pre_keys", "signal_signed_pre_keys", "record_data"),
+    ("kyber_pre_keys", "signal_kyber_pre_keys", "record_data"),
+    ("sender_keys", "signal_sender_keys", "record_data"),
+    ("base_keys_seen", "signal_base_keys_seen", "base_key"),
+    ("state", "signal_state", "value"),
+    ("pni_sessions", "signal_pni_sessions", "session_data"),
+    ("pni_pre_keys", "signal_pni_pre_keys", "record_data"),
+    (
+        "pni_signed_pre_keys",
+        "signal_pni_signed_pre_keys",
+        "record_data",
+    ),
+    (
+        "pni_kyber_pre_keys",
+        "signal_pni_kyber_pre_keys",
+        "record_data",
+    ),
+    ("pni_sender_keys", "signal_pni_sender_keys", "record_data"),
+    ("pni_state", "signal_pni_state", "value"),
+    ("profiles", "signal_profiles", "profile_data"),
+    ("profile_keys", "signal_profile_keys", "profile_key"),
+    ("profile_avatars", "signal_profile_avatars", "avatar_data"),
+    ("contacts", "signal_contacts", "contact_data"),
+    ("groups", "signal_groups", "group_data"),
+    ("group_avatars", "signal_group_avatars", "avatar_data"),
+    ("sticker_packs", "signal_sticker_packs", "pack_data"),
+    ("messages", "signal_messages", "content_data"),
+];
+
+/// Number of rows and approximate payload size of one tree of a channel's store
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeStats {
+    pub tree: String,
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+pub async fn get_all(channel_id: &str, pool: &Pool) -> Result<Vec<TreeStats>, BitpartStoreError> {
+    let conn = pool.get().await.map_err(pool_err)?;
+    let channel_id = channel_id.to_owned();
+    conn.interact(move |c| -> rusqlite::Result<Vec<TreeStats>> {
+        TREES
+            .iter()
+            .map(|(tree, table, column)| {
+                c.query_row(
+                    &format!(
+                        "SELECT COUNT(*), COALESCE(SUM(LENGTH({column})), 0) \
+                         FROM {table} WHERE channel_id = ?1"
+                    ),
+                    params![channel_id],
+                    |row| {
+                        Ok(TreeStats {
+                            tree: (*tree).to_owned(),
+                            rows: row.get(0)?,
+                            bytes: row.get(1)?,
+                        })
+                    },
+                )
+            })
+            .collect()
+    })
+    .await
+    .map_err(pool_err)?
+    .map_err(BitpartStoreError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_trees_map_to_their_own_tables() {
+        let names: HashSet<_> = TREES.iter().map(|(tree, _, _)| tree).collect();
+        assert_eq!(names.len(), TREES.len(), "tree names must be distinct");
+        let tables: HashSet<_> = TREES.iter().map(|(_, table, _)| table).collect();
+        assert_eq!(tables.len(), TREES.len(), "tables must be distinct");
+        for (tree, table, _) in TREES {
+            assert_eq!(*table, format!("signal_{tree}"), "{tree} maps to {table}");
+        }
+    }
+}
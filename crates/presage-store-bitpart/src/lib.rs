@@ -20,18 +20,21 @@
 use base64::prelude::*;
 use presage::{
     libsignal_service::{
+        content::Metadata,
         prelude::{MasterKey, ProfileKey, Uuid},
-        protocol::{IdentityKeyPair, SenderCertificate},
+        protocol::{IdentityKeyPair, SenderCertificate, ServiceId},
+        zkgroup::GroupMasterKeyBytes,
     },
     manager::RegistrationData,
     model::identity::OnNewIdentity,
-    store::{ContentsStore, StateStore, Store},
+    store::{ContentsStore, StateStore, Store, Thread},
 };
 use protocol::BitpartProtocolStore;
 
 use deadpool_sqlite::Pool;
 use sha2::{Digest, Sha256};
 use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 mod content;
 mod db;
@@ -39,11 +42,38 @@ mod error;
 mod protobuf;
 mod protocol;
 
+pub use db::stats::TreeStats;
 pub use error::BitpartStoreError;
 
 const BITPART_KEY_REGISTRATION: &str = "registration";
 const BITPART_KEY_SENDER_CERTIFICATE: &str = "sender_certificate";
 const BITPART_KEY_MASTER: &str = "master";
+const BITPART_KEY_REGISTERED_AT: &str = "registered_at";
+
+/// How long received messages are remembered, to skip them if they are
+/// delivered again
+pub const SEEN_MESSAGES_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The key a received message is remembered by: its server guid, or else its
+/// sender and timestamp
+pub fn seen_message_key(metadata: &Metadata) -> String {
+    match metadata.server_guid {
+        Some(guid) => guid.to_string(),
+        None => format!(
+            "{}:{}",
+            metadata.sender.service_id_string(),
+            metadata.timestamp
+        ),
+    }
+}
+
+/// Seconds since the epoch, as the seen messages are timed
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
 
 #[derive(Clone)]
 pub struct BitpartStore {
@@ -53,6 +83,9 @@ pub struct BitpartStore {
 
     /// Whether to trust new identities automatically (for instance, when a somebody's phone has changed)
     trust_new_identities: OnNewIdentity,
+
+    /// How long after linking new identities are trusted regardless of `trust_new_identities`
+    trust_window: Option<Duration>,
 }
 
 impl BitpartStore {
@@ -65,13 +98,118 @@ impl BitpartStore {
             id: id.to_owned(),
             pool: pool.clone(),
             trust_new_identities,
+            trust_window: None,
         })
     }
 
+    /// Trust changed identities for `window` after this channel was linked,
+    /// before falling back to the `OnNewIdentity` policy. Useful while
+    /// contacts are still settling onto their devices.
+    pub fn with_trust_window(mut self, window: Duration) -> Self {
+        self.trust_window = Some(window);
+        self
+    }
+
+    async fn set_registered_at(&self, secs: u64) -> Result<(), BitpartStoreError> {
+        db::state::set_aci(
+            &self.id,
+            BITPART_KEY_REGISTERED_AT,
+            &secs.to_be_bytes(),
+            &self.pool,
+        )
+        .await
+    }
+
+    /// Whether we are still inside the trust window that follows linking.
+    pub(crate) async fn within_trust_window(&self) -> Result<bool, BitpartStoreError> {
+        let Some(window) = self.trust_window else {
+            return Ok(false);
+        };
+        let Some(data) =
+            db::state::get_aci(&self.id, BITPART_KEY_REGISTERED_AT, &self.pool).await?
+        else {
+            return Ok(false);
+        };
+        let Ok(bytes) = <[u8; 8]>::try_from(data.as_slice()) else {
+            return Ok(false);
+        };
+        let registered_at = UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(bytes));
+        Ok(SystemTime::now()
+            .duration_since(registered_at)
+            .is_ok_and(|elapsed| elapsed <= window))
+    }
+
     pub async fn aci_sessions(&self) -> Result<Vec<(String, Vec<u8>)>, BitpartStoreError> {
         db::sessions::get_all_aci(&self.id, &self.pool).await
     }
 
+    /// Which service id to address a contact by: their ACI, unless the only
+    /// sessions held for them are keyed by their PNI (e.g. a contact found by
+    /// phone number who has not shared their ACI yet).
+    pub async fn service_id_for(&self, uuid: Uuid) -> Result<ServiceId, BitpartStoreError> {
+        let aci_pattern = format!("{uuid}.%");
+        if db::sessions::count_like_aci(&self.id, &aci_pattern, &self.pool).await? == 0 {
+            let pni_pattern = format!("PNI:{uuid}.%");
+            let pni_sessions = db::sessions::count_like_aci(&self.id, &pni_pattern, &self.pool)
+                .await?
+                + db::sessions::count_like_pni(&self.id, &pni_pattern, &self.pool).await?;
+            if pni_sessions > 0 {
+                return Ok(ServiceId::Pni(uuid.into()));
+            }
+        }
+        Ok(ServiceId::Aci(uuid.into()))
+    }
+
+    /// Row counts and payload sizes of every tree in this channel's store, for
+    /// diagnosing store bloat.
+    pub async fn tree_stats(&self) -> Result<Vec<TreeStats>, BitpartStoreError> {
+        db::stats::get_all(&self.id, &self.pool).await
+    }
+
+    /// Forget a single contact along with its message thread. Returns whether
+    /// the contact was stored.
+    pub async fn delete_contact(&self, uuid: Uuid) -> Result<bool, BitpartStoreError> {
+        let removed = db::contacts::remove(&self.id, uuid.as_bytes(), &self.pool).await?;
+        let thread = Thread::Contact(ServiceId::Aci(uuid.into()));
+        db::messages::clear_thread(&self.id, &content::messages_thread_id(&thread), &self.pool)
+            .await?;
+        Ok(removed > 0)
+    }
+
+    /// Forget a single group, its avatar and its message thread. Returns
+    /// whether the group was stored.
+    pub async fn delete_group(
+        &self,
+        master_key: GroupMasterKeyBytes,
+    ) -> Result<bool, BitpartStoreError> {
+        let removed = db::groups::remove_group(&self.id, &master_key, &self.pool).await?;
+        let thread = Thread::Group(master_key);
+        db::messages::clear_thread(&self.id, &content::messages_thread_id(&thread), &self.pool)
+            .await?;
+        Ok(removed > 0)
+    }
+
+    /**
+     * Record a received message by its key, see `seen_message_key`. Returns
+     * whether it is the first time it was received, so messages that Signal
+     * delivers again can be skipped. Messages are remembered for
+     * `SEEN_MESSAGES_TTL`.
+     */
+    pub async fn first_seen(&self, key: &str) -> Result<bool, BitpartStoreError> {
+        let now = unix_now();
+        let expired_before = now - SEEN_MESSAGES_TTL.as_secs() as i64;
+        db::seen_messages::insert(&self.id, key, now, expired_before, &self.pool).await
+    }
+
+    /**
+     * Whether a message was recorded with `first_seen` in the last
+     * `SEEN_MESSAGES_TTL`, without recording it.
+     */
+    pub async fn seen(&self, key: &str) -> Result<bool, BitpartStoreError> {
+        let expired_before = unix_now() - SEEN_MESSAGES_TTL.as_secs() as i64;
+        db::seen_messages::contains(&self.id, key, expired_before, &self.pool).await
+    }
+
     #[cfg(test)]
     async fn temporary() -> Result<Self, BitpartStoreError> {
         use deadpool_sqlite::{Config, Hook, HookError, Runtime};
@@ -236,6 +374,12 @@ impl BitpartStore {
                 content_data blob NOT NULL,
                 PRIMARY KEY (channel_id, thread_id, timestamp)
             );
+            CREATE TABLE signal_seen_messages (
+                channel_id varchar NOT NULL,
+                key varchar NOT NULL,
+                seen_at integer NOT NULL,
+                PRIMARY KEY (channel_id, key)
+            );
         ";
 
         let cfg = Config::new(&path);
@@ -275,6 +419,7 @@ impl BitpartStore {
             id: "test".to_owned(),
             pool,
             trust_new_identities: OnNewIdentity::Reject,
+            trust_window: None,
         })
     }
 
@@ -340,6 +485,15 @@ impl StateStore for BitpartStore {
     ) -> Result<(), Self::StateStoreError> {
         let data = serde_json::to_vec(state)?;
         db::state::set_aci(&self.id, BITPART_KEY_REGISTRATION, &data, &self.pool).await?;
+        if db::state::get_aci(&self.id, BITPART_KEY_REGISTERED_AT, &self.pool)
+            .await?
+            .is_none()
+        {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            self.set_registered_at(now.as_secs()).await?;
+        }
         Ok(())
     }
 
@@ -351,15 +505,10 @@ impl StateStore for BitpartStore {
     }
 
     async fn clear_registration(&mut self) -> Result<(), Self::StateStoreError> {
-        // drop registration data (includes identity keys)
-        db::state::remove_all_aci(&self.id, &self.pool).await?;
-        db::state::remove_all_pni(&self.id, &self.pool).await?;
-        // drop all saved profile (+avatards) and profile keys
-        self.clear_profiles().await?;
-
-        // drop all keys
-        self.aci_protocol_store().clear(true).await?;
-        self.pni_protocol_store().clear(true).await?;
+        // drop registration data (includes identity keys), all saved profiles
+        // (+avatars) and profile keys, and all ACI and PNI keys and sessions,
+        // in one transaction so a failure can't leave a half-cleared account
+        db::registration::remove_all(&self.id, &self.pool).await?;
 
         Ok(())
     }
@@ -567,6 +716,274 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_messages_loads_only_the_window() -> anyhow::Result<()> {
+        let db = BitpartStore::temporary().await?;
+        let content = Content::arbitrary(&mut Gen::new(10));
+        let thread = presage::store::Thread::Contact(content.0.metadata.sender);
+        for ts in 1..=3000 {
+            db.save_message(&thread, content_with_timestamp(&content, ts))
+                .await?;
+        }
+
+        let mut window = db.messages(&thread, 1000..1010).await?;
+        assert_eq!(window.len(), 10);
+        assert_eq!(window.next().unwrap()?.metadata.timestamp, 1000);
+        assert_eq!(window.next_back().unwrap()?.metadata.timestamp, 1009);
+        assert_eq!(window.len(), 8);
+        assert_eq!(window.by_ref().count(), 8);
+        assert!(window.next_back().is_none());
+
+        assert_eq!(db.messages(&thread, ..).await?.len(), 3000);
+        assert_eq!(db.messages(&thread, ..1).await?.len(), 0);
+        assert_eq!(db.messages(&thread, ..0).await?.len(), 0);
+        assert_eq!(db.messages(&thread, 2990..=u64::MAX).await?.len(), 11);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remove_one_time_kyber_pre_key() -> anyhow::Result<()> {
+        use presage::libsignal_service::pre_keys::{KyberPreKeyStoreExt, PreKeysStore};
+
+        let store = BitpartStore::temporary().await?;
+        db::kyber_pre_keys::set_aci(&store.id, 1, b"one-time", false, &store.pool).await?;
+        db::kyber_pre_keys::set_aci(&store.id, 2, b"last resort", true, &store.pool).await?;
+
+        let mut protocol_store = store.aci_protocol_store();
+        assert_eq!(protocol_store.kyber_pre_keys_count(false).await?, 1);
+        assert_eq!(protocol_store.kyber_pre_keys_count(true).await?, 1);
+
+        protocol_store.remove_kyber_pre_key(1.into()).await?;
+        assert_eq!(protocol_store.kyber_pre_keys_count(false).await?, 0);
+        assert_eq!(protocol_store.kyber_pre_keys_count(true).await?, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_service_id_for_pni_only_contact() -> anyhow::Result<()> {
+        let store = BitpartStore::temporary().await?;
+
+        let aci_uuid = Uuid::from_u128(0x11111111_2222_3333_4444_555555555555);
+        let pni_uuid = Uuid::from_u128(0x66666666_7777_8888_9999_AAAAAAAAAAAA);
+        db::sessions::set_aci(&store.id, &format!("{aci_uuid}.1"), b"aci", &store.pool).await?;
+        db::sessions::set_aci(&store.id, &format!("PNI:{pni_uuid}.1"), b"pni", &store.pool).await?;
+
+        assert_eq!(
+            store.service_id_for(aci_uuid).await?,
+            ServiceId::Aci(aci_uuid.into())
+        );
+        assert_eq!(
+            store.service_id_for(pni_uuid).await?,
+            ServiceId::Pni(pni_uuid.into())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tree_stats() -> anyhow::Result<()> {
+        let store = BitpartStore::temporary().await?;
+
+        db::sessions::set_aci(&store.id, "addr1.1", b"data1", &store.pool).await?;
+        db::sessions::set_aci(&store.id, "addr2.1", b"data22", &store.pool).await?;
+        db::sessions::set_aci("other", "addr3.1", b"data3", &store.pool).await?;
+
+        let stats = store.tree_stats().await?;
+        for tree in [
+            "identities",
+            "sessions",
+            "pre_keys",
+            "pni_sessions",
+            "contacts",
+        ] {
+            assert!(stats.iter().any(|s| s.tree == tree), "missing tree {tree}");
+        }
+        let sessions = stats.iter().find(|s| s.tree == "sessions").unwrap();
+        assert_eq!(sessions.rows, 2);
+        assert_eq!(sessions.bytes, 11);
+        let contacts = stats.iter().find(|s| s.tree == "contacts").unwrap();
+        assert_eq!(contacts.rows, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_contact() -> anyhow::Result<()> {
+        let store = BitpartStore::temporary().await?;
+
+        let uuid = Uuid::from_u128(1);
+        let other = Uuid::from_u128(2);
+        let thread = presage::store::Thread::Contact(ServiceId::Aci(uuid.into()));
+        let other_thread = presage::store::Thread::Contact(ServiceId::Aci(other.into()));
+        db::contacts::set(&store.id, uuid.as_bytes(), b"contact", &store.pool).await?;
+        db::contacts::set(&store.id, other.as_bytes(), b"contact", &store.pool).await?;
+        let mut g = Gen::new(10);
+        store
+            .save_message(&thread, Content::arbitrary(&mut g).0)
+            .await?;
+        store
+            .save_message(&other_thread, Content::arbitrary(&mut g).0)
+            .await?;
+
+        assert!(store.delete_contact(uuid).await?);
+        assert!(!store.delete_contact(uuid).await?);
+
+        assert!(
+            db::contacts::get(&store.id, uuid.as_bytes(), &store.pool)
+                .await?
+                .is_none()
+        );
+        assert!(
+            db::contacts::get(&store.id, other.as_bytes(), &store.pool)
+                .await?
+                .is_some()
+        );
+        assert_eq!(store.messages(&thread, ..).await?.count(), 0);
+        assert_eq!(store.messages(&other_thread, ..).await?.count(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_save_synced_contact() -> anyhow::Result<()> {
+        let mut store = BitpartStore::temporary().await?;
+
+        let uuid = Uuid::from_u128(1);
+        let contact = presage::model::contacts::Contact {
+            uuid,
+            phone_number: None,
+            name: "Alice".to_owned(),
+            color: None,
+            verified: Default::default(),
+            profile_key: vec![],
+            expire_timer: 0,
+            expire_timer_version: 1,
+            inbox_position: 0,
+            archived: false,
+            avatar: None,
+        };
+        store.save_contact(&contact).await?;
+
+        let saved = store
+            .contact_by_id(&ServiceId::Aci(uuid.into()))
+            .await?
+            .expect("saved contact");
+        assert_eq!(saved.uuid, uuid);
+        assert_eq!(saved.name, "Alice");
+        assert!(
+            store
+                .contact_by_id(&ServiceId::Aci(Uuid::from_u128(2).into()))
+                .await?
+                .is_none()
+        );
+        assert_eq!(store.contacts().await?.count(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skip_redelivered_messages() -> anyhow::Result<()> {
+        let store = BitpartStore::temporary().await?;
+        let mut g = Gen::new(10);
+        let mut metadata = Content::arbitrary(&mut g).0.metadata;
+
+        // without a guid, a message is known by its sender and timestamp
+        metadata.server_guid = None;
+        let key = seen_message_key(&metadata);
+        assert_eq!(
+            key,
+            format!(
+                "{}:{}",
+                metadata.sender.service_id_string(),
+                metadata.timestamp
+            )
+        );
+        assert!(!store.seen(&key).await?);
+        assert!(store.first_seen(&key).await?);
+        assert!(store.seen(&key).await?);
+        assert!(!store.first_seen(&key).await?);
+
+        let guid = Uuid::from_u128(1);
+        metadata.server_guid = Some(guid);
+        let key = seen_message_key(&metadata);
+        assert_eq!(key, guid.to_string());
+        assert!(store.first_seen(&key).await?);
+        assert!(!store.first_seen(&key).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clear_seen_messages() -> anyhow::Result<()> {
+        let mut store = BitpartStore::temporary().await?;
+
+        let key = Uuid::from_u128(1).to_string();
+        assert!(store.first_seen(&key).await?);
+        store.clear_registration().await?;
+        assert!(!store.seen(&key).await?);
+
+        assert!(store.first_seen(&key).await?);
+        store.clear().await?;
+        assert!(!store.seen(&key).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_group() -> anyhow::Result<()> {
+        let store = BitpartStore::temporary().await?;
+
+        let master_key = [7u8; 32];
+        let thread = presage::store::Thread::Group(master_key);
+        db::groups::set_group(&store.id, &master_key, b"group", &store.pool).await?;
+        store
+            .save_message(&thread, Content::arbitrary(&mut Gen::new(10)).0)
+            .await?;
+
+        assert!(store.delete_group(master_key).await?);
+        assert!(
+            db::groups::get_group(&store.id, &master_key, &store.pool)
+                .await?
+                .is_none()
+        );
+        assert_eq!(store.messages(&thread, ..).await?.count(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_clear_registration_is_atomic() -> anyhow::Result<()> {
+        let mut store = BitpartStore::temporary().await?;
+
+        db::state::set_aci(&store.id, BITPART_KEY_REGISTRATION, b"{}", &store.pool).await?;
+        db::state::set_pni(&store.id, "pni_key", b"pni", &store.pool).await?;
+        db::sessions::set_aci(&store.id, "addr.1", b"session", &store.pool).await?;
+
+        // a failure partway through leaves everything in place
+        const BROKEN: &[&str] = &["signal_state", "signal_missing", "signal_sessions"];
+        assert!(
+            db::registration::remove_all_in(&store.id, BROKEN, &store.pool)
+                .await
+                .is_err()
+        );
+        assert!(
+            db::state::get_aci(&store.id, BITPART_KEY_REGISTRATION, &store.pool)
+                .await?
+                .is_some()
+        );
+
+        store.clear_registration().await?;
+        let stats = store.tree_stats().await?;
+        for tree in ["state", "pni_state", "sessions"] {
+            let stats = stats.iter().find(|s| s.tree == tree).unwrap();
+            assert_eq!(stats.rows, 0, "{tree} was not cleared");
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_profile_key_round_trip() -> anyhow::Result<()> {
         let mut store = BitpartStore::temporary().await?;
@@ -483,7 +483,13 @@ impl IdentityKeyStore for BitpartProtocolStore {
                 } else {
                     match self.store.trust_new_identities {
                         OnNewIdentity::Trust => Ok(true),
-                        OnNewIdentity::Reject => Ok(false),
+                        OnNewIdentity::Reject => {
+                            let trusted = self.store.within_trust_window().await?;
+                            if trusted {
+                                warn!(%address, "trusting changed identity inside trust window");
+                            }
+                            Ok(trusted)
+                        }
                     }
                 }
             }
@@ -596,7 +602,7 @@ impl PreKeysStore for BitpartProtocolStore {
         Ok(all_keys.len())
     }
 
-    async fn kyber_pre_keys_count(&self, _last_resort: bool) -> Result<usize, SignalProtocolError> {
+    async fn kyber_pre_keys_count(&self, last_resort: bool) -> Result<usize, SignalProtocolError> {
         debug!("kyber_pre_keys_count");
         let all_keys = if self.is_pni {
             db::kyber_pre_keys::get_all_pni(&self.store.id, &self.store.pool).await
@@ -607,7 +613,12 @@ impl PreKeysStore for BitpartProtocolStore {
             error!(%error, "store error");
             SignalProtocolError::InvalidState("kyber_pre_keys_count", "store error".into())
         })?;
-        Ok(all_keys.len())
+        // One-time and last-resort keys share a table, so count only the kind
+        // asked for.
+        Ok(all_keys
+            .iter()
+            .filter(|(_, _, is_last_resort)| *is_last_resort == last_resort)
+            .count())
     }
 
     async fn signed_prekey_id(&self) -> Result<Option<SignedPreKeyId>, SignalProtocolError> {
@@ -792,6 +803,7 @@ mod tests {
         store::Store,
     };
     use quickcheck::{Arbitrary, Gen, TestResult};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
     use super::BitpartStore;
     use rand::prelude::*;
@@ -874,6 +886,37 @@ mod tests {
             .unwrap()
     }
 
+    #[tokio::test]
+    async fn test_trust_window() {
+        let store = BitpartStore::temporary()
+            .await
+            .unwrap()
+            .with_trust_window(Duration::from_secs(3600));
+        let mut db = store.aci_protocol_store();
+        let addr = protocol::ProtocolAddress::new("addr".to_owned(), 1u8.try_into().unwrap());
+        let old_key = protocol::IdentityKey::new(KeyPair::arbitrary(&mut Gen::new(1)).0.public_key);
+        let new_key = protocol::IdentityKey::new(KeyPair::arbitrary(&mut Gen::new(1)).0.public_key);
+        db.save_identity(&addr, &old_key).await.unwrap();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        store.set_registered_at(now - 60).await.unwrap();
+        assert!(
+            db.is_trusted_identity(&addr, &new_key, Direction::Receiving)
+                .await
+                .unwrap()
+        );
+
+        store.set_registered_at(now - 7200).await.unwrap();
+        assert!(
+            !db.is_trusted_identity(&addr, &new_key, Direction::Receiving)
+                .await
+                .unwrap()
+        );
+    }
+
     #[quickcheck_async::tokio]
     async fn test_save_get_kyber_prekey(id: KyberPreKeyId, record: KyberPreKeyRecord) -> bool {
         let mut db = BitpartStore::temporary()